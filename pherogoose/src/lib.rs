@@ -3,23 +3,63 @@
 //! watchers trying to guess the true color of the magic goose by adding/removing
 //! a `PrimaryColor` to the shared `GossipSet`.
 
-use std::{cell::RefCell, collections::VecDeque, iter, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    hash::{Hash, Hasher},
+    iter,
+    rc::Rc,
+};
 
 use pheromessage::{
     data::{GossipSet, GossipSetMessage},
+    rng::derive_rng,
+    seen::UnboundedSeenStore,
+    select::UniformSelector,
     Delivery, Gossip, Message, PreferentialGossip, UniformGossip,
 };
 use rand::prelude::*;
 use wasm_bindgen::prelude::*;
 
-/// An implementation of `Delivery` that delivers to `RefCell<VecDeque>` receivers as endpoints.
+/// A shared queue of messages for a node, identified by its address (so clones of the
+/// same queue are equal).
+#[derive(Debug)]
+struct MessageQueue<M>(Rc<RefCell<VecDeque<M>>>);
+
+impl<M> Default for MessageQueue<M> {
+    fn default() -> Self {
+        MessageQueue(Rc::default())
+    }
+}
+
+impl<M> Clone for MessageQueue<M> {
+    fn clone(&self) -> Self {
+        MessageQueue(self.0.clone())
+    }
+}
+
+impl<M> PartialEq for MessageQueue<M> {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<M> Eq for MessageQueue<M> {}
+
+impl<M> Hash for MessageQueue<M> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state);
+    }
+}
+
+/// An implementation of `Delivery` that delivers to `MessageQueue` receivers as endpoints.
 /// Since in this wasm library we'll never be multi-threaded, it's safe to just put messages
 /// into a queue for each node in the gossip network.
 struct Queues();
 /// The singleton `Queues`.
 const QUEUES: Queues = Queues();
 
-impl<M> Delivery<M, Rc<RefCell<VecDeque<M>>>> for Queues
+impl<M> Delivery<M, MessageQueue<M>> for Queues
 where
     M: Clone,
 {
@@ -27,11 +67,11 @@ where
 
     fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), ()>
     where
-        I: ExactSizeIterator<Item = &'a Rc<RefCell<VecDeque<M>>>>,
+        I: ExactSizeIterator<Item = &'a MessageQueue<M>>,
         M: 'a,
     {
         for endpoint in endpoints {
-            endpoint.borrow_mut().push_back(message.clone());
+            endpoint.0.borrow_mut().push_back(message.clone());
         }
         Ok(())
     }
@@ -70,25 +110,46 @@ where
     watcher_colors: Vec<u32>,
     /// The individual watchers in the cult.
     watchers: Vec<GooseWatcher<G>>,
+    /// The source of all randomness in the simulation (other than in the gossips themselves).
+    rng: StdRng,
 }
 
 /// The type of peer in the network (a reference to their message queue).
-type GooseWatcherPeer = Rc<RefCell<VecDeque<GossipSetMessage<PrimaryColor>>>>;
+type GooseWatcherPeer = MessageQueue<GossipSetMessage<PrimaryColor>>;
 // The type of message ID
 type MesageId = <GossipSetMessage<PrimaryColor> as Message>::I;
+/// The type of peer selection in gossip for this network.
+type GooseSelector = UniformSelector<StdRng>;
 /// The type of uniform gossip for this network.
-type UniformGooseGossip =
-    UniformGossip<GooseWatcherPeer, GossipSet<PrimaryColor>, Queues, MesageId>;
+type UniformGooseGossip = UniformGossip<
+    GooseWatcherPeer,
+    GossipSet<PrimaryColor>,
+    Queues,
+    MesageId,
+    UnboundedSeenStore<MesageId>,
+    GooseSelector,
+>;
 /// The type of preferential gossip for this network.
-type PreferentialGooseGossip =
-    PreferentialGossip<GooseWatcherPeer, GossipSet<PrimaryColor>, Queues, MesageId>;
+type PreferentialGooseGossip = PreferentialGossip<
+    GooseWatcherPeer,
+    GossipSet<PrimaryColor>,
+    Queues,
+    MesageId,
+    UnboundedSeenStore<MesageId>,
+    GooseSelector,
+>;
 
 /// Create a new uniform cult with the given number of watchers and the fanout to use
-/// for gossipping.
-fn new_uniform(num_watchers: usize, fanout: usize) -> GooseCult<UniformGooseGossip> {
-    let watcher_colors = iter::repeat(0_u32).take(num_watchers).collect();
-    let message_queues: Vec<GooseWatcherPeer> =
-        iter::repeat_with(Rc::default).take(num_watchers).collect();
+/// for gossipping, with all randomness coming from `rng`.
+fn new_uniform(
+    num_watchers: usize,
+    fanout: usize,
+    mut rng: StdRng,
+) -> GooseCult<UniformGooseGossip> {
+    let watcher_colors = iter::repeat_n(0_u32, num_watchers).collect();
+    let message_queues: Vec<GooseWatcherPeer> = iter::repeat_with(MessageQueue::default)
+        .take(num_watchers)
+        .collect();
     // First create a Vec<> with all the gossips
     let mut gossips = Vec::with_capacity(num_watchers);
     for i in 0..num_watchers {
@@ -103,12 +164,14 @@ fn new_uniform(num_watchers: usize, fanout: usize) -> GooseCult<UniformGooseGoss
             }
         }
         // Add the node
-        gossips.push(UniformGossip::create(peers, fanout, data, QUEUES));
+        let selector = UniformSelector::with_rng(derive_rng(&mut rng));
+        gossips
+            .push(UniformGossip::create(peers, fanout, data, QUEUES).with_peer_selector(selector));
     }
     // Then add the message queues to create the network
     let watchers = gossips
         .into_iter()
-        .zip(message_queues.into_iter())
+        .zip(message_queues)
         .map(|(gossip, message_queue)| GooseWatcher {
             gossip,
             message_queue,
@@ -117,19 +180,23 @@ fn new_uniform(num_watchers: usize, fanout: usize) -> GooseCult<UniformGooseGoss
     GooseCult {
         watcher_colors,
         watchers,
+        rng,
     }
 }
 
 /// Create a new preferential cult with the given number of watchers and the fanout to use
-/// for gossipping, and the number of privileged high priests among the watchers.
+/// for gossipping, and the number of privileged high priests among the watchers, with all
+/// randomness coming from `rng`.
 fn new_preferential(
     num_watchers: usize,
     num_high_priests: usize,
     fanout: usize,
+    mut rng: StdRng,
 ) -> GooseCult<PreferentialGooseGossip> {
-    let watcher_colors = iter::repeat(0_u32).take(num_watchers).collect();
-    let message_queues: Vec<GooseWatcherPeer> =
-        iter::repeat_with(Rc::default).take(num_watchers).collect();
+    let watcher_colors = iter::repeat_n(0_u32, num_watchers).collect();
+    let message_queues: Vec<GooseWatcherPeer> = iter::repeat_with(MessageQueue::default)
+        .take(num_watchers)
+        .collect();
     // First create a Vec<> with all the gossips
     let mut gossips = Vec::with_capacity(num_watchers);
     let num_secondaries = num_watchers - num_high_priests;
@@ -159,19 +226,16 @@ fn new_preferential(
             }
         }
         // Add the node
-        gossips.push(PreferentialGossip::create(
-            primaries,
-            secondaries,
-            primary,
-            fanout,
-            data,
-            QUEUES,
-        ));
+        let selector = UniformSelector::with_rng(derive_rng(&mut rng));
+        gossips.push(
+            PreferentialGossip::create(primaries, secondaries, primary, fanout, data, QUEUES)
+                .with_peer_selector(selector),
+        );
     }
     // Then add the message queues to create the network
     let watchers = gossips
         .into_iter()
-        .zip(message_queues.into_iter())
+        .zip(message_queues)
         .map(|(gossip, message_queue)| GooseWatcher {
             gossip,
             message_queue,
@@ -180,6 +244,7 @@ fn new_preferential(
     GooseCult {
         watcher_colors,
         watchers,
+        rng,
     }
 }
 
@@ -209,11 +274,11 @@ where
         let mut some_messages_remaining = false;
         // Go over the watchers in random order every time so as not to bias.
         let mut order: Vec<_> = (0..self.watchers.len()).collect();
-        order.shuffle(&mut thread_rng());
+        order.shuffle(&mut self.rng);
         for i in order {
             let watcher = self.watchers.get_mut(i).unwrap();
             let color = self.watcher_colors.get_mut(i).unwrap();
-            if let Some(message) = watcher.message_queue.borrow_mut().pop_front() {
+            if let Some(message) = watcher.message_queue.0.borrow_mut().pop_front() {
                 watcher.gossip.receive(&message).unwrap();
                 *color = color_for_set(watcher.gossip.data());
                 some_messages_remaining = true;
@@ -226,7 +291,7 @@ where
     pub fn add_color(&mut self, inspired_watcher: usize, color: PrimaryColor) {
         self.watchers[inspired_watcher]
             .gossip
-            .update(&GossipSetMessage::add(color))
+            .update(&GossipSetMessage::add_with_rng(color, &mut self.rng))
             .unwrap();
         self.watcher_colors[inspired_watcher] =
             color_for_set(self.watchers[inspired_watcher].gossip.data());
@@ -236,7 +301,7 @@ where
     pub fn remove_color(&mut self, inspired_watcher: usize, color: PrimaryColor) {
         self.watchers[inspired_watcher]
             .gossip
-            .update(&GossipSetMessage::remove(color))
+            .update(&GossipSetMessage::remove_with_rng(color, &mut self.rng))
             .unwrap();
         self.watcher_colors[inspired_watcher] =
             color_for_set(self.watchers[inspired_watcher].gossip.data());
@@ -255,7 +320,15 @@ impl UniformCult {
     /// while gossipping (the number of watchers to talk to when spreading gossip).
     pub fn new(num_watchers: usize, fanout: usize) -> UniformCult {
         UniformCult {
-            cult: new_uniform(num_watchers, fanout),
+            cult: new_uniform(num_watchers, fanout, StdRng::from_entropy()),
+        }
+    }
+
+    /// Create a new uniform cult like `new()`, but with all randomness generated from
+    /// the given `seed` so that the simulation can be replayed exactly.
+    pub fn new_seeded(num_watchers: usize, fanout: usize, seed: u64) -> UniformCult {
+        UniformCult {
+            cult: new_uniform(num_watchers, fanout, StdRng::seed_from_u64(seed)),
         }
     }
 
@@ -295,7 +368,30 @@ impl StriatedCult {
     /// word of any new gossip.
     pub fn new(num_watchers: usize, num_high_priests: usize, fanout: usize) -> StriatedCult {
         StriatedCult {
-            cult: new_preferential(num_watchers, num_high_priests, fanout),
+            cult: new_preferential(
+                num_watchers,
+                num_high_priests,
+                fanout,
+                StdRng::from_entropy(),
+            ),
+        }
+    }
+
+    /// Create a new striated cult like `new()`, but with all randomness generated from
+    /// the given `seed` so that the simulation can be replayed exactly.
+    pub fn new_seeded(
+        num_watchers: usize,
+        num_high_priests: usize,
+        fanout: usize,
+        seed: u64,
+    ) -> StriatedCult {
+        StriatedCult {
+            cult: new_preferential(
+                num_watchers,
+                num_high_priests,
+                fanout,
+                StdRng::seed_from_u64(seed),
+            ),
         }
     }
 
@@ -346,4 +442,19 @@ mod tests {
         cult.add_color(50, PrimaryColor::Red);
         assert_eq!(255 << 16, cult.cult.watcher_colors[50]);
     }
+
+    #[test]
+    fn seeded_replay() {
+        let run = || {
+            let mut cult = StriatedCult::new_seeded(100, 10, 3, 17);
+            cult.add_color(50, PrimaryColor::Green);
+            let mut history = Vec::new();
+            for _ in 0..5 {
+                cult.tick();
+                history.push(cult.cult.watcher_colors.clone());
+            }
+            history
+        };
+        assert_eq!(run(), run());
+    }
 }
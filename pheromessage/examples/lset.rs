@@ -5,22 +5,34 @@ use std::{
     fmt::Debug,
     fs::OpenOptions,
     io::prelude::*,
-    sync::mpsc::{self, RecvTimeoutError},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
     thread::spawn,
     time::{Duration, Instant},
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use hdrhistogram::Histogram;
 use itertools::Itertools;
-use log::{debug, info, LevelFilter};
+use log::{info, LevelFilter};
 use pheromessage::{
+    bloom::BloomSeenStore,
     data::{GossipSet, GossipSetAction},
     multiplex::{
-        preferential_local_gossip_set, uniform_local_gossip_set, Envelope, LocalGossipNodeGroup,
-        NodeGroupInfo,
+        latency_local_gossip_set, latency_local_gossip_set_with_seen_store,
+        plumtree_local_gossip_set, plumtree_local_gossip_set_with_seen_store,
+        preferential_local_gossip_set_with_rng,
+        preferential_local_gossip_set_with_seen_store_and_rng, rumor_local_gossip_set,
+        rumor_local_gossip_set_with_seen_store, uniform_local_gossip_set_with_rng,
+        uniform_local_gossip_set_with_seen_store_and_rng, Envelope, LatencyMatrix,
+        LocalGossipNodeGroup, LocalPlumtreeGossipSetNodeGroup, NodeGroupInfo,
     },
-    Gossip, SharedData,
+    plumtree::{PlumtreeConfig, PlumtreeControl, PlumtreeMessage},
+    rumor::{HopCount, RumorConfig, StoppingRule},
+    seen::SeenStore,
+    ReceiveFrom, SharedData,
 };
 use rand::prelude::*;
 use serde_json::json;
@@ -57,13 +69,105 @@ struct Args {
     /// If specified, statistics will be appended as a single JSON line to this file for this run.
     #[arg(short, long)]
     result_file: Option<String>,
+
+    /// If specified, nodes will keep track of seen messages using Bloom filters with this false positive rate
+    /// (instead of remembering every message ID exactly).
+    #[arg(short, long)]
+    bloom_false_positive_rate: Option<f64>,
+
+    /// The number of messages every generation of Bloom filters is sized for (if Bloom filters are used).
+    #[arg(short = 'c', long, default_value_t = 100_000)]
+    bloom_capacity: usize,
+
+    /// If specified, the network topology and the peers every node gossips to are generated from this seed.
+    #[arg(short, long)]
+    seed: Option<u64>,
+
+    /// If specified, we'll use rumor mongering with this rule for when nodes lose interest in a rumor.
+    #[arg(long, value_enum, conflicts_with = "primaries")]
+    rumor: Option<RumorStoppingRule>,
+
+    /// The parameter `k` of the rumor mongering stopping rule (the counter, or the inverse of the
+    /// probability of losing interest).
+    #[arg(long, default_value_t = 2)]
+    rumor_k: usize,
+
+    /// The maximum number of hops a rumor is passed along (if rumor mongering is used).
+    #[arg(long, default_value_t = 16)]
+    max_hops: usize,
+
+    /// If specified, we'll use Plumtree gossip (eager push along a spanning tree, lazy push to everyone else).
+    #[arg(long, conflicts_with_all = ["primaries", "rumor"])]
+    plumtree: bool,
+
+    /// How long (in milliseconds) Plumtree nodes wait for an announced message before grafting it.
+    #[arg(long, default_value_t = 100)]
+    graft_timeout_millis: u64,
+
+    /// If more than 0, nodes are spread across this many regions with simulated latencies between
+    /// them, and select the peers to gossip to by latency.
+    #[arg(long, default_value_t = 0, conflicts_with_all = ["primaries", "rumor", "plumtree"])]
+    regions: usize,
+
+    /// The simulated latency (in microseconds) between nodes in the same region.
+    #[arg(long, default_value_t = 200)]
+    region_latency_micros: u64,
+
+    /// The simulated latency (in microseconds) between nodes in different regions.
+    #[arg(long, default_value_t = 20_000)]
+    cross_region_latency_micros: u64,
+
+    /// The fraction of the fanout that goes to the nearest peers when using regions (the rest goes
+    /// to random farther peers).
+    #[arg(long, default_value_t = 0.5, value_parser = parse_fraction)]
+    near_fraction: f64,
+}
+
+/// Parse a fraction between 0 and 1 (inclusive) from the command line.
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(format!("{fraction} is not between 0 and 1"))
+    }
+}
+
+/// The stopping rules for rumor mongering that can be chosen on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RumorStoppingRule {
+    /// Lose interest after receiving the rumor `k` times.
+    Counter,
+    /// Lose interest with probability `1/k` after every time the rumor is spread.
+    BlindCoin,
+    /// Lose interest with probability `1/k` every time the rumor is received again.
+    Feedback,
+}
+
+impl RumorStoppingRule {
+    fn with_k(self, k: usize) -> StoppingRule {
+        match self {
+            RumorStoppingRule::Counter => StoppingRule::Counter(k),
+            RumorStoppingRule::BlindCoin => StoppingRule::BlindCoin(k),
+            RumorStoppingRule::Feedback => StoppingRule::Feedback(k),
+        }
+    }
 }
 
+/// The number of gossip messages received by all the nodes, to compare how redundant different
+/// gossip modes are.
+static GOSSIP_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of control messages (for Plumtree) received by all the nodes.
+static CONTROL_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
 /// The action that can be taken by each node upon receiving a message.
 #[derive(Debug, Clone)]
 enum Action {
     /// A gossip message about modifying a set (as sent from another node).
     GossipModifySet(GossipSetAction<u128>),
+    /// A Plumtree control message (as sent from another node).
+    PlumtreeControl(PlumtreeControl<u128>),
     /// A primary message about modifying a set (as sent from the main program).
     ModifySet(GossipSetAction<u128>),
     /// Terminate the node.
@@ -82,6 +186,10 @@ enum Action {
 struct Message {
     id: u128,
     action: Action,
+    /// The number of hops this message has been gossipped along (for rumor mongering).
+    hops: usize,
+    /// The node that sent this message (for Plumtree).
+    sender: Option<NodeGroupInfo>,
 }
 
 impl Message {
@@ -89,6 +197,8 @@ impl Message {
         Message {
             id: thread_rng().gen(),
             action,
+            hops: 0,
+            sender: None,
         }
     }
 }
@@ -101,6 +211,48 @@ impl pheromessage::Message for Message {
     }
 }
 
+impl HopCount for Message {
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        Message {
+            id: self.id,
+            action: self.action.clone(),
+            hops: self.hops + 1,
+            sender: self.sender,
+        }
+    }
+}
+
+impl PlumtreeMessage<NodeGroupInfo> for Message {
+    fn sender(&self) -> Option<NodeGroupInfo> {
+        self.sender
+    }
+
+    fn control(&self) -> Option<PlumtreeControl<u128>> {
+        match &self.action {
+            Action::PlumtreeControl(control) => Some(control.clone()),
+            _ => None,
+        }
+    }
+
+    fn forward_from(&self, sender: NodeGroupInfo) -> Self {
+        Message {
+            sender: Some(sender),
+            ..self.clone()
+        }
+    }
+
+    fn control_from(sender: NodeGroupInfo, control: PlumtreeControl<u128>) -> Self {
+        Message {
+            sender: Some(sender),
+            ..Message::new(Action::PlumtreeControl(control))
+        }
+    }
+}
+
 impl SharedData<Message> for GossipSet<u128> {
     fn update(&mut self, message: &Message) {
         if let Action::GossipModifySet(action) = message.action {
@@ -117,31 +269,91 @@ fn run_node_group<G>(
     mut node_group: LocalGossipNodeGroup<G, GossipSet<u128>, Message>,
 ) -> Result<(), G::Error>
 where
-    G: Gossip<Message, GossipSet<u128>>,
+    G: ReceiveFrom<Message, GossipSet<u128>, NodeGroupInfo>,
 {
     while let Ok(envelope) = node_group.receiver.recv() {
         let gossip = &mut node_group.gossips[envelope.node_index];
-        let message = envelope.message;
-        match message.action {
-            Action::GossipModifySet(_) => gossip.receive(&message)?,
-            Action::ModifySet(v) => {
-                // This is a bit confusing, but when the main program is asking me to modify
-                // the set, I should use the `update()` function on the gossip but use a GossipModifySet
-                // action so that's the one that's gossipped to the other nodes.
-                gossip.update(&Message {
-                    id: message.id,
-                    action: Action::GossipModifySet(v),
-                })?
+        if !handle_message(gossip, envelope)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Thread function for running Plumtree gossip nodes, which also have to be ticked regularly
+/// (every `tick_period`) to graft missing messages.
+fn run_plumtree_node_group<L>(
+    mut node_group: LocalPlumtreeGossipSetNodeGroup<u128, Message, u128, L>,
+    tick_period: Duration,
+) -> Result<(), Box<mpsc::SendError<Envelope<Message>>>>
+where
+    L: SeenStore<u128>,
+{
+    let mut next_tick = Instant::now() + tick_period;
+    loop {
+        let now = Instant::now();
+        if now >= next_tick {
+            for gossip in node_group.gossips.iter_mut() {
+                gossip.tick(now)?;
             }
-            Action::Terminate => break,
-            Action::Query { element, answer } => {
-                answer.send(gossip.data().is_present(&element)).unwrap()
+            next_tick = now + tick_period;
+        }
+        match node_group
+            .receiver
+            .recv_timeout(next_tick.saturating_duration_since(now))
+        {
+            Ok(envelope) => {
+                let gossip = &mut node_group.gossips[envelope.node_index];
+                if !handle_message(gossip, envelope)? {
+                    break;
+                }
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
     }
     Ok(())
 }
 
+/// Handle a message sent to a gossip node. Returns `false` if the node should terminate.
+fn handle_message<G>(gossip: &mut G, envelope: Envelope<Message>) -> Result<bool, G::Error>
+where
+    G: ReceiveFrom<Message, GossipSet<u128>, NodeGroupInfo>,
+{
+    let message = envelope.message;
+    // Tell the node who sent the message (if another node did), so it isn't echoed back.
+    let receive = |gossip: &mut G, message: &Message| match &envelope.from {
+        Some(from) => gossip.receive_from(message, from),
+        None => gossip.receive(message),
+    };
+    match message.action {
+        Action::GossipModifySet(_) => {
+            GOSSIP_MESSAGES.fetch_add(1, Ordering::Relaxed);
+            receive(gossip, &message)?
+        }
+        Action::PlumtreeControl(_) => {
+            CONTROL_MESSAGES.fetch_add(1, Ordering::Relaxed);
+            receive(gossip, &message)?
+        }
+        Action::ModifySet(v) => {
+            // This is a bit confusing, but when the main program is asking me to modify
+            // the set, I should use the `update()` function on the gossip but use a GossipModifySet
+            // action so that's the one that's gossipped to the other nodes.
+            gossip.update(&Message {
+                id: message.id,
+                action: Action::GossipModifySet(v),
+                hops: 0,
+                sender: None,
+            })?
+        }
+        Action::Terminate => return Ok(false),
+        Action::Query { element, answer } => {
+            answer.send(gossip.data().is_present(&element)).unwrap()
+        }
+    }
+    Ok(true)
+}
+
 /// An aggregate of latency.
 #[derive(Clone)]
 struct LatencyAggregate {
@@ -295,10 +507,11 @@ impl Aggregator for PreferentialGossipAggregator {
 
 enum MainAggregator {
     Uniform(UniformGossipAggregator),
-    Preferential(PreferentialGossipAggregator),
+    Preferential(Box<PreferentialGossipAggregator>),
 }
 
 struct EndResult {
+    overall_lost_percent: f64,
     overall_mean_latency_micros: f64,
     overall_percentile_latency_micros: HashMap<u8, u64>,
     primary_mean_latency_micros: Option<f64>,
@@ -344,6 +557,10 @@ impl MainAggregator {
     pub fn end_result(&self) -> EndResult {
         match self {
             MainAggregator::Uniform(a) => EndResult {
+                overall_lost_percent: lost_percent(
+                    a.lost_elements,
+                    a.lost_elements + a.aggregate.num_elements,
+                ),
                 overall_mean_latency_micros: a.aggregate.mean_micros(),
                 overall_percentile_latency_micros: get_percentiles(&a.aggregate.histogram),
                 primary_mean_latency_micros: None,
@@ -352,6 +569,10 @@ impl MainAggregator {
                 secondary_percentile_latency_micros: None,
             },
             MainAggregator::Preferential(a) => EndResult {
+                overall_lost_percent: lost_percent(
+                    a.lost_in_primaries + a.lost_in_secondaries,
+                    a.lost_in_primaries + a.lost_in_secondaries + a.overall_aggregate.num_elements,
+                ),
                 overall_mean_latency_micros: a.overall_aggregate.mean_micros(),
                 overall_percentile_latency_micros: get_percentiles(&a.overall_aggregate.histogram),
                 primary_mean_latency_micros: Some(a.primaries_aggregate.mean_micros()),
@@ -369,7 +590,7 @@ impl MainAggregator {
 
 fn create_aggregator(args: &Args) -> MainAggregator {
     if args.primaries > 0 {
-        MainAggregator::Preferential(PreferentialGossipAggregator::new(args.primaries))
+        MainAggregator::Preferential(Box::new(PreferentialGossipAggregator::new(args.primaries)))
     } else {
         MainAggregator::Uniform(UniformGossipAggregator::default())
     }
@@ -410,6 +631,7 @@ fn wait_for_element(
             .send(Envelope {
                 message,
                 node_index: target_node_index,
+                from: None,
             })
             .unwrap();
         // Don't wait for the answer beyond our end time
@@ -428,7 +650,7 @@ fn wait_for_element(
         let answer = match my_rx.recv_timeout(timeout) {
             Ok(answer) => answer,
             Err(RecvTimeoutError::Timeout) => return timeout_result,
-            Err(e) => Err(e).unwrap(),
+            Err(e) => panic!("Error waiting for an answer: {e:?}"),
         };
         if answer {
             // The target node has seen the element we inserted.
@@ -441,20 +663,43 @@ fn wait_for_element(
     }
 }
 
-fn run_network<G>(
+/// The gossip traffic generated over a run of the network.
+struct Traffic {
+    inserted_elements: usize,
+    gossip_messages: usize,
+    control_messages: usize,
+}
+
+impl Traffic {
+    pub fn per_element(&self, messages: usize) -> f64 {
+        if self.inserted_elements == 0 {
+            0.0
+        } else {
+            messages as f64 / self.inserted_elements as f64
+        }
+    }
+}
+
+/// Run the given network, with every node group in its own thread running `run_group`.
+fn run_network<G, F, E>(
     network: Vec<LocalGossipNodeGroup<G, GossipSet<u128>, Message>>,
     args: &Args,
-) -> MainAggregator
+    run_group: F,
+) -> (MainAggregator, Traffic)
 where
-    G: Gossip<Message, GossipSet<u128>> + Send + 'static,
-    G::Error: Send + Debug,
+    G: ReceiveFrom<Message, GossipSet<u128>, NodeGroupInfo> + Send + 'static,
+    F: Fn(LocalGossipNodeGroup<G, GossipSet<u128>, Message>) -> Result<(), E>
+        + Send
+        + Copy
+        + 'static,
+    E: Send + Debug + 'static,
 {
     let num_groups = network.len();
     let mut threads = Vec::with_capacity(num_groups);
     let mut senders = Vec::with_capacity(num_groups);
     for group in network.into_iter() {
         senders.push(group.sender.clone());
-        threads.push(spawn(move || run_node_group(group)))
+        threads.push(spawn(move || run_group(group)))
     }
 
     info!("Running");
@@ -469,6 +714,7 @@ where
     let mut next_log_target = start + log_period;
     let end = start + Duration::from_secs(args.time);
     let mut aggregator = create_aggregator(args);
+    let mut inserted_elements = 0;
     while Instant::now() < end {
         // Generate a random element to insert, and choose a start and target node
         let element: u128 = thread_rng().gen();
@@ -482,8 +728,10 @@ where
             .send(Envelope {
                 message,
                 node_index: start_node_info.node_index,
+                from: None,
             })
             .unwrap();
+        inserted_elements += 1;
         // Wait for the element to appear in the target
         let outcome = wait_for_element(
             &senders[target_node_info.group_index],
@@ -509,24 +757,34 @@ where
     }
 
     info!("Terminating");
+    // Nodes that gossip to a node group that already terminated just fail to reach it and
+    // keep going, so every group is still around to get its terminate signal.
     for sender in senders {
-        if let Err(e) = sender.send(Envelope {
-            message: Message::new(Action::Terminate),
-            node_index: 0,
-        }) {
-            // There's a race in the end when one node terminates and the other nodes try to gossip to it
-            // then those nodes end up failing to send to that node and exit, so I can't send to them...
-            // For that I just ignore errors at the end.
-            debug!("Error sending terminate signal: {:?}", e);
-        }
+        sender
+            .send(Envelope {
+                message: Message::new(Action::Terminate),
+                node_index: 0,
+                from: None,
+            })
+            .unwrap();
     }
     for thread in threads {
-        if let Err(e) = thread.join().unwrap() {
-            // See above why I'm not worried about errors from the threads.
-            debug!("Error sending terminate signal: {:?}", e);
-        }
+        thread.join().unwrap().unwrap();
     }
-    aggregator
+    let traffic = Traffic {
+        inserted_elements,
+        gossip_messages: GOSSIP_MESSAGES.load(Ordering::Relaxed),
+        control_messages: CONTROL_MESSAGES.load(Ordering::Relaxed),
+    };
+    info!(
+        "Nodes received {} gossip messages ({:.2} per element) and {} control messages ({:.2} per element) for {} inserted elements.",
+        traffic.gossip_messages,
+        traffic.per_element(traffic.gossip_messages),
+        traffic.control_messages,
+        traffic.per_element(traffic.control_messages),
+        traffic.inserted_elements,
+    );
+    (aggregator, traffic)
 }
 
 fn add_percentiles(json: &mut serde_json::Value, prefix: &str, percentiles: &HashMap<u8, u64>) {
@@ -549,27 +807,157 @@ fn main() {
         .unwrap();
     info!("Creating network");
     let num_groups = num_cpus::get();
-    let results = if args.primaries == 0 {
-        run_network(
-            uniform_local_gossip_set(args.nodes, num_groups, args.peers_per_node, args.fanout),
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let rumor_config = args.rumor.map(|rule| RumorConfig {
+        fanout: args.fanout,
+        max_hops: args.max_hops,
+        stopping_rule: rule.with_k(args.rumor_k),
+    });
+    let plumtree_config = PlumtreeConfig {
+        graft_timeout: Duration::from_millis(args.graft_timeout_millis),
+        ..PlumtreeConfig::default()
+    };
+    // Tick often enough that grafts aren't delayed much beyond their timeout.
+    let tick_period = (plumtree_config.graft_timeout / 4).max(Duration::from_millis(1));
+    let (results, traffic) = match (rumor_config, args.primaries, args.bloom_false_positive_rate) {
+        _ if args.plumtree => match args.bloom_false_positive_rate {
+            None => run_network(
+                plumtree_local_gossip_set(
+                    args.nodes,
+                    num_groups,
+                    args.peers_per_node,
+                    plumtree_config,
+                    &mut rng,
+                ),
+                &args,
+                move |group| run_plumtree_node_group(group, tick_period),
+            ),
+            Some(false_positive_rate) => run_network(
+                plumtree_local_gossip_set_with_seen_store(
+                    args.nodes,
+                    num_groups,
+                    args.peers_per_node,
+                    plumtree_config,
+                    || BloomSeenStore::new(args.bloom_capacity, false_positive_rate, 2),
+                    &mut rng,
+                ),
+                &args,
+                move |group| run_plumtree_node_group(group, tick_period),
+            ),
+        },
+        _ if args.regions > 0 => {
+            let matrix = LatencyMatrix::regions(
+                args.nodes,
+                args.regions,
+                Duration::from_micros(args.region_latency_micros),
+                Duration::from_micros(args.cross_region_latency_micros),
+            );
+            match args.bloom_false_positive_rate {
+                None => run_network(
+                    latency_local_gossip_set(
+                        num_groups,
+                        args.peers_per_node,
+                        args.fanout,
+                        matrix,
+                        args.near_fraction,
+                        &mut rng,
+                    ),
+                    &args,
+                    run_node_group,
+                ),
+                Some(false_positive_rate) => run_network(
+                    latency_local_gossip_set_with_seen_store(
+                        num_groups,
+                        args.peers_per_node,
+                        args.fanout,
+                        matrix,
+                        args.near_fraction,
+                        || BloomSeenStore::new(args.bloom_capacity, false_positive_rate, 2),
+                        &mut rng,
+                    ),
+                    &args,
+                    run_node_group,
+                ),
+            }
+        }
+        (Some(config), _, None) => run_network(
+            rumor_local_gossip_set(
+                args.nodes,
+                num_groups,
+                args.peers_per_node,
+                config,
+                &mut rng,
+            ),
             &args,
-        )
-    } else {
-        run_network(
-            preferential_local_gossip_set(
+            run_node_group,
+        ),
+        (Some(config), _, Some(false_positive_rate)) => run_network(
+            rumor_local_gossip_set_with_seen_store(
+                args.nodes,
+                num_groups,
+                args.peers_per_node,
+                config,
+                || BloomSeenStore::new(args.bloom_capacity, false_positive_rate, 2),
+                &mut rng,
+            ),
+            &args,
+            run_node_group,
+        ),
+        (None, 0, None) => run_network(
+            uniform_local_gossip_set_with_rng(
+                args.nodes,
+                num_groups,
+                args.peers_per_node,
+                args.fanout,
+                &mut rng,
+            ),
+            &args,
+            run_node_group,
+        ),
+        (None, 0, Some(false_positive_rate)) => run_network(
+            uniform_local_gossip_set_with_seen_store_and_rng(
+                args.nodes,
+                num_groups,
+                args.peers_per_node,
+                args.fanout,
+                || BloomSeenStore::new(args.bloom_capacity, false_positive_rate, 2),
+                &mut rng,
+            ),
+            &args,
+            run_node_group,
+        ),
+        (None, _, None) => run_network(
+            preferential_local_gossip_set_with_rng(
                 args.nodes,
                 num_groups,
                 args.peers_per_node,
                 args.primaries,
                 args.fanout,
+                &mut rng,
             ),
             &args,
-        )
+            run_node_group,
+        ),
+        (None, _, Some(false_positive_rate)) => run_network(
+            preferential_local_gossip_set_with_seen_store_and_rng(
+                args.nodes,
+                num_groups,
+                args.peers_per_node,
+                args.primaries,
+                args.fanout,
+                || BloomSeenStore::new(args.bloom_capacity, false_positive_rate, 3),
+                &mut rng,
+            ),
+            &args,
+            run_node_group,
+        ),
     };
     if let Some(result_file) = &args.result_file {
         let mut file = OpenOptions::new()
             .append(true)
-            .write(true)
             .create(true)
             .open(result_file)
             .unwrap();
@@ -579,6 +967,22 @@ fn main() {
             "fanout": args.fanout,
             "peers_per_node": args.peers_per_node,
             "primaries": args.primaries,
+            "seed": args.seed,
+            "bloom_false_positive_rate": args.bloom_false_positive_rate,
+            "rumor": args.rumor.map(|rule| format!("{rule:?}")),
+            "rumor_k": args.rumor.map(|_| args.rumor_k),
+            "max_hops": args.rumor.map(|_| args.max_hops),
+            "plumtree": args.plumtree,
+            "graft_timeout_millis": args.plumtree.then_some(args.graft_timeout_millis),
+            "regions": args.regions,
+            "region_latency_micros": (args.regions > 0).then_some(args.region_latency_micros),
+            "cross_region_latency_micros": (args.regions > 0).then_some(args.cross_region_latency_micros),
+            "near_fraction": (args.regions > 0).then_some(args.near_fraction),
+            "gossip_messages": traffic.gossip_messages,
+            "gossip_messages_per_element": traffic.per_element(traffic.gossip_messages),
+            "control_messages": traffic.control_messages,
+            "control_messages_per_element": traffic.per_element(traffic.control_messages),
+            "overall_lost_percent": end_result.overall_lost_percent,
             "overall_mean": end_result.overall_mean_latency_micros,
             "primary_mean": end_result.primary_mean_latency_micros,
             "secondary_mean": end_result.secondary_mean_latency_micros,
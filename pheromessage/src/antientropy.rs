@@ -0,0 +1,666 @@
+//! Push-pull anti-entropy: every node periodically reconciles the messages it has with a random
+//! peer, so that messages lost by the (push-only, fire-and-forget) gossip are eventually
+//! recovered and all nodes converge. Messages are only kept around for so long though (see
+//! `AntiEntropyConfig::log_capacity`): to also recover older ones, nodes can exchange snapshots
+//! of their whole state instead (see `AntiEntropy::with_snapshots()`).
+//!
+//! The exchange doesn't keep its own time: `tick()` should be called regularly with the current
+//! time, and `receive_exchange()` for every exchange message, like the `swim` protocol.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use rand::{seq::SliceRandom, RngCore};
+
+use crate::{
+    bootstrap::{StateSnapshot, StateTransfer},
+    peers::{Membership, PeerSet},
+    rng::ThreadLocalRng,
+    seen::MessageLog,
+    Delivery, Gossip, Message, ReceiveFrom,
+};
+
+/// How two nodes reconcile their messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExchangeMode {
+    /// Exchange the IDs of the messages each node has, then only the messages the other is
+    /// missing.
+    Digest,
+    /// Exchange all the messages in each node's log. This takes one round trip less than
+    /// exchanging digests, at the cost of sending the entire log.
+    /// Note that this is not a snapshot of the node's data: only the latest `log_capacity`
+    /// messages are ever exchanged, so a node that missed older ones (e.g. after a long
+    /// partition) won't recover them this way.
+    FullLog,
+    /// Exchange snapshots of each node's whole state (its data and the IDs of the messages it
+    /// has seen), merging them on both sides. This recovers everything a node missed, however
+    /// long ago, at the cost of sending the whole state every time.
+    /// Taking and restoring snapshots has to be set up with `AntiEntropy::with_snapshots()`,
+    /// which switches to this mode.
+    Snapshot,
+}
+
+/// The configuration of anti-entropy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AntiEntropyConfig {
+    /// How often to start an exchange with a random peer.
+    pub period: Duration,
+    /// How to reconcile messages with the peer.
+    pub mode: ExchangeMode,
+    /// How many of the latest messages to keep around to pass on to peers that missed them.
+    pub log_capacity: usize,
+}
+
+impl Default for AntiEntropyConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(1),
+            mode: ExchangeMode::Digest,
+            log_capacity: 10_000,
+        }
+    }
+}
+
+/// The kind of an anti-entropy exchange message, carrying messages (`M`) or their IDs (`I`),
+/// or snapshots of data (`T`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExchangeKind<M, I, T = ()> {
+    /// The IDs of all the messages the sender has (starting a digest exchange).
+    Digest(Vec<I>),
+    /// The IDs of the messages the sender is missing.
+    Request(Vec<I>),
+    /// Messages the recipient is missing.
+    Messages(Vec<M>),
+    /// All the messages in the sender's log (starting a full log exchange), to be answered with
+    /// all the messages in the recipient's log.
+    Log(Vec<M>),
+    /// A snapshot of the sender's state (starting a snapshot exchange), to be answered with a
+    /// snapshot of the recipient's state.
+    Snapshot(StateSnapshot<T, I>),
+    /// A snapshot of the sender's state, answering a snapshot of the recipient's.
+    SnapshotReply(StateSnapshot<T, I>),
+}
+
+/// An anti-entropy exchange message between nodes with IDs of type `K`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExchangeMessage<K, M, I, T = ()> {
+    /// The node that sent the message.
+    pub from: K,
+    /// What the message is about.
+    pub kind: ExchangeKind<M, I, T>,
+}
+
+/// How to take and restore snapshots of the state (data snapshots `T` and message IDs `I`) of
+/// a gossip mechanism (`G`), for snapshot exchanges.
+struct Snapshots<G, T, I> {
+    take: fn(&G) -> StateSnapshot<T, I>,
+    restore: fn(&mut G, StateSnapshot<T, I>),
+}
+
+/// A wrapper around a gossip mechanism (`G`) maintaining data (`S`), that logs the messages
+/// (`M`, by ID `I`) it goes through and periodically reconciles them with a random peer.
+/// Peers are identified by IDs (`K`), with the endpoints (`P`) to deliver exchange messages to
+/// them using the delivery mechanism `D`, and are chosen using the random number generator `R`.
+/// Messages recovered from a peer are passed to the gossip as if they were received normally
+/// (so new ones are gossipped on).
+/// Delivery errors are treated like lost messages: the next exchange will make up for them.
+/// With snapshot exchanges, `T` is the type of snapshots of the data.
+pub struct AntiEntropy<G, S, K, P, D, M, I, R = ThreadLocalRng, T = ()> {
+    /// The gossip mechanism being made reliable.
+    gossip: G,
+    /// My own ID.
+    id: K,
+    /// The endpoint for every peer.
+    endpoints: HashMap<K, P>,
+    /// The peers to exchange with.
+    peers: PeerSet<K>,
+    config: AntiEntropyConfig,
+    /// The latest messages, to pass on to peers that missed them.
+    log: MessageLog<I, M>,
+    /// The delivery mechanism to send exchange messages.
+    delivery: D,
+    rng: R,
+    /// When the last exchange was started.
+    last_exchange: Option<Instant>,
+    /// How to take and restore snapshots, if they're exchanged.
+    snapshots: Option<Snapshots<G, T, I>>,
+    _s: PhantomData<S>,
+}
+
+impl<G, S, K, P, D, M, I> AntiEntropy<G, S, K, P, D, M, I>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Add anti-entropy to the given `gossip`, for the node with the given `id` that will
+    /// exchange with the given `peers` (by ID and endpoint) as configured by `config`, using
+    /// the given `delivery` mechanism.
+    /// Panics if the configured mode is `ExchangeMode::Snapshot`: use `with_snapshots()` for it.
+    pub fn create(
+        gossip: G,
+        id: K,
+        peers: Vec<(K, P)>,
+        config: AntiEntropyConfig,
+        delivery: D,
+    ) -> AntiEntropy<G, S, K, P, D, M, I> {
+        assert!(
+            config.mode != ExchangeMode::Snapshot,
+            "Snapshot exchanges have to be set up with with_snapshots()"
+        );
+        let mut anti_entropy = AntiEntropy {
+            gossip,
+            id,
+            endpoints: HashMap::new(),
+            peers: PeerSet::default(),
+            config,
+            log: MessageLog::new(config.log_capacity),
+            delivery,
+            rng: ThreadLocalRng,
+            last_exchange: None,
+            snapshots: None,
+            _s: PhantomData,
+        };
+        for peer in peers {
+            anti_entropy.join(peer);
+        }
+        anti_entropy
+    }
+}
+
+impl<G, S, K, P, D, M, I, R> AntiEntropy<G, S, K, P, D, M, I, R> {
+    /// Exchange snapshots of the gossip's whole state with peers (see `ExchangeMode::Snapshot`)
+    /// from now on, instead of messages.
+    pub fn with_snapshots<GP, T>(self) -> AntiEntropy<G, S, K, P, D, M, I, R, T>
+    where
+        G: StateTransfer<GP, T, I>,
+    {
+        AntiEntropy {
+            gossip: self.gossip,
+            id: self.id,
+            endpoints: self.endpoints,
+            peers: self.peers,
+            config: AntiEntropyConfig {
+                mode: ExchangeMode::Snapshot,
+                ..self.config
+            },
+            log: self.log,
+            delivery: self.delivery,
+            rng: self.rng,
+            last_exchange: self.last_exchange,
+            snapshots: Some(Snapshots {
+                take: G::snapshot,
+                restore: G::restore,
+            }),
+            _s: PhantomData,
+        }
+    }
+}
+
+impl<G, S, K, P, D, M, I, R, T> AntiEntropy<G, S, K, P, D, M, I, R, T> {
+    /// Use the given `rng` to choose peers to exchange with from now on.
+    pub fn with_rng<R2>(self, rng: R2) -> AntiEntropy<G, S, K, P, D, M, I, R2, T> {
+        AntiEntropy {
+            gossip: self.gossip,
+            id: self.id,
+            endpoints: self.endpoints,
+            peers: self.peers,
+            config: self.config,
+            log: self.log,
+            delivery: self.delivery,
+            rng,
+            last_exchange: self.last_exchange,
+            snapshots: self.snapshots,
+            _s: PhantomData,
+        }
+    }
+
+    /// The gossip mechanism being made reliable.
+    pub fn gossip(&self) -> &G {
+        &self.gossip
+    }
+
+    /// The gossip mechanism being made reliable, to change it.
+    pub fn gossip_mut(&mut self) -> &mut G {
+        &mut self.gossip
+    }
+
+    /// My own ID.
+    pub fn id(&self) -> &K {
+        &self.id
+    }
+
+    /// The log of the latest messages.
+    pub fn log(&self) -> &MessageLog<I, M> {
+        &self.log
+    }
+}
+
+impl<G, S, K, P, D, M, I, R, T> AntiEntropy<G, S, K, P, D, M, I, R, T>
+where
+    G: Gossip<M, S>,
+    K: Eq + Hash + Clone,
+    M: Message<I = I> + Clone,
+    I: Eq + Hash + Clone,
+    D: Delivery<ExchangeMessage<K, M, I, T>, P>,
+    R: RngCore,
+{
+    /// Move the protocol forward to the given time: start an exchange with a random peer if
+    /// it's been a period since the last one.
+    pub fn tick(&mut self, now: Instant) {
+        if let Some(last) = self.last_exchange {
+            if now.duration_since(last) < self.config.period {
+                return;
+            }
+        }
+        self.last_exchange = Some(now);
+        let Some(peer) = self.peers.as_slice().choose(&mut self.rng).cloned() else {
+            return;
+        };
+        let kind = match self.config.mode {
+            ExchangeMode::Digest => ExchangeKind::Digest(self.log.ids().cloned().collect()),
+            ExchangeMode::FullLog => ExchangeKind::Log(self.log.messages().cloned().collect()),
+            ExchangeMode::Snapshot => match self.snapshot() {
+                Some(snapshot) => ExchangeKind::Snapshot(snapshot),
+                None => return,
+            },
+        };
+        self.send(&peer, kind);
+    }
+
+    /// Handle an exchange message from a peer, passing any messages recovered from it on to
+    /// the gossip.
+    pub fn receive_exchange(
+        &mut self,
+        message: &ExchangeMessage<K, M, I, T>,
+    ) -> Result<(), G::Error>
+    where
+        T: Clone,
+    {
+        let from = &message.from;
+        match &message.kind {
+            ExchangeKind::Digest(ids) => {
+                // Push what they're missing, and pull what I'm missing.
+                let theirs: HashSet<_> = ids.iter().collect();
+                let missing_there: Vec<_> = self
+                    .log
+                    .messages()
+                    .filter(|m| !theirs.contains(&m.id()))
+                    .cloned()
+                    .collect();
+                if !missing_there.is_empty() {
+                    self.send(from, ExchangeKind::Messages(missing_there));
+                }
+                let missing_here: Vec<_> = ids
+                    .iter()
+                    .filter(|id| !self.log.contains(id))
+                    .cloned()
+                    .collect();
+                if !missing_here.is_empty() {
+                    self.send(from, ExchangeKind::Request(missing_here));
+                }
+            }
+            ExchangeKind::Request(ids) => {
+                let requested: Vec<_> = ids
+                    .iter()
+                    .filter_map(|id| self.log.get(id))
+                    .cloned()
+                    .collect();
+                if !requested.is_empty() {
+                    self.send(from, ExchangeKind::Messages(requested));
+                }
+            }
+            ExchangeKind::Messages(messages) => {
+                for message in messages {
+                    self.receive(message)?;
+                }
+            }
+            ExchangeKind::Log(messages) => {
+                let mine = self.log.messages().cloned().collect();
+                self.send(from, ExchangeKind::Messages(mine));
+                for message in messages {
+                    self.receive(message)?;
+                }
+            }
+            ExchangeKind::Snapshot(snapshot) => {
+                // Answer with the merged state, so we both end up with it.
+                self.restore(snapshot.clone());
+                if let Some(mine) = self.snapshot() {
+                    self.send(from, ExchangeKind::SnapshotReply(mine));
+                }
+            }
+            ExchangeKind::SnapshotReply(snapshot) => self.restore(snapshot.clone()),
+        }
+        Ok(())
+    }
+
+    /// A snapshot of the gossip's state, if snapshots are exchanged.
+    fn snapshot(&self) -> Option<StateSnapshot<T, I>> {
+        self.snapshots
+            .as_ref()
+            .map(|snapshots| (snapshots.take)(&self.gossip))
+    }
+
+    /// Merge the given snapshot into the gossip's state, if snapshots are exchanged.
+    fn restore(&mut self, snapshot: StateSnapshot<T, I>) {
+        if let Some(snapshots) = &self.snapshots {
+            (snapshots.restore)(&mut self.gossip, snapshot);
+        }
+    }
+
+    /// Send an exchange message to the given peer (if it's still a peer).
+    fn send(&self, peer: &K, kind: ExchangeKind<M, I, T>) {
+        if let Some(endpoint) = self.endpoints.get(peer) {
+            let message = ExchangeMessage {
+                from: self.id.clone(),
+                kind,
+            };
+            // Lost exchanges are made up for by the next ones.
+            let _ = self.delivery.deliver(&message, [endpoint].into_iter());
+        }
+    }
+}
+
+impl<G, S, K, P, D, M, I, R, T> Gossip<M, S> for AntiEntropy<G, S, K, P, D, M, I, R, T>
+where
+    G: Gossip<M, S>,
+    M: Message<I = I> + Clone,
+    I: Eq + Hash + Clone,
+{
+    type Error = G::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.log.insert(message.id(), message.clone());
+        self.gossip.receive(message)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.log.insert(message.id(), message.clone());
+        self.gossip.update(message)
+    }
+
+    fn data(&self) -> &S {
+        self.gossip.data()
+    }
+}
+
+impl<G, S, K, P, D, M, I, R, T, Q> ReceiveFrom<M, S, Q> for AntiEntropy<G, S, K, P, D, M, I, R, T>
+where
+    G: ReceiveFrom<M, S, Q>,
+    M: Message<I = I> + Clone,
+    I: Eq + Hash + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.log.insert(message.id(), message.clone());
+        self.gossip
+            .receive_from_excluding(message, sender, excluded)
+    }
+}
+
+impl<G, S, K, P, D, M, I, R, T> Membership<(K, P)> for AntiEntropy<G, S, K, P, D, M, I, R, T>
+where
+    K: Eq + Hash + Clone,
+{
+    fn join(&mut self, (peer, endpoint): (K, P)) {
+        if peer == self.id || self.endpoints.contains_key(&peer) {
+            return;
+        }
+        self.endpoints.insert(peer.clone(), endpoint);
+        self.peers.insert(peer);
+    }
+
+    fn leave(&mut self, (peer, _): &(K, P)) {
+        self.endpoints.remove(peer);
+        self.peers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::{BTreeSet, VecDeque},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        bootstrap::Snapshot, lossy::LossyDelivery, seen::UnboundedSeenStore,
+        select::UniformSelector, SharedData, UniformGossip,
+    };
+
+    /// Queues every message sent as (endpoint, message), where the endpoint is the index of
+    /// the node in the test network.
+    struct Outbox<M>(RefCell<VecDeque<(usize, M)>>);
+
+    impl<M: Clone> Delivery<M, usize> for &Outbox<M> {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            for endpoint in endpoints {
+                self.0.borrow_mut().push_back((*endpoint, message.clone()));
+            }
+            Ok(())
+        }
+    }
+
+    /// The messages that updated the data.
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    struct Received(BTreeSet<usize>);
+
+    impl SharedData<usize> for Received {
+        fn update(&mut self, message: &usize) {
+            self.0.insert(*message);
+        }
+    }
+
+    /// A snapshot is every message that updated the data.
+    impl Snapshot for Received {
+        type State = Vec<usize>;
+
+        fn snapshot(&self) -> Vec<usize> {
+            self.0.iter().copied().collect()
+        }
+
+        fn restore(&mut self, state: Vec<usize>) {
+            self.0.extend(state);
+        }
+    }
+
+    type Exchange = ExchangeMessage<usize, usize, usize>;
+    type Lossy<'a, M> = LossyDelivery<&'a Outbox<M>, StdRng>;
+    type Selector = UniformSelector<StdRng>;
+    type Node<'a> = AntiEntropy<
+        UniformGossip<
+            usize,
+            Received,
+            Lossy<'a, usize>,
+            usize,
+            UnboundedSeenStore<usize>,
+            Selector,
+        >,
+        Received,
+        usize,
+        usize,
+        Lossy<'a, Exchange>,
+        usize,
+        usize,
+        StdRng,
+    >;
+
+    /// Run a network of 10 nodes where gossip and exchanges lose half their messages, and
+    /// check that every node ends up with every message.
+    fn converges(mode: ExchangeMode) {
+        let gossip_outbox = Outbox(RefCell::new(VecDeque::new()));
+        let exchange_outbox = Outbox(RefCell::new(VecDeque::new()));
+        let n = 10;
+        let config = AntiEntropyConfig {
+            mode,
+            ..AntiEntropyConfig::default()
+        };
+        let mut nodes: Vec<Node<'_>> = (0..n)
+            .map(|i| {
+                let peers: Vec<_> = (0..n).filter(|&j| j != i).collect();
+                let seed = i as u64;
+                let gossip = UniformGossip::create(
+                    peers.clone(),
+                    2,
+                    Received::default(),
+                    LossyDelivery::with_rng(&gossip_outbox, 0.5, StdRng::seed_from_u64(seed)),
+                )
+                .with_peer_selector(UniformSelector::with_rng(StdRng::seed_from_u64(seed)));
+                AntiEntropy::create(
+                    gossip,
+                    i,
+                    peers.into_iter().map(|j| (j, j)).collect(),
+                    config,
+                    LossyDelivery::with_rng(&exchange_outbox, 0.5, StdRng::seed_from_u64(seed)),
+                )
+                .with_rng(StdRng::seed_from_u64(seed))
+            })
+            .collect();
+        let deliver_all = |nodes: &mut Vec<Node<'_>>| loop {
+            let gossip = gossip_outbox.0.borrow_mut().pop_front();
+            let exchange = exchange_outbox.0.borrow_mut().pop_front();
+            match (gossip, exchange) {
+                (None, None) => return,
+                (gossip, exchange) => {
+                    if let Some((to, message)) = gossip {
+                        nodes[to].receive(&message).unwrap();
+                    }
+                    if let Some((to, message)) = exchange {
+                        nodes[to].receive_exchange(&message).unwrap();
+                    }
+                }
+            }
+        };
+        let all: BTreeSet<usize> = (0..20).collect();
+        for message in all.iter() {
+            nodes[message % n].update(message).unwrap();
+        }
+        deliver_all(&mut nodes);
+        // With that much loss, the gossip alone doesn't get everything everywhere.
+        assert!(nodes.iter().any(|node| node.data().0 != all));
+        let start = Instant::now();
+        for round in 0..20 {
+            for node in nodes.iter_mut() {
+                node.tick(start + config.period * round);
+            }
+            deliver_all(&mut nodes);
+        }
+        for node in nodes.iter() {
+            assert_eq!(all, node.data().0, "node {}", node.id());
+        }
+    }
+
+    #[test]
+    fn digests_converge() {
+        converges(ExchangeMode::Digest);
+    }
+
+    #[test]
+    fn full_log_converges() {
+        converges(ExchangeMode::FullLog);
+    }
+
+    type SnapshotExchange = ExchangeMessage<usize, usize, usize, Vec<usize>>;
+    type SnapshotNode<'a> = AntiEntropy<
+        UniformGossip<usize, Received, &'a Outbox<usize>, usize>,
+        Received,
+        usize,
+        usize,
+        &'a Outbox<SnapshotExchange>,
+        usize,
+        usize,
+        ThreadLocalRng,
+        Vec<usize>,
+    >;
+
+    /// A node cut off for longer than the log goes back still catches up with snapshots.
+    #[test]
+    fn snapshots_recover_rolled_over_messages() {
+        let gossip_outbox = Outbox(RefCell::new(VecDeque::new()));
+        let exchange_outbox = Outbox(RefCell::new(VecDeque::new()));
+        let n = 3;
+        let config = AntiEntropyConfig {
+            log_capacity: 5,
+            ..AntiEntropyConfig::default()
+        };
+        let mut nodes: Vec<SnapshotNode<'_>> = (0..n)
+            .map(|i| {
+                let peers: Vec<_> = (0..n).filter(|&j| j != i).collect();
+                let gossip =
+                    UniformGossip::create(peers.clone(), 2, Received::default(), &gossip_outbox);
+                AntiEntropy::create(
+                    gossip,
+                    i,
+                    peers.into_iter().map(|j| (j, j)).collect(),
+                    config,
+                    &exchange_outbox,
+                )
+                .with_snapshots()
+            })
+            .collect();
+        let deliver_all = |nodes: &mut Vec<SnapshotNode<'_>>, cut_off: Option<usize>| loop {
+            let gossip = gossip_outbox.0.borrow_mut().pop_front();
+            let exchange = exchange_outbox.0.borrow_mut().pop_front();
+            match (gossip, exchange) {
+                (None, None) => return,
+                (gossip, exchange) => {
+                    if let Some((to, message)) = gossip.filter(|(to, _)| Some(*to) != cut_off) {
+                        nodes[to].receive(&message).unwrap();
+                    }
+                    if let Some((to, message)) = exchange {
+                        nodes[to].receive_exchange(&message).unwrap();
+                    }
+                }
+            }
+        };
+        let all: BTreeSet<usize> = (0..20).collect();
+        for message in all.iter() {
+            nodes[message % 2].update(message).unwrap();
+        }
+        deliver_all(&mut nodes, Some(2));
+        assert!(nodes[2].data().0.is_empty());
+        // The first messages are long gone from the logs.
+        assert_eq!(5, nodes[0].log().len());
+        assert!(!nodes[0].log().contains(&0) && !nodes[1].log().contains(&1));
+        let start = Instant::now();
+        for round in 0..3 {
+            for node in nodes.iter_mut() {
+                node.tick(start + config.period * round);
+            }
+            deliver_all(&mut nodes, None);
+        }
+        for node in nodes.iter() {
+            assert_eq!(all, node.data().0, "node {}", node.id());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "with_snapshots()")]
+    fn snapshot_mode_needs_snapshots() {
+        let outbox: Outbox<Exchange> = Outbox(RefCell::new(VecDeque::new()));
+        let _: AntiEntropy<(), Received, usize, usize, _, usize, usize> = AntiEntropy::create(
+            (),
+            0,
+            vec![],
+            AntiEntropyConfig {
+                mode: ExchangeMode::Snapshot,
+                ..AntiEntropyConfig::default()
+            },
+            &outbox,
+        );
+    }
+}
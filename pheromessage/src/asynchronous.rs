@@ -0,0 +1,633 @@
+//! Async versions of the gossip and delivery traits, for use with `tokio` (behind the `async`
+//! feature).
+//! The gossip algorithms themselves stay synchronous and are shared with sync users: they're
+//! created with an `Outbox` as their delivery mechanism, and wrapped in an `AsyncGossipNode` that
+//! sends whatever they put in the outbox using an async delivery mechanism.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    io, mem,
+    net::SocketAddr,
+    slice,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use crate::{
+    net::{Error, ToBytes},
+    observe::{GossipObserver, NoopObserver},
+    Delivery, DeliveryReport, Gossip, ReceiveFrom,
+};
+
+/// An async mechanism for delivering messages (of type `M`) to endpoints (of type `P`).
+pub trait AsyncDelivery<M, P> {
+    /// The type of error that can happen during delivery.
+    type Error;
+
+    /// Deliver the message to all the given endpoints.
+    fn deliver(
+        &self,
+        message: &M,
+        endpoints: &[P],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Deliver the message to all the given endpoints, going on to the rest of them when
+    /// delivering to some fails, and report which ones failed (like `Delivery::deliver_each()`).
+    /// By default, the message is delivered to every endpoint separately.
+    fn deliver_each(
+        &self,
+        message: &M,
+        endpoints: &[P],
+    ) -> impl Future<Output = Result<DeliveryReport<Self::Error>, Self::Error>> + Send
+    where
+        Self: Sync,
+        M: Sync,
+        P: Sync,
+        Self::Error: Send,
+    {
+        async move {
+            let mut report = DeliveryReport::default();
+            for (position, endpoint) in endpoints.iter().enumerate() {
+                if let Err(e) = self.deliver(message, slice::from_ref(endpoint)).await {
+                    report.failures.push((position, e));
+                }
+            }
+            Ok(report)
+        }
+    }
+}
+
+/// An async gossip mechanism for messages (of type `M`) maintaining data (of type `S`).
+pub trait AsyncGossip<M, S> {
+    /// The type of error that can happen while gossipping.
+    type Error;
+
+    /// Receive a message from a peer.
+    fn receive(&mut self, message: &M) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Update the data with a new message originating from this node, and gossip about it.
+    fn update(&mut self, message: &M) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// The data being gossipped about.
+    fn data(&self) -> &S;
+}
+
+/// An async gossip mechanism that can also be told which peer (of type `Q`) a message came
+/// from, so it isn't sent straight back (the async version of `ReceiveFrom`).
+pub trait AsyncReceiveFrom<M, S, Q>: AsyncGossip<M, S> {
+    /// Receive a message from the given peer.
+    fn receive_from(
+        &mut self,
+        message: &M,
+        sender: &Q,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// A delivery mechanism that just queues messages (with their endpoints) to be sent later,
+/// e.g. by an `AsyncGossipNode`. It can be cloned to share the same queue.
+#[derive(Debug)]
+pub struct Outbox<M, P>(Arc<Mutex<Queued<M, P>>>);
+
+/// The messages queued in an `Outbox`, with the endpoints to deliver them to.
+type Queued<M, P> = Vec<(M, Vec<P>)>;
+
+impl<M, P> Outbox<M, P> {
+    /// Create a new empty outbox.
+    pub fn new() -> Outbox<M, P> {
+        Outbox(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    /// Take all the queued messages (with their endpoints) out of the outbox, oldest first.
+    pub fn take(&self) -> Queued<M, P> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl<M, P> Default for Outbox<M, P> {
+    fn default() -> Self {
+        Outbox::new()
+    }
+}
+
+impl<M, P> Clone for Outbox<M, P> {
+    fn clone(&self) -> Self {
+        Outbox(self.0.clone())
+    }
+}
+
+impl<M, P> Delivery<M, P> for Outbox<M, P>
+where
+    M: Clone,
+    P: Clone,
+{
+    type Error = Infallible;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        if endpoints.len() > 0 {
+            self.0
+                .lock()
+                .unwrap()
+                .push((message.clone(), endpoints.cloned().collect()));
+        }
+        Ok(())
+    }
+}
+
+/// An async gossip node running a synchronous gossip mechanism (`G`) that delivers into an
+/// `Outbox`, and sending everything it queues there using an async delivery mechanism (`D`).
+/// `O` is the observer notified of the peers messages couldn't be sent to.
+pub struct AsyncGossipNode<G, M, P, D, O = NoopObserver> {
+    gossip: G,
+    outbox: Outbox<M, P>,
+    delivery: D,
+    observer: O,
+}
+
+impl<G, M, P, D> AsyncGossipNode<G, M, P, D> {
+    /// Create a new async gossip node running the given `gossip` mechanism, which has to deliver
+    /// its messages into (a clone of) the given `outbox`, and sending them using `delivery`.
+    pub fn new(gossip: G, outbox: Outbox<M, P>, delivery: D) -> AsyncGossipNode<G, M, P, D> {
+        AsyncGossipNode {
+            gossip,
+            outbox,
+            delivery,
+            observer: NoopObserver,
+        }
+    }
+}
+
+impl<G, M, P, D, O> AsyncGossipNode<G, M, P, D, O> {
+    /// Notify the given `observer` of the peers messages couldn't be sent to from now on.
+    pub fn with_observer<O2>(self, observer: O2) -> AsyncGossipNode<G, M, P, D, O2> {
+        AsyncGossipNode {
+            gossip: self.gossip,
+            outbox: self.outbox,
+            delivery: self.delivery,
+            observer,
+        }
+    }
+
+    /// The observer of the peers messages couldn't be sent to.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The gossip mechanism run by this node.
+    pub fn gossip(&self) -> &G {
+        &self.gossip
+    }
+
+    /// The gossip mechanism run by this node (e.g. to change its peers).
+    /// Anything it queues in the outbox will be sent on the next receive or update.
+    pub fn gossip_mut(&mut self) -> &mut G {
+        &mut self.gossip
+    }
+
+    /// The async delivery mechanism used to send messages.
+    pub fn delivery(&self) -> &D {
+        &self.delivery
+    }
+
+    /// Send everything queued in the outbox. Peers a message couldn't be sent to are reported
+    /// to the observer, without stopping the rest from being sent. If a message couldn't be
+    /// sent to any peer for reasons that have nothing to do with the peers (e.g. it couldn't be
+    /// serialized), the first such error is returned once everything else is sent.
+    pub async fn flush(&mut self) -> Result<(), D::Error>
+    where
+        D: AsyncDelivery<M, P> + Sync,
+        D::Error: Send,
+        M: Sync,
+        P: Sync,
+        O: GossipObserver<M, P>,
+    {
+        let mut result = Ok(());
+        for (message, endpoints) in self.outbox.take() {
+            match self.delivery.deliver_each(&message, &endpoints).await {
+                Ok(report) => {
+                    for (position, _) in report.failures {
+                        self.observer
+                            .on_delivery_error(&message, &endpoints[position]);
+                    }
+                }
+                Err(e) => {
+                    for endpoint in endpoints.iter() {
+                        self.observer.on_delivery_error(&message, endpoint);
+                    }
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<G, S, M, P, D, O> AsyncGossip<M, S> for AsyncGossipNode<G, M, P, D, O>
+where
+    G: Gossip<M, S, Error = Infallible> + Send,
+    M: Send + Sync,
+    P: Send + Sync,
+    D: AsyncDelivery<M, P> + Send + Sync,
+    D::Error: Send,
+    O: GossipObserver<M, P> + Send,
+{
+    type Error = D::Error;
+
+    async fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        let Ok(()) = self.gossip.receive(message);
+        self.flush().await
+    }
+
+    async fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        let Ok(()) = self.gossip.update(message);
+        self.flush().await
+    }
+
+    fn data(&self) -> &S {
+        self.gossip.data()
+    }
+}
+
+impl<G, S, M, P, D, O, Q> AsyncReceiveFrom<M, S, Q> for AsyncGossipNode<G, M, P, D, O>
+where
+    G: ReceiveFrom<M, S, Q, Error = Infallible> + Send,
+    M: Send + Sync,
+    P: Send + Sync,
+    D: AsyncDelivery<M, P> + Send + Sync,
+    D::Error: Send,
+    O: GossipObserver<M, P> + Send,
+    Q: Sync,
+{
+    async fn receive_from(&mut self, message: &M, sender: &Q) -> Result<(), Self::Error> {
+        let Ok(()) = self.gossip.receive_from(message, sender);
+        self.flush().await
+    }
+}
+
+/// An async delivery mechanism for messages using UDP.
+pub struct AsyncUdpDelivery<S> {
+    /// The local UDP socket for delivery (shared, so it can also be used to receive).
+    pub socket: Arc<UdpSocket>,
+    /// The serializer to convert messages to raw bytes.
+    pub serializer: S,
+}
+
+impl<S> AsyncUdpDelivery<S> {
+    /// Create a new `AsyncUdpDelivery` over the given local socket and using the given serializer for messages.
+    pub fn new(socket: Arc<UdpSocket>, serializer: S) -> AsyncUdpDelivery<S> {
+        AsyncUdpDelivery { socket, serializer }
+    }
+}
+
+impl<S, M> AsyncDelivery<M, SocketAddr> for AsyncUdpDelivery<S>
+where
+    S: ToBytes<M> + Sync,
+    S::Bytes: Send,
+    S::Error: Send,
+    M: Sync,
+{
+    type Error = Error<S::Error>;
+
+    async fn deliver(&self, message: &M, endpoints: &[SocketAddr]) -> Result<(), Self::Error> {
+        self.deliver_each(message, endpoints).await?.into_result()
+    }
+
+    async fn deliver_each(
+        &self,
+        message: &M,
+        endpoints: &[SocketAddr],
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error> {
+        // The message is serialized once for all endpoints, so failing that fails them all.
+        let bytes = self
+            .serializer
+            .to_bytes(message)
+            .map_err(Error::Serialization)?;
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.iter().enumerate() {
+            if let Err(e) = self.socket.send_to(bytes.as_ref(), endpoint).await {
+                report.failures.push((position, Error::Send(e)));
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Error while running an async gossip node.
+#[derive(Debug)]
+pub enum RunError<E> {
+    /// An error while receiving a message from the network that the socket can't recover from.
+    Receive(io::Error),
+    /// An error from the gossip node.
+    Gossip(E),
+}
+
+/// Run the given gossip `node`: receive the messages coming from peers on `received`, and update
+/// it with the messages originating from this node coming on `updates`, until `updates` is
+/// closed. Returns the node, so its data can be inspected.
+pub async fn run_node<N, M, S>(
+    mut node: N,
+    mut received: mpsc::Receiver<M>,
+    mut updates: mpsc::Receiver<M>,
+) -> Result<N, N::Error>
+where
+    N: AsyncGossip<M, S>,
+{
+    let mut receiving = true;
+    loop {
+        tokio::select! {
+            message = received.recv(), if receiving => match message {
+                Some(message) => node.receive(&message).await?,
+                None => receiving = false,
+            },
+            message = updates.recv() => match message {
+                Some(message) => node.update(&message).await?,
+                None => return Ok(node),
+            },
+        }
+    }
+}
+
+/// Whether the given error from receiving on a UDP socket is only about a single datagram (e.g.
+/// the port unreachable answer to an earlier send, which Windows reports as a reset connection),
+/// so the socket can keep receiving.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Run the given gossip `node` like `run_node()`, but receiving the messages from peers
+/// directly from the given UDP `socket`. Every datagram is converted to a message with
+/// `from_bytes`, and dropped if it can't be. The node is told the address every message came
+/// from, so it doesn't send it straight back.
+/// Errors receiving that only affect a single datagram (e.g. a peer that went away answering
+/// an earlier send) are passed to `on_receive_error` and the node keeps going; any other error
+/// stops it.
+pub async fn run_udp_node<N, M, S, F, O>(
+    mut node: N,
+    socket: &UdpSocket,
+    mut from_bytes: F,
+    mut on_receive_error: O,
+    mut updates: mpsc::Receiver<M>,
+) -> Result<N, RunError<N::Error>>
+where
+    N: AsyncReceiveFrom<M, S, SocketAddr>,
+    F: FnMut(&[u8]) -> Option<M>,
+    O: FnMut(&io::Error),
+{
+    let mut buf = vec![0; 65536];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, source) = match received {
+                    Ok(received) => received,
+                    Err(e) if is_transient(&e) => {
+                        on_receive_error(&e);
+                        continue;
+                    }
+                    Err(e) => return Err(RunError::Receive(e)),
+                };
+                if let Some(message) = from_bytes(&buf[..len]) {
+                    node.receive_from(&message, &source).await.map_err(RunError::Gossip)?;
+                }
+            },
+            message = updates.recv() => match message {
+                Some(message) => node.update(&message).await.map_err(RunError::Gossip)?,
+                None => return Ok(node),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{Message, SharedData, UniformGossip};
+
+    struct ByteSer();
+
+    impl ToBytes<u8> for ByteSer {
+        type Bytes = [u8; 1];
+
+        type Error = ();
+
+        fn to_bytes(&self, message: &u8) -> Result<Self::Bytes, Self::Error> {
+            Ok([*message])
+        }
+    }
+
+    impl Message for u8 {
+        type I = u8;
+
+        fn id(&self) -> u8 {
+            *self
+        }
+    }
+
+    /// Reports every message it's updated with.
+    struct Reported(mpsc::UnboundedSender<u8>);
+
+    impl SharedData<u8> for Reported {
+        fn update(&mut self, message: &u8) {
+            let _ = self.0.send(*message);
+        }
+    }
+
+    type UdpNode = AsyncGossipNode<
+        UniformGossip<SocketAddr, Reported, Outbox<u8, SocketAddr>, u8>,
+        u8,
+        SocketAddr,
+        AsyncUdpDelivery<ByteSer>,
+    >;
+
+    fn node(socket: Arc<UdpSocket>, peer: SocketAddr, data: Reported) -> UdpNode {
+        let outbox = Outbox::new();
+        AsyncGossipNode::new(
+            UniformGossip::create(vec![peer], 1, data, outbox.clone()),
+            outbox,
+            AsyncUdpDelivery::new(socket, ByteSer()),
+        )
+    }
+
+    /// An endpoint that can't be sent to doesn't stop delivery to the others.
+    #[tokio::test]
+    async fn deliver_each_past_bad_endpoint() {
+        let sender = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receivers = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        // An IPv6 address can't be reached from an IPv4 socket.
+        let bad: SocketAddr = "[::1]:9".parse().unwrap();
+        let endpoints = [
+            receivers[0].local_addr().unwrap(),
+            bad,
+            receivers[1].local_addr().unwrap(),
+        ];
+        let delivery = AsyncUdpDelivery::new(sender, ByteSer());
+        let report = delivery.deliver_each(&5, &endpoints).await.unwrap();
+        assert_eq!(
+            vec![1],
+            report.failures.iter().map(|(i, _)| *i).collect::<Vec<_>>()
+        );
+        for receiver in receivers.iter() {
+            let mut buf = [0; 1];
+            timeout(Duration::from_secs(5), receiver.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!([5], buf);
+        }
+        assert!(delivery.deliver(&5, &endpoints).await.is_err());
+    }
+
+    /// Records the peers messages couldn't be sent to.
+    #[derive(Default)]
+    struct Failed(Vec<SocketAddr>);
+
+    impl GossipObserver<u8, SocketAddr> for Failed {
+        fn on_delivery_error(&mut self, _message: &u8, target: &SocketAddr) {
+            self.0.push(*target);
+        }
+    }
+
+    /// A peer that can't be sent to is reported, and doesn't stop the node or the messages to
+    /// the other peers.
+    #[tokio::test]
+    async fn node_survives_bad_peer() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receivers = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let bad: SocketAddr = "[::1]:9".parse().unwrap();
+        let peers = vec![
+            receivers[0].local_addr().unwrap(),
+            bad,
+            receivers[1].local_addr().unwrap(),
+        ];
+        let (report_tx, _report_rx) = mpsc::unbounded_channel();
+        let outbox = Outbox::new();
+        let failed = Arc::new(Mutex::new(Failed::default()));
+        let mut node = AsyncGossipNode::new(
+            UniformGossip::<_, _, _, u8>::create(peers, 3, Reported(report_tx), outbox.clone()),
+            outbox,
+            AsyncUdpDelivery::new(socket, ByteSer()),
+        )
+        .with_observer(failed.clone());
+        node.update(&7).await.unwrap();
+        node.update(&8).await.unwrap();
+        for receiver in receivers.iter() {
+            for expected in [7, 8] {
+                let mut buf = [0; 1];
+                timeout(Duration::from_secs(5), receiver.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!([expected], buf);
+            }
+        }
+        assert_eq!(vec![bad, bad], failed.lock().unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn udp_nodes() {
+        let sockets = [
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+        ];
+        let addrs = sockets.clone().map(|s| s.local_addr().unwrap());
+        let mut updates = Vec::new();
+        let mut reports = Vec::new();
+        let mut runs = Vec::new();
+        for (i, socket) in sockets.into_iter().enumerate() {
+            let (report_tx, report_rx) = mpsc::unbounded_channel();
+            let (update_tx, update_rx) = mpsc::channel(1);
+            let node = node(socket.clone(), addrs[1 - i], Reported(report_tx));
+            runs.push(tokio::spawn(async move {
+                run_udp_node(
+                    node,
+                    &socket,
+                    |bytes| bytes.first().copied(),
+                    |e| panic!("{e}"),
+                    update_rx,
+                )
+                .await
+            }));
+            updates.push(update_tx);
+            reports.push(report_rx);
+        }
+        updates[0].send(7).await.unwrap();
+        updates[1].send(8).await.unwrap();
+        for report in reports.iter_mut() {
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                received.push(
+                    timeout(Duration::from_secs(5), report.recv())
+                        .await
+                        .unwrap(),
+                );
+            }
+            received.sort();
+            assert_eq!(vec![Some(7), Some(8)], received);
+        }
+        drop(updates);
+        for run in runs {
+            run.await.unwrap().unwrap();
+        }
+    }
+
+    /// A peer that went away answering an earlier send doesn't stop the node.
+    #[tokio::test]
+    async fn udp_node_survives_unreachable_peer() {
+        let gone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gone_addr = gone.local_addr().unwrap();
+        drop(gone);
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        // A connected socket reports the port unreachable answer on its next receive.
+        socket.connect(gone_addr).await.unwrap();
+        socket.send(&[1]).await.unwrap();
+        let (report_tx, mut report_rx) = mpsc::unbounded_channel();
+        let (update_tx, update_rx) = mpsc::channel(1);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let node = node(socket.clone(), gone_addr, Reported(report_tx));
+        let observed = errors.clone();
+        let run = tokio::spawn(async move {
+            let on_receive_error = |e: &io::Error| observed.lock().unwrap().push(e.kind());
+            let from_bytes = |bytes: &[u8]| bytes.first().copied();
+            run_udp_node(node, &socket, from_bytes, on_receive_error, update_rx).await
+        });
+        // The peer comes back and gets through.
+        let peer = UdpSocket::bind(gone_addr).await.unwrap();
+        peer.send_to(&[9], addr).await.unwrap();
+        assert_eq!(
+            Some(9),
+            timeout(Duration::from_secs(5), report_rx.recv())
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            vec![io::ErrorKind::ConnectionRefused],
+            *errors.lock().unwrap()
+        );
+        drop(update_tx);
+        run.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Probabilistic seen stores based on rotating Bloom filters. These take a small, fixed
+//! amount of memory per message regardless of the size of message IDs, at the cost of
+//! occasionally mistaking a new message for one already seen (a false positive), which
+//! would then be dropped instead of gossipped.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    f64::consts::LN_2,
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use crate::seen::SeenStore;
+
+/// A Bloom filter: a compact set that can tell for sure if an item was never inserted,
+/// but may wrongly say an item was inserted with a (configurable) small probability.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    /// The bits of the filter.
+    bits: Vec<u64>,
+    /// The number of bits in the filter.
+    num_bits: u64,
+    /// The number of bits set for every item.
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Create a new empty Bloom filter sized to hold `capacity` items with the given
+    /// `false_positive_rate` (e.g. `0.01` for 1%).
+    pub fn new(capacity: usize, false_positive_rate: f64) -> BloomFilter {
+        let capacity = capacity.max(1) as f64;
+        let false_positive_rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        // The standard optimal sizing: m = -n ln(p) / ln(2)^2 bits and k = (m / n) ln(2) hashes.
+        let num_bits = (-capacity * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / capacity) * LN_2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// Insert the given item into the filter.
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        let (h1, h2) = hashes(item);
+        for i in 0..self.num_hashes {
+            let bit = self.bit_index(h1, h2, i);
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Checks if the given item may have been inserted into the filter. If this returns
+    /// `false` the item was definitely never inserted.
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        let (h1, h2) = hashes(item);
+        (0..self.num_hashes).all(|i| {
+            let bit = self.bit_index(h1, h2, i);
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    /// Remove all items from the filter.
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|b| *b = 0);
+    }
+
+    /// The size of the filter in bits.
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    fn bit_index(&self, h1: u64, h2: u64, i: u32) -> u64 {
+        h1.wrapping_add((i as u64).wrapping_mul(h2)) % self.num_bits
+    }
+}
+
+/// Two independent-ish hashes of the item, used to derive all the bit positions for it
+/// (the Kirsch-Mitzenmacher technique).
+fn hashes<T: Hash + ?Sized>(item: &T) -> (u64, u64) {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    let h1 = hasher.finish();
+    hasher.write_u64(0x9E37_79B9_7F4A_7C15);
+    // Make sure the second hash is odd so we don't keep hitting the same bit.
+    let h2 = hasher.finish() | 1;
+    (h1, h2)
+}
+
+/// A seen store based on two generations of Bloom filters: once the current generation has had
+/// `capacity` items inserted into it, the previous one is cleared and takes its place.
+/// So it remembers at least the last `capacity` messages, and its false positive rate
+/// never exceeds (roughly) twice the configured one.
+///
+/// Since a Bloom filter can't count, every generation has a filter per count up to `max_count`:
+/// a message seen `n` times goes into the `n`th filter. Counts returned by `see()` saturate at
+/// `max_count`, so it has to be bigger than the biggest count the gossip using it acts on:
+/// `2` is enough for gossip that only tells new messages from duplicates (like `UniformGossip`),
+/// but a primary in `PreferentialGossip` needs `3`.
+/// With a `max_count` of `1` every duplicate would look new and get passed on again, so it's
+/// not allowed.
+#[derive(Debug, Clone)]
+pub struct BloomSeenStore {
+    /// The number of insertions into a generation before it's rotated out.
+    capacity: usize,
+    /// The filters for the current generation, indexed by count - 1.
+    current: Vec<BloomFilter>,
+    /// The filters for the previous generation, indexed by count - 1.
+    previous: Vec<BloomFilter>,
+    /// The number of insertions into the current generation.
+    current_len: usize,
+    /// The number of insertions into the previous generation.
+    previous_len: usize,
+}
+
+impl BloomSeenStore {
+    /// Create a new store with generations of the given `capacity`, each generation's filters
+    /// having the given `false_positive_rate`, and counting messages up to `max_count` times.
+    /// Panics if `max_count` is less than `2`.
+    pub fn new(capacity: usize, false_positive_rate: f64, max_count: usize) -> BloomSeenStore {
+        assert!(
+            max_count >= 2,
+            "A Bloom seen store has to count past 1 to tell duplicates apart"
+        );
+        let filters: Vec<_> = (0..max_count)
+            .map(|_| BloomFilter::new(capacity, false_positive_rate))
+            .collect();
+        BloomSeenStore {
+            capacity,
+            previous: filters.clone(),
+            current: filters,
+            current_len: 0,
+            previous_len: 0,
+        }
+    }
+
+    /// The number of times the given ID has been seen (as far as the filters can tell).
+    fn count<I: Hash>(&self, id: &I) -> usize {
+        (0..self.current.len())
+            .rev()
+            .find(|&level| self.current[level].contains(id) || self.previous[level].contains(id))
+            .map_or(0, |level| level + 1)
+    }
+
+    fn rotate(&mut self) {
+        mem::swap(&mut self.current, &mut self.previous);
+        self.current.iter_mut().for_each(BloomFilter::clear);
+        self.previous_len = self.current_len;
+        self.current_len = 0;
+    }
+}
+
+impl<I> SeenStore<I> for BloomSeenStore
+where
+    I: Hash,
+{
+    fn see(&mut self, id: I) -> usize {
+        let count = (self.count(&id) + 1).min(self.current.len());
+        self.current[count - 1].insert(&id);
+        self.current_len += 1;
+        if self.current_len >= self.capacity {
+            self.rotate();
+        }
+        count
+    }
+
+    fn contains(&self, id: &I) -> bool {
+        self.count(id) > 0
+    }
+
+    fn len(&self) -> usize {
+        self.current_len + self.previous_len
+    }
+
+    fn max_count(&self) -> Option<usize> {
+        Some(self.current.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::Network, Gossip, UniformGossip};
+
+    #[test]
+    fn filter_false_positive_rate() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000_u64 {
+            filter.insert(&i);
+        }
+        assert!((0..10_000_u64).all(|i| filter.contains(&i)));
+        let false_positives = (10_000..110_000_u64).filter(|i| filter.contains(i)).count();
+        // Allow some slack over the expected 1,000.
+        assert!(false_positives < 1_500, "{false_positives}");
+    }
+
+    #[test]
+    fn counts() {
+        let mut store = BloomSeenStore::new(1_000, 0.001, 3);
+        assert_eq!(1, store.see(5_u64));
+        assert_eq!(2, store.see(5_u64));
+        assert_eq!(3, store.see(5_u64));
+        assert_eq!(3, store.see(5_u64));
+        assert_eq!(Some(3), SeenStore::<u64>::max_count(&store));
+        assert!(store.contains(&5_u64));
+        assert!(!store.contains(&6_u64));
+    }
+
+    #[test]
+    fn stays_bounded() {
+        let mut store = BloomSeenStore::new(100, 0.001, 2);
+        for i in 0..10_000_u64 {
+            assert!(store.see(i) >= 1);
+            if i >= 10 {
+                assert_eq!(2, store.see(i - 10));
+            }
+            assert!(SeenStore::<u64>::len(&store) <= 200);
+        }
+        assert!(store.contains(&9_999_u64));
+        assert!(!store.contains(&0_u64));
+    }
+
+    #[test]
+    #[should_panic(expected = "has to count past 1")]
+    fn saturating_at_one() {
+        BloomSeenStore::new(100, 0.01, 1);
+    }
+
+    /// Duplicates aren't taken for new messages and passed on again.
+    #[test]
+    fn uniform_gossip_drops_duplicates() {
+        let mut gossip: UniformGossip<_, _, _, usize, _> = UniformGossip::create_with_seen_store(
+            vec![1, 2, 3],
+            3,
+            (),
+            Network(Default::default()),
+            BloomSeenStore::new(100, 0.01, 2),
+        );
+        for _ in 0..3 {
+            gossip.receive(&10).unwrap();
+        }
+        let delivered = gossip.delivery.0.borrow();
+        assert_eq!(3, delivered.len());
+        assert!(delivered.values().all(|messages| messages == &[10]));
+    }
+}
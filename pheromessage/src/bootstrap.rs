@@ -0,0 +1,445 @@
+//! Bootstrapping a new gossip node into an existing network: the new node asks one or more seed
+//! nodes to let it join, and every seed answers with its peers (and their roles), a snapshot of
+//! its data and the IDs of the messages it has seen. The new node takes all of that over, so it
+//! starts out in sync and doesn't take old messages for new ones, and from then on gossips
+//! normally.
+//!
+//! Only the seeds a node joins through learn about it directly: use a membership protocol (e.g.
+//! `swim`) to let the rest of the network know about it.
+
+use std::hash::Hash;
+
+use crate::{seen::SeenIds, Delivery, PreferentialGossip, UniformGossip};
+
+/// The role of a peer in the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerRole {
+    /// A peer that gets word of updates first (e.g. a primary in `PreferentialGossip`).
+    Primary,
+    /// Any other peer.
+    Secondary,
+}
+
+/// Shared data whose whole state can be taken (e.g. to hand it over to a new node) and restored.
+pub trait Snapshot {
+    /// The type of a snapshot of the state.
+    type State;
+
+    /// Take a snapshot of the current state.
+    fn snapshot(&self) -> Self::State;
+
+    /// Restore the given snapshot of the state of another node, merging it with whatever this
+    /// data already has.
+    fn restore(&mut self, state: Self::State);
+}
+
+/// A snapshot of the state of a gossip node: its data (`T`) and the IDs (`I`) of the messages
+/// it has seen.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StateSnapshot<T, I> {
+    /// A snapshot of the data.
+    pub data: T,
+    /// The IDs of the messages seen.
+    pub seen: Vec<I>,
+}
+
+/// A gossip mechanism whose peers (`P`) and state (data snapshots `T` and message IDs `I`) can be
+/// handed over to a new node.
+pub trait StateTransfer<P, T, I> {
+    /// All the peers, with their roles.
+    fn peers_with_roles(&self) -> Vec<(P, PeerRole)>;
+
+    /// Add the given peer to gossip to, in the given role.
+    fn add_peer_with_role(&mut self, peer: P, role: PeerRole);
+
+    /// Take a snapshot of the current state.
+    fn snapshot(&self) -> StateSnapshot<T, I>;
+
+    /// Take over the state of another node, so the messages it has seen won't be passed on
+    /// again.
+    fn restore(&mut self, snapshot: StateSnapshot<T, I>);
+}
+
+/// The answer of a seed to a node that asked to join.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Welcome<P, T, I> {
+    /// The peers of the seed (including the seed itself) with their roles.
+    pub peers: Vec<(P, PeerRole)>,
+    /// A snapshot of the state of the seed.
+    pub snapshot: StateSnapshot<T, I>,
+}
+
+/// A message of the bootstrap protocol, between nodes that get bootstrap messages at endpoints
+/// of type `B` and gossip at endpoints of type `P`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootstrapMessage<B, P, T, I> {
+    /// A node asks to join the network.
+    Join {
+        /// Where to send the answer to.
+        reply_to: B,
+        /// The endpoint to gossip to the node at.
+        endpoint: P,
+        /// The role of the node.
+        role: PeerRole,
+    },
+    /// A seed lets a node join.
+    Welcome(Welcome<P, T, I>),
+}
+
+/// The bootstrap protocol for a single node: it asks its seeds to let it join, takes over their
+/// state, and then acts as a seed for nodes joining after it.
+/// Bootstrap messages are delivered to endpoints of type `B` using the delivery mechanism `D`,
+/// and peers are gossipped to at endpoints of type `P`.
+pub struct Bootstrap<B, P, D> {
+    /// Where I get bootstrap messages.
+    mailbox: B,
+    /// The endpoint to gossip to me at.
+    endpoint: P,
+    /// My role in the network.
+    role: PeerRole,
+    /// The nodes to ask to let me join.
+    seeds: Vec<B>,
+    /// The delivery mechanism to send bootstrap messages.
+    delivery: D,
+    /// Whether I've been let into the network.
+    joined: bool,
+}
+
+impl<B, P, D> Bootstrap<B, P, D> {
+    /// Create the bootstrap protocol for the node that gets bootstrap messages at `mailbox` and
+    /// is gossipped to at `endpoint` in the given `role`, which joins the network through the
+    /// given `seeds` (if there are none, it's the first node and starts out joined), sending
+    /// bootstrap messages using the given `delivery` mechanism.
+    pub fn create(
+        mailbox: B,
+        endpoint: P,
+        role: PeerRole,
+        seeds: Vec<B>,
+        delivery: D,
+    ) -> Bootstrap<B, P, D> {
+        let joined = seeds.is_empty();
+        Bootstrap {
+            mailbox,
+            endpoint,
+            role,
+            seeds,
+            delivery,
+            joined,
+        }
+    }
+
+    /// Whether the node has been let into the network (by at least one seed).
+    pub fn is_joined(&self) -> bool {
+        self.joined
+    }
+
+    /// Where the node gets bootstrap messages.
+    pub fn mailbox(&self) -> &B {
+        &self.mailbox
+    }
+
+    /// The endpoint the node is gossipped to at.
+    pub fn endpoint(&self) -> &P {
+        &self.endpoint
+    }
+}
+
+impl<B, P, D> Bootstrap<B, P, D>
+where
+    B: Clone,
+    P: Clone + PartialEq,
+{
+    /// Ask all the seeds to let the node join. This can be called again (e.g. periodically)
+    /// until the node `is_joined()`, in case the messages got lost.
+    pub fn join<T, I>(&self) -> Result<(), D::Error>
+    where
+        D: Delivery<BootstrapMessage<B, P, T, I>, B>,
+    {
+        let message = BootstrapMessage::Join {
+            reply_to: self.mailbox.clone(),
+            endpoint: self.endpoint.clone(),
+            role: self.role,
+        };
+        self.delivery.deliver(&message, self.seeds.iter())
+    }
+
+    /// Handle a bootstrap message for the node running the given `gossip`: let a node that asks
+    /// to join in (once this node has joined itself), or take over the state of a seed that
+    /// let this node in.
+    pub fn receive<G, T, I>(
+        &mut self,
+        gossip: &mut G,
+        message: &BootstrapMessage<B, P, T, I>,
+    ) -> Result<(), D::Error>
+    where
+        G: StateTransfer<P, T, I>,
+        D: Delivery<BootstrapMessage<B, P, T, I>, B>,
+        T: Clone,
+        I: Clone,
+    {
+        match message {
+            BootstrapMessage::Join {
+                reply_to,
+                endpoint,
+                role,
+            } => {
+                // I can't vouch for the state of the network before I've joined it myself.
+                if !self.joined {
+                    return Ok(());
+                }
+                let mut peers: Vec<_> = gossip
+                    .peers_with_roles()
+                    .into_iter()
+                    .filter(|(peer, _)| peer != endpoint)
+                    .collect();
+                peers.push((self.endpoint.clone(), self.role));
+                let welcome = BootstrapMessage::Welcome(Welcome {
+                    peers,
+                    snapshot: gossip.snapshot(),
+                });
+                gossip.add_peer_with_role(endpoint.clone(), *role);
+                self.delivery.deliver(&welcome, [reply_to].into_iter())
+            }
+            BootstrapMessage::Welcome(welcome) => {
+                for (peer, role) in welcome.peers.iter() {
+                    if *peer != self.endpoint {
+                        gossip.add_peer_with_role(peer.clone(), *role);
+                    }
+                }
+                gossip.restore(welcome.snapshot.clone());
+                self.joined = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// All peers are equal in uniform gossip, so they're all secondaries.
+impl<P, S, D, I, L, C, O> StateTransfer<P, S::State, I> for UniformGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+    S: Snapshot,
+    L: SeenIds<I>,
+{
+    fn peers_with_roles(&self) -> Vec<(P, PeerRole)> {
+        self.peers
+            .iter()
+            .map(|peer| (peer.clone(), PeerRole::Secondary))
+            .collect()
+    }
+
+    fn add_peer_with_role(&mut self, peer: P, _role: PeerRole) {
+        self.add_peer(peer);
+    }
+
+    fn snapshot(&self) -> StateSnapshot<S::State, I> {
+        StateSnapshot {
+            data: self.data.snapshot(),
+            seen: self.seen_messages.seen_ids(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: StateSnapshot<S::State, I>) {
+        self.data.restore(snapshot.data);
+        for id in snapshot.seen {
+            if !self.seen_messages.contains(&id) {
+                self.seen_messages.see(id);
+            }
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> StateTransfer<P, S::State, I> for PreferentialGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+    S: Snapshot,
+    L: SeenIds<I>,
+    I: Clone,
+{
+    fn peers_with_roles(&self) -> Vec<(P, PeerRole)> {
+        self.tiered.peers_with_roles()
+    }
+
+    fn add_peer_with_role(&mut self, peer: P, role: PeerRole) {
+        self.tiered.add_peer_with_role(peer, role);
+    }
+
+    fn snapshot(&self) -> StateSnapshot<S::State, I> {
+        self.tiered.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: StateSnapshot<S::State, I>) {
+        self.tiered.restore(snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        channel::{
+            uniform_local_gossip_set_with_rng, ChannelEndpoint, LocalGossipNode,
+            LocalUniformGossipSetNode, CHANNELS,
+        },
+        data::{GossipSet, GossipSetMessage},
+        select::UniformSelector,
+        Gossip,
+    };
+
+    type Node = LocalUniformGossipSetNode<usize, GossipSetMessage<usize>, u128>;
+
+    type Boot = BootstrapMessage<
+        usize,
+        ChannelEndpoint<GossipSetMessage<usize>>,
+        Vec<(usize, usize, usize)>,
+        u128,
+    >;
+
+    /// Delivers bootstrap messages to the channel of the node with the index of the endpoint.
+    #[derive(Clone)]
+    struct Mailboxes<M>(Vec<mpsc::Sender<M>>);
+
+    impl<M: Clone> Delivery<M, usize> for Mailboxes<M> {
+        type Error = mpsc::SendError<M>;
+
+        fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            for endpoint in endpoints {
+                self.0[*endpoint].send(message.clone())?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Pass all the gossip messages around until there are none left.
+    fn pump(nodes: &mut [Node]) {
+        let mut busy = true;
+        while busy {
+            busy = false;
+            for node in nodes.iter_mut() {
+                while let Ok(message) = node.receiver.try_recv() {
+                    node.gossip.receive(&message).unwrap();
+                    busy = true;
+                }
+            }
+        }
+    }
+
+    /// A node joining a running network through a seed gets everything gossipped before it
+    /// joined without any of it being gossipped again, and then takes part in gossip normally.
+    #[test]
+    fn join_mid_run() {
+        let mut nodes: Vec<Node> =
+            uniform_local_gossip_set_with_rng(4, 4, &mut StdRng::seed_from_u64(7));
+        let old: Vec<_> = (0..10).map(GossipSetMessage::add).collect();
+        for (i, message) in old.iter().enumerate() {
+            nodes[i % 4].gossip.update(message).unwrap();
+        }
+        pump(&mut nodes);
+
+        // Node 0 is the seed, and node 4 joins through it.
+        let (mailboxes, receivers): (Vec<_>, Vec<mpsc::Receiver<Boot>>) =
+            (0..5).map(|_| mpsc::channel()).unzip();
+        let mailboxes = Mailboxes(mailboxes);
+        let mut seed = Bootstrap::create(
+            0,
+            nodes[0].endpoint.clone(),
+            PeerRole::Secondary,
+            vec![],
+            mailboxes.clone(),
+        );
+        let (sender, receiver) = mpsc::channel();
+        let endpoint = ChannelEndpoint::new(4, sender);
+        let mut joiner =
+            Bootstrap::create(4, endpoint.clone(), PeerRole::Secondary, vec![0], mailboxes);
+        let mut new_node: Node = LocalGossipNode::new(
+            UniformGossip::create(vec![], 4, GossipSet::default(), CHANNELS)
+                .with_peer_selector(UniformSelector::with_rng(StdRng::seed_from_u64(8))),
+            receiver,
+            endpoint,
+        );
+        assert!(!joiner.is_joined());
+        joiner.join().unwrap();
+        nodes[1].gossip.update(&GossipSetMessage::add(10)).unwrap();
+        pump(&mut nodes);
+        for message in receivers[0].try_iter() {
+            seed.receive(&mut nodes[0].gossip, &message).unwrap();
+        }
+        // This one gets to the new node through the seed before it's even heard back.
+        nodes[2].gossip.update(&GossipSetMessage::add(11)).unwrap();
+        pump(&mut nodes);
+        for message in receivers[4].try_iter() {
+            joiner.receive(&mut new_node.gossip, &message).unwrap();
+        }
+        assert!(joiner.is_joined());
+        assert_eq!(4, new_node.gossip.peers.len());
+        assert!((0..=10).all(|i| new_node.gossip.data().is_present(&i)));
+
+        // Old messages aren't news.
+        new_node.gossip.receive(&old[0]).unwrap();
+        assert!(nodes.iter().all(|n| n.receiver.try_recv().is_err()));
+
+        nodes.push(new_node);
+        nodes[4].gossip.update(&GossipSetMessage::add(12)).unwrap();
+        nodes[3].gossip.update(&GossipSetMessage::add(13)).unwrap();
+        pump(&mut nodes);
+        for node in nodes.iter() {
+            assert!((0..=13).all(|i| node.gossip.data().is_present(&i)));
+        }
+    }
+
+    /// A node that hasn't joined yet doesn't let others in.
+    #[test]
+    fn no_welcome_before_joining() {
+        let (sender, receiver) = mpsc::channel();
+        let mut node = Bootstrap::create(
+            1,
+            1,
+            PeerRole::Primary,
+            vec![0],
+            Mailboxes(vec![sender.clone(), sender]),
+        );
+        let mut gossip = PreferentialGossip::<usize, GossipSet<usize>, _, u128>::create(
+            vec![],
+            vec![],
+            true,
+            1,
+            GossipSet::default(),
+            crate::tests::Network(Default::default()),
+        );
+        let join = BootstrapMessage::Join {
+            reply_to: 0,
+            endpoint: 2,
+            role: PeerRole::Primary,
+        };
+        node.receive(&mut gossip, &join).unwrap();
+        assert!(receiver.try_recv().is_err());
+        node.receive(
+            &mut gossip,
+            &BootstrapMessage::Welcome(Welcome {
+                peers: vec![(0, PeerRole::Primary), (1, PeerRole::Primary)],
+                snapshot: StateSnapshot {
+                    data: vec![(5, 1, 0)],
+                    seen: vec![],
+                },
+            }),
+        )
+        .unwrap();
+        assert_eq!(vec![(5, 1, 0)], gossip.snapshot().data);
+        node.receive(&mut gossip, &join).unwrap();
+        assert_eq!(&[0, 2], gossip.primaries());
+        let Ok(BootstrapMessage::Welcome(welcome)) = receiver.try_recv() else {
+            panic!("Expected a welcome");
+        };
+        assert_eq!(
+            vec![(0, PeerRole::Primary), (1, PeerRole::Primary)],
+            welcome.peers
+        );
+    }
+}
@@ -0,0 +1,554 @@
+//! A fluent builder for gossip nodes, so the basic use cases don't need positional `create()`
+//! calls with every generic parameter spelled out.
+//! The builder only covers what the simpler gossip algorithms share (peers, primaries, fanout,
+//! seen store, peer selection and observation): any of them can be built from it by
+//! implementing `FromBuilder`.
+
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    io,
+    marker::PhantomData,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use crate::{
+    data::GossipSet,
+    net::UdpDelivery,
+    observe::NoopObserver,
+    seen::{SeenStore, UnboundedSeenStore},
+    select::UniformSelector,
+    PreferentialGossip, UniformGossip, PRIMARY_FORWARD_AFTER,
+};
+
+/// The fanout used when none is given to the builder.
+pub const DEFAULT_FANOUT: usize = 3;
+
+/// Error while building a gossip node.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The fanout is zero, so messages would never be passed on.
+    ZeroFanout,
+    /// The node's own endpoint is listed as one of its peers.
+    SelfAsPeer,
+    /// The same peer is listed more than once (e.g. as both a primary and a secondary).
+    DuplicatePeer,
+    /// Primaries were given for a gossip algorithm that treats all peers equally.
+    UnexpectedPrimaries,
+    /// The seen store stops counting before duplicates can be told apart from new messages,
+    /// or before a primary would pass messages on to secondaries.
+    SaturatingSeenStore,
+    /// An I/O error while setting up the delivery mechanism (e.g. binding a socket).
+    Io(io::Error),
+}
+
+/// The result of building a gossip node of type `G`.
+pub type BuildResult<G> = Result<G, BuildError>;
+
+/// Everything given to a `GossipBuilder`, validated, for a gossip algorithm to be built from.
+pub struct GossipParts<P, S, D, L, C, O> {
+    /// The (non-primary) peers.
+    pub peers: Vec<P>,
+    /// The primary peers.
+    pub primaries: Vec<P>,
+    /// Whether this node is a primary.
+    pub primary: bool,
+    /// How many peers to reach out to when gossipping.
+    pub fanout: usize,
+    /// The data being gossipped about.
+    pub data: S,
+    /// The delivery mechanism to send gossip messages.
+    pub delivery: D,
+    /// The store keeping track of the messages seen so far.
+    pub seen_messages: L,
+    /// The strategy for selecting which peers to gossip to.
+    pub selector: C,
+    /// The observer of the gossip decisions.
+    pub observer: O,
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach.
+    pub replace_failed: bool,
+}
+
+/// A gossip algorithm that can be built by a `GossipBuilder`.
+pub trait FromBuilder<P, S, D, I, L, C, O>: Sized {
+    /// Build the gossip algorithm from the (already validated) parts given to the builder,
+    /// failing if they don't make sense for it.
+    fn from_parts(parts: GossipParts<P, S, D, L, C, O>) -> BuildResult<Self>;
+}
+
+/// A fluent builder for gossip nodes that maintain the data `S` and deliver messages with `D`,
+/// with the same generic parameters as the gossip algorithms it builds.
+/// By default a node has no peers, isn't a primary, gossips with a fanout of `DEFAULT_FANOUT`
+/// to uniformly selected peers, remembers every message ID seen forever and isn't observed.
+pub struct GossipBuilder<
+    P,
+    S,
+    D,
+    I,
+    L = UnboundedSeenStore<I>,
+    C = UniformSelector,
+    O = NoopObserver,
+> {
+    parts: GossipParts<P, S, D, L, C, O>,
+    /// The node's own endpoint, if known (so it can't be listed as a peer).
+    own_endpoint: Option<P>,
+    _i: PhantomData<I>,
+}
+
+impl<P, S, D, I> GossipBuilder<P, S, D, I> {
+    /// Start building a gossip node that maintains the given `data` and delivers messages
+    /// using the given `delivery` mechanism.
+    pub fn new(data: S, delivery: D) -> GossipBuilder<P, S, D, I> {
+        GossipBuilder {
+            parts: GossipParts {
+                peers: Vec::new(),
+                primaries: Vec::new(),
+                primary: false,
+                fanout: DEFAULT_FANOUT,
+                data,
+                delivery,
+                seen_messages: UnboundedSeenStore::default(),
+                selector: UniformSelector::new(),
+                observer: NoopObserver,
+                replace_failed: false,
+            },
+            own_endpoint: None,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> GossipBuilder<P, S, D, I, L, C, O> {
+    /// Add the given peer to gossip to (as a secondary for algorithms that prefer primaries).
+    pub fn peer(mut self, peer: P) -> Self {
+        self.parts.peers.push(peer);
+        self
+    }
+
+    /// Add the given peers to gossip to (as secondaries for algorithms that prefer primaries).
+    pub fn peers<T: IntoIterator<Item = P>>(mut self, peers: T) -> Self {
+        self.parts.peers.extend(peers);
+        self
+    }
+
+    /// Add the given primary peers to gossip to.
+    pub fn primaries<T: IntoIterator<Item = P>>(mut self, primaries: T) -> Self {
+        self.parts.primaries.extend(primaries);
+        self
+    }
+
+    /// Whether this node acts as a primary itself.
+    pub fn primary(mut self, primary: bool) -> Self {
+        self.parts.primary = primary;
+        self
+    }
+
+    /// How many peers to reach out to when gossipping.
+    pub fn fanout(mut self, fanout: usize) -> Self {
+        self.parts.fanout = fanout;
+        self
+    }
+
+    /// The node's own endpoint, to make sure it's not listed as one of its own peers.
+    pub fn own_endpoint(mut self, endpoint: P) -> Self {
+        self.own_endpoint = Some(endpoint);
+        self
+    }
+
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach.
+    pub fn failed_peer_replacement(mut self, replace_failed: bool) -> Self {
+        self.parts.replace_failed = replace_failed;
+        self
+    }
+
+    /// Keep track of the messages seen in the given store.
+    pub fn seen_store<L2>(self, seen_messages: L2) -> GossipBuilder<P, S, D, I, L2, C, O> {
+        let parts = self.parts;
+        GossipBuilder {
+            parts: GossipParts {
+                peers: parts.peers,
+                primaries: parts.primaries,
+                primary: parts.primary,
+                fanout: parts.fanout,
+                data: parts.data,
+                delivery: parts.delivery,
+                seen_messages,
+                selector: parts.selector,
+                observer: parts.observer,
+                replace_failed: parts.replace_failed,
+            },
+            own_endpoint: self.own_endpoint,
+            _i: PhantomData,
+        }
+    }
+
+    /// Use the given `selector` to select which peers to gossip to.
+    pub fn peer_selector<C2>(self, selector: C2) -> GossipBuilder<P, S, D, I, L, C2, O> {
+        let parts = self.parts;
+        GossipBuilder {
+            parts: GossipParts {
+                peers: parts.peers,
+                primaries: parts.primaries,
+                primary: parts.primary,
+                fanout: parts.fanout,
+                data: parts.data,
+                delivery: parts.delivery,
+                seen_messages: parts.seen_messages,
+                selector,
+                observer: parts.observer,
+                replace_failed: parts.replace_failed,
+            },
+            own_endpoint: self.own_endpoint,
+            _i: PhantomData,
+        }
+    }
+
+    /// Notify the given `observer` of the gossip decisions.
+    pub fn observer<O2>(self, observer: O2) -> GossipBuilder<P, S, D, I, L, C, O2> {
+        let parts = self.parts;
+        GossipBuilder {
+            parts: GossipParts {
+                peers: parts.peers,
+                primaries: parts.primaries,
+                primary: parts.primary,
+                fanout: parts.fanout,
+                data: parts.data,
+                delivery: parts.delivery,
+                seen_messages: parts.seen_messages,
+                selector: parts.selector,
+                observer,
+                replace_failed: parts.replace_failed,
+            },
+            own_endpoint: self.own_endpoint,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> GossipBuilder<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash,
+    L: SeenStore<I>,
+{
+    /// Check that the fanout is positive, that the seen store counts past `1`, that no peer
+    /// is listed twice and that the node isn't its own peer.
+    fn validate(&self) -> Result<(), BuildError> {
+        if self.parts.fanout == 0 {
+            return Err(BuildError::ZeroFanout);
+        }
+        // A store that saturates at 1 reports every duplicate as new, so it'd be passed on forever.
+        if self
+            .parts
+            .seen_messages
+            .max_count()
+            .is_some_and(|max| max < 2)
+        {
+            return Err(BuildError::SaturatingSeenStore);
+        }
+        let all_peers = self.parts.primaries.iter().chain(self.parts.peers.iter());
+        let mut seen = HashSet::new();
+        for peer in all_peers {
+            if self.own_endpoint.as_ref() == Some(peer) {
+                return Err(BuildError::SelfAsPeer);
+            }
+            if !seen.insert(peer) {
+                return Err(BuildError::DuplicatePeer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Build any gossip algorithm that can be built from the builder.
+    pub fn build<G>(self) -> BuildResult<G>
+    where
+        G: FromBuilder<P, S, D, I, L, C, O>,
+    {
+        self.validate()?;
+        G::from_parts(self.parts)
+    }
+
+    /// Build a uniform gossip node, that gossips to all its peers equally.
+    /// Fails if any primaries were given.
+    pub fn build_uniform(self) -> BuildResult<UniformGossip<P, S, D, I, L, C, O>>
+    where
+        P: Clone,
+    {
+        self.build()
+    }
+
+    /// Build a preferential gossip node, that gossips to its primaries first.
+    /// Fails if this node is a primary and the seen store can't count past `2`.
+    pub fn build_preferential(self) -> BuildResult<PreferentialGossip<P, S, D, I, L, C, O>>
+    where
+        P: Clone,
+    {
+        self.build()
+    }
+}
+
+impl<P, S, D, I, L, C, O> FromBuilder<P, S, D, I, L, C, O> for UniformGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn from_parts(parts: GossipParts<P, S, D, L, C, O>) -> BuildResult<Self> {
+        if !parts.primaries.is_empty() {
+            return Err(BuildError::UnexpectedPrimaries);
+        }
+        Ok(UniformGossip::create_with_seen_store(
+            parts.peers,
+            parts.fanout,
+            parts.data,
+            parts.delivery,
+            parts.seen_messages,
+        )
+        .with_peer_selector(parts.selector)
+        .with_observer(parts.observer)
+        .with_failed_peer_replacement(parts.replace_failed))
+    }
+}
+
+impl<P, S, D, I, L, C, O> FromBuilder<P, S, D, I, L, C, O>
+    for PreferentialGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+    L: SeenStore<I>,
+{
+    fn from_parts(parts: GossipParts<P, S, D, L, C, O>) -> BuildResult<Self> {
+        let max_count = parts.seen_messages.max_count();
+        if parts.primary && max_count.is_some_and(|max| max <= PRIMARY_FORWARD_AFTER) {
+            return Err(BuildError::SaturatingSeenStore);
+        }
+        Ok(PreferentialGossip::create_with_seen_store(
+            parts.primaries,
+            parts.peers,
+            parts.primary,
+            parts.fanout,
+            parts.data,
+            parts.delivery,
+            parts.seen_messages,
+        )
+        .with_peer_selector(parts.selector)
+        .with_observer(parts.observer)
+        .with_failed_peer_replacement(parts.replace_failed))
+    }
+}
+
+/// A uniform gossip node over UDP maintaining a `GossipSet`, as created by `udp_gossip_set()`.
+pub type UdpGossipSetNode<T, Ser> = UniformGossip<SocketAddr, GossipSet<T>, UdpDelivery<Ser>, u128>;
+
+/// Create a uniform gossip node maintaining an (initially empty) `GossipSet`, that gossips
+/// over a UDP socket bound to the given `address` to the given `peers`, with the given
+/// `fanout`, serializing messages with `serializer`.
+/// Messages for the node arrive on the same socket (`delivery.socket`), and should be
+/// passed to `receive_from()` with the address they came from.
+/// The node's own endpoint is the address the socket is actually bound to, so binding to a
+/// wildcard address (e.g. `0.0.0.0:4000`) won't catch the node being listed as a peer under
+/// a specific one (e.g. `127.0.0.1:4000`).
+pub fn udp_gossip_set<T, Ser, A>(
+    address: A,
+    peers: Vec<SocketAddr>,
+    fanout: usize,
+    serializer: Ser,
+) -> BuildResult<UdpGossipSetNode<T, Ser>>
+where
+    A: ToSocketAddrs,
+{
+    let socket = UdpSocket::bind(address).map_err(BuildError::Io)?;
+    let own_endpoint = socket.local_addr().map_err(BuildError::Io)?;
+    GossipBuilder::new(GossipSet::default(), UdpDelivery::new(socket, serializer))
+        .peers(peers)
+        .fanout(fanout)
+        .own_endpoint(own_endpoint)
+        .build_uniform()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use crate::{
+        bloom::BloomSeenStore,
+        classified::ClassifiedGossip,
+        data::{GossipSetAction, GossipSetMessage},
+        metrics::GossipMetrics,
+        net::ToBytes,
+        seen::LruSeenStore,
+        tests::Network,
+        Gossip, ReceiveFrom,
+    };
+
+    fn network() -> Network {
+        Network(RefCell::new(HashMap::new()))
+    }
+
+    /// A seen store that can't tell how many times it's seen a message.
+    struct CountsToOne(HashSet<usize>);
+
+    impl SeenStore<usize> for CountsToOne {
+        fn see(&mut self, id: usize) -> usize {
+            self.0.insert(id);
+            1
+        }
+
+        fn contains(&self, id: &usize) -> bool {
+            self.0.contains(id)
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn max_count(&self) -> Option<usize> {
+            Some(1)
+        }
+    }
+
+    /// The defaults and every setting given to the builder make it into the gossip.
+    #[test]
+    fn uniform() {
+        let gossip: UniformGossip<usize, (), Network, usize, _> = GossipBuilder::new((), network())
+            .peers([1, 2])
+            .peer(3)
+            .seen_store(LruSeenStore::<usize>::new(10))
+            .failed_peer_replacement(true)
+            .build_uniform()
+            .unwrap();
+        assert_eq!(&[1, 2, 3], gossip.peers.as_slice());
+        assert_eq!(DEFAULT_FANOUT, gossip.fanout);
+        assert!(gossip.replace_failed);
+        let mut gossip: UniformGossip<_, _, _, usize> = GossipBuilder::new((), network())
+            .peers([1, 2, 3])
+            .fanout(1)
+            .build_uniform()
+            .unwrap();
+        gossip.update(&10).unwrap();
+        assert_eq!(1, gossip.delivery.0.borrow().len());
+    }
+
+    /// Nonsensical inputs are caught before anything is built.
+    #[test]
+    fn validation() {
+        let builder = || GossipBuilder::<usize, (), Network, usize>::new((), network());
+        assert!(matches!(
+            builder().peer(1).fanout(0).build_uniform(),
+            Err(BuildError::ZeroFanout)
+        ));
+        assert!(matches!(
+            builder().peers([1, 2]).own_endpoint(2).build_uniform(),
+            Err(BuildError::SelfAsPeer)
+        ));
+        assert!(matches!(
+            builder()
+                .primaries([0, 1])
+                .primary(true)
+                .own_endpoint(0)
+                .build_preferential(),
+            Err(BuildError::SelfAsPeer)
+        ));
+        assert!(matches!(
+            builder().primaries([1]).peers([1, 2]).build_preferential(),
+            Err(BuildError::DuplicatePeer)
+        ));
+        assert!(matches!(
+            builder().primaries([1]).peers([2]).build_uniform(),
+            Err(BuildError::UnexpectedPrimaries)
+        ));
+        assert!(matches!(
+            GossipBuilder::<usize, (), Network, usize>::new((), network())
+                .primaries([1])
+                .primary(true)
+                .seen_store(BloomSeenStore::new(100, 0.01, 2))
+                .build_preferential(),
+            Err(BuildError::SaturatingSeenStore)
+        ));
+        assert!(matches!(
+            GossipBuilder::<usize, (), Network, usize>::new((), network())
+                .peers([1, 2])
+                .seen_store(CountsToOne(HashSet::new()))
+                .build_uniform(),
+            Err(BuildError::SaturatingSeenStore)
+        ));
+        assert!(matches!(
+            GossipBuilder::<usize, (), Network, usize>::new((), network())
+                .primaries([1])
+                .peers([2])
+                .seen_store(CountsToOne(HashSet::new()))
+                .build::<ClassifiedGossip<_, _, _, usize, _>>(),
+            Err(BuildError::SaturatingSeenStore)
+        ));
+    }
+
+    /// Primaries and peers end up as primaries and secondaries.
+    #[test]
+    fn preferential() {
+        let mut gossip: PreferentialGossip<_, _, _, usize, _, _, _> =
+            GossipBuilder::new((), network())
+                .primaries([1, 2])
+                .peers([3])
+                .primary(true)
+                .own_endpoint(0)
+                .observer(GossipMetrics::default())
+                .build_preferential()
+                .unwrap();
+        assert_eq!(&[1, 2], gossip.primaries());
+        assert_eq!(&[3], gossip.secondaries());
+        gossip.update(&10).unwrap();
+        assert_eq!(1, gossip.observer().updates);
+    }
+
+    /// Algorithms other than the basic two can be built too.
+    #[test]
+    fn classified() {
+        let gossip: ClassifiedGossip<_, _, _, usize> = GossipBuilder::new((), network())
+            .primaries([1])
+            .peers([2, 3])
+            .build()
+            .unwrap();
+        assert_eq!(&[1], gossip.primaries());
+        assert_eq!(3, gossip.peers().len());
+    }
+
+    struct Ser();
+
+    impl ToBytes<GossipSetMessage<u8>> for Ser {
+        type Bytes = [u8; 1];
+
+        type Error = ();
+
+        fn to_bytes(&self, message: &GossipSetMessage<u8>) -> Result<Self::Bytes, ()> {
+            match message.action {
+                GossipSetAction::Add(v) => Ok([v]),
+                GossipSetAction::Remove(_) => Err(()),
+            }
+        }
+    }
+
+    /// A UDP node can be created in one call.
+    #[test]
+    fn udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = receiver.local_addr().unwrap();
+        let mut node: UdpGossipSetNode<u8, _> =
+            udp_gossip_set("127.0.0.1:0", vec![peer], 1, Ser()).unwrap();
+        let own = node.delivery.socket.local_addr().unwrap();
+        node.update(&GossipSetMessage::add(5)).unwrap();
+        assert!(node.data().is_present(&5));
+        let mut buf = [0; 1];
+        let (len, from) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!((1, own, 5), (len, from, buf[0]));
+        // Echoing a message back to its sender is skipped.
+        node.receive_from(&GossipSetMessage::add(6), &peer).unwrap();
+        assert!(node.data().is_present(&6));
+        receiver.set_nonblocking(true).unwrap();
+        assert_eq!(
+            io::ErrorKind::WouldBlock,
+            receiver.recv_from(&mut buf).unwrap_err().kind()
+        );
+        // The address is taken now.
+        assert!(matches!(
+            udp_gossip_set::<u8, _, _>(own, vec![own], 1, Ser()),
+            Err(BuildError::Io(_))
+        ));
+    }
+}
@@ -1,13 +1,22 @@
 //! Implementation of Gossip over local `mpsc` channels.
 
+use rand::{rngs::StdRng, thread_rng, Rng};
 use std::{
-    hash::Hash,
+    hash::{Hash, Hasher},
     marker::PhantomData,
     sync::mpsc::{self, SendError},
 };
 
 use crate::{
-    data::GossipSet, Delivery, Gossip, Message, PreferentialGossip, SharedData, UniformGossip,
+    data::GossipSet,
+    plumtree::{PlumtreeConfig, PlumtreeGossip, PlumtreeMessage},
+    rng::derive_rng,
+    rumor::{HopCount, RumorConfig, RumorGossip},
+    seen::{SeenStore, UnboundedSeenStore},
+    select::UniformSelector,
+    tiered::{tier_for_node, TierConfig, TieredGossip},
+    Delivery, DeliveryReport, Gossip, Message, PreferentialGossip, ReceiveFrom, SharedData,
+    UniformGossip,
 };
 
 /// An implementation of `Delivery` that delivers to `mpsc` receivers as endpoints.
@@ -16,7 +25,79 @@ pub struct Channels();
 /// The singleton `Channels`.
 pub const CHANNELS: Channels = Channels();
 
-impl<M> Delivery<M, mpsc::Sender<M>> for Channels
+/// A message sent through `TaggedChannels`, tagged with the index of the node that sent it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tagged<M> {
+    /// The index of the sending node in the network.
+    pub sender: usize,
+    /// The message.
+    pub message: M,
+}
+
+/// An implementation of `Delivery` that delivers to `mpsc` receivers as endpoints like `Channels`,
+/// but tags every message with the index of the sending node (so the receiving node can pass
+/// it to `receive_from()`).
+pub struct TaggedChannels {
+    /// The index of the sending node in the network.
+    sender: usize,
+}
+
+impl TaggedChannels {
+    /// Create a delivery mechanism for the node with the given `sender` index.
+    pub fn new(sender: usize) -> TaggedChannels {
+        TaggedChannels { sender }
+    }
+}
+
+/// An endpoint for a peer within a local gossip network, identified by the index of the node
+/// in the network (so two endpoints for the same node are equal).
+#[derive(Clone)]
+pub struct ChannelEndpoint<M> {
+    /// The index of the node in the network.
+    index: usize,
+    /// The sender of messages to the node.
+    sender: mpsc::Sender<M>,
+}
+
+impl<M> ChannelEndpoint<M> {
+    /// Create a new endpoint for the node with the given `index` that receives messages
+    /// sent to the given `sender`.
+    pub fn new(index: usize, sender: mpsc::Sender<M>) -> ChannelEndpoint<M> {
+        ChannelEndpoint { index, sender }
+    }
+
+    /// The index of the node in the network.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The sender of messages to the node.
+    pub fn sender(&self) -> &mpsc::Sender<M> {
+        &self.sender
+    }
+}
+
+impl<M> PartialEq for ChannelEndpoint<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<M> Eq for ChannelEndpoint<M> {}
+
+impl<M> PartialEq<usize> for ChannelEndpoint<M> {
+    fn eq(&self, other: &usize) -> bool {
+        self.index == *other
+    }
+}
+
+impl<M> Hash for ChannelEndpoint<M> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<M> Delivery<M, ChannelEndpoint<M>> for Channels
 where
     M: Clone,
 {
@@ -24,13 +105,65 @@ where
 
     fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), SendError<M>>
     where
-        I: ExactSizeIterator<Item = &'a mpsc::Sender<M>>,
+        I: ExactSizeIterator<Item = &'a ChannelEndpoint<M>>,
+        M: 'a,
+    {
+        self.deliver_each(message, endpoints)?.into_result()
+    }
+
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<SendError<M>>, SendError<M>>
+    where
+        I: ExactSizeIterator<Item = &'a ChannelEndpoint<M>>,
+        M: 'a,
+    {
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.enumerate() {
+            if let Err(e) = endpoint.sender.send(message.clone()) {
+                report.failures.push((position, e));
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<M> Delivery<M, ChannelEndpoint<Tagged<M>>> for TaggedChannels
+where
+    M: Clone,
+{
+    type Error = SendError<Tagged<M>>;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a ChannelEndpoint<Tagged<M>>>,
+        M: 'a,
+    {
+        self.deliver_each(message, endpoints)?.into_result()
+    }
+
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a ChannelEndpoint<Tagged<M>>>,
         M: 'a,
     {
-        for endpoint in endpoints {
-            endpoint.send(message.clone())?;
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.enumerate() {
+            let tagged = Tagged {
+                sender: self.sender,
+                message: message.clone(),
+            };
+            if let Err(e) = endpoint.sender.send(tagged) {
+                report.failures.push((position, e));
+            }
         }
-        Ok(())
+        Ok(report)
     }
 }
 
@@ -44,22 +177,148 @@ where
     pub gossip: G,
     /// The receiver for messages intended for this node.
     pub receiver: mpsc::Receiver<M>,
-    /// The sender of messages to this node.
-    pub sender: mpsc::Sender<M>,
+    /// The endpoint for sending messages to this node (e.g. to add it as a peer to other nodes).
+    pub endpoint: ChannelEndpoint<M>,
+    _s: PhantomData<S>,
+}
+
+impl<G, S, M> LocalGossipNode<G, S, M>
+where
+    G: Gossip<M, S>,
+{
+    /// Create a node running the given `gossip` for the messages coming on `receiver`, which
+    /// are sent to it through `endpoint` (e.g. for a node joining an existing network).
+    pub fn new(
+        gossip: G,
+        receiver: mpsc::Receiver<M>,
+        endpoint: ChannelEndpoint<M>,
+    ) -> LocalGossipNode<G, S, M> {
+        LocalGossipNode {
+            gossip,
+            receiver,
+            endpoint,
+            _s: PhantomData,
+        }
+    }
+}
+
+/// A representation of a gossip "node" like `LocalGossipNode`, but whose messages come through
+/// `TaggedChannels`, tagged with the index of the node that sent them. Passing them to the
+/// gossip with `receive_from()` (which `receive()` does) keeps them from being echoed back to
+/// their sender.
+pub struct LocalTaggedGossipNode<G, S, M>
+where
+    G: Gossip<M, S>,
+{
+    /// The gossip for that node.
+    pub gossip: G,
+    /// The receiver for messages intended for this node.
+    pub receiver: mpsc::Receiver<Tagged<M>>,
+    /// The endpoint for sending messages to this node (e.g. to add it as a peer to other nodes).
+    pub endpoint: ChannelEndpoint<Tagged<M>>,
     _s: PhantomData<S>,
 }
 
+impl<G, S, M> LocalTaggedGossipNode<G, S, M>
+where
+    G: Gossip<M, S>,
+{
+    /// Create a node running the given `gossip` for the messages coming on `receiver`, which
+    /// are sent to it through `endpoint` (e.g. for a node joining an existing network).
+    pub fn new(
+        gossip: G,
+        receiver: mpsc::Receiver<Tagged<M>>,
+        endpoint: ChannelEndpoint<Tagged<M>>,
+    ) -> LocalTaggedGossipNode<G, S, M> {
+        LocalTaggedGossipNode {
+            gossip,
+            receiver,
+            endpoint,
+            _s: PhantomData,
+        }
+    }
+
+    /// Pass the given message received by this node on to the gossip, as coming from the
+    /// node it's tagged with.
+    pub fn receive(&mut self, tagged: &Tagged<M>) -> Result<(), G::Error>
+    where
+        G: ReceiveFrom<M, S, usize>,
+    {
+        self.gossip.receive_from(&tagged.message, &tagged.sender)
+    }
+}
+
 /// A representation of a gossip "node" that is a local `mpsc` receiver using uniform gossip technique.
-pub type LocalUniformGossipSetNode<T, M, I> =
-    LocalGossipNode<UniformGossip<mpsc::Sender<M>, GossipSet<T>, Channels, I>, GossipSet<T>, M>;
+/// `S` is the data every node maintains.
+pub type LocalUniformGossipNode<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNode<
+    UniformGossip<ChannelEndpoint<M>, S, Channels, I, L, UniformSelector<StdRng>>,
+    S,
+    M,
+>;
+
+/// A `LocalUniformGossipNode` maintaining a gossip set.
+pub type LocalUniformGossipSetNode<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalUniformGossipNode<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node" that is a local `mpsc` receiver using uniform gossip
+/// technique, whose messages are tagged with the node that sent them.
+/// `S` is the data every node maintains.
+pub type LocalTaggedUniformGossipNode<S, M, I, L = UnboundedSeenStore<I>> = LocalTaggedGossipNode<
+    UniformGossip<ChannelEndpoint<Tagged<M>>, S, TaggedChannels, I, L, UniformSelector<StdRng>>,
+    S,
+    M,
+>;
+
+/// A `LocalTaggedUniformGossipNode` maintaining a gossip set.
+pub type LocalTaggedUniformGossipSetNode<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalTaggedUniformGossipNode<GossipSet<T>, M, I, L>;
 
 /// A representation of a gossip "node" that is a local `mpsc` receiver using preferential gossip technique.
-pub type LocalPreferentialGossipSetNode<T, M, I> = LocalGossipNode<
-    PreferentialGossip<mpsc::Sender<M>, GossipSet<T>, Channels, I>,
-    GossipSet<T>,
+/// `S` is the data every node maintains.
+pub type LocalPreferentialGossipNode<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNode<
+    PreferentialGossip<ChannelEndpoint<M>, S, Channels, I, L, UniformSelector<StdRng>>,
+    S,
     M,
 >;
 
+/// A `LocalPreferentialGossipNode` maintaining a gossip set.
+pub type LocalPreferentialGossipSetNode<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalPreferentialGossipNode<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node" that is a local `mpsc` receiver using tiered gossip technique.
+/// `S` is the data every node maintains.
+pub type LocalTieredGossipNode<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNode<
+    TieredGossip<ChannelEndpoint<M>, S, Channels, I, L, UniformSelector<StdRng>>,
+    S,
+    M,
+>;
+
+/// A `LocalTieredGossipNode` maintaining a gossip set.
+pub type LocalTieredGossipSetNode<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalTieredGossipNode<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node" that is a local `mpsc` receiver using Plumtree gossip,
+/// where nodes are identified by their index in the network.
+/// `S` is the data every node maintains.
+pub type LocalPlumtreeGossipNode<S, M, I, L = UnboundedSeenStore<I>> =
+    LocalGossipNode<PlumtreeGossip<usize, ChannelEndpoint<M>, S, Channels, M, I, L>, S, M>;
+
+/// A `LocalPlumtreeGossipNode` maintaining a gossip set.
+pub type LocalPlumtreeGossipSetNode<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalPlumtreeGossipNode<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node" that is a local `mpsc` receiver using rumor mongering.
+/// `S` is the data every node maintains.
+pub type LocalRumorGossipNode<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNode<
+    RumorGossip<ChannelEndpoint<M>, S, Channels, I, L, UniformSelector<StdRng>, StdRng>,
+    S,
+    M,
+>;
+
+/// A `LocalRumorGossipNode` maintaining a gossip set.
+pub type LocalRumorGossipSetNode<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalRumorGossipNode<GossipSet<T>, M, I, L>;
+
 /// Creates a set of local gossip "nodes" that maintain a gossip set.
 /// Each node can be independently maintained in its own thread and will gossip
 /// with the other threads.
@@ -73,38 +332,202 @@ where
     M: Clone + Message,
     GossipSet<T>: SharedData<M>,
     <M as Message>::I: Hash + Eq,
+{
+    uniform_local_gossip_set_with_rng(num_nodes, fanout, &mut thread_rng())
+}
+
+/// Creates a set of local gossip "nodes" like `uniform_local_gossip_set()`, but
+/// with every node selecting the peers to gossip to with its own generator seeded from
+/// `rng`, so a seeded `rng` creates a reproducible network.
+pub fn uniform_local_gossip_set_with_rng<T, M, R>(
+    num_nodes: usize,
+    fanout: usize,
+    rng: &mut R,
+) -> Vec<LocalUniformGossipSetNode<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    uniform_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        fanout,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `uniform_local_gossip_set()`, but with every
+/// node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn uniform_local_gossip_set_with_seen_store<T, M, L, F>(
+    num_nodes: usize,
+    fanout: usize,
+    new_seen_store: F,
+) -> Vec<LocalUniformGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    uniform_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        fanout,
+        new_seen_store,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `uniform_local_gossip_set_with_seen_store()`, but
+/// with every node selecting the peers to gossip to with its own generator seeded from
+/// `rng`, so a seeded `rng` creates a reproducible network.
+pub fn uniform_local_gossip_set_with_seen_store_and_rng<T, M, L, F, R>(
+    num_nodes: usize,
+    fanout: usize,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalUniformGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    uniform_local_gossip(num_nodes, fanout, GossipSet::default, new_seen_store, rng)
+}
+
+/// Creates a set of local gossip "nodes" like `uniform_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn uniform_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    fanout: usize,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalUniformGossipNode<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
 {
     // Create the senders and receivers for the nodes.
     let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
     // First create a Vec<> with all the gossips
     let mut gossips = Vec::with_capacity(num_nodes);
     for i in 0..num_nodes {
-        // Create an empty set
-        let data = GossipSet::default();
+        // Create the data
+        let data = new_data();
         // Create the set of senders (peers) for the node
         let mut peers = Vec::with_capacity(num_nodes - 1);
         for (j, other) in channels.iter().enumerate() {
             // Add every sender except the one for the node
             if i != j {
-                peers.push(other.0.clone());
+                peers.push(ChannelEndpoint::new(j, other.0.clone()));
             }
         }
         // Add the node
-        gossips.push(UniformGossip::create(peers, fanout, data, CHANNELS));
+        gossips.push(
+            UniformGossip::create_with_seen_store(peers, fanout, data, CHANNELS, new_seen_store())
+                .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
     }
     // Then add the senders and receivers to create the network
     gossips
         .into_iter()
-        .zip(channels.into_iter())
-        .map(|(gossip, (sender, receiver))| LocalGossipNode {
+        .zip(channels)
+        .enumerate()
+        .map(|(i, (gossip, (sender, receiver)))| LocalGossipNode {
             gossip,
             receiver,
-            sender,
+            endpoint: ChannelEndpoint::new(i, sender),
             _s: PhantomData,
         })
         .collect()
 }
 
+/// Creates a set of local gossip "nodes" like `uniform_local_gossip_set()`, but with every
+/// message tagged with the node that sent it, so that it isn't echoed back to its sender
+/// (see `LocalTaggedGossipNode`).
+pub fn uniform_local_tagged_gossip_set<T, M>(
+    num_nodes: usize,
+    fanout: usize,
+) -> Vec<LocalTaggedUniformGossipSetNode<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+{
+    uniform_local_tagged_gossip(
+        num_nodes,
+        fanout,
+        GossipSet::default,
+        UnboundedSeenStore::default,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `uniform_local_gossip()`, but with every
+/// message tagged with the node that sent it, so that it isn't echoed back to its sender
+/// (see `LocalTaggedGossipNode`).
+pub fn uniform_local_tagged_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    fanout: usize,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalTaggedUniformGossipNode<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    // Create the senders and receivers for the nodes.
+    let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> with all the gossips
+    let mut gossips = Vec::with_capacity(num_nodes);
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of senders (peers) for the node
+        let mut peers = Vec::with_capacity(num_nodes - 1);
+        for (j, other) in channels.iter().enumerate() {
+            // Add every sender except the one for the node
+            if i != j {
+                peers.push(ChannelEndpoint::new(j, other.0.clone()));
+            }
+        }
+        // Add the node, tagging everything it sends with its index
+        gossips.push(
+            UniformGossip::create_with_seen_store(
+                peers,
+                fanout,
+                data,
+                TaggedChannels::new(i),
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .enumerate()
+        .map(|(i, (gossip, (sender, receiver)))| {
+            LocalTaggedGossipNode::new(gossip, receiver, ChannelEndpoint::new(i, sender))
+        })
+        .collect()
+}
+
 /// Creates a set of local gossip "nodes" that maintain a gossip set.
 /// Each node can be independently maintained in its own thread and will gossip
 /// with the other threads.
@@ -121,6 +544,102 @@ where
     M: Clone + Message,
     GossipSet<T>: SharedData<M>,
     <M as Message>::I: Hash + Eq,
+{
+    preferential_local_gossip_set_with_rng(num_nodes, num_primaries, fanout, &mut thread_rng())
+}
+
+/// Creates a set of local gossip "nodes" like `preferential_local_gossip_set()`, but
+/// with every node selecting the peers to gossip to with its own generator seeded from
+/// `rng`, so a seeded `rng` creates a reproducible network.
+pub fn preferential_local_gossip_set_with_rng<T, M, R>(
+    num_nodes: usize,
+    num_primaries: usize,
+    fanout: usize,
+    rng: &mut R,
+) -> Vec<LocalPreferentialGossipSetNode<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    preferential_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        num_primaries,
+        fanout,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `preferential_local_gossip_set()`, but with every
+/// node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn preferential_local_gossip_set_with_seen_store<T, M, L, F>(
+    num_nodes: usize,
+    num_primaries: usize,
+    fanout: usize,
+    new_seen_store: F,
+) -> Vec<LocalPreferentialGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    preferential_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        num_primaries,
+        fanout,
+        new_seen_store,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `preferential_local_gossip_set_with_seen_store()`, but
+/// with every node selecting the peers to gossip to with its own generator seeded from
+/// `rng`, so a seeded `rng` creates a reproducible network.
+pub fn preferential_local_gossip_set_with_seen_store_and_rng<T, M, L, F, R>(
+    num_nodes: usize,
+    num_primaries: usize,
+    fanout: usize,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalPreferentialGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    preferential_local_gossip(
+        num_nodes,
+        num_primaries,
+        fanout,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `preferential_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn preferential_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    num_primaries: usize,
+    fanout: usize,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalPreferentialGossipNode<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
 {
     // Create the senders and receivers for the nodes.
     let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
@@ -128,8 +647,8 @@ where
     let mut gossips = Vec::with_capacity(num_nodes);
     let num_secondaries = num_nodes - num_primaries;
     for i in 0..num_nodes {
-        // Create an empty set
-        let data = GossipSet::default();
+        // Create the data
+        let data = new_data();
         // Create the set of senders (peers) for the node
         let primary = i < num_primaries;
         let mut primaries = Vec::with_capacity(if primary {
@@ -146,30 +665,364 @@ where
             // Add every sender except the one for the node
             if i != j {
                 if j < num_primaries {
-                    primaries.push(other.0.clone());
+                    primaries.push(ChannelEndpoint::new(j, other.0.clone()));
                 } else {
-                    secondaries.push(other.0.clone());
+                    secondaries.push(ChannelEndpoint::new(j, other.0.clone()));
                 }
             }
         }
         // Add the node
-        gossips.push(PreferentialGossip::create(
-            primaries,
-            secondaries,
-            primary,
-            fanout,
+        gossips.push(
+            PreferentialGossip::create_with_seen_store(
+                primaries,
+                secondaries,
+                primary,
+                fanout,
+                data,
+                CHANNELS,
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .enumerate()
+        .map(|(i, (gossip, (sender, receiver)))| LocalGossipNode {
+            gossip,
+            receiver,
+            endpoint: ChannelEndpoint::new(i, sender),
+            _s: PhantomData,
+        })
+        .collect()
+}
+
+/// Creates a set of local gossip "nodes" that maintain a gossip set.
+/// Each node can be independently maintained in its own thread and will gossip
+/// with the other threads.
+/// The nodes spread updates by rumor mongering as configured by `config`.
+/// Every node selects the peers to gossip to and flips its coins with its own generators
+/// seeded from `rng`, so a seeded `rng` creates a reproducible network.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn rumor_local_gossip_set<T, M, R>(
+    num_nodes: usize,
+    config: RumorConfig,
+    rng: &mut R,
+) -> Vec<LocalRumorGossipSetNode<T, M, M::I>>
+where
+    M: Clone + HopCount,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    R: Rng + ?Sized,
+{
+    rumor_local_gossip_set_with_seen_store(num_nodes, config, UnboundedSeenStore::default, rng)
+}
+
+/// Creates a set of local gossip "nodes" like `rumor_local_gossip_set()`, but with every
+/// node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn rumor_local_gossip_set_with_seen_store<T, M, L, F, R>(
+    num_nodes: usize,
+    config: RumorConfig,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalRumorGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + HopCount,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I> + Clone,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    rumor_local_gossip(num_nodes, config, GossipSet::default, new_seen_store, rng)
+}
+
+/// Creates a set of local gossip "nodes" like `rumor_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn rumor_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    config: RumorConfig,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalRumorGossipNode<S, M, M::I, L>>
+where
+    M: Clone + HopCount,
+    S: SharedData<M>,
+    L: SeenStore<M::I> + Clone,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    // Create the senders and receivers for the nodes.
+    let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> with all the gossips
+    let mut gossips = Vec::with_capacity(num_nodes);
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of senders (peers) for the node
+        let mut peers = Vec::with_capacity(num_nodes - 1);
+        for (j, other) in channels.iter().enumerate() {
+            // Add every sender except the one for the node
+            if i != j {
+                peers.push(ChannelEndpoint::new(j, other.0.clone()));
+            }
+        }
+        // Add the node
+        gossips.push(
+            RumorGossip::create_with_seen_store(peers, config, data, CHANNELS, new_seen_store())
+                .with_peer_selector(UniformSelector::with_rng(derive_rng(rng)))
+                .with_rng(derive_rng(rng)),
+        );
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .enumerate()
+        .map(|(i, (gossip, (sender, receiver)))| LocalGossipNode {
+            gossip,
+            receiver,
+            endpoint: ChannelEndpoint::new(i, sender),
+            _s: PhantomData,
+        })
+        .collect()
+}
+
+/// Creates a set of local gossip "nodes" that maintain a gossip set.
+/// Each node can be independently maintained in its own thread and will gossip
+/// with the other threads.
+/// The nodes use Plumtree gossip as configured by `config`, identified to each other by their
+/// index in the returned `Vec<>` (so `tick()` has to be called on every node regularly).
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn plumtree_local_gossip_set<T, M>(
+    num_nodes: usize,
+    config: PlumtreeConfig,
+) -> Vec<LocalPlumtreeGossipSetNode<T, M, M::I>>
+where
+    M: Clone + PlumtreeMessage<usize>,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+{
+    plumtree_local_gossip_set_with_seen_store(num_nodes, config, UnboundedSeenStore::default)
+}
+
+/// Creates a set of local gossip "nodes" like `plumtree_local_gossip_set()`, but with every
+/// node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn plumtree_local_gossip_set_with_seen_store<T, M, L, F>(
+    num_nodes: usize,
+    config: PlumtreeConfig,
+    new_seen_store: F,
+) -> Vec<LocalPlumtreeGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + PlumtreeMessage<usize>,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    plumtree_local_gossip(num_nodes, config, GossipSet::default, new_seen_store)
+}
+
+/// Creates a set of local gossip "nodes" like `plumtree_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn plumtree_local_gossip<S, M, L, N, F>(
+    num_nodes: usize,
+    config: PlumtreeConfig,
+    mut new_data: N,
+    mut new_seen_store: F,
+) -> Vec<LocalPlumtreeGossipNode<S, M, M::I, L>>
+where
+    M: Clone + PlumtreeMessage<usize>,
+    S: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+{
+    // Create the senders and receivers for the nodes.
+    let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> with all the gossips
+    let mut gossips = Vec::with_capacity(num_nodes);
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of senders (peers) for the node, by index
+        let mut peers = Vec::with_capacity(num_nodes - 1);
+        for (j, other) in channels.iter().enumerate() {
+            // Add every sender except the one for the node
+            if i != j {
+                peers.push((j, ChannelEndpoint::new(j, other.0.clone())));
+            }
+        }
+        // Add the node
+        gossips.push(PlumtreeGossip::create_with_seen_store(
+            i,
+            peers,
+            config,
             data,
             CHANNELS,
+            new_seen_store(),
         ));
     }
     // Then add the senders and receivers to create the network
     gossips
         .into_iter()
-        .zip(channels.into_iter())
-        .map(|(gossip, (sender, receiver))| LocalGossipNode {
+        .zip(channels)
+        .enumerate()
+        .map(|(i, (gossip, (sender, receiver)))| LocalGossipNode {
             gossip,
             receiver,
-            sender,
+            endpoint: ChannelEndpoint::new(i, sender),
+            _s: PhantomData,
+        })
+        .collect()
+}
+
+/// Creates a set of local gossip "nodes" that maintain a gossip set.
+/// Each node can be independently maintained in its own thread and will gossip
+/// with the other threads.
+/// The nodes are divided into tiers, the first `tier_sizes[0]` nodes returned being in
+/// the first tier, the next `tier_sizes[1]` nodes being in the second and so on, and
+/// every tier is configured by the corresponding entry in `configs`.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn tiered_local_gossip_set<T, M>(
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+) -> Vec<LocalTieredGossipSetNode<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+{
+    tiered_local_gossip_set_with_rng(tier_sizes, configs, &mut thread_rng())
+}
+
+/// Creates a set of local gossip "nodes" like `tiered_local_gossip_set()`, but
+/// with every node selecting the peers to gossip to with its own generator seeded from
+/// `rng`, so a seeded `rng` creates a reproducible network.
+pub fn tiered_local_gossip_set_with_rng<T, M, R>(
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    rng: &mut R,
+) -> Vec<LocalTieredGossipSetNode<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    tiered_local_gossip_set_with_seen_store_and_rng(
+        tier_sizes,
+        configs,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `tiered_local_gossip_set()`, but with every
+/// node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn tiered_local_gossip_set_with_seen_store<T, M, L, F>(
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    new_seen_store: F,
+) -> Vec<LocalTieredGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    tiered_local_gossip_set_with_seen_store_and_rng(
+        tier_sizes,
+        configs,
+        new_seen_store,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "nodes" like `tiered_local_gossip_set_with_seen_store()`, but
+/// with every node selecting the peers to gossip to with its own generator seeded from
+/// `rng`, so a seeded `rng` creates a reproducible network.
+pub fn tiered_local_gossip_set_with_seen_store_and_rng<T, M, L, F, R>(
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalTieredGossipSetNode<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    tiered_local_gossip(tier_sizes, configs, GossipSet::default, new_seen_store, rng)
+}
+
+/// Creates a set of local gossip "nodes" like `tiered_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn tiered_local_gossip<S, M, L, N, F, R>(
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalTieredGossipNode<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    let num_nodes = tier_sizes.iter().sum();
+    // Create the senders and receivers for the nodes.
+    let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> with all the gossips
+    let mut gossips = Vec::with_capacity(num_nodes);
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of senders (peers) for the node in every tier
+        let mut tiers: Vec<_> = tier_sizes.iter().map(|s| Vec::with_capacity(*s)).collect();
+        for (j, other) in channels.iter().enumerate() {
+            // Add every sender except the one for the node
+            if i != j {
+                tiers[tier_for_node(tier_sizes, j)].push(ChannelEndpoint::new(j, other.0.clone()));
+            }
+        }
+        // Add the node
+        gossips.push(
+            TieredGossip::create_with_seen_store(
+                tiers,
+                configs.to_vec(),
+                tier_for_node(tier_sizes, i),
+                data,
+                CHANNELS,
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .enumerate()
+        .map(|(i, (gossip, (sender, receiver)))| LocalGossipNode {
+            gossip,
+            receiver,
+            endpoint: ChannelEndpoint::new(i, sender),
             _s: PhantomData,
         })
         .collect()
@@ -186,14 +1039,69 @@ mod tests {
         time::{Duration, Instant},
     };
 
-    use crate::{data::GossipSetMessage, Gossip};
+    use crate::{data::GossipSetMessage, Gossip, ReceiveFrom};
 
     use super::*;
     use rand::prelude::*;
     use rayon::{prelude::*, ThreadPoolBuilder};
 
+    /// A dead endpoint doesn't stop the message from reaching the ones after it.
+    #[test]
+    fn deliver_past_dead_endpoint() {
+        let (dead_tx, dead_rx) = mpsc::channel();
+        let (alive_tx, alive_rx) = mpsc::channel();
+        drop(dead_rx);
+        let endpoints = [
+            ChannelEndpoint::new(0, dead_tx),
+            ChannelEndpoint::new(1, alive_tx),
+        ];
+        let report = CHANNELS.deliver_each(&10, endpoints.iter()).unwrap();
+        assert_eq!(
+            vec![0],
+            report.failures.iter().map(|f| f.0).collect::<Vec<_>>()
+        );
+        assert!(CHANNELS.deliver(&11, endpoints.iter()).is_err());
+        assert_eq!(vec![10, 11], alive_rx.try_iter().collect::<Vec<_>>());
+    }
+
+    /// Tagged messages say who sent them, and receiving from the sender doesn't echo back to it.
+    #[test]
+    fn tagged_channels() {
+        let channels: Vec<_> = (0..3).map(|_| mpsc::channel()).collect();
+        let endpoint = |i: usize| ChannelEndpoint::new(i, channels[i].0.clone());
+        let mut gossip = UniformGossip::create(
+            vec![endpoint(0), endpoint(2)],
+            2,
+            GossipSet::default(),
+            TaggedChannels::new(1),
+        );
+        let message = GossipSetMessage::add(5);
+        gossip.receive_from(&message, &0).unwrap();
+        assert!(gossip.data().is_present(&5));
+        assert_eq!(0, channels[0].1.try_iter().count());
+        let received: Vec<_> = channels[2].1.try_iter().collect();
+        assert_eq!(vec![Tagged { sender: 1, message }], received);
+    }
+
+    /// Nodes in a tagged network never get back the messages they passed on.
+    #[test]
+    fn tagged_network_doesnt_echo() {
+        let mut nodes = uniform_local_tagged_gossip_set(3, 2);
+        nodes[0].gossip.update(&GossipSetMessage::add(5)).unwrap();
+        for node in &mut nodes[1..] {
+            let received: Vec<_> = node.receiver.try_iter().collect();
+            for tagged in &received {
+                node.receive(tagged).unwrap();
+            }
+        }
+        assert!(nodes.iter().all(|n| n.gossip.data().is_present(&5)));
+        // Nodes 1 and 2 only passed the message on to each other.
+        assert_eq!(0, nodes[0].receiver.try_iter().count());
+    }
+
     /// End-to-end test of a local gossip network.
     #[test]
+    #[allow(clippy::redundant_closure, clippy::manual_range_contains)]
     fn local_network() {
         let num_nodes = 12;
         let fanout = 6;
@@ -281,4 +1189,66 @@ mod tests {
             }
         }
     }
+
+    /// A tiered network where every node gossips to its whole tier should reach every node
+    /// in every tier, starting from the first.
+    #[test]
+    fn tiered_network() {
+        let tier_sizes = [2, 3, 4];
+        let configs: Vec<_> = tier_sizes
+            .iter()
+            .map(|&size| TierConfig {
+                fanout: size,
+                forward_after: 2,
+            })
+            .collect();
+        let mut nodes = tiered_local_gossip_set(&tier_sizes, &configs);
+        let tiers: Vec<_> = nodes.iter().map(|n| n.gossip.tier()).collect();
+        assert_eq!(vec![0, 0, 1, 1, 1, 2, 2, 2, 2], tiers);
+        // Start the update from the last tier, then pump messages until it's all quiet.
+        nodes[8].gossip.update(&GossipSetMessage::add(5)).unwrap();
+        let mut any_received = true;
+        while any_received {
+            any_received = false;
+            for node in nodes.iter_mut() {
+                while let Ok(message) = node.receiver.try_recv() {
+                    node.gossip.receive(&message).unwrap();
+                    any_received = true;
+                }
+            }
+        }
+        assert!(nodes.iter().all(|n| n.gossip.data().is_present(&5)));
+    }
+
+    /// Run a small network seeded with the given `seed` to quiescence, processing messages
+    /// in a fixed order, and return the trace of which node received which message.
+    fn seeded_trace(seed: u64) -> Vec<(usize, u128)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut nodes = uniform_local_gossip_set_with_rng(8, 3, &mut rng);
+        let mut trace = Vec::new();
+        for i in 0..10 {
+            let message = GossipSetMessage::add_with_rng(i, &mut rng);
+            nodes[i % 8].gossip.update(&message).unwrap();
+            let mut any_received = true;
+            while any_received {
+                any_received = false;
+                for (n, node) in nodes.iter_mut().enumerate() {
+                    while let Ok(message) = node.receiver.try_recv() {
+                        trace.push((n, message.id()));
+                        node.gossip.receive(&message).unwrap();
+                        any_received = true;
+                    }
+                }
+            }
+        }
+        trace
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let trace = seeded_trace(11);
+        assert!(!trace.is_empty());
+        assert_eq!(trace, seeded_trace(11));
+        assert_ne!(trace, seeded_trace(12));
+    }
 }
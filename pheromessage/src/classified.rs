@@ -0,0 +1,545 @@
+//! Gossip where every message declares how it should be spread (its consistency class), so
+//! urgent updates can go to the primaries first while bulk updates spread uniformly.
+
+use std::{hash::Hash, marker::PhantomData};
+
+use crate::{
+    builder::{BuildResult, FromBuilder, GossipParts},
+    gossip_observed, is_excluded,
+    leave::Departure,
+    observe::{GossipObserver, NoopObserver},
+    peers::{Membership, PeerSet},
+    seen::{SeenStore, UnboundedSeenStore},
+    select::{ExcludingSelector, Exclusion, PeerSelector, UniformSelector},
+    Delivery, Gossip, Message, ReceiveFrom, SharedData,
+};
+
+/// How a message is spread through the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dissemination {
+    /// Spread to all peers equally, like `UniformGossip`.
+    Uniform,
+    /// Spread to the primaries first, like `PreferentialGossip`.
+    Preferential,
+}
+
+/// A message that declares how it should be spread.
+pub trait Classified: Message {
+    /// How this message should be spread.
+    fn dissemination(&self) -> Dissemination;
+}
+
+/// A gossip mechanism that spreads every message as the message itself declares: either
+/// uniformly to all peers (like `UniformGossip`), or to the primaries first (like
+/// `PreferentialGossip`). Messages of both classes share the same peers and the same store
+/// keeping track of seen messages.
+/// `L` is the store used to keep track of how often messages (by ID `I`) have been seen,
+/// `C` is the strategy used to select which peers to gossip to, and `O` is the observer
+/// notified of the decisions made about every message.
+pub struct ClassifiedGossip<
+    P,
+    S,
+    D,
+    I,
+    L = UnboundedSeenStore<I>,
+    C = UniformSelector,
+    O = NoopObserver,
+> {
+    /// All the peers (primaries and secondaries).
+    peers: PeerSet<P>,
+    /// The primary peers.
+    primaries: PeerSet<P>,
+    /// The secondary peers.
+    secondaries: PeerSet<P>,
+    /// Whether I'm a primary.
+    primary: bool,
+    /// How many peers to reach out to when gossipping.
+    fanout: usize,
+    /// Count of how often I've seen each message by ID.
+    message_log: L,
+    /// The delivery mechanism to send gossip messages.
+    delivery: D,
+    /// The data being gossipped about.
+    data: S,
+    /// The strategy for selecting which peers to gossip to.
+    selector: C,
+    /// The observer of the gossip decisions.
+    observer: O,
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach.
+    replace_failed: bool,
+    _i: PhantomData<I>,
+}
+
+impl<P, S, D, I> ClassifiedGossip<P, S, D, I>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new gossip mechanism that will gossip to the given set of `primaries` and
+    /// `secondaries`, with this node itself acting as primary if `primary` is `true`,
+    /// using the given `delivery` mechanism and maintaining the given `data`.
+    /// The gossip will be done using the given `fanout` - each message will be delivered
+    /// to a random subset of peers of that size (use `with_peer_selector()` to select
+    /// them differently).
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
+    pub fn create(
+        primaries: Vec<P>,
+        secondaries: Vec<P>,
+        primary: bool,
+        fanout: usize,
+        data: S,
+        delivery: D,
+    ) -> ClassifiedGossip<P, S, D, I> {
+        ClassifiedGossip::create_with_seen_store(
+            primaries,
+            secondaries,
+            primary,
+            fanout,
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
+    }
+}
+
+impl<P, S, D, I, L> ClassifiedGossip<P, S, D, I, L>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new gossip mechanism like `create()`, but keeping track of how often messages
+    /// have been seen in the given `message_log` store.
+    pub fn create_with_seen_store(
+        primaries: Vec<P>,
+        secondaries: Vec<P>,
+        primary: bool,
+        fanout: usize,
+        data: S,
+        delivery: D,
+        message_log: L,
+    ) -> ClassifiedGossip<P, S, D, I, L> {
+        let primaries: PeerSet<P> = primaries.into();
+        // A peer that's listed as both is a primary.
+        let secondaries: PeerSet<P> = secondaries
+            .into_iter()
+            .filter(|p| !primaries.contains(p))
+            .collect();
+        ClassifiedGossip {
+            peers: primaries
+                .iter()
+                .chain(secondaries.iter())
+                .cloned()
+                .collect(),
+            primaries,
+            secondaries,
+            primary,
+            fanout,
+            message_log,
+            delivery,
+            data,
+            selector: UniformSelector::new(),
+            observer: NoopObserver,
+            replace_failed: false,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> ClassifiedGossip<P, S, D, I, L, C, O> {
+    /// Use the given `selector` to select which peers to gossip to from now on.
+    pub fn with_peer_selector<C2>(self, selector: C2) -> ClassifiedGossip<P, S, D, I, L, C2, O> {
+        ClassifiedGossip {
+            peers: self.peers,
+            primaries: self.primaries,
+            secondaries: self.secondaries,
+            primary: self.primary,
+            fanout: self.fanout,
+            message_log: self.message_log,
+            delivery: self.delivery,
+            data: self.data,
+            selector,
+            observer: self.observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Notify the given `observer` of the gossip decisions from now on.
+    pub fn with_observer<O2>(self, observer: O2) -> ClassifiedGossip<P, S, D, I, L, C, O2> {
+        ClassifiedGossip {
+            peers: self.peers,
+            primaries: self.primaries,
+            secondaries: self.secondaries,
+            primary: self.primary,
+            fanout: self.fanout,
+            message_log: self.message_log,
+            delivery: self.delivery,
+            data: self.data,
+            selector: self.selector,
+            observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Whether to pass a message on to other peers (of the same kind) instead of the ones it
+    /// failed to reach (by default, failed peers are just reported to the observer).
+    pub fn with_failed_peer_replacement(self, replace_failed: bool) -> Self {
+        ClassifiedGossip {
+            replace_failed,
+            ..self
+        }
+    }
+
+    /// The observer of the gossip decisions.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The store keeping track of how often messages have been seen.
+    pub fn seen_messages(&self) -> &L {
+        &self.message_log
+    }
+
+    /// The delivery mechanism used to send gossip messages.
+    pub fn delivery(&self) -> &D {
+        &self.delivery
+    }
+
+    /// Checks if I'm a primary.
+    pub fn is_primary(&self) -> bool {
+        self.primary
+    }
+
+    /// All the peers (primaries and secondaries).
+    pub fn peers(&self) -> &[P] {
+        self.peers.as_slice()
+    }
+
+    /// The primary peers.
+    pub fn primaries(&self) -> &[P] {
+        self.primaries.as_slice()
+    }
+
+    /// The secondary peers.
+    pub fn secondaries(&self) -> &[P] {
+        self.secondaries.as_slice()
+    }
+}
+
+impl<P, S, D, I, L, C, O> ClassifiedGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Add the given peer to gossip to as a primary or secondary (moving it if it's already
+    /// a peer of the other kind). Returns `false` if it was already that kind of peer.
+    pub fn add_peer(&mut self, peer: P, primary: bool) -> bool {
+        let (to, from) = if primary {
+            (&mut self.primaries, &mut self.secondaries)
+        } else {
+            (&mut self.secondaries, &mut self.primaries)
+        };
+        from.remove(&peer);
+        self.peers.insert(peer.clone());
+        to.insert(peer)
+    }
+
+    /// Stop gossipping to the given peer. Returns `false` if it wasn't a peer.
+    pub fn remove_peer(&mut self, peer: &P) -> bool {
+        self.primaries.remove(peer);
+        self.secondaries.remove(peer);
+        self.peers.remove(peer).is_some()
+    }
+}
+
+/// New nodes join as secondaries (and can be made primaries with `add_peer()` later).
+/// Primaries and (other) peers given to the builder are the primaries and secondaries.
+impl<P, S, D, I, L, C, O> FromBuilder<P, S, D, I, L, C, O> for ClassifiedGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn from_parts(parts: GossipParts<P, S, D, L, C, O>) -> BuildResult<Self> {
+        Ok(ClassifiedGossip::create_with_seen_store(
+            parts.primaries,
+            parts.peers,
+            parts.primary,
+            parts.fanout,
+            parts.data,
+            parts.delivery,
+            parts.seen_messages,
+        )
+        .with_peer_selector(parts.selector)
+        .with_observer(parts.observer)
+        .with_failed_peer_replacement(parts.replace_failed))
+    }
+}
+
+impl<P, S, D, I, L, C, O> Membership<P> for ClassifiedGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn join(&mut self, peer: P) {
+        if !self.peers.contains(&peer) {
+            self.add_peer(peer, false);
+        }
+    }
+
+    fn leave(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+/// A leaving primary is replaced by its successor, if that's a secondary.
+impl<P, S, D, I, L, C, O> Departure<P> for ClassifiedGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn peer_left(&mut self, peer: &P, successor: Option<&P>) {
+        let was_primary = self.primaries.contains(peer);
+        self.remove_peer(peer);
+        match successor {
+            Some(successor) if was_primary && self.secondaries.contains(successor) => {
+                self.add_peer(successor.clone(), true);
+            }
+            _ => {}
+        }
+    }
+
+    fn succeed(&mut self, peer: &P) {
+        if self.primaries.contains(peer) {
+            self.primary = true;
+        }
+        self.remove_peer(peer);
+    }
+}
+
+impl<P, S, D, I, L, C, O> ClassifiedGossip<P, S, D, I, L, C, O> {
+    /// Gossip the given message to the given peers, except any `excluded` ones.
+    fn gossip_to<M>(
+        &mut self,
+        message: &M,
+        targets: Targets,
+        excluded: Exclusion<P>,
+    ) -> Result<(), D::Error>
+    where
+        D: Delivery<M, P>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
+        let targets = match targets {
+            Targets::All => &self.peers,
+            Targets::Primaries => &self.primaries,
+            Targets::Secondaries => &self.secondaries,
+        };
+        gossip_observed(
+            &self.delivery,
+            &mut ExcludingSelector::new(&mut self.selector, excluded),
+            &mut self.observer,
+            message,
+            targets.as_slice(),
+            self.fanout,
+            self.replace_failed,
+        )
+    }
+
+    /// Receive the given message from a peer, not passing it on to any `excluded` peers.
+    fn receive_excluding<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        M: Classified<I = I>,
+        D: Delivery<M, P>,
+        L: SeenStore<I>,
+        S: SharedData<M>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
+        let count_seen = self.message_log.see(message.id());
+        self.observer.on_seen_store_size(self.message_log.len());
+        let dissemination = message.dissemination();
+        if count_seen == 1 {
+            // This is the first time I've seen this message - update the data and
+            // pass it on as the message says.
+            self.observer.on_first_seen(message);
+            self.data.update(message);
+            let targets = match dissemination {
+                Dissemination::Uniform => Targets::All,
+                Dissemination::Preferential if self.primary => Targets::Primaries,
+                Dissemination::Preferential => Targets::Secondaries,
+            };
+            self.gossip_to(message, targets, excluded)?;
+        } else {
+            self.observer.on_duplicate(message, count_seen);
+        }
+        // Like in preferential gossip, primaries let the secondaries know once they've seen
+        // a message twice.
+        if dissemination == Dissemination::Preferential && self.primary && count_seen == 2 {
+            self.gossip_to(message, Targets::Secondaries, excluded)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which of the peers to gossip to.
+#[derive(Debug, Clone, Copy)]
+enum Targets {
+    All,
+    Primaries,
+    Secondaries,
+}
+
+impl<P, S, D, M, I, L, C, O> Gossip<M, S> for ClassifiedGossip<P, S, D, I, L, C, O>
+where
+    M: Classified<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: Clone,
+{
+    type Error = D::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.receive_excluding(message, None)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.observer.on_update(message);
+        self.data.update(message);
+        self.message_log.see(message.id());
+        self.observer.on_seen_store_size(self.message_log.len());
+        let targets = match message.dissemination() {
+            Dissemination::Uniform => Targets::All,
+            Dissemination::Preferential => Targets::Primaries,
+        };
+        self.gossip_to(message, targets, None)
+    }
+
+    fn data(&self) -> &S {
+        &self.data
+    }
+}
+
+impl<P, S, D, M, I, L, C, O, Q> ReceiveFrom<M, S, Q> for ClassifiedGossip<P, S, D, I, L, C, O>
+where
+    M: Classified<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: PartialEq<Q> + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.receive_excluding(message, Some(&|p: &P| is_excluded(p, sender, excluded)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use crate::tests::Network;
+
+    /// Even messages are urgent, odd ones are bulk.
+    impl Classified for usize {
+        fn dissemination(&self) -> Dissemination {
+            if self.is_multiple_of(2) {
+                Dissemination::Preferential
+            } else {
+                Dissemination::Uniform
+            }
+        }
+    }
+
+    /// Create a node with primaries 1-2 and secondaries 3-4, that gossips to two peers.
+    fn node(primary: bool) -> ClassifiedGossip<usize, (), Network, usize> {
+        ClassifiedGossip::create(
+            vec![1, 2],
+            vec![3, 4],
+            primary,
+            2,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        )
+    }
+
+    /// The endpoints that got the given message so far, sorted.
+    fn reached(gossip: &ClassifiedGossip<usize, (), Network, usize>, message: usize) -> Vec<usize> {
+        let network = gossip.delivery.0.borrow();
+        let mut reached: Vec<_> = network
+            .iter()
+            .filter(|(_, messages)| messages.contains(&message))
+            .map(|(&endpoint, _)| endpoint)
+            .collect();
+        reached.sort();
+        reached
+    }
+
+    #[test]
+    fn urgent_updates_go_to_primaries() {
+        let mut gossip = node(false);
+        gossip.update(&10).unwrap();
+        assert_eq!(vec![1, 2], reached(&gossip, 10));
+    }
+
+    #[test]
+    fn bulk_updates_spread_uniformly() {
+        // With enough bulk updates, every peer gets some of them.
+        let mut gossip = node(false);
+        for i in 0..50 {
+            gossip.update(&(2 * i + 1)).unwrap();
+        }
+        let network = gossip.delivery.0.borrow();
+        assert_eq!(4, network.len());
+        let total: usize = network.values().map(Vec::len).sum();
+        assert_eq!(100, total);
+    }
+
+    #[test]
+    fn primaries_forward_urgent_messages_to_secondaries() {
+        let mut gossip = node(true);
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![1, 2], reached(&gossip, 10));
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], reached(&gossip, 10));
+        // Bulk messages are passed on once, whether I'm a primary or not.
+        gossip.receive(&11).unwrap();
+        gossip.receive(&11).unwrap();
+        assert_eq!(2, reached(&gossip, 11).len());
+    }
+
+    #[test]
+    fn secondaries_keep_urgent_messages_among_secondaries() {
+        let mut gossip = node(false);
+        gossip.receive(&10).unwrap();
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![3, 4], reached(&gossip, 10));
+    }
+
+    #[test]
+    fn membership() {
+        let mut gossip = node(false);
+        gossip.join(5);
+        gossip.join(1);
+        assert_eq!(vec![1, 2], gossip.primaries());
+        assert!(gossip.add_peer(3, true));
+        assert!(!gossip.add_peer(3, true));
+        assert_eq!(vec![4, 5], {
+            let mut secondaries = gossip.secondaries().to_vec();
+            secondaries.sort();
+            secondaries
+        });
+        gossip.leave(&1);
+        gossip.leave(&2);
+        assert_eq!(3, gossip.peers().len());
+        gossip.update(&10).unwrap();
+        assert_eq!(vec![3], reached(&gossip, 10));
+    }
+}
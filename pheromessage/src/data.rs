@@ -1,9 +1,13 @@
-//! Shared data sets that can be updated through gossip.
+//! Shared data (sets, registers, maps and counters) that can be updated through gossip.
 
 use rand::prelude::*;
-use std::{collections::HashMap, hash::Hash};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::{Message, SharedData};
+use crate::{bootstrap::Snapshot, rumor::HopCount, Message, SharedData};
 
 /// An action to add/remove an item to a gossipped set.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -38,6 +42,8 @@ impl<T> Default for GossipSet<T> {
 pub struct GossipSetMessage<T> {
     id: u128,
     pub action: GossipSetAction<T>,
+    /// The number of hops this message has been passed along (only used by rumor mongering).
+    hops: usize,
 }
 
 impl<T> Message for GossipSetMessage<T> {
@@ -48,24 +54,55 @@ impl<T> Message for GossipSetMessage<T> {
     }
 }
 
-fn new_id() -> u128 {
-    thread_rng().gen()
+fn new_id<R: Rng + ?Sized>(rng: &mut R) -> u128 {
+    rng.gen()
+}
+
+impl<T> HopCount for GossipSetMessage<T>
+where
+    T: Clone,
+{
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        GossipSetMessage {
+            id: self.id,
+            action: self.action.clone(),
+            hops: self.hops + 1,
+        }
+    }
 }
 
 impl<T> GossipSetMessage<T> {
     /// Create a new message to add the given value to a set.
     pub fn add(value: T) -> GossipSetMessage<T> {
+        GossipSetMessage::add_with_rng(value, &mut thread_rng())
+    }
+
+    /// Create a new message to remove the given value from a set.
+    pub fn remove(value: T) -> GossipSetMessage<T> {
+        GossipSetMessage::remove_with_rng(value, &mut thread_rng())
+    }
+
+    /// Create a new message to add the given value to a set, using the given `rng`
+    /// to generate its ID.
+    pub fn add_with_rng<R: Rng + ?Sized>(value: T, rng: &mut R) -> GossipSetMessage<T> {
         GossipSetMessage {
-            id: new_id(),
+            id: new_id(rng),
             action: GossipSetAction::Add(value),
+            hops: 0,
         }
     }
 
-    /// Create a new message to remove the given value from a set.
-    pub fn remove(value: T) -> GossipSetMessage<T> {
+    /// Create a new message to remove the given value from a set, using the given `rng`
+    /// to generate its ID.
+    pub fn remove_with_rng<R: Rng + ?Sized>(value: T, rng: &mut R) -> GossipSetMessage<T> {
         GossipSetMessage {
-            id: new_id(),
+            id: new_id(rng),
             action: GossipSetAction::Remove(value),
+            hops: 0,
         }
     }
 }
@@ -114,9 +151,893 @@ where
     }
 }
 
+/// The state of a set is every item with how many times it was added and removed.
+/// Restoring takes the larger of the counts for every item, since both sets counted the same
+/// messages (just not necessarily all of them).
+impl<T> Snapshot for GossipSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    type State = Vec<(T, usize, usize)>;
+
+    fn snapshot(&self) -> Self::State {
+        self.items
+            .iter()
+            .map(|(item, a)| (item.clone(), a.added_count, a.removed_count))
+            .collect()
+    }
+
+    fn restore(&mut self, state: Self::State) {
+        for (item, added_count, removed_count) in state {
+            let actions = self.items.entry(item).or_default();
+            actions.added_count = actions.added_count.max(added_count);
+            actions.removed_count = actions.removed_count.max(removed_count);
+        }
+    }
+}
+
+/// An action to add/remove an item to an observed-remove set.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum ORSetAction<T> {
+    /// Add the item (tagged with the ID of the message).
+    Add(T),
+    /// Remove the item, as added by the messages with the given tags (all the adds the remover
+    /// had observed).
+    Remove(T, Vec<u128>),
+}
+
+/// A set of unique items maintained through gossip, with observed-remove semantics: every add
+/// is tagged uniquely, and a remove only cancels the adds its issuer had observed. So an add
+/// concurrent with a remove wins, and the outcome only depends on which operations saw which
+/// (unlike `GossipSet`, where it depends on how many adds and removes there were).
+/// Applying a message more than once has no further effect.
+/// Every removed add leaves a tag (16 bytes) behind that's never garbage collected, so the
+/// memory taken by the set grows with every remove, even once the items are gone.
+#[derive(Debug)]
+pub struct ORSet<T> {
+    /// The tags of the adds of every item that haven't been removed.
+    items: HashMap<T, HashSet<u128>>,
+    /// The tags of the adds that have been removed, so they aren't added back if they arrive
+    /// after their removal.
+    removed: HashSet<u128>,
+}
+
+impl<T> Default for ORSet<T> {
+    fn default() -> Self {
+        Self {
+            items: HashMap::new(),
+            removed: HashSet::new(),
+        }
+    }
+}
+
+/// A message that can be used to add/remove items from an observed-remove set.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct ORSetMessage<T> {
+    id: u128,
+    pub action: ORSetAction<T>,
+    /// The number of hops this message has been passed along (only used by rumor mongering).
+    hops: usize,
+}
+
+impl<T> Message for ORSetMessage<T> {
+    type I = u128;
+
+    fn id(&self) -> Self::I {
+        self.id
+    }
+}
+
+impl<T> HopCount for ORSetMessage<T>
+where
+    T: Clone,
+{
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        ORSetMessage {
+            id: self.id,
+            action: self.action.clone(),
+            hops: self.hops + 1,
+        }
+    }
+}
+
+impl<T> ORSetMessage<T> {
+    /// Create a new message to add the given value to a set.
+    pub fn add(value: T) -> ORSetMessage<T> {
+        ORSetMessage::add_with_rng(value, &mut thread_rng())
+    }
+
+    /// Create a new message to remove the given value from a set, as far as the given `set`
+    /// (typically the local copy of the set) has observed it being added.
+    pub fn remove(value: T, set: &ORSet<T>) -> ORSetMessage<T>
+    where
+        T: Eq + Hash,
+    {
+        ORSetMessage::remove_with_rng(value, set, &mut thread_rng())
+    }
+
+    /// Create a new message to add the given value to a set, using the given `rng`
+    /// to generate its ID (which is also the tag of the add).
+    pub fn add_with_rng<R: Rng + ?Sized>(value: T, rng: &mut R) -> ORSetMessage<T> {
+        ORSetMessage {
+            id: new_id(rng),
+            action: ORSetAction::Add(value),
+            hops: 0,
+        }
+    }
+
+    /// Create a new message to remove the given value from a set like `remove()`, using the
+    /// given `rng` to generate its ID.
+    pub fn remove_with_rng<R: Rng + ?Sized>(
+        value: T,
+        set: &ORSet<T>,
+        rng: &mut R,
+    ) -> ORSetMessage<T>
+    where
+        T: Eq + Hash,
+    {
+        let tags = set.tags(&value);
+        ORSetMessage {
+            id: new_id(rng),
+            action: ORSetAction::Remove(value, tags),
+            hops: 0,
+        }
+    }
+}
+
+impl<T> ORSet<T> {
+    /// Checks if the given item is present in the set.
+    pub fn is_present(&self, item: &T) -> bool
+    where
+        T: Eq + Hash,
+    {
+        self.items.contains_key(item)
+    }
+
+    /// All the items present in the set.
+    pub fn items(&self) -> impl Iterator<Item = &T> {
+        self.items.keys()
+    }
+
+    /// The tags of the adds of the given item that haven't been removed.
+    pub fn tags(&self, item: &T) -> Vec<u128>
+    where
+        T: Eq + Hash,
+    {
+        self.items
+            .get(item)
+            .map(|tags| tags.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Adds the given item to the set, by the add with the given `tag` (unless that was already
+    /// removed). Typically you wouldn't call this directly, but rather update the gossip with
+    /// an add message to update the whole network.
+    pub fn add_item(&mut self, item: T, tag: u128)
+    where
+        T: Eq + Hash,
+    {
+        if !self.removed.contains(&tag) {
+            self.items.entry(item).or_default().insert(tag);
+        }
+    }
+
+    /// Removes the given item from the set, as added by the given `tags`. Typically you
+    /// wouldn't call this directly, but rather update the gossip with a remove message to
+    /// update the whole network.
+    pub fn remove_item(&mut self, item: &T, tags: &[u128])
+    where
+        T: Eq + Hash,
+    {
+        self.removed.extend(tags);
+        if let Some(live) = self.items.get_mut(item) {
+            for tag in tags {
+                live.remove(tag);
+            }
+            if live.is_empty() {
+                self.items.remove(item);
+            }
+        }
+    }
+}
+
+impl<T> SharedData<ORSetMessage<T>> for ORSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn update(&mut self, message: &ORSetMessage<T>) {
+        match &message.action {
+            ORSetAction::Add(v) => self.add_item(v.clone(), message.id),
+            ORSetAction::Remove(v, tags) => self.remove_item(v, tags),
+        }
+    }
+}
+
+/// The state of an observed-remove set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ORSetState<T> {
+    /// Every item present with the tags of its adds.
+    pub items: Vec<(T, Vec<u128>)>,
+    /// The tags of all the adds removed.
+    pub removed: Vec<u128>,
+}
+
+/// Restoring merges the states: an add is kept if either set has it and neither removed it.
+impl<T> Snapshot for ORSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    type State = ORSetState<T>;
+
+    fn snapshot(&self) -> Self::State {
+        ORSetState {
+            items: self
+                .items
+                .iter()
+                .map(|(item, tags)| (item.clone(), tags.iter().copied().collect()))
+                .collect(),
+            removed: self.removed.iter().copied().collect(),
+        }
+    }
+
+    fn restore(&mut self, state: Self::State) {
+        for (item, tags) in state.items {
+            for tag in tags {
+                self.add_item(item.clone(), tag);
+            }
+        }
+        self.removed.extend(state.removed);
+        self.items.retain(|_, tags| {
+            tags.retain(|tag| !self.removed.contains(tag));
+            !tags.is_empty()
+        });
+    }
+}
+
+/// A timestamp from a hybrid logical clock: the (physical) wall clock time in milliseconds, a
+/// logical counter ordering events within the same millisecond (or while the wall clock is
+/// behind timestamps seen from other nodes), and the ID of the node (`N`) as a tiebreak.
+/// Timestamps are ordered by these fields in turn.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct HybridTimestamp<N> {
+    /// The wall clock time (in milliseconds since the Unix epoch).
+    pub wall: u64,
+    /// The logical counter.
+    pub logical: u64,
+    /// The node that took the timestamp.
+    pub node: N,
+}
+
+/// A hybrid logical clock (after "Logical Physical Clocks" by Kulkarni et al.) for the node
+/// with ID `N`: its timestamps are close to the wall clock, but always later than any
+/// timestamp it took or observed before, so a write that follows another (having seen it) is
+/// ordered after it even if the wall clocks of the nodes disagree.
+#[derive(Debug, Clone)]
+pub struct HybridClock<N> {
+    node: N,
+    /// The latest wall clock time taken or observed.
+    wall: u64,
+    /// The logical counter for that time.
+    logical: u64,
+}
+
+impl<N> HybridClock<N> {
+    /// Create a clock for the node with the given ID.
+    pub fn new(node: N) -> HybridClock<N> {
+        HybridClock {
+            node,
+            wall: 0,
+            logical: 0,
+        }
+    }
+
+    /// The ID of the node this clock is for.
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    /// Take a timestamp for a local event now.
+    pub fn now(&mut self) -> HybridTimestamp<N>
+    where
+        N: Clone,
+    {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.now_at(millis)
+    }
+
+    /// Take a timestamp for a local event at the given wall clock time (in milliseconds since
+    /// the Unix epoch).
+    pub fn now_at(&mut self, wall: u64) -> HybridTimestamp<N>
+    where
+        N: Clone,
+    {
+        if wall > self.wall {
+            self.wall = wall;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+        HybridTimestamp {
+            wall: self.wall,
+            logical: self.logical,
+            node: self.node.clone(),
+        }
+    }
+
+    /// Observe a timestamp from another node, so timestamps taken from now on are later.
+    pub fn observe(&mut self, timestamp: &HybridTimestamp<N>) {
+        if (timestamp.wall, timestamp.logical) > (self.wall, self.logical) {
+            self.wall = timestamp.wall;
+            self.logical = timestamp.logical;
+        }
+    }
+}
+
+/// A register holding a single value (`T`) maintained through gossip, where the last write (by
+/// the hybrid logical clock timestamps of the nodes, with IDs `N`) wins.
+/// Applying a message more than once, or out of order, has no further effect.
+#[derive(Debug, Clone)]
+pub struct LwwRegister<T, N> {
+    clock: HybridClock<N>,
+    /// The latest value written with its timestamp.
+    value: Option<(T, HybridTimestamp<N>)>,
+}
+
+/// A message writing a value (`T`) to a last-writer-wins register.
+/// Its ID is the timestamp of the write, which is unique.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct LwwRegisterMessage<T, N> {
+    pub value: T,
+    pub timestamp: HybridTimestamp<N>,
+    /// The number of hops this message has been passed along (only used by rumor mongering).
+    hops: usize,
+}
+
+impl<T, N> Message for LwwRegisterMessage<T, N>
+where
+    N: Clone,
+{
+    type I = HybridTimestamp<N>;
+
+    fn id(&self) -> Self::I {
+        self.timestamp.clone()
+    }
+}
+
+impl<T, N> HopCount for LwwRegisterMessage<T, N>
+where
+    T: Clone,
+    N: Clone,
+{
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        LwwRegisterMessage {
+            value: self.value.clone(),
+            timestamp: self.timestamp.clone(),
+            hops: self.hops + 1,
+        }
+    }
+}
+
+impl<T, N> LwwRegister<T, N> {
+    /// Create an empty register for the node with the given ID.
+    pub fn new(node: N) -> LwwRegister<T, N> {
+        LwwRegister {
+            clock: HybridClock::new(node),
+            value: None,
+        }
+    }
+
+    /// The current value, if any was written.
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref().map(|(value, _)| value)
+    }
+
+    /// The timestamp of the current value, if any was written.
+    pub fn timestamp(&self) -> Option<&HybridTimestamp<N>> {
+        self.value.as_ref().map(|(_, timestamp)| timestamp)
+    }
+
+    /// Create a message to write the given value now, to update the gossip with.
+    pub fn write(&mut self, value: T) -> LwwRegisterMessage<T, N>
+    where
+        N: Clone,
+    {
+        LwwRegisterMessage {
+            value,
+            timestamp: self.clock.now(),
+            hops: 0,
+        }
+    }
+
+    /// Create a message to write the given value at the given wall clock time (in milliseconds
+    /// since the Unix epoch), to update the gossip with.
+    pub fn write_at(&mut self, value: T, wall: u64) -> LwwRegisterMessage<T, N>
+    where
+        N: Clone,
+    {
+        LwwRegisterMessage {
+            value,
+            timestamp: self.clock.now_at(wall),
+            hops: 0,
+        }
+    }
+
+    /// Sets the value written at the given timestamp, unless a later one was already written.
+    /// Returns `false` if it wasn't set. Typically you wouldn't call this directly, but rather
+    /// update the gossip with a write message to update the whole network.
+    pub fn set(&mut self, value: T, timestamp: HybridTimestamp<N>) -> bool
+    where
+        N: Ord,
+    {
+        self.clock.observe(&timestamp);
+        if self
+            .timestamp()
+            .is_some_and(|current| current >= &timestamp)
+        {
+            return false;
+        }
+        self.value = Some((value, timestamp));
+        true
+    }
+}
+
+impl<T, N> SharedData<LwwRegisterMessage<T, N>> for LwwRegister<T, N>
+where
+    T: Clone,
+    N: Ord + Clone,
+{
+    fn update(&mut self, message: &LwwRegisterMessage<T, N>) {
+        self.set(message.value.clone(), message.timestamp.clone());
+    }
+}
+
+/// A map from keys (`K`) to values (`V`) maintained through gossip, where every key is a
+/// last-writer-wins register: the last write to it (by the hybrid logical clock timestamps of
+/// the nodes, with IDs `N`) wins, whether it set or removed the value.
+/// Applying a message more than once, or out of order, has no further effect.
+#[derive(Debug, Clone)]
+pub struct LwwMap<K, V, N> {
+    clock: HybridClock<N>,
+    /// The latest write of every key with its timestamp (where removed keys have no value,
+    /// so older writes that arrive late don't bring them back).
+    entries: HashMap<K, (Option<V>, HybridTimestamp<N>)>,
+}
+
+/// A message writing the value (`V`) of a key (`K`) in a last-writer-wins map, or removing it
+/// if there's no value.
+/// Its ID is the timestamp of the write, which is unique.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct LwwMapMessage<K, V, N> {
+    pub key: K,
+    pub value: Option<V>,
+    pub timestamp: HybridTimestamp<N>,
+    /// The number of hops this message has been passed along (only used by rumor mongering).
+    hops: usize,
+}
+
+impl<K, V, N> LwwMapMessage<K, V, N> {
+    fn new(key: K, value: Option<V>, timestamp: HybridTimestamp<N>) -> LwwMapMessage<K, V, N> {
+        LwwMapMessage {
+            key,
+            value,
+            timestamp,
+            hops: 0,
+        }
+    }
+}
+
+impl<K, V, N> Message for LwwMapMessage<K, V, N>
+where
+    N: Clone,
+{
+    type I = HybridTimestamp<N>;
+
+    fn id(&self) -> Self::I {
+        self.timestamp.clone()
+    }
+}
+
+impl<K, V, N> HopCount for LwwMapMessage<K, V, N>
+where
+    K: Clone,
+    V: Clone,
+    N: Clone,
+{
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        LwwMapMessage {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            timestamp: self.timestamp.clone(),
+            hops: self.hops + 1,
+        }
+    }
+}
+
+impl<K, V, N> LwwMap<K, V, N> {
+    /// Create an empty map for the node with the given ID.
+    pub fn new(node: N) -> LwwMap<K, V, N> {
+        LwwMap {
+            clock: HybridClock::new(node),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The value of the given key, if it's in the map.
+    pub fn get(&self, key: &K) -> Option<&V>
+    where
+        K: Eq + Hash,
+    {
+        self.entries.get(key).and_then(|(value, _)| value.as_ref())
+    }
+
+    /// All the keys in the map with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, (value, _))| value.as_ref().map(|value| (key, value)))
+    }
+
+    /// Create a message to set the value of the given key now, to update the gossip with.
+    pub fn insert(&mut self, key: K, value: V) -> LwwMapMessage<K, V, N>
+    where
+        N: Clone,
+    {
+        let timestamp = self.clock.now();
+        LwwMapMessage::new(key, Some(value), timestamp)
+    }
+
+    /// Create a message to remove the given key now, to update the gossip with.
+    pub fn remove(&mut self, key: K) -> LwwMapMessage<K, V, N>
+    where
+        N: Clone,
+    {
+        let timestamp = self.clock.now();
+        LwwMapMessage::new(key, None, timestamp)
+    }
+
+    /// Create a message to set the value of the given key at the given wall clock time (in
+    /// milliseconds since the Unix epoch), to update the gossip with.
+    pub fn insert_at(&mut self, key: K, value: V, wall: u64) -> LwwMapMessage<K, V, N>
+    where
+        N: Clone,
+    {
+        LwwMapMessage::new(key, Some(value), self.clock.now_at(wall))
+    }
+
+    /// Create a message to remove the given key at the given wall clock time (in milliseconds
+    /// since the Unix epoch), to update the gossip with.
+    pub fn remove_at(&mut self, key: K, wall: u64) -> LwwMapMessage<K, V, N>
+    where
+        N: Clone,
+    {
+        LwwMapMessage::new(key, None, self.clock.now_at(wall))
+    }
+
+    /// Sets the value of the given key (or removes it, if there's no value) as written at the
+    /// given timestamp, unless a later write to it was already made. Returns `false` if it
+    /// wasn't set. Typically you wouldn't call this directly, but rather update the gossip with
+    /// a write message to update the whole network.
+    pub fn set(&mut self, key: K, value: Option<V>, timestamp: HybridTimestamp<N>) -> bool
+    where
+        K: Eq + Hash,
+        N: Ord,
+    {
+        self.clock.observe(&timestamp);
+        if let Some((_, current)) = self.entries.get(&key) {
+            if current >= &timestamp {
+                return false;
+            }
+        }
+        self.entries.insert(key, (value, timestamp));
+        true
+    }
+}
+
+impl<K, V, N> SharedData<LwwMapMessage<K, V, N>> for LwwMap<K, V, N>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    N: Ord + Clone,
+{
+    fn update(&mut self, message: &LwwMapMessage<K, V, N>) {
+        self.set(
+            message.key.clone(),
+            message.value.clone(),
+            message.timestamp.clone(),
+        );
+    }
+}
+
+/// A grow-only counter maintained through gossip, as a count per node (with IDs `N`) that only
+/// that node increments. Its value is the sum of the counts.
+/// Every message carries the new count of the node that incremented it, so applying a message
+/// more than once, or out of order, has no further effect.
+#[derive(Debug, Clone)]
+pub struct GCounter<N> {
+    /// My own ID.
+    node: N,
+    counts: HashMap<N, u64>,
+}
+
+/// A message with the new count of a node in a grow-only counter.
+/// Its ID is the node with its count, which only grows.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct GCounterMessage<N> {
+    pub node: N,
+    pub count: u64,
+    /// The number of hops this message has been passed along (only used by rumor mongering).
+    hops: usize,
+}
+
+impl<N> Message for GCounterMessage<N>
+where
+    N: Clone,
+{
+    type I = (N, u64);
+
+    fn id(&self) -> Self::I {
+        (self.node.clone(), self.count)
+    }
+}
+
+impl<N> HopCount for GCounterMessage<N>
+where
+    N: Clone,
+{
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        GCounterMessage {
+            node: self.node.clone(),
+            count: self.count,
+            hops: self.hops + 1,
+        }
+    }
+}
+
+impl<N> GCounter<N>
+where
+    N: Eq + Hash + Clone,
+{
+    /// Create a zero counter for the node with the given ID.
+    pub fn new(node: N) -> GCounter<N> {
+        GCounter {
+            node,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// The value of the counter (the sum of the counts of all the nodes).
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// The count of the given node.
+    pub fn count(&self, node: &N) -> u64 {
+        self.counts.get(node).copied().unwrap_or_default()
+    }
+
+    /// Increments my own count by the given amount, and creates a message to update the gossip
+    /// with (applying it again changes nothing).
+    pub fn increment(&mut self, by: u64) -> GCounterMessage<N> {
+        let count = self.counts.entry(self.node.clone()).or_default();
+        *count += by;
+        GCounterMessage {
+            node: self.node.clone(),
+            count: *count,
+            hops: 0,
+        }
+    }
+
+    /// Sets the count of the given node, unless it's already higher. Typically you wouldn't
+    /// call this directly, but rather update the gossip with an increment message to update
+    /// the whole network.
+    pub fn set_count(&mut self, node: N, count: u64) {
+        let current = self.counts.entry(node).or_default();
+        *current = (*current).max(count);
+    }
+
+    /// Merges the full state of another counter into this one, taking the higher count of
+    /// every node.
+    pub fn merge(&mut self, other: &GCounter<N>) {
+        for (node, count) in other.counts.iter() {
+            self.set_count(node.clone(), *count);
+        }
+    }
+}
+
+impl<N> SharedData<GCounterMessage<N>> for GCounter<N>
+where
+    N: Eq + Hash + Clone,
+{
+    fn update(&mut self, message: &GCounterMessage<N>) {
+        self.set_count(message.node.clone(), message.count);
+    }
+}
+
+/// The state of a grow-only counter is the count of every node, and restoring merges it.
+impl<N> Snapshot for GCounter<N>
+where
+    N: Eq + Hash + Clone,
+{
+    type State = Vec<(N, u64)>;
+
+    fn snapshot(&self) -> Self::State {
+        self.counts
+            .iter()
+            .map(|(node, count)| (node.clone(), *count))
+            .collect()
+    }
+
+    fn restore(&mut self, state: Self::State) {
+        for (node, count) in state {
+            self.set_count(node, count);
+        }
+    }
+}
+
+/// A counter that can be incremented and decremented, maintained through gossip as two
+/// grow-only counts per node (with IDs `N`): its increments and its decrements. Its value is
+/// the sum of the increments minus the sum of the decrements.
+/// Every message carries the new counts of the node that changed them, so applying a message
+/// more than once, or out of order, has no further effect.
+#[derive(Debug, Clone)]
+pub struct PNCounter<N> {
+    increments: GCounter<N>,
+    decrements: GCounter<N>,
+}
+
+/// A message with the new counts of a node in a positive-negative counter.
+/// Its ID is the node with its counts, which only grow.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct PNCounterMessage<N> {
+    pub node: N,
+    pub increments: u64,
+    pub decrements: u64,
+    /// The number of hops this message has been passed along (only used by rumor mongering).
+    hops: usize,
+}
+
+impl<N> Message for PNCounterMessage<N>
+where
+    N: Clone,
+{
+    type I = (N, u64, u64);
+
+    fn id(&self) -> Self::I {
+        (self.node.clone(), self.increments, self.decrements)
+    }
+}
+
+impl<N> HopCount for PNCounterMessage<N>
+where
+    N: Clone,
+{
+    fn hops(&self) -> usize {
+        self.hops
+    }
+
+    fn next_hop(&self) -> Self {
+        PNCounterMessage {
+            node: self.node.clone(),
+            increments: self.increments,
+            decrements: self.decrements,
+            hops: self.hops + 1,
+        }
+    }
+}
+
+impl<N> PNCounter<N>
+where
+    N: Eq + Hash + Clone,
+{
+    /// Create a zero counter for the node with the given ID.
+    pub fn new(node: N) -> PNCounter<N> {
+        PNCounter {
+            increments: GCounter::new(node.clone()),
+            decrements: GCounter::new(node),
+        }
+    }
+
+    /// The value of the counter (all the increments minus all the decrements).
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+
+    /// Increments the counter by the given amount, and creates a message to update the gossip
+    /// with (applying it again changes nothing).
+    pub fn increment(&mut self, by: u64) -> PNCounterMessage<N> {
+        self.increments.increment(by);
+        self.message()
+    }
+
+    /// Decrements the counter by the given amount, and creates a message to update the gossip
+    /// with (applying it again changes nothing).
+    pub fn decrement(&mut self, by: u64) -> PNCounterMessage<N> {
+        self.decrements.increment(by);
+        self.message()
+    }
+
+    /// A message with my own current counts.
+    fn message(&self) -> PNCounterMessage<N> {
+        let node = &self.increments.node;
+        PNCounterMessage {
+            node: node.clone(),
+            increments: self.increments.count(node),
+            decrements: self.decrements.count(node),
+            hops: 0,
+        }
+    }
+
+    /// Sets the counts of the given node, unless they're already higher. Typically you wouldn't
+    /// call this directly, but rather update the gossip with increment/decrement messages to
+    /// update the whole network.
+    pub fn set_counts(&mut self, node: N, increments: u64, decrements: u64) {
+        self.increments.set_count(node.clone(), increments);
+        self.decrements.set_count(node, decrements);
+    }
+
+    /// Merges the full state of another counter into this one, taking the higher counts of
+    /// every node.
+    pub fn merge(&mut self, other: &PNCounter<N>) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+impl<N> SharedData<PNCounterMessage<N>> for PNCounter<N>
+where
+    N: Eq + Hash + Clone,
+{
+    fn update(&mut self, message: &PNCounterMessage<N>) {
+        self.set_counts(message.node.clone(), message.increments, message.decrements);
+    }
+}
+
+/// The state of a positive-negative counter is the counts of every node, and restoring merges
+/// it.
+impl<N> Snapshot for PNCounter<N>
+where
+    N: Eq + Hash + Clone,
+{
+    type State = (Vec<(N, u64)>, Vec<(N, u64)>);
+
+    fn snapshot(&self) -> Self::State {
+        (self.increments.snapshot(), self.decrements.snapshot())
+    }
+
+    fn restore(&mut self, (increments, decrements): Self::State) {
+        self.increments.restore(increments);
+        self.decrements.restore(decrements);
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use rand::rngs::StdRng;
+
     use super::*;
+    use crate::{
+        channel::{uniform_local_gossip, ChannelEndpoint, LocalUniformGossipNode, CHANNELS},
+        seen::{LruSeenStore, UnboundedSeenStore},
+        Gossip, UniformGossip,
+    };
 
     #[test]
     pub fn simple_set() {
@@ -134,4 +1055,249 @@ mod tests {
         assert!(set.is_present(&5));
         assert!(!set.is_present(&6));
     }
+
+    #[test]
+    pub fn snapshot_restore() {
+        let mut set = GossipSet::default();
+        set.add_item(5);
+        set.add_item(6);
+        set.remove_item(6);
+        let mut other = GossipSet::default();
+        other.add_item(7);
+        other.restore(set.snapshot());
+        // Restoring the same state again changes nothing.
+        other.restore(set.snapshot());
+        assert!(other.is_present(&5));
+        assert!(!other.is_present(&6));
+        assert!(other.is_present(&7));
+    }
+
+    /// A remove only cancels the adds it observed, whatever order the messages arrive in.
+    #[test]
+    pub fn or_set_observed_remove() {
+        let mut a = ORSet::default();
+        let add_a = ORSetMessage::add(5);
+        a.update(&add_a);
+        // Concurrently with this add (which node a hasn't seen), a removes its own.
+        let add_b = ORSetMessage::add(5);
+        let remove_a = ORSetMessage::remove(5, &a);
+        a.update(&remove_a);
+        assert!(!a.is_present(&5));
+        let messages = [add_a, add_b, remove_a];
+        for order in [[0, 1, 2], [2, 1, 0], [1, 2, 0], [2, 0, 1]] {
+            let mut set = ORSet::default();
+            for i in order {
+                set.update(&messages[i]);
+                // Applying messages again changes nothing.
+                set.update(&messages[i]);
+            }
+            assert!(set.is_present(&5));
+            // A remove that observed both adds wins.
+            let remove_all = ORSetMessage::remove(5, &set);
+            set.update(&remove_all);
+            assert!(!set.is_present(&5));
+            set.update(&messages[1]);
+            assert!(!set.is_present(&5));
+        }
+    }
+
+    #[test]
+    pub fn or_set_snapshot_restore() {
+        let mut set = ORSet::default();
+        set.update(&ORSetMessage::add(5));
+        set.update(&ORSetMessage::add(6));
+        let add_7 = ORSetMessage::add(7);
+        let mut other = ORSet::default();
+        other.update(&add_7);
+        set.update(&add_7);
+        set.update(&ORSetMessage::remove(7, &set));
+        other.update(&ORSetMessage::add(8));
+        other.restore(set.snapshot());
+        // Restoring the same state again changes nothing.
+        other.restore(set.snapshot());
+        let mut items: Vec<_> = other.items().copied().collect();
+        items.sort();
+        assert_eq!(vec![5, 6, 8], items);
+    }
+
+    /// Observed-remove sets converge over a local gossip network.
+    #[test]
+    pub fn or_set_local_network() {
+        let mut nodes: Vec<LocalUniformGossipNode<ORSet<usize>, ORSetMessage<usize>, u128>> =
+            uniform_local_gossip(
+                5,
+                4,
+                ORSet::default,
+                UnboundedSeenStore::default,
+                &mut StdRng::seed_from_u64(7),
+            );
+        let pump = |nodes: &mut Vec<LocalUniformGossipNode<_, _, _>>| {
+            let mut busy = true;
+            while busy {
+                busy = false;
+                for node in nodes.iter_mut() {
+                    while let Ok(message) = node.receiver.try_recv() {
+                        node.gossip.receive(&message).unwrap();
+                        busy = true;
+                    }
+                }
+            }
+        };
+        nodes[0].gossip.update(&ORSetMessage::add(1)).unwrap();
+        nodes[0].gossip.update(&ORSetMessage::add(2)).unwrap();
+        pump(&mut nodes);
+        // Node 1 adds 1 again while node 2 removes it.
+        nodes[1].gossip.update(&ORSetMessage::add(1)).unwrap();
+        let remove = ORSetMessage::remove(1, nodes[2].gossip.data());
+        nodes[2].gossip.update(&remove).unwrap();
+        let remove = ORSetMessage::remove(2, nodes[3].gossip.data());
+        nodes[3].gossip.update(&remove).unwrap();
+        pump(&mut nodes);
+        for node in nodes.iter() {
+            assert!(node.gossip.data().is_present(&1));
+            assert!(!node.gossip.data().is_present(&2));
+        }
+    }
+
+    #[test]
+    pub fn hybrid_clock() {
+        let mut a = HybridClock::new(0);
+        let first = a.now_at(100);
+        let second = a.now_at(100);
+        assert!(second > first);
+        assert_eq!((100, 1), (second.wall, second.logical));
+        // A node whose wall clock is behind still orders its timestamps after what it saw.
+        let mut b = HybridClock::new(1);
+        b.observe(&second);
+        let third = b.now_at(50);
+        assert!(third > second);
+        assert_eq!((100, 2), (third.wall, third.logical));
+        // Once the wall clock catches up, timestamps follow it again.
+        let fourth = b.now_at(200);
+        assert_eq!((200, 0), (fourth.wall, fourth.logical));
+        // Timestamps taken at the same time by different nodes are ordered by node.
+        assert!(HybridClock::new(2).now_at(300) > HybridClock::new(1).now_at(300));
+    }
+
+    /// Apply the given messages (each twice) to the given data in a few shuffled orders, and
+    /// return the results.
+    fn apply_shuffled<S, M>(new_data: impl Fn() -> S, messages: &[M]) -> Vec<S>
+    where
+        S: SharedData<M>,
+    {
+        (0..10)
+            .map(|seed| {
+                let mut order: Vec<_> = messages.iter().chain(messages.iter()).collect();
+                order.shuffle(&mut StdRng::seed_from_u64(seed));
+                let mut data = new_data();
+                for message in order {
+                    data.update(message);
+                }
+                data
+            })
+            .collect()
+    }
+
+    /// Registers converge on the last write whatever order they get the writes in.
+    #[test]
+    pub fn lww_register_converges() {
+        let mut a = LwwRegister::new(0);
+        let mut b = LwwRegister::new(1);
+        let mut c = LwwRegister::new(2);
+        let mut messages = vec![a.write_at("a1", 100), b.write_at("b1", 100)];
+        a.update(&messages[1]);
+        // Node a's clock is behind, but this write follows b's.
+        messages.push(a.write_at("a2", 90));
+        messages.push(c.write_at("c1", 50));
+        for register in apply_shuffled(|| LwwRegister::new(3), &messages) {
+            assert_eq!(Some(&"a2"), register.get());
+        }
+    }
+
+    /// Maps converge on the last write to every key whatever order they get the writes in.
+    #[test]
+    pub fn lww_map_converges() {
+        let mut a = LwwMap::new(0);
+        let mut b = LwwMap::new(1);
+        let mut messages = vec![
+            a.insert_at("x", 1, 100),
+            a.insert_at("y", 2, 100),
+            b.insert_at("x", 3, 100),
+            b.insert_at("z", 4, 110),
+        ];
+        b.update(&messages[1]);
+        messages.push(b.remove_at("y", 105));
+        messages.push(a.remove_at("z", 105));
+        messages.push(a.insert_at("w", 5, 120));
+        for map in apply_shuffled(|| LwwMap::new(2), &messages) {
+            let mut entries: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+            entries.sort();
+            assert_eq!(vec![("w", 5), ("x", 3), ("z", 4)], entries);
+            assert_eq!(None, map.get(&"y"));
+        }
+    }
+
+    #[test]
+    pub fn g_counter() {
+        let mut a = GCounter::new(0);
+        let mut b = GCounter::new(1);
+        let mut messages = vec![a.increment(2), a.increment(3), b.increment(4)];
+        // Applying my own increments again changes nothing.
+        a.update(&messages[1]);
+        a.update(&messages[0]);
+        assert_eq!(5, a.value());
+        messages.push(b.increment(1));
+        for counter in apply_shuffled(|| GCounter::new(2), &messages) {
+            assert_eq!(10, counter.value());
+            assert_eq!(5, counter.count(&0));
+        }
+        a.merge(&b);
+        b.merge(&a);
+        a.merge(&b);
+        assert_eq!(10, a.value());
+        assert_eq!(10, b.value());
+    }
+
+    #[test]
+    pub fn pn_counter() {
+        let mut a = PNCounter::new(0);
+        let mut b = PNCounter::new(1);
+        let messages = vec![
+            a.increment(5),
+            b.decrement(7),
+            a.decrement(1),
+            b.increment(2),
+        ];
+        assert_eq!(4, a.value());
+        assert_eq!(-5, b.value());
+        for counter in apply_shuffled(|| PNCounter::new(2), &messages) {
+            assert_eq!(-1, counter.value());
+        }
+        a.merge(&b);
+        assert_eq!(-1, a.value());
+        let mut c = PNCounter::new(2);
+        c.restore(a.snapshot());
+        c.restore(b.snapshot());
+        assert_eq!(-1, c.value());
+    }
+
+    /// A counter increment that the gossip forgot it's seen isn't counted twice.
+    #[test]
+    pub fn counter_with_forgetful_gossip() {
+        let mut gossip = UniformGossip::<ChannelEndpoint<_>, _, _, _, _>::create_with_seen_store(
+            vec![],
+            1,
+            GCounter::new(0),
+            CHANNELS,
+            LruSeenStore::new(1),
+        );
+        let mut other = GCounter::new(1);
+        let first = other.increment(3);
+        let second = GCounter::new(2).increment(4);
+        gossip.receive(&first).unwrap();
+        gossip.receive(&second).unwrap();
+        // The first message has been forgotten, so it isn't recognized as a duplicate.
+        gossip.receive(&first).unwrap();
+        assert_eq!(7, gossip.data().value());
+    }
 }
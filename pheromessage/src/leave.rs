@@ -0,0 +1,412 @@
+//! Leaving the network gracefully: a node that's about to shut down (e.g. for a planned restart)
+//! gossips a leave notice, and every node that gets it stops gossipping to the leaving node, so
+//! fanout isn't wasted on a node that's gone. If the leaving node had a special role (e.g. a
+//! primary in `PreferentialGossip`), the notice can name a successor to take it over.
+//!
+//! Leave notices are ordinary gossip messages (see `LeaveNotice`), disseminated like any other
+//! message by wrapping the gossip mechanism in `GracefulLeave`.
+
+use std::{hash::Hash, marker::PhantomData};
+
+use crate::{
+    rumor::RumorGossip,
+    seen::{SeenStore, UnboundedSeenStore},
+    zone::ZoneGossip,
+    Gossip, Message, PreferentialGossip, ReceiveFrom, UniformGossip,
+};
+
+/// A message that may announce that a node (with endpoint `P`) is leaving the network.
+pub trait LeaveNotice<P> {
+    /// The node that's leaving, if this is a leave notice.
+    fn leaving(&self) -> Option<&P>;
+
+    /// The node named to take over the role of the one that's leaving, if any.
+    fn successor(&self) -> Option<&P> {
+        None
+    }
+}
+
+/// A gossip mechanism that can let peers (`P`) leave the network, handing their roles over to
+/// a successor.
+pub trait Departure<P> {
+    /// Stop gossipping to the given peer that's leaving. If it had a special role and the given
+    /// `successor` is one of my other peers, it takes that role over.
+    fn peer_left(&mut self, peer: &P, successor: Option<&P>);
+
+    /// Take over the role of the given peer that's leaving (since I was named its successor),
+    /// and stop gossipping to it.
+    fn succeed(&mut self, peer: &P);
+}
+
+/// A wrapper around a gossip mechanism (`G`) that acts on the leave notices going through it,
+/// for the node with the endpoint `P`.
+/// Every notice is only acted on the first time it's seen, as kept track of (by message ID `I`)
+/// in the store `L`: gossip delivers the same notice more than once, and a late copy mustn't
+/// remove a node that has restarted and joined again since. So the store should remember
+/// notices for longer than they can take to go around (or forever, as by default, since nodes
+/// leave a lot less often than messages are sent).
+///
+/// To leave the network, a node sends a leave notice naming itself with `update()`, and should
+/// then keep going until it can expect the notice to have been passed on.
+pub struct GracefulLeave<G, P, I, L = UnboundedSeenStore<I>> {
+    /// The gossip mechanism whose peers leave.
+    gossip: G,
+    /// My own endpoint.
+    endpoint: P,
+    /// The leave notices seen so far.
+    notices: L,
+    _i: PhantomData<I>,
+}
+
+impl<G, P, I> GracefulLeave<G, P, I> {
+    /// Act on leave notices for the given `gossip`, for the node with the given `endpoint`.
+    pub fn new(gossip: G, endpoint: P) -> GracefulLeave<G, P, I> {
+        GracefulLeave::create_with_seen_store(gossip, endpoint, UnboundedSeenStore::default())
+    }
+}
+
+impl<G, P, I, L> GracefulLeave<G, P, I, L> {
+    /// Act on leave notices for the given `gossip`, for the node with the given `endpoint`,
+    /// keeping track of the notices seen in the given store.
+    pub fn create_with_seen_store(gossip: G, endpoint: P, notices: L) -> GracefulLeave<G, P, I, L> {
+        GracefulLeave {
+            gossip,
+            endpoint,
+            notices,
+            _i: PhantomData,
+        }
+    }
+
+    /// The gossip mechanism whose peers leave.
+    pub fn gossip(&self) -> &G {
+        &self.gossip
+    }
+
+    /// The gossip mechanism whose peers leave, to change it.
+    pub fn gossip_mut(&mut self) -> &mut G {
+        &mut self.gossip
+    }
+
+    /// My own endpoint.
+    pub fn endpoint(&self) -> &P {
+        &self.endpoint
+    }
+}
+
+impl<G, P, I, L> GracefulLeave<G, P, I, L>
+where
+    G: Departure<P>,
+    P: PartialEq,
+    L: SeenStore<I>,
+{
+    /// Act on the given message if it's a notice of another node leaving that hasn't been seen
+    /// before (before passing it on, so it isn't passed on to the leaving node).
+    fn handle<M>(&mut self, message: &M)
+    where
+        M: LeaveNotice<P> + Message<I = I>,
+    {
+        let Some(peer) = message.leaving() else {
+            return;
+        };
+        if self.notices.see(message.id()) > 1 || peer == &self.endpoint {
+            return;
+        }
+        match message.successor() {
+            Some(successor) if successor == &self.endpoint => self.gossip.succeed(peer),
+            successor => self.gossip.peer_left(peer, successor),
+        }
+    }
+}
+
+impl<G, P, I, L, M, S> Gossip<M, S> for GracefulLeave<G, P, I, L>
+where
+    G: Gossip<M, S> + Departure<P>,
+    M: LeaveNotice<P> + Message<I = I>,
+    P: PartialEq,
+    L: SeenStore<I>,
+{
+    type Error = G::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.handle(message);
+        self.gossip.receive(message)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.handle(message);
+        self.gossip.update(message)
+    }
+
+    fn data(&self) -> &S {
+        self.gossip.data()
+    }
+}
+
+impl<G, P, I, L, M, S, Q> ReceiveFrom<M, S, Q> for GracefulLeave<G, P, I, L>
+where
+    G: ReceiveFrom<M, S, Q> + Departure<P>,
+    M: LeaveNotice<P> + Message<I = I>,
+    P: PartialEq,
+    L: SeenStore<I>,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.handle(message);
+        self.gossip
+            .receive_from_excluding(message, sender, excluded)
+    }
+}
+
+/// All peers are alike, so there's no role to hand over.
+impl<P, S, D, I, L, C, O> Departure<P> for UniformGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn peer_left(&mut self, peer: &P, _successor: Option<&P>) {
+        self.remove_peer(peer);
+    }
+
+    fn succeed(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+/// A leaving primary is replaced by its successor, if that's a secondary.
+impl<P, S, D, I, L, C, O> Departure<P> for PreferentialGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn peer_left(&mut self, peer: &P, successor: Option<&P>) {
+        self.tiered.peer_left(peer, successor);
+    }
+
+    fn succeed(&mut self, peer: &P) {
+        self.tiered.succeed(peer);
+    }
+}
+
+/// Gateways are configured explicitly, so there's no role to hand over.
+impl<P, S, D, I, L, C, O> Departure<P> for ZoneGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn peer_left(&mut self, peer: &P, _successor: Option<&P>) {
+        self.remove_peer(peer);
+    }
+
+    fn succeed(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+/// All peers are alike, so there's no role to hand over.
+impl<P, S, D, I, L, C, R> Departure<P> for RumorGossip<P, S, D, I, L, C, R>
+where
+    P: Eq + Hash + Clone,
+{
+    fn peer_left(&mut self, peer: &P, _successor: Option<&P>) {
+        self.remove_peer(peer);
+    }
+
+    fn succeed(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{peers::Membership, Delivery, SharedData};
+
+    /// A test message that's either data (by ID) or a leave notice.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Notice {
+        Data(usize),
+        Leave {
+            id: usize,
+            peer: usize,
+            successor: Option<usize>,
+        },
+    }
+
+    impl Message for Notice {
+        type I = usize;
+
+        fn id(&self) -> usize {
+            match self {
+                Notice::Data(id) | Notice::Leave { id, .. } => *id,
+            }
+        }
+    }
+
+    impl LeaveNotice<usize> for Notice {
+        fn leaving(&self) -> Option<&usize> {
+            match self {
+                Notice::Leave { peer, .. } => Some(peer),
+                Notice::Data(_) => None,
+            }
+        }
+
+        fn successor(&self) -> Option<&usize> {
+            match self {
+                Notice::Leave { successor, .. } => successor.as_ref(),
+                Notice::Data(_) => None,
+            }
+        }
+    }
+
+    impl SharedData<Notice> for () {
+        fn update(&mut self, _message: &Notice) {}
+    }
+
+    /// Records the endpoints every message was delivered to.
+    #[derive(Default)]
+    struct Outbox(RefCell<Vec<usize>>);
+
+    impl Delivery<Notice, usize> for Outbox {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, _message: &Notice, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            self.0.borrow_mut().extend(endpoints);
+            Ok(())
+        }
+    }
+
+    impl Outbox {
+        /// The endpoints messages were delivered to since the last time, sorted.
+        fn sent(&self) -> Vec<usize> {
+            let mut sent = self.0.take();
+            sent.sort();
+            sent
+        }
+    }
+
+    /// A leaving node isn't gossipped to, not even the notice of its leaving.
+    #[test]
+    fn left_peer_not_gossipped_to() {
+        let gossip = UniformGossip::<usize, (), Outbox, usize>::create(
+            vec![1, 2, 3],
+            3,
+            (),
+            Outbox::default(),
+        );
+        let mut node = GracefulLeave::new(gossip, 0);
+        let leave = Notice::Leave {
+            id: 10,
+            peer: 2,
+            successor: None,
+        };
+        node.receive(&leave).unwrap();
+        assert_eq!(vec![1, 3], node.gossip().delivery.sent());
+        node.update(&Notice::Data(11)).unwrap();
+        assert_eq!(vec![1, 3], node.gossip().delivery.sent());
+    }
+
+    /// A late copy of a notice doesn't remove a node that has restarted and joined again.
+    #[test]
+    fn duplicate_notice_after_rejoin() {
+        let gossip = UniformGossip::<usize, (), Outbox, usize>::create(
+            vec![1, 2, 3],
+            3,
+            (),
+            Outbox::default(),
+        );
+        let mut node = GracefulLeave::new(gossip, 0);
+        let leave = Notice::Leave {
+            id: 10,
+            peer: 2,
+            successor: None,
+        };
+        node.receive(&leave).unwrap();
+        node.gossip_mut().join(2);
+        node.receive(&leave).unwrap();
+        let mut peers = node.gossip().peers.as_slice().to_vec();
+        peers.sort();
+        assert_eq!(vec![1, 2, 3], peers);
+    }
+
+    /// A node announcing its own leave keeps its peers to pass the notice on to.
+    #[test]
+    fn own_leave_gossipped() {
+        let gossip =
+            UniformGossip::<usize, (), Outbox, usize>::create(vec![1, 2], 2, (), Outbox::default());
+        let mut node = GracefulLeave::new(gossip, 0);
+        let leave = Notice::Leave {
+            id: 10,
+            peer: 0,
+            successor: Some(1),
+        };
+        node.update(&leave).unwrap();
+        assert_eq!(vec![1, 2], node.gossip().delivery.sent());
+    }
+
+    /// The successor of a leaving primary is promoted by the nodes that know it, and becomes a
+    /// primary itself.
+    #[test]
+    fn successor_promoted() {
+        let leave = Notice::Leave {
+            id: 10,
+            peer: 1,
+            successor: Some(3),
+        };
+        let gossip = PreferentialGossip::<usize, (), Outbox, usize>::create(
+            vec![1, 2],
+            vec![3, 4],
+            false,
+            2,
+            (),
+            Outbox::default(),
+        );
+        let mut node = GracefulLeave::new(gossip, 0);
+        node.receive(&leave).unwrap();
+        let mut primaries = node.gossip().primaries().to_vec();
+        primaries.sort();
+        assert_eq!(vec![2, 3], primaries);
+        assert_eq!(&[4], node.gossip().secondaries());
+
+        let gossip = PreferentialGossip::<usize, (), Outbox, usize>::create(
+            vec![1],
+            vec![4],
+            false,
+            2,
+            (),
+            Outbox::default(),
+        );
+        let mut successor = GracefulLeave::new(gossip, 3);
+        assert!(!successor.gossip().is_primary());
+        successor.receive(&leave).unwrap();
+        assert!(successor.gossip().is_primary());
+        assert!(successor.gossip().primaries().is_empty());
+    }
+
+    /// Only a primary's role is handed over.
+    #[test]
+    fn secondary_not_replaced() {
+        let gossip = PreferentialGossip::<usize, (), Outbox, usize>::create(
+            vec![1],
+            vec![2, 3],
+            true,
+            2,
+            (),
+            Outbox::default(),
+        );
+        let mut node = GracefulLeave::new(gossip, 0);
+        let leave = Notice::Leave {
+            id: 10,
+            peer: 2,
+            successor: Some(3),
+        };
+        node.receive(&leave).unwrap();
+        assert_eq!(&[1], node.gossip().primaries());
+        assert_eq!(&[3], node.gossip().secondaries());
+    }
+}
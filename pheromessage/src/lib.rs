@@ -1,15 +1,37 @@
-use rand::prelude::*;
-use std::{
-    collections::{HashMap, HashSet},
-    hash::Hash,
-};
+use observe::{GossipObserver, NoopObserver};
+use peers::{Membership, PeerSet};
+use seen::{SeenStore, UnboundedSeenStore};
+use select::{ExcludingSelector, Exclusion, PeerSelector, UniformSelector};
+use std::{hash::Hash, iter, marker::PhantomData};
+use tiered::{TierConfig, TieredGossip};
 
+pub mod antientropy;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod bloom;
+pub mod bootstrap;
+pub mod builder;
 pub mod channel;
+pub mod classified;
 pub mod data;
+pub mod leave;
+pub mod lossy;
+pub mod metrics;
 pub mod multiplex;
 pub mod net;
+pub mod observe;
+pub mod peers;
+pub mod plumtree;
 #[cfg(feature = "postcard")]
 pub mod postmessage;
+pub mod reliable;
+pub mod rng;
+pub mod rumor;
+pub mod seen;
+pub mod select;
+pub mod swim;
+pub mod tiered;
+pub mod zone;
 
 /// Delivery mechanism for delivering messages (`M`) to endpoints (`P`).
 pub trait Delivery<M, P> {
@@ -20,6 +42,61 @@ pub trait Delivery<M, P> {
     where
         I: ExactSizeIterator<Item = &'a P>,
         P: 'a;
+
+    /// Deliver the given message to the given endpoints, going on to the rest of them when
+    /// delivering to some fails, and report which ones failed. This only fails as a whole if
+    /// the message couldn't be delivered to any endpoint for reasons that have nothing to do
+    /// with the endpoints (e.g. it couldn't be serialized).
+    /// By default, the message is delivered to every endpoint separately.
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.enumerate() {
+            if let Err(e) = self.deliver(message, iter::once(endpoint)) {
+                report.failures.push((position, e));
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The outcome of delivering a message to a set of endpoints, where delivering to some of them
+/// may have failed without affecting the others.
+#[derive(Debug)]
+pub struct DeliveryReport<E> {
+    /// The endpoints the message couldn't be delivered to, as (position among the endpoints
+    /// delivered to, error) pairs in order.
+    pub failures: Vec<(usize, E)>,
+}
+
+impl<E> Default for DeliveryReport<E> {
+    fn default() -> Self {
+        DeliveryReport {
+            failures: Vec::new(),
+        }
+    }
+}
+
+impl<E> DeliveryReport<E> {
+    /// Checks if the message was delivered to all endpoints.
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The first failure as an error, if delivery failed for any endpoint.
+    pub fn into_result(self) -> Result<(), E> {
+        match self.failures.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
 }
 
 /// A gossip mechanism for maintaining shared data and updating it by gossiping with peers.
@@ -36,6 +113,51 @@ pub trait Gossip<M, S> {
     fn data(&self) -> &S;
 }
 
+/// A gossip mechanism that can be told which peer a message came from, so it doesn't waste a
+/// send passing the message back to it. The sender is identified by `Q`, which the peers
+/// have to be comparable to (e.g. the peer type itself, or the `NodeGroupInfo` in `multiplex`
+/// envelopes).
+pub trait ReceiveFrom<M, S, Q>: Gossip<M, S> {
+    /// Indicate that the given message has been received from the given `sender`, so it
+    /// shouldn't be passed on to it, nor to any of the `excluded` peers (e.g. because they're
+    /// known to have it already).
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error>;
+
+    /// Indicate that the given message has been received from the given `sender`, so it
+    /// shouldn't be passed back to it.
+    fn receive_from(&mut self, message: &M, sender: &Q) -> Result<(), Self::Error> {
+        self.receive_from_excluding(message, sender, &[])
+    }
+
+    /// Indicate that the given message has been received from the given `sender`, so it
+    /// shouldn't be passed on to it, nor to the peers the message says already have it.
+    fn receive_from_skipping_holders(&mut self, message: &M, sender: &Q) -> Result<(), Self::Error>
+    where
+        M: KnownHolders<Q>,
+    {
+        self.receive_from_excluding(message, sender, message.known_holders())
+    }
+}
+
+/// A message that carries the peers (identified by `Q`) that are already known to have it.
+pub trait KnownHolders<Q> {
+    /// The peers already known to have this message.
+    fn known_holders(&self) -> &[Q];
+}
+
+/// Checks if the given peer is the `sender` of a message or one of the `excluded` peers.
+pub(crate) fn is_excluded<P, Q>(peer: &P, sender: &Q, excluded: &[Q]) -> bool
+where
+    P: PartialEq<Q>,
+{
+    peer == sender || excluded.iter().any(|q| peer == q)
+}
+
 /// A message that can update shared data.
 pub trait Message {
     type I;
@@ -51,64 +173,230 @@ pub trait SharedData<M> {
 }
 
 /// A gossip mechanism that treats all peers equally in updating them.
-pub struct UniformGossip<P, S, D, I> {
+/// `L` is the store used to keep track of the IDs (`I`) of messages seen so far,
+/// `C` is the strategy used to select which peers to gossip to, and `O` is the observer
+/// notified of the decisions made about every message.
+pub struct UniformGossip<
+    P,
+    S,
+    D,
+    I,
+    L = UnboundedSeenStore<I>,
+    C = UniformSelector,
+    O = NoopObserver,
+> {
     /// The set of peers.
-    pub peers: Vec<P>,
-    /// Set of all message IDs seen so far.
-    seen_messages: HashSet<I>,
+    pub peers: PeerSet<P>,
+    /// The message IDs seen so far.
+    seen_messages: L,
     /// The delivery mechanism to send gossip messages.
     pub delivery: D,
     /// The data being gossipped about.
     pub data: S,
     /// How many peers to reach out to when gossipping.
     pub fanout: usize,
+    /// The strategy for selecting which peers to gossip to.
+    pub selector: C,
+    /// The observer of the gossip decisions.
+    pub observer: O,
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach.
+    pub replace_failed: bool,
+    _i: PhantomData<I>,
 }
 
-impl<P, S, D, I> UniformGossip<P, S, D, I> {
+impl<P, S, D, I> UniformGossip<P, S, D, I>
+where
+    P: Eq + Hash + Clone,
+{
     /// Create a new uniform gossip mechanism that will gossip to the given set of `peers`,
     /// using the given `delivery` mechanism and maintaining the given `data`.
     /// The gossip will be done using the given `fanout` - each message will be delivered
-    /// to a random subset of peers of that size.
+    /// to a random subset of peers of that size (use `with_peer_selector()` to select
+    /// them differently).
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
     pub fn create(peers: Vec<P>, fanout: usize, data: S, delivery: D) -> UniformGossip<P, S, D, I> {
-        UniformGossip {
+        UniformGossip::create_with_seen_store(
             peers,
-            seen_messages: HashSet::new(),
+            fanout,
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
+    }
+}
+
+impl<P, S, D, I, L> UniformGossip<P, S, D, I, L>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new uniform gossip mechanism like `create()`, but keeping track of the
+    /// messages seen in the given `seen_messages` store.
+    pub fn create_with_seen_store(
+        peers: Vec<P>,
+        fanout: usize,
+        data: S,
+        delivery: D,
+        seen_messages: L,
+    ) -> UniformGossip<P, S, D, I, L> {
+        UniformGossip {
+            peers: peers.into(),
+            seen_messages,
             delivery,
             data,
             fanout,
+            selector: UniformSelector::new(),
+            observer: NoopObserver,
+            replace_failed: false,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> UniformGossip<P, S, D, I, L, C, O> {
+    /// The store keeping track of the messages seen so far.
+    pub fn seen_messages(&self) -> &L {
+        &self.seen_messages
+    }
+
+    /// Use the given `selector` to select which peers to gossip to from now on.
+    pub fn with_peer_selector<C2>(self, selector: C2) -> UniformGossip<P, S, D, I, L, C2, O> {
+        UniformGossip {
+            peers: self.peers,
+            seen_messages: self.seen_messages,
+            delivery: self.delivery,
+            data: self.data,
+            fanout: self.fanout,
+            selector,
+            observer: self.observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Notify the given `observer` of the gossip decisions from now on.
+    pub fn with_observer<O2>(self, observer: O2) -> UniformGossip<P, S, D, I, L, C, O2> {
+        UniformGossip {
+            peers: self.peers,
+            seen_messages: self.seen_messages,
+            delivery: self.delivery,
+            data: self.data,
+            fanout: self.fanout,
+            selector: self.selector,
+            observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach
+    /// (by default, failed peers are just reported to the observer).
+    pub fn with_failed_peer_replacement(self, replace_failed: bool) -> Self {
+        UniformGossip {
+            replace_failed,
+            ..self
         }
     }
 }
 
-impl<P, S, D, M, I> Gossip<M, S> for UniformGossip<P, S, D, I>
+impl<P, S, D, I, L, C, O> UniformGossip<P, S, D, I, L, C, O>
 where
-    M: Message<I = I>,
-    D: Delivery<M, P>,
-    I: Eq + Hash,
-    S: SharedData<M>,
+    P: Eq + Hash + Clone,
 {
-    type Error = D::Error;
+    /// Add the given peer to gossip to. Returns `false` if it was already a peer.
+    pub fn add_peer(&mut self, peer: P) -> bool {
+        self.peers.insert(peer)
+    }
 
-    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+    /// Stop gossipping to the given peer. Returns `false` if it wasn't a peer.
+    pub fn remove_peer(&mut self, peer: &P) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+}
+
+impl<P, S, D, I, L, C, O> Membership<P> for UniformGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn join(&mut self, peer: P) {
+        self.add_peer(peer);
+    }
+
+    fn leave(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+impl<P, S, D, I, L, C, O> UniformGossip<P, S, D, I, L, C, O> {
+    /// Receive the given message from a peer, not passing it on to any `excluded` peers.
+    fn receive_excluding<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        M: Message<I = I>,
+        D: Delivery<M, P>,
+        L: SeenStore<I>,
+        S: SharedData<M>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
         // Mark the message as seen
         let id = message.id();
-        let new = self.seen_messages.insert(id);
+        let count_seen = self.seen_messages.see(id);
+        self.observer.on_seen_store_size(self.seen_messages.len());
         // Only pass the message on if I've never seen it before, otherwise it's a repeat so throw it away.
-        if new {
+        if count_seen == 1 {
             // This is the first time I see this message, update my data and pass it on.
+            self.observer.on_first_seen(message);
             self.data.update(message);
-            gossip(&self.delivery, message, &self.peers, self.fanout)?;
+            gossip_observed(
+                &self.delivery,
+                &mut ExcludingSelector::new(&mut self.selector, excluded),
+                &mut self.observer,
+                message,
+                self.peers.as_slice(),
+                self.fanout,
+                self.replace_failed,
+            )?;
+        } else {
+            self.observer.on_duplicate(message, count_seen);
         }
         Ok(())
     }
+}
+
+impl<P, S, D, M, I, L, C, O> Gossip<M, S> for UniformGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: Clone,
+{
+    type Error = D::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.receive_excluding(message, None)
+    }
 
     fn update(&mut self, message: &M) -> Result<(), Self::Error> {
         // Update my data.
+        self.observer.on_update(message);
         self.data.update(message);
         // Mark it as seen.
-        self.seen_messages.insert(message.id());
+        self.seen_messages.see(message.id());
+        self.observer.on_seen_store_size(self.seen_messages.len());
         // Pass it on to my peers.
-        gossip(&self.delivery, message, &self.peers, self.fanout)
+        gossip_observed(
+            &self.delivery,
+            &mut self.selector,
+            &mut self.observer,
+            message,
+            self.peers.as_slice(),
+            self.fanout,
+            self.replace_failed,
+        )
     }
 
     fn data(&self) -> &S {
@@ -116,50 +404,58 @@ where
     }
 }
 
-/// Indicator for whether I've seen a message only once, twice or more. Primary
-/// nodes behave differently based on that in the preferential gossip algorithm.
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-enum SeenCount {
-    Once,
-    Twice,
-    MoreThanTwice,
-}
-
-impl SeenCount {
-    pub fn increment(&mut self) {
-        match self {
-            SeenCount::Once => *self = SeenCount::Twice,
-            SeenCount::Twice => *self = SeenCount::MoreThanTwice,
-            SeenCount::MoreThanTwice => (),
-        }
+impl<P, S, D, M, I, L, C, O, Q> ReceiveFrom<M, S, Q> for UniformGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: PartialEq<Q> + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.receive_excluding(message, Some(&|p: &P| is_excluded(p, sender, excluded)))
     }
 }
 
 /// A gossip mechanism that treats a subset of peers as primaries that should get priority
 /// in getting updates faster.
-pub struct PreferentialGossip<P, S, D, I> {
-    /// The endpoints for all the primary peers.
-    primaries: Vec<P>,
-    /// The endpoints for all the rest of the peers (not primary).
-    secondaries: Vec<P>,
-    /// Count of how often I've seen each message by ID.
-    message_log: HashMap<I, SeenCount>,
-    /// Whether I myself am primary or secondary.
-    primary: bool,
-    /// The delivery mechanism to send gossip messages.
-    delivery: D,
-    /// The data being gossipped about.
-    data: S,
-    /// How many peers to reach out to when gossipping.
-    fanout: usize,
+/// This is tiered gossip with two tiers (primaries then secondaries), where primaries pass a
+/// message on to other primaries the first time they see it, then to secondaries the second time.
+/// `L` is the store used to keep track of how often messages (by ID `I`) have been seen,
+/// `C` is the strategy used to select which peers to gossip to, and `O` is the observer
+/// notified of the decisions made about every message.
+pub struct PreferentialGossip<
+    P,
+    S,
+    D,
+    I,
+    L = UnboundedSeenStore<I>,
+    C = UniformSelector,
+    O = NoopObserver,
+> {
+    /// The underlying two-tier gossip.
+    tiered: TieredGossip<P, S, D, I, L, C, O>,
 }
 
-impl<P, S, D, I> PreferentialGossip<P, S, D, I> {
+impl<P, S, D, I> PreferentialGossip<P, S, D, I>
+where
+    P: Eq + Hash + Clone,
+{
     /// Create a new preferential gossip mechanism that will gossip to the given set of `primaries`
     /// and `secondaries`, with this node itself acting as primary if `primary` is `true`,
     /// using the given `delivery` mechanism and maintaining the given `data`.
     /// The gossip will be done using the given `fanout` - each message will be delivered
-    /// to a random subset of peers of that size.
+    /// to a random subset of peers of that size (use `with_peer_selector()` to select
+    /// them differently).
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
     pub fn create(
         primaries: Vec<P>,
         secondaries: Vec<P>,
@@ -167,107 +463,303 @@ impl<P, S, D, I> PreferentialGossip<P, S, D, I> {
         fanout: usize,
         data: S,
         delivery: D,
-    ) -> PreferentialGossip<P, S, D, I> {
-        PreferentialGossip {
+    ) -> PreferentialGossip<P, S, D, I>
+    where
+        I: Eq + Hash,
+    {
+        PreferentialGossip::create_with_seen_store(
             primaries,
             secondaries,
-            message_log: HashMap::new(),
             primary,
-            delivery,
-            data,
             fanout,
-        }
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
     }
+}
 
-    fn increment_seen(&mut self, message_id: I) -> SeenCount
+/// The number of times a primary has to see a message before passing it on to secondaries.
+pub(crate) const PRIMARY_FORWARD_AFTER: usize = 2;
+
+impl<P, S, D, I, L> PreferentialGossip<P, S, D, I, L>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new preferential gossip mechanism like `create()`, but keeping track of how
+    /// often messages have been seen in the given `message_log` store.
+    /// If this node is a primary and the store's counts saturate, they have to go past `2`.
+    pub fn create_with_seen_store(
+        primaries: Vec<P>,
+        secondaries: Vec<P>,
+        primary: bool,
+        fanout: usize,
+        data: S,
+        delivery: D,
+        message_log: L,
+    ) -> PreferentialGossip<P, S, D, I, L>
     where
-        I: Eq + Hash,
+        L: SeenStore<I>,
     {
-        *self
-            .message_log
-            .entry(message_id)
-            .and_modify(|count| count.increment())
-            .or_insert(SeenCount::Once)
+        // Primaries pass messages on to secondaries once they've seen them twice, while
+        // secondaries are the last tier so never pass it on beyond.
+        let config = TierConfig {
+            fanout,
+            forward_after: PRIMARY_FORWARD_AFTER,
+        };
+        PreferentialGossip {
+            tiered: TieredGossip::create_with_seen_store(
+                vec![primaries, secondaries],
+                vec![config, config],
+                if primary { 0 } else { 1 },
+                data,
+                delivery,
+                message_log,
+            ),
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> PreferentialGossip<P, S, D, I, L, C, O> {
+    /// The store keeping track of how often messages have been seen.
+    pub fn seen_messages(&self) -> &L {
+        self.tiered.seen_messages()
+    }
+
+    /// Use the given `selector` to select which peers to gossip to from now on.
+    pub fn with_peer_selector<C2>(self, selector: C2) -> PreferentialGossip<P, S, D, I, L, C2, O> {
+        PreferentialGossip {
+            tiered: self.tiered.with_peer_selector(selector),
+        }
+    }
+
+    /// Whether to pass a message on to other peers (in the same tier) instead of the ones it
+    /// failed to reach.
+    pub fn with_failed_peer_replacement(self, replace_failed: bool) -> Self {
+        PreferentialGossip {
+            tiered: self.tiered.with_failed_peer_replacement(replace_failed),
+        }
+    }
+
+    /// Notify the given `observer` of the gossip decisions from now on.
+    pub fn with_observer<O2>(self, observer: O2) -> PreferentialGossip<P, S, D, I, L, C, O2> {
+        PreferentialGossip {
+            tiered: self.tiered.with_observer(observer),
+        }
+    }
+
+    /// The observer of the gossip decisions.
+    pub fn observer(&self) -> &O {
+        self.tiered.observer()
+    }
+
+    /// Checks if I'm a primary.
+    pub fn is_primary(&self) -> bool {
+        self.tiered.tier() == 0
+    }
+
+    /// The primary peers.
+    pub fn primaries(&self) -> &[P] {
+        self.tiered.peers(0)
+    }
+
+    /// The secondary peers.
+    pub fn secondaries(&self) -> &[P] {
+        self.tiered.peers(1)
+    }
+}
+
+impl<P, S, D, I, L, C, O> PreferentialGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Add the given peer to gossip to as a primary or secondary (moving it if it's already
+    /// a peer of the other kind). Returns `false` if it was already that kind of peer.
+    pub fn add_peer(&mut self, peer: P, primary: bool) -> bool {
+        self.tiered.add_peer(peer, if primary { 0 } else { 1 })
+    }
+
+    /// Stop gossipping to the given peer. Returns `false` if it wasn't a peer.
+    pub fn remove_peer(&mut self, peer: &P) -> bool {
+        self.tiered.remove_peer(peer)
+    }
+
+    /// Make the given secondary peer a primary. Returns `false` if it wasn't a secondary.
+    pub fn promote(&mut self, peer: &P) -> bool {
+        self.move_peer(peer, 1, 0)
+    }
+
+    /// Make the given primary peer a secondary. Returns `false` if it wasn't a primary.
+    pub fn demote(&mut self, peer: &P) -> bool {
+        self.move_peer(peer, 0, 1)
+    }
+
+    fn move_peer(&mut self, peer: &P, from: usize, to: usize) -> bool {
+        if self.tiered.peer_tier(peer) != Some(from) {
+            return false;
+        }
+        self.tiered.add_peer(peer.clone(), to)
+    }
+}
+
+/// New nodes join as secondaries (and can be promoted later).
+impl<P, S, D, I, L, C, O> Membership<P> for PreferentialGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn join(&mut self, peer: P) {
+        self.tiered.join(peer);
+    }
+
+    fn leave(&mut self, peer: &P) {
+        self.tiered.leave(peer);
     }
 }
 
-impl<P, S, D, M, I> Gossip<M, S> for PreferentialGossip<P, S, D, I>
+impl<P, S, D, M, I, L, C, O> Gossip<M, S> for PreferentialGossip<P, S, D, I, L, C, O>
 where
     M: Message<I = I>,
     D: Delivery<M, P>,
-    I: Eq + Hash,
+    L: SeenStore<I>,
     S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: Clone,
 {
     type Error = D::Error;
 
     fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
-        // Update the amount of times I've seen this message.
-        let count_seen = self.increment_seen(message.id());
-        if count_seen == SeenCount::Once {
-            // This is the first time I've seen this message - update the data.
-            self.data.update(message);
-        }
-        // Now check who I should send the message to - if any - based on if I'm primary
-        // and how many times I've seen this message.
-        let targets = if self.primary {
-            // If I'm primary - I pass it on to other primaries if it's the first time
-            // I've seen this message, otherwise I pass it on to secondary if this is
-            // the second time I've seen it.
-            match count_seen {
-                SeenCount::Once => Some(&self.primaries),
-                SeenCount::Twice => Some(&self.secondaries),
-                SeenCount::MoreThanTwice => None,
-            }
-        } else if count_seen == SeenCount::Once {
-            // I'm secondary and this is the first time I've seen it, pass it on to
-            // other secondaries.
-            Some(&self.secondaries)
-        } else {
-            // I'm secondary and I've seen it before, throw it away.
-            None
-        };
-        if let Some(targets) = targets {
-            gossip(&self.delivery, message, targets, self.fanout)?;
-        }
-        Ok(())
+        self.tiered.receive(message)
     }
 
     fn update(&mut self, message: &M) -> Result<(), Self::Error> {
-        self.data.update(message);
-        self.increment_seen(message.id());
-        gossip(&self.delivery, message, &self.primaries, self.fanout)
+        self.tiered.update(message)
     }
 
     fn data(&self) -> &S {
-        &self.data
+        self.tiered.data()
+    }
+}
+
+impl<P, S, D, M, I, L, C, O, Q> ReceiveFrom<M, S, Q> for PreferentialGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: PartialEq<Q> + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.tiered
+            .receive_from_excluding(message, sender, excluded)
     }
 }
 
-/// Gossip the given `message` to a random subset of size `fanout` of `targets`.
-fn gossip<P, D, M, I>(
+/// Gossip the given `message` to a subset of size `fanout` of `targets` as chosen by `selector`.
+/// Failing to reach some of them doesn't stop the message from reaching the others.
+fn gossip<P, D, M, C>(
     delivery: &D,
+    selector: &mut C,
     message: &M,
-    targets: &Vec<P>,
+    targets: &[P],
     fanout: usize,
 ) -> Result<(), D::Error>
 where
-    M: Message<I = I>,
     D: Delivery<M, P>,
-    I: Eq + Hash,
+    C: PeerSelector<P>,
+{
+    gossip_observed(
+        delivery,
+        selector,
+        &mut NoopObserver,
+        message,
+        targets,
+        fanout,
+        false,
+    )
+}
+
+/// Gossip the given `message` like `gossip()`, letting the `observer` know who it was passed on to
+/// and who it failed to reach. If `replace_failed` is set, the selector is asked for other
+/// targets to pass the message on to instead of the ones it failed to reach (once).
+fn gossip_observed<P, D, M, C, O>(
+    delivery: &D,
+    selector: &mut C,
+    observer: &mut O,
+    message: &M,
+    targets: &[P],
+    fanout: usize,
+    replace_failed: bool,
+) -> Result<(), D::Error>
+where
+    D: Delivery<M, P>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+{
+    let chosen = selector.select(targets, fanout);
+    let failed = deliver_observed(delivery, observer, message, targets, &chosen)?;
+    if replace_failed && failed > 0 {
+        // Ask for enough targets that there are replacements even if all the ones already
+        // tried are chosen again.
+        let replacements: Vec<_> = selector
+            .select(targets, chosen.len() + failed)
+            .into_iter()
+            .filter(|i| !chosen.contains(i))
+            .take(failed)
+            .collect();
+        if !replacements.is_empty() {
+            deliver_observed(delivery, observer, message, targets, &replacements)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deliver the given `message` to the `targets` at the given `indices`, letting the `observer`
+/// know. Returns how many of them it failed to reach.
+fn deliver_observed<P, D, M, O>(
+    delivery: &D,
+    observer: &mut O,
+    message: &M,
+    targets: &[P],
+    indices: &[usize],
+) -> Result<usize, D::Error>
+where
+    D: Delivery<M, P>,
+    O: GossipObserver<M, P>,
 {
-    let mut rng = rand::thread_rng();
-    let chosen = targets.choose_multiple(&mut rng, fanout);
-    delivery.deliver(message, chosen)
+    observer.on_forward(message, indices.iter().map(|&i| &targets[i]));
+    match delivery.deliver_each(message, indices.iter().map(|&i| &targets[i])) {
+        Ok(report) => {
+            for &(position, _) in &report.failures {
+                observer.on_delivery_error(message, &targets[indices[position]]);
+            }
+            Ok(report.failures.len())
+        }
+        Err(e) => {
+            for &i in indices {
+                observer.on_delivery_error(message, &targets[i]);
+            }
+            Err(e)
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::cell::RefCell;
+pub(crate) mod tests {
+    use std::{cell::RefCell, collections::HashMap};
 
     use super::*;
+    use crate::seen::LruSeenStore;
 
     /// A "network" that just keeps track of which endpoints (keys) received which messages (values).
-    struct Network(RefCell<HashMap<usize, Vec<usize>>>);
+    pub(crate) struct Network(pub(crate) RefCell<HashMap<usize, Vec<usize>>>);
 
     impl Delivery<usize, usize> for Network {
         type Error = ();
@@ -296,11 +788,16 @@ mod tests {
         }
     }
 
+    /// Implement `SharedData` for the unit type for testing purposes where the data doesn't matter.
+    impl SharedData<usize> for () {
+        fn update(&mut self, _message: &usize) {}
+    }
+
     /// When gossipping to the entire network, all of them should receive it.
     #[test]
     fn gossip_to_all() {
         let network = Network(RefCell::new(HashMap::new()));
-        gossip(&network, &10, &vec![1, 2, 3], 3).unwrap();
+        gossip(&network, &mut UniformSelector::new(), &10, &[1, 2, 3], 3).unwrap();
         assert_eq!(Some(&vec![10]), network.0.borrow().get(&1));
         assert_eq!(Some(&vec![10]), network.0.borrow().get(&2));
         assert_eq!(Some(&vec![10]), network.0.borrow().get(&3));
@@ -310,7 +807,320 @@ mod tests {
     #[test]
     fn gossip_to_some() {
         let network = Network(RefCell::new(HashMap::new()));
-        gossip(&network, &10, &vec![1, 2, 3, 4, 5], 3).unwrap();
+        gossip(
+            &network,
+            &mut UniformSelector::new(),
+            &10,
+            &[1, 2, 3, 4, 5],
+            3,
+        )
+        .unwrap();
         assert_eq!(3, network.0.borrow().len());
     }
+
+    /// Data that just counts how many updates it got.
+    #[derive(Default)]
+    struct UpdateCount(usize);
+
+    impl SharedData<usize> for UpdateCount {
+        fn update(&mut self, _message: &usize) {
+            self.0 += 1;
+        }
+    }
+
+    /// With a bounded seen store, memory stays flat over a long stream of messages while
+    /// duplicates that arrive within the retention window are still suppressed.
+    #[test]
+    fn bounded_seen_store() {
+        let network = Network(RefCell::new(HashMap::new()));
+        let mut gossip = UniformGossip::create_with_seen_store(
+            vec![1],
+            1,
+            UpdateCount::default(),
+            network,
+            LruSeenStore::new(100),
+        );
+        for i in 0..10_000 {
+            gossip.receive(&i).unwrap();
+            if i >= 20 {
+                gossip.receive(&(i - 20)).unwrap();
+            }
+            assert!(gossip.seen_messages().len() <= 100);
+        }
+        // Every message was only taken in and passed on once.
+        assert_eq!(10_000, gossip.data().0);
+        assert_eq!(10_000, gossip.delivery.0.borrow()[&1].len());
+    }
+
+    /// A message received from a peer isn't passed back to it, even when it could be.
+    #[test]
+    fn receive_from_skips_sender() {
+        let mut gossip =
+            UniformGossip::create(vec![1, 2, 3], 2, (), Network(RefCell::new(HashMap::new())));
+        gossip.receive_from(&10, &1).unwrap();
+        let network = gossip.delivery.0.borrow();
+        assert_eq!(None, network.get(&1));
+        assert_eq!(Some(&vec![10]), network.get(&2));
+        assert_eq!(Some(&vec![10]), network.get(&3));
+    }
+
+    /// A message that knows which peers already have it.
+    struct Held(usize, Vec<usize>);
+
+    impl Message for Held {
+        type I = usize;
+
+        fn id(&self) -> usize {
+            self.0
+        }
+    }
+
+    impl KnownHolders<usize> for Held {
+        fn known_holders(&self) -> &[usize] {
+            &self.1
+        }
+    }
+
+    impl SharedData<Held> for () {
+        fn update(&mut self, _message: &Held) {}
+    }
+
+    impl Delivery<Held, usize> for Network {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, message: &Held, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            self.deliver(&message.0, endpoints)
+        }
+    }
+
+    /// Peers the message says already have it are skipped along with the sender, and whatever
+    /// is left is still picked up to the fanout.
+    #[test]
+    fn receive_from_skipping_holders() {
+        let mut gossip = UniformGossip::create(
+            vec![1, 2, 3, 4, 5],
+            2,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        );
+        gossip
+            .receive_from_skipping_holders(&Held(10, vec![2, 3]), &1)
+            .unwrap();
+        let network = gossip.delivery.0.borrow();
+        let mut reached: Vec<_> = network.keys().copied().collect();
+        reached.sort();
+        assert_eq!(vec![4, 5], reached);
+    }
+
+    /// A primary passes a message on to other primaries the first time it sees it,
+    /// to secondaries the second time, and then stops.
+    #[test]
+    fn primary_forwarding() {
+        let mut gossip = PreferentialGossip::create(
+            vec![1, 2],
+            vec![3, 4],
+            true,
+            2,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        );
+        let received = |gossip: &PreferentialGossip<_, _, Network, _>| {
+            let network = gossip.tiered.delivery().0.borrow();
+            (1..=4)
+                .map(|e| network.get(&e).map_or(0, Vec::len))
+                .collect::<Vec<_>>()
+        };
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![1, 1, 0, 0], received(&gossip));
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![1, 1, 1, 1], received(&gossip));
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![1, 1, 1, 1], received(&gossip));
+    }
+
+    /// Records every event observed, in order.
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl GossipObserver<usize, usize> for Recorder {
+        fn on_first_seen(&mut self, message: &usize) {
+            self.0.push(format!("new {message}"));
+        }
+
+        fn on_duplicate(&mut self, message: &usize, times_seen: usize) {
+            self.0.push(format!("dup {message} {times_seen}"));
+        }
+
+        fn on_forward<'a, T>(&mut self, message: &usize, targets: T)
+        where
+            T: ExactSizeIterator<Item = &'a usize>,
+        {
+            let mut targets: Vec<_> = targets.collect();
+            targets.sort();
+            self.0.push(format!("fwd {message} {targets:?}"));
+        }
+
+        fn on_update(&mut self, message: &usize) {
+            self.0.push(format!("upd {message}"));
+        }
+
+        fn on_delivery_error(&mut self, message: &usize, target: &usize) {
+            self.0.push(format!("fail {message} {target}"));
+        }
+    }
+
+    #[test]
+    fn observe_uniform() {
+        let mut gossip =
+            UniformGossip::create(vec![1, 2], 2, (), Network(RefCell::new(HashMap::new())))
+                .with_observer(Recorder::default());
+        gossip.receive(&10).unwrap();
+        gossip.receive(&10).unwrap();
+        gossip.update(&11).unwrap();
+        assert_eq!(
+            vec![
+                "new 10",
+                "fwd 10 [1, 2]",
+                "dup 10 2",
+                "upd 11",
+                "fwd 11 [1, 2]"
+            ],
+            gossip.observer.0
+        );
+    }
+
+    #[test]
+    fn observe_preferential() {
+        let mut gossip = PreferentialGossip::create(
+            vec![1],
+            vec![2],
+            true,
+            1,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        )
+        .with_observer((Recorder::default(), Recorder::default()));
+        gossip.receive(&10).unwrap();
+        gossip.receive(&10).unwrap();
+        let expected = vec!["new 10", "fwd 10 [1]", "dup 10 2", "fwd 10 [2]"];
+        assert_eq!(expected, gossip.observer().0 .0);
+        assert_eq!(expected, gossip.observer().1 .0);
+    }
+
+    /// A "network" where endpoint 1 is down.
+    struct Partitioned(Network);
+
+    impl Delivery<usize, usize> for Partitioned {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, message: &usize, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            let (down, up): (Vec<_>, Vec<_>) = endpoints.partition(|&&e| e == 1);
+            self.0.deliver(message, up.into_iter())?;
+            if down.is_empty() {
+                Ok(())
+            } else {
+                Err(())
+            }
+        }
+    }
+
+    #[test]
+    fn partial_failure() {
+        let mut gossip = UniformGossip::create(
+            vec![1, 2, 3],
+            3,
+            (),
+            Partitioned(Network(RefCell::new(HashMap::new()))),
+        )
+        .with_observer(Recorder::default());
+        gossip.receive(&10).unwrap();
+        assert_eq!(1, gossip.delivery.0 .0.borrow()[&2].len());
+        assert_eq!(1, gossip.delivery.0 .0.borrow()[&3].len());
+        assert_eq!("fail 10 1", gossip.observer.0[2]);
+    }
+
+    #[test]
+    fn replace_failed_peers() {
+        let mut gossip = UniformGossip::create(
+            vec![1, 2],
+            1,
+            (),
+            Partitioned(Network(RefCell::new(HashMap::new()))),
+        )
+        .with_failed_peer_replacement(true);
+        for message in 0..20 {
+            gossip.receive(&message).unwrap();
+        }
+        // Whenever 1 was chosen, 2 got the message instead.
+        assert_eq!(20, gossip.delivery.0 .0.borrow()[&2].len());
+    }
+
+    #[test]
+    fn dynamic_peers() {
+        let mut gossip =
+            UniformGossip::create(vec![1, 2], 3, (), Network(RefCell::new(HashMap::new())));
+        assert!(gossip.add_peer(3));
+        assert!(!gossip.add_peer(3));
+        assert!(gossip.remove_peer(&1));
+        assert!(!gossip.remove_peer(&1));
+        gossip.receive(&10).unwrap();
+        let mut reached: Vec<_> = gossip.delivery.0.borrow().keys().copied().collect();
+        reached.sort();
+        assert_eq!(vec![2, 3], reached);
+    }
+
+    #[test]
+    fn promote_and_demote() {
+        let mut gossip = PreferentialGossip::create(
+            vec![1, 2],
+            vec![3, 4],
+            true,
+            3,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        );
+        assert!(gossip.promote(&3));
+        assert!(!gossip.promote(&3));
+        assert!(gossip.demote(&1));
+        assert!(!gossip.demote(&4));
+        assert!(gossip.add_peer(5, true));
+        let sorted = |peers: &[usize]| {
+            let mut peers = peers.to_vec();
+            peers.sort();
+            peers
+        };
+        assert_eq!(vec![2, 3, 5], sorted(gossip.primaries()));
+        assert_eq!(vec![1, 4], sorted(gossip.secondaries()));
+        // The first time it's seen, a message only goes to the current primaries.
+        gossip.receive(&10).unwrap();
+        let mut reached: Vec<_> = gossip
+            .tiered
+            .delivery()
+            .0
+            .borrow()
+            .keys()
+            .copied()
+            .collect();
+        reached.sort();
+        assert_eq!(vec![2, 3, 5], reached);
+    }
+
+    #[test]
+    fn custom_peer_selector() {
+        let mut gossip =
+            UniformGossip::create(vec![1, 2, 3], 2, (), Network(RefCell::new(HashMap::new())))
+                .with_peer_selector(select::RoundRobinSelector::default());
+        gossip.receive(&10).unwrap();
+        gossip.receive(&11).unwrap();
+        let network = gossip.delivery.0.borrow();
+        assert_eq!(vec![10, 11], network[&1]);
+        assert_eq!(vec![10], network[&2]);
+        assert_eq!(vec![11], network[&3]);
+    }
 }
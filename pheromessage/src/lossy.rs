@@ -0,0 +1,114 @@
+//! A delivery mechanism that loses messages on purpose, to simulate an unreliable network
+//! over any other delivery mechanism (e.g. for testing how gossip copes with message loss).
+
+use std::cell::RefCell;
+
+use rand::{Rng, RngCore};
+
+use crate::{rng::ThreadLocalRng, Delivery, DeliveryReport};
+
+/// A wrapper around a delivery mechanism (`D`) that independently drops the message to every
+/// endpoint with a given probability, drawn from the random number generator `R`.
+pub struct LossyDelivery<D, R = ThreadLocalRng> {
+    /// The delivery mechanism for the messages that aren't lost.
+    inner: D,
+    /// The probability of losing a message to an endpoint.
+    loss_rate: f64,
+    rng: RefCell<R>,
+}
+
+impl<D> LossyDelivery<D> {
+    /// Create a new lossy delivery mechanism that loses messages with probability `loss_rate`
+    /// (between 0 and 1) and delivers the rest using `inner`.
+    pub fn new(inner: D, loss_rate: f64) -> LossyDelivery<D> {
+        LossyDelivery::with_rng(inner, loss_rate, ThreadLocalRng)
+    }
+}
+
+impl<D, R> LossyDelivery<D, R> {
+    /// Create a new lossy delivery mechanism like `new()`, but deciding which messages to lose
+    /// with the given `rng`.
+    pub fn with_rng(inner: D, loss_rate: f64, rng: R) -> LossyDelivery<D, R> {
+        assert!(
+            (0.0..=1.0).contains(&loss_rate),
+            "Loss rate has to be a probability"
+        );
+        LossyDelivery {
+            inner,
+            loss_rate,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    /// The delivery mechanism for the messages that aren't lost.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<M, P, D, R> Delivery<M, P> for LossyDelivery<D, R>
+where
+    D: Delivery<M, P>,
+    R: RngCore,
+{
+    type Error = D::Error;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let mut rng = self.rng.borrow_mut();
+        let delivered: Vec<_> = endpoints
+            .filter(|_| !rng.gen_bool(self.loss_rate))
+            .collect();
+        self.inner.deliver(message, delivered.into_iter())
+    }
+
+    /// Lost messages aren't failures (the sender can't tell), only failures of the inner
+    /// delivery mechanism are reported.
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let mut rng = self.rng.borrow_mut();
+        let (positions, delivered): (Vec<_>, Vec<_>) = endpoints
+            .enumerate()
+            .filter(|_| !rng.gen_bool(self.loss_rate))
+            .unzip();
+        let mut report = self.inner.deliver_each(message, delivered.into_iter())?;
+        // Map the failures back to their positions among all the endpoints.
+        for (position, _) in report.failures.iter_mut() {
+            *position = positions[*position];
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::tests::Network;
+
+    #[test]
+    fn loses_some() {
+        let lossy = LossyDelivery::with_rng(
+            Network(RefCell::new(HashMap::new())),
+            0.5,
+            StdRng::seed_from_u64(5),
+        );
+        let endpoints: Vec<usize> = (0..1000).collect();
+        lossy.deliver(&10, endpoints.iter()).unwrap();
+        let delivered = lossy.inner().0.borrow().len();
+        assert!((400..600).contains(&delivered), "{delivered}");
+    }
+}
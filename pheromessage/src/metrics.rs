@@ -0,0 +1,310 @@
+//! Built-in per-node gossip metrics (counters and histograms, gathered by observing the gossip),
+//! and rendering them in the Prometheus text exposition format.
+
+use std::fmt::{Display, Write};
+
+use crate::observe::GossipObserver;
+
+/// A histogram of observed values, counted in buckets by upper bound (like a Prometheus
+/// histogram).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// The (inclusive, increasing) upper bounds of the buckets, not including the final
+    /// unbounded one.
+    bounds: Vec<f64>,
+    /// How many values fell in every bucket (and not in any lower one), including the final
+    /// unbounded bucket.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Create a new empty histogram with the given (increasing) bucket upper `bounds`. Values
+    /// above the last bound are counted in an extra unbounded bucket.
+    pub fn new(bounds: Vec<f64>) -> Histogram {
+        assert!(
+            bounds.windows(2).all(|w| w[0] < w[1]),
+            "Histogram bounds have to be increasing"
+        );
+        Histogram {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum: 0.0,
+        }
+    }
+
+    /// Count the given value.
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// How many values were counted.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sum of all the values counted.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// The buckets as (upper bound, how many values were at most that bound) pairs, ending with
+    /// the unbounded bucket (as infinity).
+    pub fn buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        let bounds = self.bounds.iter().copied().chain([f64::INFINITY]);
+        bounds.zip(self.counts.iter().scan(0, |total, count| {
+            *total += count;
+            Some(*total)
+        }))
+    }
+}
+
+/// The metrics of a single gossip node. This is an observer, so a gossip mechanism given it
+/// with `with_observer()` keeps it up to date (wrap it in an `Arc<Mutex<_>>` to read it from
+/// another thread while the node is running).
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipMetrics {
+    /// How many messages were received from peers (including duplicates).
+    pub received: u64,
+    /// How many received messages were dropped as duplicates.
+    pub duplicates: u64,
+    /// How many copies of messages were passed on to peers.
+    pub forwarded: u64,
+    /// How many messages originated from this node.
+    pub updates: u64,
+    /// How many times passing a message on to a peer failed in the delivery mechanism.
+    pub delivery_errors: u64,
+    /// How many message IDs the store keeping track of seen messages remembered last.
+    pub seen_store_size: usize,
+    /// How many peers every message was passed on to.
+    pub fanout: Histogram,
+}
+
+impl Default for GossipMetrics {
+    fn default() -> Self {
+        GossipMetrics {
+            received: 0,
+            duplicates: 0,
+            forwarded: 0,
+            updates: 0,
+            delivery_errors: 0,
+            seen_store_size: 0,
+            fanout: Histogram::new(vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]),
+        }
+    }
+}
+
+impl GossipMetrics {
+    /// Render these metrics in the Prometheus text exposition format, labelled with the given
+    /// `node` name.
+    pub fn render(&self, node: impl Display) -> String {
+        render_prometheus([(node, self)])
+    }
+}
+
+impl<M, P> GossipObserver<M, P> for GossipMetrics {
+    fn on_first_seen(&mut self, _message: &M) {
+        self.received += 1;
+    }
+
+    fn on_duplicate(&mut self, _message: &M, _times_seen: usize) {
+        self.received += 1;
+        self.duplicates += 1;
+    }
+
+    fn on_forward<'a, T>(&mut self, _message: &M, targets: T)
+    where
+        T: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        self.forwarded += targets.len() as u64;
+        self.fanout.observe(targets.len() as f64);
+    }
+
+    fn on_update(&mut self, _message: &M) {
+        self.updates += 1;
+    }
+
+    fn on_delivery_error(&mut self, _message: &M, _target: &P) {
+        self.delivery_errors += 1;
+    }
+
+    fn on_seen_store_size(&mut self, len: usize) {
+        self.seen_store_size = len;
+    }
+}
+
+/// How to get the value of a metric out of the metrics of a node.
+type MetricValue = fn(&GossipMetrics) -> u64;
+
+/// The simple (non-histogram) metrics: name, type, help and how to get the value.
+const SIMPLE_METRICS: [(&str, &str, &str, MetricValue); 6] = [
+    (
+        "pheromessage_messages_received_total",
+        "counter",
+        "Messages received from peers (including duplicates).",
+        |m| m.received,
+    ),
+    (
+        "pheromessage_duplicates_dropped_total",
+        "counter",
+        "Received messages dropped as duplicates.",
+        |m| m.duplicates,
+    ),
+    (
+        "pheromessage_messages_forwarded_total",
+        "counter",
+        "Copies of messages passed on to peers.",
+        |m| m.forwarded,
+    ),
+    (
+        "pheromessage_updates_total",
+        "counter",
+        "Messages originating from this node.",
+        |m| m.updates,
+    ),
+    (
+        "pheromessage_delivery_errors_total",
+        "counter",
+        "Failures to pass messages on to peers.",
+        |m| m.delivery_errors,
+    ),
+    (
+        "pheromessage_seen_store_size",
+        "gauge",
+        "Message IDs remembered by the seen store.",
+        |m| m.seen_store_size as u64,
+    ),
+];
+
+const FANOUT_METRIC: &str = "pheromessage_forward_fanout";
+
+/// Render the metrics of all the given nodes (as pairs of node name and metrics) in the
+/// Prometheus text exposition format, where every node's metrics are labelled by its name.
+pub fn render_prometheus<'a, N>(nodes: impl IntoIterator<Item = (N, &'a GossipMetrics)>) -> String
+where
+    N: Display,
+{
+    let nodes: Vec<_> = nodes
+        .into_iter()
+        .map(|(node, metrics)| (escape_label(&node.to_string()), metrics))
+        .collect();
+    // Writing to a string never fails.
+    let mut out = String::new();
+    for (name, kind, help, value) in SIMPLE_METRICS {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+        for (node, metrics) in &nodes {
+            writeln!(out, "{name}{{node=\"{node}\"}} {}", value(metrics)).unwrap();
+        }
+    }
+    writeln!(
+        out,
+        "# HELP {FANOUT_METRIC} Number of peers every message was passed on to."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE {FANOUT_METRIC} histogram").unwrap();
+    for (node, metrics) in &nodes {
+        for (bound, count) in metrics.fanout.buckets() {
+            let le = if bound.is_infinite() {
+                "+Inf".to_string()
+            } else {
+                bound.to_string()
+            };
+            writeln!(
+                out,
+                "{FANOUT_METRIC}_bucket{{node=\"{node}\",le=\"{le}\"}} {count}"
+            )
+            .unwrap();
+        }
+        let (sum, count) = (metrics.fanout.sum(), metrics.fanout.count());
+        writeln!(out, "{FANOUT_METRIC}_sum{{node=\"{node}\"}} {sum}").unwrap();
+        writeln!(out, "{FANOUT_METRIC}_count{{node=\"{node}\"}} {count}").unwrap();
+    }
+    out
+}
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{tests::Network, Delivery, Gossip, UniformGossip};
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new(vec![1.0, 2.0]);
+        for value in [0.0, 1.0, 2.0, 3.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(
+            vec![(1.0, 2), (2.0, 3), (f64::INFINITY, 4)],
+            histogram.buckets().collect::<Vec<_>>()
+        );
+        assert_eq!(6.0, histogram.sum());
+    }
+
+    #[test]
+    fn scrape_uniform_gossip() {
+        let metrics = Arc::new(Mutex::new(GossipMetrics::default()));
+        let mut gossip =
+            UniformGossip::create(vec![1, 2], 2, (), Network(RefCell::new(HashMap::new())))
+                .with_observer(metrics.clone());
+        gossip.receive(&10).unwrap();
+        gossip.receive(&10).unwrap();
+        gossip.update(&11).unwrap();
+        let text = metrics.lock().unwrap().render("a\"b");
+        for line in [
+            "pheromessage_messages_received_total{node=\"a\\\"b\"} 2",
+            "pheromessage_duplicates_dropped_total{node=\"a\\\"b\"} 1",
+            "pheromessage_messages_forwarded_total{node=\"a\\\"b\"} 4",
+            "pheromessage_updates_total{node=\"a\\\"b\"} 1",
+            "pheromessage_delivery_errors_total{node=\"a\\\"b\"} 0",
+            "pheromessage_seen_store_size{node=\"a\\\"b\"} 2",
+            "pheromessage_forward_fanout_bucket{node=\"a\\\"b\",le=\"1\"} 0",
+            "pheromessage_forward_fanout_bucket{node=\"a\\\"b\",le=\"2\"} 2",
+            "pheromessage_forward_fanout_bucket{node=\"a\\\"b\",le=\"+Inf\"} 2",
+            "pheromessage_forward_fanout_count{node=\"a\\\"b\"} 2",
+            "# TYPE pheromessage_forward_fanout histogram",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} not in:\n{text}");
+        }
+    }
+
+    /// A delivery mechanism that always fails.
+    struct Broken;
+
+    impl Delivery<usize, usize> for Broken {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, _message: &usize, _endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            Err(())
+        }
+    }
+
+    #[test]
+    fn count_delivery_errors() {
+        let mut gossip = UniformGossip::create(vec![1, 2], 2, (), Broken)
+            .with_observer(GossipMetrics::default());
+        // Failing to reach peers doesn't fail the update, but every failure is counted.
+        gossip.update(&10).unwrap();
+        assert_eq!(2, gossip.observer.delivery_errors);
+    }
+}
@@ -1,22 +1,52 @@
 //! Implementation of Gossip over local `mpsc` channels where groups of gossip nodes can each share a channel.
 //! This is meant to scale local simulations to have N nodes per thread.
 
-use rand::{prelude::*, seq::index::sample};
+use rand::{prelude::*, rngs::StdRng, seq::index::sample};
 use std::{
-    hash::Hash,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeSet, BinaryHeap},
+    hash::{Hash, Hasher},
     marker::PhantomData,
-    sync::mpsc::{self, SendError},
+    sync::{
+        mpsc::{self, RecvTimeoutError, SendError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    data::GossipSet, Delivery, Gossip, Message, PreferentialGossip, SharedData, UniformGossip,
+    data::GossipSet,
+    plumtree::{PlumtreeConfig, PlumtreeGossip, PlumtreeMessage},
+    rng::derive_rng,
+    rumor::{HopCount, RumorConfig, RumorGossip},
+    seen::{SeenStore, UnboundedSeenStore},
+    select::{LatencySelector, UniformSelector},
+    tiered::{tier_for_node, TierConfig, TieredGossip},
+    zone::{ZoneConfig, ZoneGossip},
+    Delivery, DeliveryReport, Gossip, Message, PreferentialGossip, SharedData, UniformGossip,
 };
 
 /// An implementation of `Delivery` that delivers to `mpsc` receivers as shared endpoints for a group of nodes.
-pub struct Multiplex();
+/// Use `MULTIPLEX` or `Multiplex::for_node()` to get one.
+#[non_exhaustive]
+pub struct Multiplex {
+    /// The sending node, if envelopes should say where they came from.
+    from: Option<NodeGroupInfo>,
+}
+
+/// The singleton `Multiplex` that doesn't mark envelopes with their sender.
+pub const MULTIPLEX: Multiplex = Multiplex { from: None };
 
-/// The singleton `Multiplex`.
-pub const MULTIPLEX: Multiplex = Multiplex();
+impl Multiplex {
+    /// Create a delivery mechanism for the node described by `group_info`, that marks every
+    /// envelope it sends as coming from that node.
+    pub fn for_node(group_info: NodeGroupInfo) -> Multiplex {
+        Multiplex {
+            from: Some(group_info),
+        }
+    }
+}
 
 /// An envelope for a message intended for a node within a node group.
 #[derive(Clone)]
@@ -25,139 +55,1463 @@ pub struct Envelope<M> {
     pub message: M,
     /// The index of the node within the node group.
     pub node_index: usize,
+    /// The node that sent the message, if known (so it can be passed to `receive_from()`).
+    pub from: Option<NodeGroupInfo>,
 }
 
-/// An endpoint for a peer within a gossip network composed of nodes within node groups.
+/// An endpoint for a peer within a gossip network composed of nodes within node groups,
+/// identified by the group and index of the node (so two endpoints for the same node are equal).
 #[derive(Clone)]
 pub struct MultiplexEndpoint<M> {
     /// The sender for sending envelopes to the node group.
     sender: mpsc::Sender<Envelope<M>>,
+    /// The index of the node group.
+    group_index: usize,
     /// The index of the node within the node group.
     node_index: usize,
 }
 
-impl<M> Delivery<M, MultiplexEndpoint<M>> for Multiplex
+impl<M> MultiplexEndpoint<M> {
+    /// Create a new endpoint for the node described by `group_info`, where `sender`
+    /// is the sender for its node group.
+    pub fn new(
+        sender: mpsc::Sender<Envelope<M>>,
+        group_info: NodeGroupInfo,
+    ) -> MultiplexEndpoint<M> {
+        MultiplexEndpoint {
+            sender,
+            group_index: group_info.group_index,
+            node_index: group_info.node_index,
+        }
+    }
+
+    /// The group and index of the node this endpoint is for.
+    pub fn group_info(&self) -> NodeGroupInfo {
+        NodeGroupInfo {
+            group_index: self.group_index,
+            node_index: self.node_index,
+        }
+    }
+}
+
+impl<M> PartialEq for MultiplexEndpoint<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.group_info() == other.group_info()
+    }
+}
+
+impl<M> Eq for MultiplexEndpoint<M> {}
+
+impl<M> PartialEq<NodeGroupInfo> for MultiplexEndpoint<M> {
+    fn eq(&self, other: &NodeGroupInfo) -> bool {
+        self.group_info() == *other
+    }
+}
+
+impl<M> Hash for MultiplexEndpoint<M> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.group_info().hash(state);
+    }
+}
+
+impl<M> Delivery<M, MultiplexEndpoint<M>> for Multiplex
+where
+    M: Clone,
+{
+    type Error = SendError<Envelope<M>>;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a MultiplexEndpoint<M>>,
+        M: 'a,
+    {
+        self.deliver_each(message, endpoints)?.into_result()
+    }
+
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a MultiplexEndpoint<M>>,
+        M: 'a,
+    {
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.enumerate() {
+            let envelope = Envelope {
+                message: message.clone(),
+                node_index: endpoint.node_index,
+                from: self.from,
+            };
+            if let Err(e) = endpoint.sender.send(envelope) {
+                report.failures.push((position, e));
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Simulated (one way) latencies between the nodes of a local network, indexed by their global
+/// node indices (as given to `NodeGroupInfo::for_node()`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyMatrix {
+    /// `latencies[i][j]` is the latency from node `i` to node `j`.
+    latencies: Vec<Vec<Duration>>,
+}
+
+impl LatencyMatrix {
+    /// Create a matrix from its rows, where `latencies[i][j]` is the latency from node `i`
+    /// to node `j`.
+    pub fn new(latencies: Vec<Vec<Duration>>) -> LatencyMatrix {
+        assert!(
+            latencies.iter().all(|row| row.len() == latencies.len()),
+            "A latency matrix has to be square"
+        );
+        LatencyMatrix { latencies }
+    }
+
+    /// Create a matrix for `num_nodes` nodes spread across `num_regions` regions (node `i` being
+    /// in region `i % num_regions`), where the latency between nodes in the same region is
+    /// `within` and between nodes in different regions is `across`.
+    pub fn regions(
+        num_nodes: usize,
+        num_regions: usize,
+        within: Duration,
+        across: Duration,
+    ) -> LatencyMatrix {
+        LatencyMatrix::new(
+            (0..num_nodes)
+                .map(|i| {
+                    (0..num_nodes)
+                        .map(|j| {
+                            if i % num_regions == j % num_regions {
+                                within
+                            } else {
+                                across
+                            }
+                        })
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    /// The number of nodes in the matrix.
+    pub fn num_nodes(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// The latency from node `from` to node `to`.
+    pub fn latency(&self, from: usize, to: usize) -> Duration {
+        self.latencies[from][to]
+    }
+}
+
+/// An envelope held back by a `Delayer` until it's due.
+struct Delayed<M> {
+    due: Instant,
+    /// The order in which the delayer got this envelope (so envelopes due at the same time keep
+    /// their order).
+    order: u64,
+    envelope: Envelope<M>,
+    sender: mpsc::Sender<Envelope<M>>,
+}
+
+impl<M> PartialEq for Delayed<M> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.order) == (other.due, other.order)
+    }
+}
+
+impl<M> Eq for Delayed<M> {}
+
+impl<M> PartialOrd for Delayed<M> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<M> Ord for Delayed<M> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.order).cmp(&(other.due, other.order))
+    }
+}
+
+/// A handle to a background thread that holds envelopes back until they're due and then sends
+/// them on to their node groups. The thread keeps running until all the handles are dropped
+/// and all the envelopes it holds were sent.
+pub struct Delayer<M> {
+    sender: mpsc::Sender<Delayed<M>>,
+}
+
+impl<M> Clone for Delayer<M> {
+    fn clone(&self) -> Self {
+        Delayer {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<M> Delayer<M>
+where
+    M: Send + 'static,
+{
+    /// Spawn a new delayer thread.
+    pub fn spawn() -> Delayer<M> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run_delayer(receiver));
+        Delayer { sender }
+    }
+}
+
+impl<M> Delayer<M> {
+    /// Send the given envelope with `sender` after `delay`.
+    fn send_after(
+        &self,
+        envelope: Envelope<M>,
+        sender: &mpsc::Sender<Envelope<M>>,
+        delay: Duration,
+    ) -> Result<(), SendError<Envelope<M>>> {
+        let delayed = Delayed {
+            due: Instant::now() + delay,
+            order: 0,
+            envelope,
+            sender: sender.clone(),
+        };
+        self.sender
+            .send(delayed)
+            .map_err(|SendError(delayed)| SendError(delayed.envelope))
+    }
+}
+
+/// Thread function of a `Delayer`.
+fn run_delayer<M>(receiver: mpsc::Receiver<Delayed<M>>) {
+    let mut pending = BinaryHeap::new();
+    let mut received = 0;
+    loop {
+        let now = Instant::now();
+        while pending
+            .peek()
+            .is_some_and(|Reverse(delayed): &Reverse<Delayed<M>>| delayed.due <= now)
+        {
+            let Reverse(delayed) = pending.pop().unwrap();
+            // Like on a real network, nobody finds out about messages to nodes that are gone.
+            let _ = delayed.sender.send(delayed.envelope);
+        }
+        let next = match pending.peek() {
+            Some(Reverse(delayed)) => receiver.recv_timeout(delayed.due - now),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok(mut delayed) => {
+                delayed.order = received;
+                received += 1;
+                pending.push(Reverse(delayed));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    // Nothing new is coming, so just send out what's left when it's due.
+    while let Some(Reverse(delayed)) = pending.pop() {
+        thread::sleep(delayed.due.saturating_duration_since(Instant::now()));
+        let _ = delayed.sender.send(delayed.envelope);
+    }
+}
+
+/// An implementation of `Delivery` to the same endpoints as `Multiplex`, that holds every
+/// message back for the latency in a `LatencyMatrix` from the sending node to its target, to
+/// simulate a network spread across far away regions.
+pub struct DelayedMultiplex<M> {
+    /// The global index of the sending node.
+    from: usize,
+    /// The number of node groups in the network (to find the global indices of endpoints).
+    num_groups: usize,
+    matrix: Arc<LatencyMatrix>,
+    delayer: Delayer<M>,
+}
+
+impl<M> DelayedMultiplex<M> {
+    /// Create a new delivery mechanism for the node with the global index `from`, in a network
+    /// of `num_groups` node groups with the latencies in `matrix`, holding messages back
+    /// in the given `delayer`.
+    pub fn new(
+        from: usize,
+        num_groups: usize,
+        matrix: Arc<LatencyMatrix>,
+        delayer: Delayer<M>,
+    ) -> DelayedMultiplex<M> {
+        DelayedMultiplex {
+            from,
+            num_groups,
+            matrix,
+            delayer,
+        }
+    }
+}
+
+impl<M> Delivery<M, MultiplexEndpoint<M>> for DelayedMultiplex<M>
+where
+    M: Clone,
+{
+    type Error = SendError<Envelope<M>>;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a MultiplexEndpoint<M>>,
+        M: 'a,
+    {
+        self.deliver_each(message, endpoints)?.into_result()
+    }
+
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a MultiplexEndpoint<M>>,
+        M: 'a,
+    {
+        let from = NodeGroupInfo::for_node(self.num_groups, self.from);
+        for endpoint in endpoints {
+            let envelope = Envelope {
+                message: message.clone(),
+                node_index: endpoint.node_index,
+                from: Some(from),
+            };
+            let to = endpoint.group_info().global_index(self.num_groups);
+            let latency = self.matrix.latency(self.from, to);
+            // Without the delayer nothing can be delivered anymore.
+            self.delayer
+                .send_after(envelope, &endpoint.sender, latency)?;
+        }
+        Ok(DeliveryReport::default())
+    }
+}
+
+/// A representation of a uniform gossip "node group" that is a local `mpsc` receiver
+/// and the gossips for it.
+pub struct LocalGossipNodeGroup<G, S, M>
+where
+    G: Gossip<M, S>,
+{
+    /// The gossips for the nodes in this group.
+    pub gossips: Vec<G>,
+    /// The receiver for messages intended for this node group.
+    pub receiver: mpsc::Receiver<Envelope<M>>,
+    /// The sender of messages to this node group.
+    pub sender: mpsc::Sender<Envelope<M>>,
+    _s: PhantomData<S>,
+}
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using uniform gossip technique.
+/// `S` is the data every node maintains.
+pub type LocalUniformGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNodeGroup<
+    UniformGossip<MultiplexEndpoint<M>, S, Multiplex, I, L, UniformSelector<StdRng>>,
+    S,
+    M,
+>;
+
+/// A `LocalUniformGossipNodeGroup` maintaining a gossip set.
+pub type LocalUniformGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalUniformGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using preferential gossip technique.
+/// `S` is the data every node maintains.
+pub type LocalPreferentialGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> =
+    LocalGossipNodeGroup<
+        PreferentialGossip<MultiplexEndpoint<M>, S, Multiplex, I, L, UniformSelector<StdRng>>,
+        S,
+        M,
+    >;
+
+/// A `LocalPreferentialGossipNodeGroup` maintaining a gossip set.
+pub type LocalPreferentialGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalPreferentialGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using tiered gossip technique.
+/// `S` is the data every node maintains.
+pub type LocalTieredGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNodeGroup<
+    TieredGossip<MultiplexEndpoint<M>, S, Multiplex, I, L, UniformSelector<StdRng>>,
+    S,
+    M,
+>;
+
+/// A `LocalTieredGossipNodeGroup` maintaining a gossip set.
+pub type LocalTieredGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalTieredGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using zone-aware gossip.
+/// `S` is the data every node maintains.
+pub type LocalZoneGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNodeGroup<
+    ZoneGossip<MultiplexEndpoint<M>, S, Multiplex, I, L, UniformSelector<StdRng>>,
+    S,
+    M,
+>;
+
+/// A `LocalZoneGossipNodeGroup` maintaining a gossip set.
+pub type LocalZoneGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalZoneGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using Plumtree gossip,
+/// where nodes are identified by their group info.
+/// `S` is the data every node maintains.
+pub type LocalPlumtreeGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNodeGroup<
+    PlumtreeGossip<NodeGroupInfo, MultiplexEndpoint<M>, S, Multiplex, M, I, L>,
+    S,
+    M,
+>;
+
+/// A `LocalPlumtreeGossipNodeGroup` maintaining a gossip set.
+pub type LocalPlumtreeGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalPlumtreeGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using rumor mongering.
+/// `S` is the data every node maintains.
+pub type LocalRumorGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNodeGroup<
+    RumorGossip<MultiplexEndpoint<M>, S, Multiplex, I, L, UniformSelector<StdRng>, StdRng>,
+    S,
+    M,
+>;
+
+/// A `LocalRumorGossipNodeGroup` maintaining a gossip set.
+pub type LocalRumorGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalRumorGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// A representation of a gossip "node group" that is a local `mpsc` receiver using uniform
+/// gossip over simulated latencies, where nodes select peers by latency.
+/// `S` is the data every node maintains.
+pub type LocalLatencyGossipNodeGroup<S, M, I, L = UnboundedSeenStore<I>> = LocalGossipNodeGroup<
+    UniformGossip<
+        MultiplexEndpoint<M>,
+        S,
+        DelayedMultiplex<M>,
+        I,
+        L,
+        LatencySelector<MultiplexEndpoint<M>, StdRng>,
+    >,
+    S,
+    M,
+>;
+
+/// A `LocalLatencyGossipNodeGroup` maintaining a gossip set.
+pub type LocalLatencyGossipSetNodeGroup<T, M, I, L = UnboundedSeenStore<I>> =
+    LocalLatencyGossipNodeGroup<GossipSet<T>, M, I, L>;
+
+/// Information about which group a node belongs to, and its index within the group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeGroupInfo {
+    /// The index of the group the node belongs in.
+    pub group_index: usize,
+    /// The index of the node within the group.
+    pub node_index: usize,
+}
+
+impl NodeGroupInfo {
+    /// Gets the group info for a node given the total number of groups and its global index.
+    pub fn for_node(num_groups: usize, global_node_index: usize) -> NodeGroupInfo {
+        let group_index = global_node_index % num_groups;
+        let node_index = global_node_index / num_groups;
+        NodeGroupInfo {
+            group_index,
+            node_index,
+        }
+    }
+
+    /// Gets the global index of the node given the total number of groups (the inverse of
+    /// `for_node()`).
+    pub fn global_index(&self, num_groups: usize) -> usize {
+        self.node_index * num_groups + self.group_index
+    }
+}
+
+/// Creates a set of local gossip "node groups" that maintain a gossip set.
+/// Each node group can be independently maintained in its own thread.
+/// Each node can gossip with any other node in its own or other groups.
+/// `peers_per_node` is the number of peers every node knows about - if set to
+/// `num_nodes - 1` (the maximum) then every node will know about every other but
+/// that can take up a lot of memory in larger networks, so may be set to lower and
+/// each node will know of a random subset of other nodes.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn uniform_local_gossip_set<T, M>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+) -> Vec<LocalUniformGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+{
+    uniform_local_gossip_set_with_rng(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        fanout,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `uniform_local_gossip_set()`, but
+/// with the random subsets of peers, as well as the peers every node selects to gossip to,
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+pub fn uniform_local_gossip_set_with_rng<T, M, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+    rng: &mut R,
+) -> Vec<LocalUniformGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    uniform_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        fanout,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `uniform_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn uniform_local_gossip_set_with_seen_store<T, M, L, F>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+    new_seen_store: F,
+) -> Vec<LocalUniformGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    uniform_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        fanout,
+        new_seen_store,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `uniform_local_gossip_set_with_seen_store()`, but
+/// with the random subsets of peers, as well as the peers every node selects to gossip to,
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+pub fn uniform_local_gossip_set_with_seen_store_and_rng<T, M, L, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalUniformGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    uniform_local_gossip(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        fanout,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `uniform_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn uniform_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalUniformGossipNodeGroup<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    // Create the senders and receivers for the node groups.
+    let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> of Vec<>s with all the gossips
+    let nodes_per_group_max = (num_nodes / num_groups) + 1;
+    let mut gossips: Vec<_> = (0..num_groups)
+        .map(|_| Vec::with_capacity(nodes_per_group_max))
+        .collect();
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node
+        let peers: Vec<_> = sample(rng, num_nodes - 1, peers_per_node)
+            .iter()
+            .map(|j| if j < i { j } else { j + 1 })
+            .map(|j| {
+                let group_info = NodeGroupInfo::for_node(num_groups, j);
+                MultiplexEndpoint::new(channels[group_info.group_index].0.clone(), group_info)
+            })
+            .collect();
+        // Add the node
+        let group_info = NodeGroupInfo::for_node(num_groups, i);
+        gossips[group_info.group_index].push(
+            UniformGossip::create_with_seen_store(
+                peers,
+                fanout,
+                data,
+                Multiplex::for_node(group_info),
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
+            gossips,
+            receiver,
+            sender,
+            _s: PhantomData,
+        })
+        .collect()
+}
+
+/// Creates a set of local gossip "node groups" that maintain a gossip set.
+/// Each node group can be independently maintained in its own thread.
+/// Each node can gossip with any other node in its own or other groups.
+/// The nodes spread updates by rumor mongering as configured by `config`.
+/// `peers_per_node` is the number of peers every node knows about - if set to
+/// `num_nodes - 1` (the maximum) then every node will know about every other but
+/// that can take up a lot of memory in larger networks, so may be set to lower and
+/// each node will know of a random subset of other nodes.
+/// The random subsets of peers, as well as the peers every node selects to gossip to and the
+/// coins it flips, are all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn rumor_local_gossip_set<T, M, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    config: RumorConfig,
+    rng: &mut R,
+) -> Vec<LocalRumorGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + HopCount,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    R: Rng + ?Sized,
+{
+    rumor_local_gossip_set_with_seen_store(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        config,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `rumor_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn rumor_local_gossip_set_with_seen_store<T, M, L, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    config: RumorConfig,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalRumorGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + HopCount,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I> + Clone,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    rumor_local_gossip(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        config,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `rumor_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn rumor_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    config: RumorConfig,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalRumorGossipNodeGroup<S, M, M::I, L>>
+where
+    M: Clone + HopCount,
+    S: SharedData<M>,
+    L: SeenStore<M::I> + Clone,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    // Create the senders and receivers for the node groups.
+    let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> of Vec<>s with all the gossips
+    let nodes_per_group_max = (num_nodes / num_groups) + 1;
+    let mut gossips: Vec<_> = (0..num_groups)
+        .map(|_| Vec::with_capacity(nodes_per_group_max))
+        .collect();
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node
+        let peers: Vec<_> = sample(rng, num_nodes - 1, peers_per_node)
+            .iter()
+            .map(|j| if j < i { j } else { j + 1 })
+            .map(|j| {
+                let group_info = NodeGroupInfo::for_node(num_groups, j);
+                MultiplexEndpoint::new(channels[group_info.group_index].0.clone(), group_info)
+            })
+            .collect();
+        // Add the node
+        let group_info = NodeGroupInfo::for_node(num_groups, i);
+        gossips[group_info.group_index].push(
+            RumorGossip::create_with_seen_store(
+                peers,
+                config,
+                data,
+                Multiplex::for_node(group_info),
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng)))
+            .with_rng(derive_rng(rng)),
+        );
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
+            gossips,
+            receiver,
+            sender,
+            _s: PhantomData,
+        })
+        .collect()
+}
+
+/// Creates a set of local gossip "node groups" that maintain a gossip set.
+/// Each node group can be independently maintained in its own thread.
+/// Each node can gossip with any other node in its own or other groups.
+/// The nodes use Plumtree gossip as configured by `config`, identified to each other by their
+/// group info (so `tick()` has to be called on every node regularly).
+/// `peers_per_node` is the number of peers every node picks to know about - if set to
+/// `num_nodes - 1` (the maximum) then every node will know about every other but
+/// that can take up a lot of memory in larger networks, so may be set to lower and
+/// each node will know of a random subset of other nodes. Since links in Plumtree go both
+/// ways, every node also knows about the nodes that picked it.
+/// The random subsets of peers are drawn from `rng`, so a seeded `rng` creates a reproducible
+/// network.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn plumtree_local_gossip_set<T, M, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    config: PlumtreeConfig,
+    rng: &mut R,
+) -> Vec<LocalPlumtreeGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + PlumtreeMessage<NodeGroupInfo>,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    R: Rng + ?Sized,
+{
+    plumtree_local_gossip_set_with_seen_store(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        config,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `plumtree_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn plumtree_local_gossip_set_with_seen_store<T, M, L, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    config: PlumtreeConfig,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalPlumtreeGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + PlumtreeMessage<NodeGroupInfo>,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    plumtree_local_gossip(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        config,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `plumtree_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn plumtree_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    config: PlumtreeConfig,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalPlumtreeGossipNodeGroup<S, M, M::I, L>>
+where
+    M: Clone + PlumtreeMessage<NodeGroupInfo>,
+    S: SharedData<M>,
+    <M as Message>::I: Hash + Eq + Clone,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    // Create the senders and receivers for the node groups.
+    let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
+    // Pick the peers for every node, and make every link go both ways
+    let mut peer_indices = vec![BTreeSet::new(); num_nodes];
+    for i in 0..num_nodes {
+        for j in sample(rng, num_nodes - 1, peers_per_node).iter() {
+            let j = if j < i { j } else { j + 1 };
+            peer_indices[i].insert(j);
+            peer_indices[j].insert(i);
+        }
+    }
+    // Then create a Vec<> of Vec<>s with all the gossips
+    let nodes_per_group_max = (num_nodes / num_groups) + 1;
+    let mut gossips: Vec<_> = (0..num_groups)
+        .map(|_| Vec::with_capacity(nodes_per_group_max))
+        .collect();
+    for (i, peer_indices) in peer_indices.into_iter().enumerate() {
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node
+        let peers: Vec<_> = peer_indices
+            .into_iter()
+            .map(|j| {
+                let group_info = NodeGroupInfo::for_node(num_groups, j);
+                let sender = channels[group_info.group_index].0.clone();
+                (group_info, MultiplexEndpoint::new(sender, group_info))
+            })
+            .collect();
+        // Add the node
+        let group_info = NodeGroupInfo::for_node(num_groups, i);
+        gossips[group_info.group_index].push(PlumtreeGossip::create_with_seen_store(
+            group_info,
+            peers,
+            config,
+            data,
+            Multiplex::for_node(group_info),
+            new_seen_store(),
+        ));
+    }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
+            gossips,
+            receiver,
+            sender,
+            _s: PhantomData,
+        })
+        .collect()
+}
+
+/// Creates a set of local gossip "node groups" that maintain a gossip set.
+/// Each node group can be independently maintained in its own thread.
+/// Each node can gossip with any other node in its own or other groups.
+/// The first `num_primaries` nodes will be the primary nodes that preferentially
+/// get first word of any update, with the rest being secondaries.
+/// `peers_per_node` is the number of peers every node knows about - if set to
+/// `num_nodes - 1` (the maximum) then every node will know about every other but
+/// that can take up a lot of memory in larger networks, so may be set to lower and
+/// each node will know of a random subset of other nodes.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn preferential_local_gossip_set<T, M>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    num_primaries: usize,
+    fanout: usize,
+) -> Vec<LocalPreferentialGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+{
+    preferential_local_gossip_set_with_rng(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        num_primaries,
+        fanout,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `preferential_local_gossip_set()`, but
+/// with the random subsets of peers, as well as the peers every node selects to gossip to,
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+pub fn preferential_local_gossip_set_with_rng<T, M, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    num_primaries: usize,
+    fanout: usize,
+    rng: &mut R,
+) -> Vec<LocalPreferentialGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    preferential_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        num_primaries,
+        fanout,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `preferential_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn preferential_local_gossip_set_with_seen_store<T, M, L, F>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    num_primaries: usize,
+    fanout: usize,
+    new_seen_store: F,
+) -> Vec<LocalPreferentialGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    preferential_local_gossip_set_with_seen_store_and_rng(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        num_primaries,
+        fanout,
+        new_seen_store,
+        &mut thread_rng(),
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `preferential_local_gossip_set_with_seen_store()`, but
+/// with the random subsets of peers, as well as the peers every node selects to gossip to,
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+pub fn preferential_local_gossip_set_with_seen_store_and_rng<T, M, L, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    num_primaries: usize,
+    fanout: usize,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalPreferentialGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    preferential_local_gossip(
+        num_nodes,
+        num_groups,
+        peers_per_node,
+        num_primaries,
+        fanout,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `preferential_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+#[allow(clippy::too_many_arguments)]
+pub fn preferential_local_gossip<S, M, L, N, F, R>(
+    num_nodes: usize,
+    num_groups: usize,
+    peers_per_node: usize,
+    num_primaries: usize,
+    fanout: usize,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalPreferentialGossipNodeGroup<S, M, M::I, L>>
 where
-    M: Clone,
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
 {
-    type Error = SendError<Envelope<M>>;
-
-    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
-    where
-        I: ExactSizeIterator<Item = &'a MultiplexEndpoint<M>>,
-        M: 'a,
-    {
-        for endpoint in endpoints {
-            endpoint.sender.send(Envelope {
-                message: message.clone(),
-                node_index: endpoint.node_index,
-            })?;
-        }
-        Ok(())
+    // Create the senders and receivers for the node groups.
+    let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> of Vec<>s with all the gossips
+    let nodes_per_group_max = (num_nodes / num_groups) + 1;
+    let mut gossips: Vec<_> = (0..num_groups)
+        .map(|_| Vec::with_capacity(nodes_per_group_max))
+        .collect();
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node
+        let primary = i < num_primaries;
+        let mut primaries = Vec::with_capacity(peers_per_node);
+        let mut secondaries = Vec::with_capacity(peers_per_node);
+        sample(rng, num_nodes - 1, peers_per_node)
+            .iter()
+            .map(|j| if j < i { j } else { j + 1 })
+            .for_each(|j| {
+                let group_info = NodeGroupInfo::for_node(num_groups, j);
+                let endpoint =
+                    MultiplexEndpoint::new(channels[group_info.group_index].0.clone(), group_info);
+                if j < num_primaries {
+                    primaries.push(endpoint);
+                } else {
+                    secondaries.push(endpoint);
+                }
+            });
+        // Add the node
+        let group_info = NodeGroupInfo::for_node(num_groups, i);
+        gossips[group_info.group_index].push(
+            PreferentialGossip::create_with_seen_store(
+                primaries,
+                secondaries,
+                primary,
+                fanout,
+                data,
+                Multiplex::for_node(group_info),
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
     }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
+            gossips,
+            receiver,
+            sender,
+            _s: PhantomData,
+        })
+        .collect()
 }
 
-/// A representation of a uniform gossip "node group" that is a local `mpsc` receiver
-/// and the gossips for it.
-pub struct LocalGossipNodeGroup<G, S, M>
+/// Creates a set of local gossip "node groups" that maintain a gossip set.
+/// Each node group can be independently maintained in its own thread.
+/// Each node can gossip with any other node in its own or other groups.
+/// The nodes are divided into tiers, the first `tier_sizes[0]` nodes being in
+/// the first tier, the next `tier_sizes[1]` nodes being in the second and so on, and
+/// every tier is configured by the corresponding entry in `configs`.
+/// `peers_per_node` is the number of peers every node knows about - if set to
+/// `num_nodes - 1` (the maximum) then every node will know about every other but
+/// that can take up a lot of memory in larger networks, so may be set to lower and
+/// each node will know of a random subset of other nodes.
+/// `T` is the type of element in the set, and `M` is the type of messages exchanged
+/// in the gossip.
+pub fn tiered_local_gossip_set<T, M>(
+    num_groups: usize,
+    peers_per_node: usize,
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+) -> Vec<LocalTieredGossipSetNodeGroup<T, M, M::I>>
 where
-    G: Gossip<M, S>,
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
 {
-    /// The gossips for the nodes in this group.
-    pub gossips: Vec<G>,
-    /// The receiver for messages intended for this node group.
-    pub receiver: mpsc::Receiver<Envelope<M>>,
-    /// The sender of messages to this node group.
-    pub sender: mpsc::Sender<Envelope<M>>,
-    _s: PhantomData<S>,
+    tiered_local_gossip_set_with_rng(
+        num_groups,
+        peers_per_node,
+        tier_sizes,
+        configs,
+        &mut thread_rng(),
+    )
 }
 
-/// A representation of a gossip "node group" that is a local `mpsc` receiver using uniform gossip technique.
-pub type LocalUniformGossipSetNodeGroup<T, M, I> = LocalGossipNodeGroup<
-    UniformGossip<MultiplexEndpoint<M>, GossipSet<T>, Multiplex, I>,
-    GossipSet<T>,
-    M,
->;
+/// Creates a set of local gossip "node groups" like `tiered_local_gossip_set()`, but
+/// with the random subsets of peers, as well as the peers every node selects to gossip to,
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+pub fn tiered_local_gossip_set_with_rng<T, M, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    rng: &mut R,
+) -> Vec<LocalTieredGossipSetNodeGroup<T, M, M::I>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    tiered_local_gossip_set_with_seen_store_and_rng(
+        num_groups,
+        peers_per_node,
+        tier_sizes,
+        configs,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
 
-/// A representation of a gossip "node group" that is a local `mpsc` receiver using preferential gossip technique.
-pub type LocalPreferentialGossipSetNodeGroup<T, M, I> = LocalGossipNodeGroup<
-    PreferentialGossip<MultiplexEndpoint<M>, GossipSet<T>, Multiplex, I>,
-    GossipSet<T>,
-    M,
->;
+/// Creates a set of local gossip "node groups" like `tiered_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn tiered_local_gossip_set_with_seen_store<T, M, L, F>(
+    num_groups: usize,
+    peers_per_node: usize,
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    new_seen_store: F,
+) -> Vec<LocalTieredGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+{
+    tiered_local_gossip_set_with_seen_store_and_rng(
+        num_groups,
+        peers_per_node,
+        tier_sizes,
+        configs,
+        new_seen_store,
+        &mut thread_rng(),
+    )
+}
 
-/// Information about which group a node belongs to, and its index within the group.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct NodeGroupInfo {
-    /// The index of the group the node belongs in.
-    pub group_index: usize,
-    /// The index of the node within the group.
-    pub node_index: usize,
+/// Creates a set of local gossip "node groups" like `tiered_local_gossip_set_with_seen_store()`, but
+/// with the random subsets of peers, as well as the peers every node selects to gossip to,
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
+pub fn tiered_local_gossip_set_with_seen_store_and_rng<T, M, L, F, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalTieredGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    tiered_local_gossip(
+        num_groups,
+        peers_per_node,
+        tier_sizes,
+        configs,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
 }
 
-impl NodeGroupInfo {
-    /// Gets the group info for a node given the total number of groups and its global index.
-    pub fn for_node(num_groups: usize, global_node_index: usize) -> NodeGroupInfo {
-        let group_index = global_node_index % num_groups;
-        let node_index = global_node_index / num_groups;
-        NodeGroupInfo {
-            group_index,
-            node_index,
-        }
+/// Creates a set of local gossip "node groups" like `tiered_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+pub fn tiered_local_gossip<S, M, L, N, F, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    tier_sizes: &[usize],
+    configs: &[TierConfig],
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalTieredGossipNodeGroup<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    let num_nodes = tier_sizes.iter().sum();
+    // Create the senders and receivers for the node groups.
+    let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
+    // First create a Vec<> of Vec<>s with all the gossips
+    let nodes_per_group_max = (num_nodes / num_groups) + 1;
+    let mut gossips: Vec<_> = (0..num_groups)
+        .map(|_| Vec::with_capacity(nodes_per_group_max))
+        .collect();
+    for i in 0..num_nodes {
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node in every tier
+        let mut tiers: Vec<_> = tier_sizes.iter().map(|_| Vec::new()).collect();
+        sample(rng, num_nodes - 1, peers_per_node)
+            .iter()
+            .map(|j| if j < i { j } else { j + 1 })
+            .for_each(|j| {
+                let group_info = NodeGroupInfo::for_node(num_groups, j);
+                tiers[tier_for_node(tier_sizes, j)].push(MultiplexEndpoint::new(
+                    channels[group_info.group_index].0.clone(),
+                    group_info,
+                ));
+            });
+        // Add the node
+        let group_info = NodeGroupInfo::for_node(num_groups, i);
+        gossips[group_info.group_index].push(
+            TieredGossip::create_with_seen_store(
+                tiers,
+                configs.to_vec(),
+                tier_for_node(tier_sizes, i),
+                data,
+                Multiplex::for_node(group_info),
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
     }
+    // Then add the senders and receivers to create the network
+    gossips
+        .into_iter()
+        .zip(channels)
+        .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
+            gossips,
+            receiver,
+            sender,
+            _s: PhantomData,
+        })
+        .collect()
 }
 
 /// Creates a set of local gossip "node groups" that maintain a gossip set.
 /// Each node group can be independently maintained in its own thread.
 /// Each node can gossip with any other node in its own or other groups.
-/// `peers_per_node` is the number of peers every node knows about - if set to
-/// `num_nodes - 1` (the maximum) then every node will know about every other but
-/// that can take up a lot of memory in larger networks, so may be set to lower and
-/// each node will know of a random subset of other nodes.
+/// The nodes are divided into zones, the first `zone_sizes[0]` nodes being in the first zone,
+/// the next `zone_sizes[1]` nodes being in the second and so on, and the first
+/// `gateways_per_zone` nodes of every zone are its gateways (which know about all the gateways
+/// of the other zones). The nodes gossip as configured by `config`.
+/// `peers_per_node` is the number of peers every node knows about within its own zone - if set
+/// to the size of the zone minus one (or more) then every node will know about every other node
+/// in its zone, otherwise each node will know of a random subset of them.
+/// There has to be at least one zone, every zone has to have nodes and `gateways_per_zone` has
+/// to be at least `1`, or the zones couldn't reach each other.
+/// The random subsets of peers, as well as the peers every node selects to gossip to, are
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
 /// `T` is the type of element in the set, and `M` is the type of messages exchanged
 /// in the gossip.
-pub fn uniform_local_gossip_set<T, M>(
-    num_nodes: usize,
+pub fn zone_local_gossip_set<T, M, R>(
     num_groups: usize,
     peers_per_node: usize,
-    fanout: usize,
-) -> Vec<LocalUniformGossipSetNodeGroup<T, M, M::I>>
+    zone_sizes: &[usize],
+    gateways_per_zone: usize,
+    config: ZoneConfig,
+    rng: &mut R,
+) -> Vec<LocalZoneGossipSetNodeGroup<T, M, M::I>>
 where
     M: Clone + Message,
     GossipSet<T>: SharedData<M>,
     <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    zone_local_gossip_set_with_seen_store(
+        num_groups,
+        peers_per_node,
+        zone_sizes,
+        gateways_per_zone,
+        config,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `zone_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn zone_local_gossip_set_with_seen_store<T, M, L, F, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    zone_sizes: &[usize],
+    gateways_per_zone: usize,
+    config: ZoneConfig,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalZoneGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    zone_local_gossip(
+        num_groups,
+        peers_per_node,
+        zone_sizes,
+        gateways_per_zone,
+        config,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `zone_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+#[allow(clippy::too_many_arguments)]
+pub fn zone_local_gossip<S, M, L, N, F, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    zone_sizes: &[usize],
+    gateways_per_zone: usize,
+    config: ZoneConfig,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalZoneGossipNodeGroup<S, M, M::I, L>>
+where
+    M: Clone + Message,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
 {
+    assert!(!zone_sizes.is_empty(), "There has to be at least one zone");
+    assert!(
+        zone_sizes.iter().all(|&size| size > 0),
+        "Every zone has to have nodes"
+    );
+    assert!(gateways_per_zone > 0, "Every zone needs a gateway");
+    let num_nodes = zone_sizes.iter().sum();
     // Create the senders and receivers for the node groups.
     let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
+    let endpoint = |j: usize| {
+        let group_info = NodeGroupInfo::for_node(num_groups, j);
+        MultiplexEndpoint::new(channels[group_info.group_index].0.clone(), group_info)
+    };
+    // The global indices where every zone starts, and the gateways of every zone.
+    let zone_starts: Vec<usize> = zone_sizes
+        .iter()
+        .scan(0, |start, size| {
+            *start += size;
+            Some(*start - size)
+        })
+        .collect();
+    let gateways: Vec<Vec<usize>> = zone_starts
+        .iter()
+        .zip(zone_sizes)
+        .map(|(&start, &size)| (start..start + gateways_per_zone.min(size)).collect())
+        .collect();
     // First create a Vec<> of Vec<>s with all the gossips
     let nodes_per_group_max = (num_nodes / num_groups) + 1;
     let mut gossips: Vec<_> = (0..num_groups)
         .map(|_| Vec::with_capacity(nodes_per_group_max))
         .collect();
-    let mut rng = thread_rng();
     for i in 0..num_nodes {
-        // Create an empty set
-        let data = GossipSet::default();
-        // Create the set of peers for the node
-        let peers: Vec<_> = sample(&mut rng, num_nodes - 1, peers_per_node)
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node within its zone
+        let zone = tier_for_node(zone_sizes, i);
+        let (start, size) = (zone_starts[zone], zone_sizes[zone]);
+        let zone_peers: Vec<_> = sample(rng, size - 1, peers_per_node.min(size - 1))
             .iter()
-            .map(|j| if j < i { j } else { j + 1 })
-            .map(|j| {
-                let group_info = NodeGroupInfo::for_node(num_groups, j);
-                MultiplexEndpoint {
-                    sender: channels[group_info.group_index].0.clone(),
-                    node_index: group_info.node_index,
-                }
-            })
+            .map(|j| start + if start + j < i { j } else { j + 1 })
+            .map(endpoint)
             .collect();
+        // Gateways also know the gateways of all the other zones
+        let remote_gateways: Vec<_> = if gateways[zone].contains(&i) {
+            gateways
+                .iter()
+                .enumerate()
+                .filter(|&(other, _)| other != zone)
+                .flat_map(|(_, g)| g.iter().copied())
+                .map(endpoint)
+                .collect()
+        } else {
+            Vec::new()
+        };
         // Add the node
         let group_info = NodeGroupInfo::for_node(num_groups, i);
-        gossips[group_info.group_index].push(UniformGossip::create(peers, fanout, data, MULTIPLEX));
+        gossips[group_info.group_index].push(
+            ZoneGossip::create_with_seen_store(
+                zone_peers,
+                remote_gateways,
+                config,
+                data,
+                Multiplex::for_node(group_info),
+                new_seen_store(),
+            )
+            .with_peer_selector(UniformSelector::with_rng(derive_rng(rng))),
+        );
     }
     // Then add the senders and receivers to create the network
     gossips
         .into_iter()
-        .zip(channels.into_iter())
+        .zip(channels)
         .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
             gossips,
             receiver,
@@ -170,72 +1524,133 @@ where
 /// Creates a set of local gossip "node groups" that maintain a gossip set.
 /// Each node group can be independently maintained in its own thread.
 /// Each node can gossip with any other node in its own or other groups.
-/// The first `num_primaries` nodes will be the primary nodes that preferentially
-/// get first word of any update, with the rest being secondaries.
+/// Every message is held back for the latency in `matrix` between its sender and target (which
+/// also decides the number of nodes), and every node selects `near_fraction` of the peers it
+/// gossips to from its nearest peers and the rest at random from the farther ones (see
+/// `LatencySelector`).
 /// `peers_per_node` is the number of peers every node knows about - if set to
 /// `num_nodes - 1` (the maximum) then every node will know about every other but
 /// that can take up a lot of memory in larger networks, so may be set to lower and
 /// each node will know of a random subset of other nodes.
+/// The random subsets of peers, as well as the peers every node selects to gossip to, are
+/// all drawn from `rng`, so a seeded `rng` creates a reproducible network.
 /// `T` is the type of element in the set, and `M` is the type of messages exchanged
 /// in the gossip.
-pub fn preferential_local_gossip_set<T, M>(
-    num_nodes: usize,
+pub fn latency_local_gossip_set<T, M, R>(
     num_groups: usize,
     peers_per_node: usize,
-    num_primaries: usize,
     fanout: usize,
-) -> Vec<LocalPreferentialGossipSetNodeGroup<T, M, M::I>>
+    matrix: LatencyMatrix,
+    near_fraction: f64,
+    rng: &mut R,
+) -> Vec<LocalLatencyGossipSetNodeGroup<T, M, M::I>>
 where
-    M: Clone + Message,
+    M: Clone + Message + Send + 'static,
     GossipSet<T>: SharedData<M>,
     <M as Message>::I: Hash + Eq,
+    R: Rng + ?Sized,
+{
+    latency_local_gossip_set_with_seen_store(
+        num_groups,
+        peers_per_node,
+        fanout,
+        matrix,
+        near_fraction,
+        UnboundedSeenStore::default,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `latency_local_gossip_set()`, but
+/// with every node keeping track of seen messages in a store created by `new_seen_store`.
+pub fn latency_local_gossip_set_with_seen_store<T, M, L, F, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+    matrix: LatencyMatrix,
+    near_fraction: f64,
+    new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalLatencyGossipSetNodeGroup<T, M, M::I, L>>
+where
+    M: Clone + Message + Send + 'static,
+    GossipSet<T>: SharedData<M>,
+    L: SeenStore<M::I>,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
+{
+    latency_local_gossip(
+        num_groups,
+        peers_per_node,
+        fanout,
+        matrix,
+        near_fraction,
+        GossipSet::default,
+        new_seen_store,
+        rng,
+    )
+}
+
+/// Creates a set of local gossip "node groups" like `latency_local_gossip_set_with_seen_store()`,
+/// but with every node maintaining the data created by `new_data` instead of a gossip set.
+/// `S` is the type of the data, and `M` is the type of messages exchanged in the gossip.
+#[allow(clippy::too_many_arguments)]
+pub fn latency_local_gossip<S, M, L, N, F, R>(
+    num_groups: usize,
+    peers_per_node: usize,
+    fanout: usize,
+    matrix: LatencyMatrix,
+    near_fraction: f64,
+    mut new_data: N,
+    mut new_seen_store: F,
+    rng: &mut R,
+) -> Vec<LocalLatencyGossipNodeGroup<S, M, M::I, L>>
+where
+    M: Clone + Message + Send + 'static,
+    S: SharedData<M>,
+    L: SeenStore<M::I>,
+    N: FnMut() -> S,
+    F: FnMut() -> L,
+    R: Rng + ?Sized,
 {
+    let num_nodes = matrix.num_nodes();
+    let matrix = Arc::new(matrix);
+    let delayer = Delayer::spawn();
     // Create the senders and receivers for the node groups.
-    let channels: Vec<_> = (0..num_nodes).map(|_| mpsc::channel()).collect();
-    // First create a Vec<> with all the gossips
+    let channels: Vec<_> = (0..num_groups).map(|_| mpsc::channel()).collect();
     // First create a Vec<> of Vec<>s with all the gossips
     let nodes_per_group_max = (num_nodes / num_groups) + 1;
     let mut gossips: Vec<_> = (0..num_groups)
         .map(|_| Vec::with_capacity(nodes_per_group_max))
         .collect();
-    let mut rng = thread_rng();
     for i in 0..num_nodes {
-        // Create an empty set
-        let data = GossipSet::default();
-        // Create the set of peers for the node
-        let primary = i < num_primaries;
-        let mut primaries = Vec::with_capacity(peers_per_node);
-        let mut secondaries = Vec::with_capacity(peers_per_node);
-        sample(&mut rng, num_nodes - 1, peers_per_node)
+        // Create the data
+        let data = new_data();
+        // Create the set of peers for the node, and let it know how far they are
+        let mut selector = LatencySelector::with_rng(near_fraction, derive_rng(rng));
+        let peers: Vec<_> = sample(rng, num_nodes - 1, peers_per_node)
             .iter()
             .map(|j| if j < i { j } else { j + 1 })
-            .for_each(|j| {
+            .map(|j| {
                 let group_info = NodeGroupInfo::for_node(num_groups, j);
-                let endpoint = MultiplexEndpoint {
-                    sender: channels[group_info.group_index].0.clone(),
-                    node_index: group_info.node_index,
-                };
-                if j < num_primaries {
-                    primaries.push(endpoint);
-                } else {
-                    secondaries.push(endpoint);
-                }
-            });
+                let endpoint =
+                    MultiplexEndpoint::new(channels[group_info.group_index].0.clone(), group_info);
+                selector.set_latency(endpoint.clone(), matrix.latency(i, j));
+                endpoint
+            })
+            .collect();
         // Add the node
         let group_info = NodeGroupInfo::for_node(num_groups, i);
-        gossips[group_info.group_index].push(PreferentialGossip::create(
-            primaries,
-            secondaries,
-            primary,
-            fanout,
-            data,
-            MULTIPLEX,
-        ));
+        let delivery = DelayedMultiplex::new(i, num_groups, matrix.clone(), delayer.clone());
+        gossips[group_info.group_index].push(
+            UniformGossip::create_with_seen_store(peers, fanout, data, delivery, new_seen_store())
+                .with_peer_selector(selector),
+        );
     }
     // Then add the senders and receivers to create the network
     gossips
         .into_iter()
-        .zip(channels.into_iter())
+        .zip(channels)
         .map(|(gossips, (sender, receiver))| LocalGossipNodeGroup {
             gossips,
             receiver,
@@ -256,13 +1671,14 @@ mod tests {
         time::{Duration, Instant},
     };
 
-    use crate::{data::GossipSetMessage, Gossip};
+    use crate::{data::GossipSetMessage, Gossip, ReceiveFrom};
 
     use super::*;
     use rayon::{prelude::*, ThreadPoolBuilder};
 
     /// End-to-end test of a local gossip network.
     #[test]
+    #[allow(clippy::redundant_closure, clippy::manual_range_contains)]
     fn local_network() {
         let num_nodes = 12;
         let num_groups = 5;
@@ -358,4 +1774,141 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn delayed_delivery() {
+        let matrix = LatencyMatrix::regions(3, 2, Duration::ZERO, Duration::from_millis(50));
+        let (sender, receiver) = mpsc::channel();
+        let endpoints: Vec<_> = (1..3)
+            .map(|j| MultiplexEndpoint::new(sender.clone(), NodeGroupInfo::for_node(1, j)))
+            .collect();
+        let delivery = DelayedMultiplex::new(0, 1, Arc::new(matrix), Delayer::spawn());
+        delivery.deliver(&"hi", endpoints.iter()).unwrap();
+        // Node 2 is in the same region so it gets the message right away, node 1 only later.
+        let near = receiver.recv_timeout(Duration::from_millis(20)).unwrap();
+        assert_eq!(2, near.node_index);
+        assert!(receiver.recv_timeout(Duration::from_millis(10)).is_err());
+        let far = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(1, far.node_index);
+    }
+
+    /// A small network over simulated latencies converges.
+    #[test]
+    fn latency_network() {
+        let num_nodes = 8;
+        let matrix = LatencyMatrix::regions(
+            num_nodes,
+            2,
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+        );
+        let mut groups = latency_local_gossip_set(
+            3,
+            num_nodes - 1,
+            4,
+            matrix,
+            0.5,
+            &mut StdRng::seed_from_u64(7),
+        );
+        converge(&mut groups);
+    }
+
+    /// A small network of zones converges.
+    #[test]
+    fn zone_network() {
+        let config = ZoneConfig {
+            fanout: 3,
+            gateway_fanout: 2,
+        };
+        let mut groups =
+            zone_local_gossip_set(2, 3, &[4, 4, 4], 1, config, &mut StdRng::seed_from_u64(7));
+        // Only the first node of every zone is a gateway.
+        let gateways: Vec<_> = groups
+            .iter()
+            .flat_map(|g| &g.gossips)
+            .map(|g| g.is_gateway())
+            .collect();
+        assert_eq!(3, gateways.iter().filter(|&&g| g).count());
+        assert!(groups[0].gossips[0].is_gateway());
+        assert_eq!(3, groups[0].gossips[0].zone_peers().len());
+        assert_eq!(2, groups[0].gossips[0].remote_gateways().len());
+        converge(&mut groups);
+    }
+
+    #[test]
+    #[should_panic(expected = "Every zone needs a gateway")]
+    fn zones_without_gateways() {
+        let config = ZoneConfig {
+            fanout: 3,
+            gateway_fanout: 2,
+        };
+        zone_local_gossip_set::<usize, GossipSetMessage<usize>, _>(
+            2,
+            3,
+            &[4, 4],
+            0,
+            config,
+            &mut StdRng::seed_from_u64(7),
+        );
+    }
+
+    /// Envelopes say which node sent them, and receiving from that node doesn't echo back to it.
+    #[test]
+    fn envelopes_carry_sender() {
+        let mut groups = uniform_local_gossip_set_with_rng::<usize, GossipSetMessage<usize>, _>(
+            3,
+            1,
+            2,
+            2,
+            &mut StdRng::seed_from_u64(7),
+        );
+        let group = &mut groups[0];
+        group.gossips[0].update(&GossipSetMessage::add(1)).unwrap();
+        let envelopes: Vec<_> = group.receiver.try_iter().collect();
+        assert_eq!(2, envelopes.len());
+        let first = NodeGroupInfo::for_node(1, 0);
+        assert!(envelopes.iter().all(|e| e.from == Some(first)));
+        // Node 1 only passes it on to node 2.
+        group.gossips[1]
+            .receive_from(&envelopes[0].message, &first)
+            .unwrap();
+        let envelopes: Vec<_> = group.receiver.try_iter().collect();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(2, envelopes[0].node_index);
+        assert_eq!(Some(NodeGroupInfo::for_node(1, 1)), envelopes[0].from);
+    }
+
+    /// Add the numbers 0..10 to the sets of the first nodes in the given groups, then process
+    /// all the messages in a single thread until every node has them all (passing the sender
+    /// of every envelope along).
+    fn converge<G>(
+        groups: &mut [LocalGossipNodeGroup<G, GossipSet<usize>, GossipSetMessage<usize>>],
+    ) where
+        G: ReceiveFrom<GossipSetMessage<usize>, GossipSet<usize>, NodeGroupInfo>,
+        G::Error: std::fmt::Debug,
+    {
+        let num_groups = groups.len();
+        for i in 0..10 {
+            let group = &mut groups[i % num_groups];
+            group.gossips[0].update(&GossipSetMessage::add(i)).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while groups
+            .iter()
+            .flat_map(|g| &g.gossips)
+            .any(|g| (0..10).any(|i| !g.data().is_present(&i)))
+        {
+            assert!(Instant::now() < deadline, "The network didn't converge");
+            for group in groups.iter_mut() {
+                while let Ok(envelope) = group.receiver.recv_timeout(Duration::from_millis(1)) {
+                    let gossip = &mut group.gossips[envelope.node_index];
+                    match &envelope.from {
+                        Some(from) => gossip.receive_from(&envelope.message, from),
+                        None => gossip.receive(&envelope.message),
+                    }
+                    .unwrap();
+                }
+            }
+        }
+    }
 }
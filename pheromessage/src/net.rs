@@ -2,7 +2,7 @@
 
 use std::net::{SocketAddr, UdpSocket};
 
-use crate::Delivery;
+use crate::{Delivery, DeliveryReport};
 
 /// A converter for messages (of type `M`) to raw bytes that can be sent over a network.
 pub trait ToBytes<M> {
@@ -50,16 +50,30 @@ where
         I: ExactSizeIterator<Item = &'a SocketAddr>,
         SocketAddr: 'a,
     {
+        self.deliver_each(message, endpoints)?.into_result()
+    }
+
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a SocketAddr>,
+        SocketAddr: 'a,
+    {
+        // The message is serialized once for all endpoints, so failing that fails them all.
         let bytes = self
             .serializer
             .to_bytes(message)
             .map_err(Error::Serialization)?;
-        for endpoint in endpoints {
-            self.socket
-                .send_to(bytes.as_ref(), endpoint)
-                .map_err(Error::Send)?;
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.enumerate() {
+            if let Err(e) = self.socket.send_to(bytes.as_ref(), endpoint) {
+                report.failures.push((position, Error::Send(e)));
+            }
         }
-        Ok(())
+        Ok(report)
     }
 }
 
@@ -0,0 +1,116 @@
+//! Hooks for observing the decisions gossip mechanisms make (e.g. for metrics, tracing or
+//! aggregating simulation results).
+
+use std::sync::{Arc, Mutex};
+
+/// An observer of the decisions a gossip mechanism makes about messages (`M`) and the peers
+/// (`P`) it gossips them to. All methods do nothing by default, so an observer only needs to
+/// implement the ones it cares about.
+pub trait GossipObserver<M, P> {
+    /// A message was received from a peer for the first time.
+    fn on_first_seen(&mut self, _message: &M) {}
+
+    /// A message was received from a peer that had already been seen `times_seen - 1` times
+    /// before (as far as the store keeping track of seen messages remembers).
+    fn on_duplicate(&mut self, _message: &M, _times_seen: usize) {}
+
+    /// A message was passed on to the given peers.
+    fn on_forward<'a, T>(&mut self, _message: &M, _targets: T)
+    where
+        T: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+    }
+
+    /// The data was updated by a message originating from this node.
+    fn on_update(&mut self, _message: &M) {}
+
+    /// Passing a message on to the given peer failed in the delivery mechanism.
+    fn on_delivery_error(&mut self, _message: &M, _target: &P) {}
+
+    /// The store keeping track of seen messages now remembers `len` message IDs.
+    fn on_seen_store_size(&mut self, _len: usize) {}
+}
+
+/// An observer that ignores everything, which costs nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct NoopObserver;
+
+impl<M, P> GossipObserver<M, P> for NoopObserver {}
+
+/// An observer shared with other threads (e.g. one that scrapes metrics) observes everything
+/// while holding the lock.
+impl<M, P, O> GossipObserver<M, P> for Arc<Mutex<O>>
+where
+    O: GossipObserver<M, P>,
+{
+    fn on_first_seen(&mut self, message: &M) {
+        self.lock().unwrap().on_first_seen(message);
+    }
+
+    fn on_duplicate(&mut self, message: &M, times_seen: usize) {
+        self.lock().unwrap().on_duplicate(message, times_seen);
+    }
+
+    fn on_forward<'a, T>(&mut self, message: &M, targets: T)
+    where
+        T: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        self.lock().unwrap().on_forward(message, targets);
+    }
+
+    fn on_update(&mut self, message: &M) {
+        self.lock().unwrap().on_update(message);
+    }
+
+    fn on_delivery_error(&mut self, message: &M, target: &P) {
+        self.lock().unwrap().on_delivery_error(message, target);
+    }
+
+    fn on_seen_store_size(&mut self, len: usize) {
+        self.lock().unwrap().on_seen_store_size(len);
+    }
+}
+
+/// A pair of observers both observe everything, so that independent observers can be combined.
+impl<M, P, A, B> GossipObserver<M, P> for (A, B)
+where
+    A: GossipObserver<M, P>,
+    B: GossipObserver<M, P>,
+{
+    fn on_first_seen(&mut self, message: &M) {
+        self.0.on_first_seen(message);
+        self.1.on_first_seen(message);
+    }
+
+    fn on_duplicate(&mut self, message: &M, times_seen: usize) {
+        self.0.on_duplicate(message, times_seen);
+        self.1.on_duplicate(message, times_seen);
+    }
+
+    fn on_forward<'a, T>(&mut self, message: &M, targets: T)
+    where
+        T: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let targets: Vec<_> = targets.collect();
+        self.0.on_forward(message, targets.iter().copied());
+        self.1.on_forward(message, targets.into_iter());
+    }
+
+    fn on_update(&mut self, message: &M) {
+        self.0.on_update(message);
+        self.1.on_update(message);
+    }
+
+    fn on_delivery_error(&mut self, message: &M, target: &P) {
+        self.0.on_delivery_error(message, target);
+        self.1.on_delivery_error(message, target);
+    }
+
+    fn on_seen_store_size(&mut self, len: usize) {
+        self.0.on_seen_store_size(len);
+        self.1.on_seen_store_size(len);
+    }
+}
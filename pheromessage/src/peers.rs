@@ -0,0 +1,130 @@
+//! Sets of peers that can be changed as nodes join and leave the network.
+
+use std::{collections::HashMap, hash::Hash};
+
+/// A gossip mechanism whose set of peers (`P`) can be kept up to date by a membership
+/// protocol (e.g. `swim::Swim`) as nodes join and leave the network.
+pub trait Membership<P> {
+    /// A new node joined the network and should be gossipped to. Should do nothing if
+    /// the node is already a peer.
+    fn join(&mut self, peer: P);
+
+    /// A node left (or failed) and should no longer be gossipped to.
+    fn leave(&mut self, peer: &P);
+}
+
+/// A set of peers (`P`) that keeps them in a contiguous slice (for gossip to select from)
+/// while also indexing them, so that adding, removing and looking up a peer are all
+/// constant time regardless of the number of peers.
+#[derive(Debug, Clone)]
+pub struct PeerSet<P> {
+    /// The peers, in no particular order.
+    peers: Vec<P>,
+    /// The position of every peer in `peers`.
+    index: HashMap<P, usize>,
+}
+
+impl<P> Default for PeerSet<P> {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<P> PeerSet<P> {
+    /// The peers in the set, in no particular order.
+    pub fn as_slice(&self) -> &[P] {
+        &self.peers
+    }
+
+    /// An iterator over the peers in the set.
+    pub fn iter(&self) -> std::slice::Iter<'_, P> {
+        self.peers.iter()
+    }
+
+    /// The number of peers in the set.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Checks if the set has no peers.
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+impl<P> PeerSet<P>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Add the given peer to the set. Returns `false` if it was already there.
+    pub fn insert(&mut self, peer: P) -> bool {
+        if self.index.contains_key(&peer) {
+            return false;
+        }
+        self.index.insert(peer.clone(), self.peers.len());
+        self.peers.push(peer);
+        true
+    }
+
+    /// Remove the given peer from the set, returning it if it was there.
+    /// This moves the last peer into its place, so doesn't preserve the order of peers.
+    pub fn remove(&mut self, peer: &P) -> Option<P> {
+        let position = self.index.remove(peer)?;
+        let removed = self.peers.swap_remove(position);
+        if let Some(moved) = self.peers.get(position) {
+            self.index.insert(moved.clone(), position);
+        }
+        Some(removed)
+    }
+
+    /// Checks if the given peer is in the set.
+    pub fn contains(&self, peer: &P) -> bool {
+        self.index.contains_key(peer)
+    }
+}
+
+impl<P> From<Vec<P>> for PeerSet<P>
+where
+    P: Eq + Hash + Clone,
+{
+    fn from(peers: Vec<P>) -> Self {
+        peers.into_iter().collect()
+    }
+}
+
+impl<P> FromIterator<P> for PeerSet<P>
+where
+    P: Eq + Hash + Clone,
+{
+    fn from_iter<T: IntoIterator<Item = P>>(iter: T) -> Self {
+        let mut set = PeerSet::default();
+        for peer in iter {
+            set.insert(peer);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove() {
+        let mut set: PeerSet<_> = vec![1, 2, 3, 2].into();
+        assert_eq!(3, set.len());
+        assert!(!set.insert(3));
+        assert!(set.insert(4));
+        assert_eq!(Some(1), set.remove(&1));
+        assert_eq!(None, set.remove(&1));
+        // The last peer took the place of the removed one and is still tracked correctly.
+        assert_eq!(Some(4), set.remove(&4));
+        let mut remaining = set.as_slice().to_vec();
+        remaining.sort();
+        assert_eq!(vec![2, 3], remaining);
+        assert!(set.contains(&2) && !set.contains(&4));
+    }
+}
@@ -0,0 +1,586 @@
+//! Plumtree gossip (after "Epidemic Broadcast Trees" by Leitão et al.): messages are eagerly
+//! pushed along a spanning tree, with just their IDs lazily pushed (IHAVE) to all other peers.
+//! The tree is built by pruning (PRUNE) every link a duplicate arrived through, and healed by
+//! grafting (GRAFT) a link to a peer that announced a message that never arrived, so most
+//! nodes receive every message exactly once while still tolerating failures.
+//!
+//! Grafting is timed: `tick()` should be called regularly (more often than the graft timeout)
+//! with the current time, like the `swim` protocol.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    is_excluded,
+    peers::{Membership, PeerSet},
+    seen::{MessageLog, SeenStore, UnboundedSeenStore},
+    select::Exclusion,
+    Delivery, Gossip, Message, ReceiveFrom, SharedData,
+};
+
+/// The control part of the Plumtree protocol, about messages with IDs of type `I`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PlumtreeControl<I> {
+    /// The sender has the message with the given ID (lazy push).
+    IHave(I),
+    /// The sender is missing the message with the given ID, and wants it (and all future
+    /// messages) eagerly pushed to it.
+    Graft(I),
+    /// The sender got a duplicate from me, so I should only lazily push to it from now on.
+    Prune,
+}
+
+/// A message that can carry the Plumtree protocol between nodes with IDs of type `K`:
+/// either a gossip payload or a control message, along with the node that sent it.
+pub trait PlumtreeMessage<K>: Message {
+    /// The node that sent this message, or `None` if it didn't come from a peer.
+    fn sender(&self) -> Option<K>;
+
+    /// The control part of this message, or `None` if it's a gossip payload.
+    fn control(&self) -> Option<PlumtreeControl<Self::I>>;
+
+    /// A copy of this gossip payload to pass on from the given `sender`.
+    fn forward_from(&self, sender: K) -> Self;
+
+    /// A control message from the given `sender`.
+    fn control_from(sender: K, control: PlumtreeControl<Self::I>) -> Self;
+}
+
+/// The configuration of Plumtree gossip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlumtreeConfig {
+    /// How long to wait for a message that was announced to me (by IHAVE) before grafting
+    /// the link to the peer that announced it.
+    pub graft_timeout: Duration,
+    /// How many of the latest messages to keep around to answer grafts.
+    pub cache_capacity: usize,
+}
+
+impl Default for PlumtreeConfig {
+    fn default() -> Self {
+        Self {
+            graft_timeout: Duration::from_millis(100),
+            cache_capacity: 1024,
+        }
+    }
+}
+
+/// A message announced to me that I haven't received yet.
+struct Missing<K> {
+    /// The peers that announced it, in order, left to graft.
+    announcers: VecDeque<K>,
+    /// When the current wait for it started (set on the first tick after the announcement).
+    since: Option<Instant>,
+}
+
+/// A gossip mechanism that eagerly pushes messages (`M`) along a spanning tree of peers and
+/// lazily pushes their IDs (`I`) to the rest.
+/// Peers are identified by IDs (`K`), with the endpoints (`P`) to deliver to them.
+/// `L` is the store used to keep track of the IDs of messages seen so far.
+pub struct PlumtreeGossip<K, P, S, D, M, I, L = UnboundedSeenStore<I>> {
+    /// My own ID.
+    id: K,
+    /// The endpoint for every peer.
+    endpoints: HashMap<K, P>,
+    /// The peers I eagerly push messages to (my links in the tree).
+    eager: PeerSet<K>,
+    /// The peers I only announce messages to.
+    lazy: PeerSet<K>,
+    config: PlumtreeConfig,
+    /// The message IDs seen so far.
+    seen_messages: L,
+    /// The latest messages, to answer grafts.
+    cache: MessageLog<I, M>,
+    /// The messages announced to me that I haven't received yet.
+    missing: HashMap<I, Missing<K>>,
+    /// The delivery mechanism to send gossip messages.
+    delivery: D,
+    /// The data being gossipped about.
+    data: S,
+}
+
+impl<K, P, S, D, M, I> PlumtreeGossip<K, P, S, D, M, I>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Create a new Plumtree gossip mechanism for the node with the given `id`, that will gossip
+    /// to the given `peers` (by ID and endpoint) as configured by `config`, using the given
+    /// `delivery` mechanism and maintaining the given `data`.
+    /// All peers start out eagerly pushed to, and the tree is pruned from there.
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
+    pub fn create(
+        id: K,
+        peers: Vec<(K, P)>,
+        config: PlumtreeConfig,
+        data: S,
+        delivery: D,
+    ) -> PlumtreeGossip<K, P, S, D, M, I> {
+        PlumtreeGossip::create_with_seen_store(
+            id,
+            peers,
+            config,
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
+    }
+}
+
+impl<K, P, S, D, M, I, L> PlumtreeGossip<K, P, S, D, M, I, L>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Create a new Plumtree gossip mechanism like `create()`, but keeping track of seen
+    /// messages in the given `seen_messages` store.
+    pub fn create_with_seen_store(
+        id: K,
+        peers: Vec<(K, P)>,
+        config: PlumtreeConfig,
+        data: S,
+        delivery: D,
+        seen_messages: L,
+    ) -> PlumtreeGossip<K, P, S, D, M, I, L> {
+        let mut gossip = PlumtreeGossip {
+            id,
+            endpoints: HashMap::new(),
+            eager: PeerSet::default(),
+            lazy: PeerSet::default(),
+            config,
+            seen_messages,
+            cache: MessageLog::new(config.cache_capacity),
+            missing: HashMap::new(),
+            delivery,
+            data,
+        };
+        for peer in peers {
+            gossip.join(peer);
+        }
+        gossip
+    }
+
+    /// My own ID.
+    pub fn id(&self) -> &K {
+        &self.id
+    }
+
+    /// The peers I eagerly push messages to.
+    pub fn eager_peers(&self) -> &[K] {
+        self.eager.as_slice()
+    }
+
+    /// The peers I only announce messages to.
+    pub fn lazy_peers(&self) -> &[K] {
+        self.lazy.as_slice()
+    }
+
+    /// The store keeping track of the seen messages.
+    pub fn seen_messages(&self) -> &L {
+        &self.seen_messages
+    }
+
+    /// Eagerly push to the given peer from now on.
+    fn graft_peer(&mut self, peer: &K) {
+        if self.lazy.remove(peer).is_some() {
+            self.eager.insert(peer.clone());
+        }
+    }
+
+    /// Only lazily push to the given peer from now on.
+    fn prune_peer(&mut self, peer: &K) {
+        if self.eager.remove(peer).is_some() {
+            self.lazy.insert(peer.clone());
+        }
+    }
+
+    /// Deliver the given message to the given peers, except `exclude` and any peers whose
+    /// endpoints are `excluded`. Failing to reach some of them isn't an error: to the protocol
+    /// that's just like losing the message, which the lazy push repairs.
+    fn deliver_to<'a, T>(
+        &self,
+        message: &M,
+        peers: T,
+        exclude: Option<&K>,
+        excluded: Exclusion<P>,
+    ) -> Result<(), D::Error>
+    where
+        T: Iterator<Item = &'a K>,
+        K: 'a,
+        D: Delivery<M, P>,
+    {
+        let endpoints: Vec<_> = peers
+            .filter(|&peer| Some(peer) != exclude)
+            .filter_map(|peer| self.endpoints.get(peer))
+            .filter(|endpoint| !excluded.is_some_and(|excluded| excluded(endpoint)))
+            .collect();
+        self.delivery
+            .deliver_each(message, endpoints.into_iter())
+            .map(|_| ())
+    }
+}
+
+impl<K, P, S, D, M, I, L> PlumtreeGossip<K, P, S, D, M, I, L>
+where
+    K: Eq + Hash + Clone,
+    M: PlumtreeMessage<K, I = I> + Clone,
+    I: Eq + Hash + Clone,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+{
+    /// Move the protocol forward to the given time: graft the links to peers that announced
+    /// messages that didn't arrive within the graft timeout.
+    pub fn tick(&mut self, now: Instant) -> Result<(), D::Error> {
+        let mut grafts = Vec::new();
+        for (id, missing) in self.missing.iter_mut() {
+            match missing.since {
+                None => missing.since = Some(now),
+                Some(since) if now.duration_since(since) >= self.config.graft_timeout => {
+                    if let Some(announcer) = missing.announcers.pop_front() {
+                        grafts.push((id.clone(), announcer));
+                    }
+                    // Give this announcer time to answer before trying the next one.
+                    missing.since = Some(now);
+                }
+                Some(_) => {}
+            }
+        }
+        self.missing.retain(|_, m| !m.announcers.is_empty());
+        for (id, announcer) in grafts {
+            self.graft_peer(&announcer);
+            let graft = M::control_from(self.id.clone(), PlumtreeControl::Graft(id));
+            self.deliver_to(&graft, [announcer].iter(), None, None)?;
+        }
+        Ok(())
+    }
+
+    /// Push the given message eagerly to my eager peers and lazily to my lazy peers, except
+    /// the one it came from and any `excluded` ones.
+    fn push(
+        &mut self,
+        message: &M,
+        exclude: Option<&K>,
+        excluded: Exclusion<P>,
+    ) -> Result<(), D::Error> {
+        let forwarded = message.forward_from(self.id.clone());
+        self.deliver_to(&forwarded, self.eager.iter(), exclude, excluded)?;
+        let announcement = M::control_from(self.id.clone(), PlumtreeControl::IHave(message.id()));
+        self.deliver_to(&announcement, self.lazy.iter(), exclude, excluded)
+    }
+
+    /// Receive the given message, not pushing it on to any `excluded` peers.
+    fn receive_excluding(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        S: SharedData<M>,
+    {
+        let sender = message.sender();
+        match (message.control(), sender) {
+            (None, sender) => {
+                let id = message.id();
+                if self.seen_messages.see(id.clone()) == 1 {
+                    // The first time I've seen this message: the link it came through is
+                    // part of the tree.
+                    self.data.update(message);
+                    self.missing.remove(&id);
+                    self.cache.insert(id.clone(), message.clone());
+                    if let Some(sender) = &sender {
+                        self.graft_peer(sender);
+                    }
+                    self.push(message, sender.as_ref(), excluded)?;
+                } else if let Some(sender) = sender {
+                    // A duplicate: the link it came through is redundant.
+                    self.prune_peer(&sender);
+                    let prune = M::control_from(self.id.clone(), PlumtreeControl::Prune);
+                    self.deliver_to(&prune, [sender].iter(), None, None)?;
+                }
+            }
+            (Some(PlumtreeControl::IHave(id)), Some(sender)) => {
+                if !self.seen_messages.contains(&id) {
+                    self.missing
+                        .entry(id)
+                        .or_insert_with(|| Missing {
+                            announcers: VecDeque::new(),
+                            since: None,
+                        })
+                        .announcers
+                        .push_back(sender);
+                }
+            }
+            (Some(PlumtreeControl::Graft(id)), Some(sender)) => {
+                self.graft_peer(&sender);
+                if let Some(cached) = self.cache.get(&id) {
+                    let forwarded = cached.forward_from(self.id.clone());
+                    self.deliver_to(&forwarded, [sender].iter(), None, None)?;
+                }
+            }
+            (Some(PlumtreeControl::Prune), Some(sender)) => self.prune_peer(&sender),
+            // Control messages are meaningless without knowing who sent them.
+            (Some(_), None) => {}
+        }
+        Ok(())
+    }
+}
+
+impl<K, P, S, D, M, I, L> Gossip<M, S> for PlumtreeGossip<K, P, S, D, M, I, L>
+where
+    K: Eq + Hash + Clone,
+    M: PlumtreeMessage<K, I = I> + Clone,
+    I: Eq + Hash + Clone,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+{
+    type Error = D::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.receive_excluding(message, None)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.data.update(message);
+        self.seen_messages.see(message.id());
+        self.cache.insert(message.id(), message.clone());
+        self.push(message, None, None)
+    }
+
+    fn data(&self) -> &S {
+        &self.data
+    }
+}
+
+/// Plumtree messages already say who sent them (and pushing never goes back to the sender),
+/// but a message received from an endpoint still isn't pushed (eagerly or lazily) to that
+/// endpoint or any of the `excluded` ones. The tree itself is still only shaped by the
+/// senders the messages carry.
+impl<K, P, S, D, M, I, L, Q> ReceiveFrom<M, S, Q> for PlumtreeGossip<K, P, S, D, M, I, L>
+where
+    K: Eq + Hash + Clone,
+    M: PlumtreeMessage<K, I = I> + Clone,
+    I: Eq + Hash + Clone,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    P: PartialEq<Q>,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.receive_excluding(message, Some(&|p: &P| is_excluded(p, sender, excluded)))
+    }
+}
+
+/// New peers start out eagerly pushed to, as they're not part of the tree yet.
+impl<K, P, S, D, M, I, L> Membership<(K, P)> for PlumtreeGossip<K, P, S, D, M, I, L>
+where
+    K: Eq + Hash + Clone,
+{
+    fn join(&mut self, (peer, endpoint): (K, P)) {
+        if peer == self.id || self.endpoints.contains_key(&peer) {
+            return;
+        }
+        self.endpoints.insert(peer.clone(), endpoint);
+        self.eager.insert(peer);
+    }
+
+    fn leave(&mut self, (peer, _): &(K, P)) {
+        self.endpoints.remove(peer);
+        self.eager.remove(peer);
+        self.lazy.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// A Plumtree message between nodes identified by `usize`, with payloads identified by
+    /// `usize` as well.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Wire {
+        sender: Option<usize>,
+        control: Option<PlumtreeControl<usize>>,
+        id: usize,
+    }
+
+    impl Message for Wire {
+        type I = usize;
+
+        fn id(&self) -> usize {
+            self.id
+        }
+    }
+
+    impl PlumtreeMessage<usize> for Wire {
+        fn sender(&self) -> Option<usize> {
+            self.sender
+        }
+
+        fn control(&self) -> Option<PlumtreeControl<usize>> {
+            self.control.clone()
+        }
+
+        fn forward_from(&self, sender: usize) -> Self {
+            Wire {
+                sender: Some(sender),
+                ..self.clone()
+            }
+        }
+
+        fn control_from(sender: usize, control: PlumtreeControl<usize>) -> Self {
+            Wire {
+                sender: Some(sender),
+                control: Some(control),
+                id: 0,
+            }
+        }
+    }
+
+    /// Counts the payloads that updated the data.
+    #[derive(Default)]
+    struct Payloads(usize);
+
+    impl SharedData<Wire> for Payloads {
+        fn update(&mut self, _message: &Wire) {
+            self.0 += 1;
+        }
+    }
+
+    /// Queues every message sent as (endpoint, message), where the endpoint is the index of
+    /// the node in the test network.
+    struct Outbox(RefCell<VecDeque<(usize, Wire)>>);
+
+    impl Delivery<Wire, usize> for &Outbox {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, message: &Wire, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            for endpoint in endpoints {
+                self.0.borrow_mut().push_back((*endpoint, message.clone()));
+            }
+            Ok(())
+        }
+    }
+
+    type Node<'a> = PlumtreeGossip<usize, usize, Payloads, &'a Outbox, Wire, usize>;
+
+    /// A network of `n` nodes that all know each other, sharing an outbox.
+    fn network(n: usize, outbox: &Outbox) -> Vec<Node<'_>> {
+        (0..n)
+            .map(|i| {
+                let peers = (0..n).filter(|&j| j != i).map(|j| (j, j)).collect();
+                PlumtreeGossip::create(i, peers, PlumtreeConfig::default(), Payloads(0), outbox)
+            })
+            .collect()
+    }
+
+    /// Deliver everything in the outbox (and everything sent as a result) except messages to
+    /// the given dead node, returning the number of payloads delivered.
+    fn run(nodes: &mut [Node<'_>], outbox: &Outbox, dead: Option<usize>) -> usize {
+        let mut payloads = 0;
+        loop {
+            let next = outbox.0.borrow_mut().pop_front();
+            let Some((to, message)) = next else {
+                return payloads;
+            };
+            if Some(to) == dead {
+                continue;
+            }
+            if message.control.is_none() {
+                payloads += 1;
+            }
+            nodes[to].receive(&message).unwrap();
+        }
+    }
+
+    fn payload(id: usize) -> Wire {
+        Wire {
+            sender: None,
+            control: None,
+            id,
+        }
+    }
+
+    #[test]
+    fn prunes_to_a_tree() {
+        let outbox = Outbox(RefCell::new(VecDeque::new()));
+        let mut nodes = network(6, &outbox);
+        nodes[0].update(&payload(1)).unwrap();
+        run(&mut nodes, &outbox, None);
+        assert!(nodes.iter().all(|n| n.data.0 == 1));
+        // The first broadcast floods and prunes every redundant link, so the next one is
+        // only pushed along the tree: exactly one payload per other node.
+        nodes[0].update(&payload(2)).unwrap();
+        assert_eq!(5, run(&mut nodes, &outbox, None));
+        assert!(nodes.iter().all(|n| n.data.0 == 2));
+    }
+
+    #[test]
+    fn grafts_missing_messages() {
+        let outbox = Outbox(RefCell::new(VecDeque::new()));
+        let mut nodes = network(6, &outbox);
+        // Node 0 floods first, so everyone is pruned down to a star around it.
+        nodes[0].update(&payload(1)).unwrap();
+        run(&mut nodes, &outbox, None);
+        assert_eq!(vec![0], nodes[1].eager_peers());
+        // Now node 0 dies, and node 1's update only reaches the others as announcements.
+        nodes[1].update(&payload(2)).unwrap();
+        run(&mut nodes, &outbox, Some(0));
+        assert!(nodes[2..].iter().all(|n| n.data.0 == 1));
+        // The first tick starts waiting for the announced message, and the next one (after
+        // the timeout) grafts the link to node 1 to get it.
+        let start = Instant::now();
+        for now in [start, start + PlumtreeConfig::default().graft_timeout] {
+            for node in nodes.iter_mut() {
+                node.tick(now).unwrap();
+            }
+            run(&mut nodes, &outbox, Some(0));
+        }
+        assert!(nodes[1..].iter().all(|n| n.data.0 == 2));
+        assert!(nodes[2].eager_peers().contains(&1));
+    }
+
+    #[test]
+    fn receive_from_skips_excluded() {
+        let outbox = Outbox(RefCell::new(VecDeque::new()));
+        let mut node = network(4, &outbox).remove(0);
+        // Neither the endpoint it came through nor the excluded peer get it pushed to them.
+        node.receive_from_excluding(&payload(1), &1, &[2]).unwrap();
+        assert_eq!(
+            vec![3],
+            outbox
+                .0
+                .borrow()
+                .iter()
+                .map(|(to, _)| *to)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, node.data.0);
+    }
+
+    #[test]
+    fn leave_forgets_peer() {
+        let outbox = Outbox(RefCell::new(VecDeque::new()));
+        let mut node = network(3, &outbox).remove(0);
+        node.leave(&(1, 1));
+        assert_eq!(vec![2], node.eager_peers());
+        node.update(&payload(1)).unwrap();
+        assert_eq!(
+            vec![2],
+            outbox
+                .0
+                .borrow()
+                .iter()
+                .map(|(to, _)| *to)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! Reliable delivery on top of any (possibly lossy) delivery mechanism: every message sent to an
+//! endpoint has to be acknowledged, and is retransmitted with exponential backoff until it is
+//! (or until we give up on it).
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    hash::Hash,
+    iter,
+    time::{Duration, Instant},
+};
+
+use rand::random;
+
+use crate::{Delivery, DeliveryReport};
+
+/// A message on the wire between reliable delivery mechanisms, wrapping messages (`M`) sent
+/// between endpoints (`P`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ReliableMessage<M, P> {
+    /// A message that has to be acknowledged back to the `from` endpoint.
+    /// `oldest_in_flight` is the oldest sequence number the sender is still waiting to have
+    /// acknowledged (everything before it was acknowledged or given up on), so the recipient
+    /// can forget about the messages before it.
+    /// `epoch` is drawn at random whenever a reliable delivery mechanism is created, so the
+    /// recipient can tell that the sender restarted and started its sequence numbers over.
+    Data {
+        seq: u64,
+        oldest_in_flight: u64,
+        epoch: u64,
+        from: P,
+        message: M,
+    },
+    /// The acknowledgement of the data message with the given sequence number and epoch.
+    Ack { seq: u64, epoch: u64 },
+}
+
+/// The configuration of reliable delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReliableConfig {
+    /// How long to wait for an acknowledgement before the first retransmission.
+    pub initial_timeout: Duration,
+    /// The longest to wait for an acknowledgement, however many times the wait was doubled.
+    pub max_timeout: Duration,
+    /// How many times to send a message (including the first time) before giving up on it.
+    pub max_attempts: usize,
+    /// How many messages can wait for acknowledgement at once. Messages beyond that are held
+    /// back until others are acknowledged (or given up on).
+    pub max_in_flight: usize,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        ReliableConfig {
+            initial_timeout: Duration::from_millis(200),
+            max_timeout: Duration::from_secs(5),
+            max_attempts: 8,
+            max_in_flight: 1024,
+        }
+    }
+}
+
+/// Counts of what reliable delivery did so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ReliableStats {
+    /// Messages sent for the first time.
+    pub sent: u64,
+    /// Retransmissions of messages that weren't acknowledged in time.
+    pub retransmitted: u64,
+    /// Messages acknowledged.
+    pub acked: u64,
+    /// Messages given up on after `max_attempts`.
+    pub given_up: u64,
+}
+
+/// A message waiting for acknowledgement.
+struct Pending<M, P> {
+    message: M,
+    endpoint: P,
+    /// How many times it's been sent so far.
+    attempts: usize,
+    /// How long to wait for an acknowledgement of the latest attempt.
+    timeout: Duration,
+    /// When to send it again.
+    retry_at: Instant,
+}
+
+/// The sequence numbers of the messages delivered from a sender that may still be
+/// retransmitted by it.
+#[derive(Default)]
+struct Delivered {
+    /// The epoch of the sender the sequence numbers are from.
+    epoch: u64,
+    /// Messages before this one can't be retransmitted anymore.
+    oldest_in_flight: u64,
+    /// The messages delivered from `oldest_in_flight` on.
+    seqs: BTreeSet<u64>,
+}
+
+impl Delivered {
+    /// Checks if the message with the given sequence number is new (as opposed to a
+    /// retransmission of one already delivered), remembering it if it is.
+    /// A new epoch means the sender restarted, so what was delivered before doesn't count.
+    fn is_new(&mut self, seq: u64, oldest_in_flight: u64, epoch: u64) -> bool {
+        if epoch != self.epoch {
+            *self = Delivered {
+                epoch,
+                ..Delivered::default()
+            };
+        }
+        if oldest_in_flight > self.oldest_in_flight {
+            self.oldest_in_flight = oldest_in_flight;
+            self.seqs = self.seqs.split_off(&oldest_in_flight);
+        }
+        seq >= self.oldest_in_flight && self.seqs.insert(seq)
+    }
+}
+
+struct State<M, P> {
+    next_seq: u64,
+    /// The messages waiting for acknowledgement by sequence number.
+    in_flight: BTreeMap<u64, Pending<M, P>>,
+    /// The messages (and endpoints) held back because too many were in flight.
+    backlog: VecDeque<(M, P)>,
+    /// The messages delivered from every sender, to tell retransmissions apart.
+    delivered: HashMap<P, Delivered>,
+    stats: ReliableStats,
+}
+
+/// A wrapper around a delivery mechanism (`D`) for `ReliableMessage`s, which delivers messages
+/// (`M`) to endpoints (`P`) reliably: every message is retransmitted until acknowledged.
+/// Gossip can use it like any other delivery mechanism. The node just has to pass everything it
+/// gets from the underlying transport to `receive()` (which sends acknowledgements and unwraps
+/// the actual messages), and call `tick()` regularly (for retransmissions).
+pub struct ReliableDelivery<D, M, P> {
+    inner: D,
+    /// The endpoint of this node, for acknowledgements to be sent to.
+    me: P,
+    /// Sent along with every message, to tell this incarnation of the node from earlier ones.
+    epoch: u64,
+    config: ReliableConfig,
+    state: RefCell<State<M, P>>,
+}
+
+impl<D, M, P> ReliableDelivery<D, M, P> {
+    /// Create a new reliable delivery mechanism for the node at endpoint `me`, configured by
+    /// `config`, sending (and retransmitting) messages and acknowledgements using `inner`.
+    pub fn new(inner: D, me: P, config: ReliableConfig) -> ReliableDelivery<D, M, P> {
+        ReliableDelivery {
+            inner,
+            me,
+            epoch: random(),
+            config,
+            state: RefCell::new(State {
+                next_seq: 0,
+                in_flight: BTreeMap::new(),
+                backlog: VecDeque::new(),
+                delivered: HashMap::new(),
+                stats: ReliableStats::default(),
+            }),
+        }
+    }
+
+    /// The underlying delivery mechanism.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// The configuration of the reliable delivery.
+    pub fn config(&self) -> &ReliableConfig {
+        &self.config
+    }
+
+    /// The epoch sent along with every message (see `ReliableMessage::Data`).
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// How many messages are waiting for acknowledgement.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().in_flight.len()
+    }
+
+    /// How many messages are held back because too many are in flight.
+    pub fn backlog(&self) -> usize {
+        self.state.borrow().backlog.len()
+    }
+
+    /// Counts of what the reliable delivery did so far.
+    pub fn stats(&self) -> ReliableStats {
+        self.state.borrow().stats
+    }
+}
+
+impl<D, M, P> ReliableDelivery<D, M, P>
+where
+    D: Delivery<ReliableMessage<M, P>, P>,
+    M: Clone,
+    P: Clone,
+{
+    /// Handle a message that came in over the underlying transport. Data messages are
+    /// acknowledged and the message in them returned, unless it's a retransmission of one
+    /// already returned (because the acknowledgement was lost), so gossip only ever sees every
+    /// message once (which matters to gossip that counts how often it's seen a message).
+    /// Acknowledgements make room for held back messages, which are sent right away.
+    pub fn receive(&self, wire: &ReliableMessage<M, P>) -> Option<M>
+    where
+        P: Eq + Hash,
+    {
+        match wire {
+            ReliableMessage::Data {
+                seq,
+                oldest_in_flight,
+                epoch,
+                from,
+                message,
+            } => {
+                // A lost acknowledgement just means the message will be sent again.
+                let ack = ReliableMessage::Ack {
+                    seq: *seq,
+                    epoch: *epoch,
+                };
+                let _ = self.inner.deliver(&ack, iter::once(from));
+                let mut state = self.state.borrow_mut();
+                let delivered = state.delivered.entry(from.clone()).or_default();
+                delivered
+                    .is_new(*seq, *oldest_in_flight, *epoch)
+                    .then(|| message.clone())
+            }
+            // Acknowledgements of what an earlier incarnation of this node sent don't count.
+            ReliableMessage::Ack { epoch, .. } if *epoch != self.epoch => None,
+            ReliableMessage::Ack { seq, .. } => {
+                let mut state = self.state.borrow_mut();
+                if state.in_flight.remove(seq).is_some() {
+                    state.stats.acked += 1;
+                    self.send_backlog(&mut state, Instant::now());
+                }
+                None
+            }
+        }
+    }
+
+    /// Retransmit all the messages that weren't acknowledged in time (as of `now`), doubling
+    /// the time to wait for them, and give up on the ones that were sent too many times.
+    pub fn tick(&self, now: Instant) {
+        let mut state = self.state.borrow_mut();
+        let due: Vec<_> = state
+            .in_flight
+            .iter()
+            .filter(|(_, pending)| pending.retry_at <= now)
+            .map(|(&seq, _)| seq)
+            .collect();
+        for seq in due {
+            let oldest_in_flight = *state.in_flight.keys().next().unwrap();
+            let pending = state.in_flight.get_mut(&seq).unwrap();
+            if pending.attempts >= self.config.max_attempts {
+                state.in_flight.remove(&seq);
+                state.stats.given_up += 1;
+                continue;
+            }
+            pending.attempts += 1;
+            pending.timeout = (pending.timeout * 2).min(self.config.max_timeout);
+            pending.retry_at = now + pending.timeout;
+            // Failing to send it is just like losing it, it'll be retransmitted again.
+            let _ = self.inner.deliver(
+                &ReliableMessage::Data {
+                    seq,
+                    oldest_in_flight,
+                    epoch: self.epoch,
+                    from: self.me.clone(),
+                    message: pending.message.clone(),
+                },
+                iter::once(&pending.endpoint),
+            );
+            state.stats.retransmitted += 1;
+        }
+        self.send_backlog(&mut state, now);
+    }
+
+    /// Send the given message to the given endpoint for the first time, and wait for its
+    /// acknowledgement (even if sending it failed, in which case it'll be retransmitted).
+    fn send(
+        &self,
+        state: &mut State<M, P>,
+        message: M,
+        endpoint: P,
+        now: Instant,
+    ) -> Result<(), D::Error> {
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.stats.sent += 1;
+        let oldest_in_flight = state.in_flight.keys().next().map_or(seq, |&oldest| oldest);
+        let sent = self.inner.deliver(
+            &ReliableMessage::Data {
+                seq,
+                oldest_in_flight,
+                epoch: self.epoch,
+                from: self.me.clone(),
+                message: message.clone(),
+            },
+            iter::once(&endpoint),
+        );
+        state.in_flight.insert(
+            seq,
+            Pending {
+                message,
+                endpoint,
+                attempts: 1,
+                timeout: self.config.initial_timeout,
+                retry_at: now + self.config.initial_timeout,
+            },
+        );
+        sent
+    }
+
+    /// Send as many held back messages as there's room for.
+    fn send_backlog(&self, state: &mut State<M, P>, now: Instant) {
+        while state.in_flight.len() < self.config.max_in_flight {
+            let Some((message, endpoint)) = state.backlog.pop_front() else {
+                break;
+            };
+            // Failing to send it is just like losing it, it'll be retransmitted.
+            let _ = self.send(state, message, endpoint, now);
+        }
+    }
+}
+
+impl<D, M, P> Delivery<M, P> for ReliableDelivery<D, M, P>
+where
+    D: Delivery<ReliableMessage<M, P>, P>,
+    M: Clone,
+    P: Clone,
+{
+    type Error = D::Error;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        self.deliver_each(message, endpoints)?.into_result()
+    }
+
+    /// Messages that failed to be sent are still retransmitted (so they may get there after all).
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let now = Instant::now();
+        let mut state = self.state.borrow_mut();
+        let mut report = DeliveryReport::default();
+        for (position, endpoint) in endpoints.enumerate() {
+            if state.in_flight.len() < self.config.max_in_flight {
+                if let Err(e) = self.send(&mut state, message.clone(), endpoint.clone(), now) {
+                    report.failures.push((position, e));
+                }
+            } else {
+                state.backlog.push_back((message.clone(), endpoint.clone()));
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, rc::Rc};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{lossy::LossyDelivery, Gossip, PreferentialGossip, UniformGossip};
+
+    type Wire = ReliableMessage<usize, usize>;
+
+    /// A local transport where every endpoint has a mailbox of messages.
+    #[derive(Clone, Default)]
+    struct Mailboxes(Rc<RefCell<HashMap<usize, VecDeque<Wire>>>>);
+
+    impl Delivery<Wire, usize> for Mailboxes {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, message: &Wire, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            for endpoint in endpoints {
+                let mut mailboxes = self.0.borrow_mut();
+                mailboxes
+                    .entry(*endpoint)
+                    .or_default()
+                    .push_back(message.clone());
+            }
+            Ok(())
+        }
+    }
+
+    impl Mailboxes {
+        fn take(&self, endpoint: usize) -> Vec<Wire> {
+            let mut mailboxes = self.0.borrow_mut();
+            mailboxes.entry(endpoint).or_default().drain(..).collect()
+        }
+    }
+
+    type Node<D> = ReliableDelivery<D, usize, usize>;
+
+    /// Pass all the messages waiting in the mailboxes to nodes 0 and 1, returning the messages
+    /// node 1 got.
+    fn exchange<D>(mailboxes: &Mailboxes, nodes: &[Node<D>; 2]) -> Vec<usize>
+    where
+        D: Delivery<Wire, usize>,
+    {
+        for wire in mailboxes.take(0) {
+            assert_eq!(None, nodes[0].receive(&wire));
+        }
+        mailboxes
+            .take(1)
+            .iter()
+            .filter_map(|wire| nodes[1].receive(wire))
+            .collect()
+    }
+
+    #[test]
+    fn recovers_losses() {
+        let mailboxes = Mailboxes::default();
+        let config = ReliableConfig {
+            max_attempts: 20,
+            ..ReliableConfig::default()
+        };
+        let nodes = [0, 1].map(|me| {
+            let lossy =
+                LossyDelivery::with_rng(mailboxes.clone(), 0.5, StdRng::seed_from_u64(me as u64));
+            ReliableDelivery::new(lossy, me, config)
+        });
+        for message in 0..20 {
+            nodes[0].deliver(&message, [1].iter()).unwrap();
+        }
+        let mut received = exchange(&mailboxes, &nodes);
+        let mut now = Instant::now();
+        while nodes[0].in_flight() > 0 {
+            now += config.max_timeout;
+            nodes[0].tick(now);
+            received.extend(exchange(&mailboxes, &nodes));
+        }
+        received.sort();
+        received.dedup();
+        assert_eq!((0..20).collect::<Vec<_>>(), received);
+        let stats = nodes[0].stats();
+        assert_eq!(20, stats.acked);
+        assert_eq!(0, stats.given_up);
+        assert!(stats.retransmitted > 0);
+    }
+
+    #[test]
+    fn caps_in_flight() {
+        let mailboxes = Mailboxes::default();
+        let config = ReliableConfig {
+            max_in_flight: 2,
+            ..ReliableConfig::default()
+        };
+        let nodes = [0, 1].map(|me| ReliableDelivery::new(mailboxes.clone(), me, config));
+        for message in 0..5 {
+            nodes[0].deliver(&message, [1].iter()).unwrap();
+        }
+        assert_eq!((2, 3), (nodes[0].in_flight(), nodes[0].backlog()));
+        // Every round of acknowledgements lets the next messages through.
+        assert_eq!(vec![0, 1], exchange(&mailboxes, &nodes));
+        assert_eq!(vec![2, 3], exchange(&mailboxes, &nodes));
+        assert_eq!(vec![4], exchange(&mailboxes, &nodes));
+        exchange(&mailboxes, &nodes);
+        assert_eq!((0, 0), (nodes[0].in_flight(), nodes[0].backlog()));
+    }
+
+    #[test]
+    fn under_gossip() {
+        let mailboxes = Mailboxes::default();
+        let mut gossip = UniformGossip::create(
+            vec![1],
+            1,
+            (),
+            ReliableDelivery::new(mailboxes.clone(), 0, ReliableConfig::default()),
+        );
+        gossip.update(&10).unwrap();
+        let receiver: Node<_> =
+            ReliableDelivery::new(mailboxes.clone(), 1, ReliableConfig::default());
+        let wires = mailboxes.take(1);
+        assert_eq!(
+            vec![Some(10)],
+            wires
+                .iter()
+                .map(|w| receiver.receive(w))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, gossip.delivery.in_flight());
+        for wire in mailboxes.take(0) {
+            gossip.delivery.receive(&wire);
+        }
+        assert_eq!(0, gossip.delivery.in_flight());
+    }
+
+    /// A message retransmitted because its acknowledgement was lost is acknowledged again, but
+    /// not passed on to gossip again: a primary in preferential gossip would take it for a
+    /// second sighting and pass it on to the secondaries early.
+    #[test]
+    fn lost_ack_not_seen_twice() {
+        let mailboxes = Mailboxes::default();
+        let sender: Node<_> =
+            ReliableDelivery::new(mailboxes.clone(), 0, ReliableConfig::default());
+        let mut primary = PreferentialGossip::<usize, (), _, usize>::create(
+            vec![],
+            vec![2],
+            true,
+            1,
+            (),
+            ReliableDelivery::new(mailboxes.clone(), 1, ReliableConfig::default()),
+        );
+        sender.deliver(&10, [1].iter()).unwrap();
+        let mut now = Instant::now();
+        for _ in 0..2 {
+            for wire in mailboxes.take(1) {
+                if let Some(message) = primary.tiered.delivery().receive(&wire) {
+                    primary.receive(&message).unwrap();
+                }
+            }
+            // The acknowledgement is lost.
+            assert_eq!(
+                vec![Wire::Ack {
+                    seq: 0,
+                    epoch: sender.epoch()
+                }],
+                mailboxes.take(0)
+            );
+            now += ReliableConfig::default().max_timeout;
+            sender.tick(now);
+        }
+        assert!(mailboxes.take(2).is_empty());
+        assert_eq!(2, sender.stats().retransmitted);
+    }
+
+    /// A sender that restarts starts its sequence numbers over, which doesn't make its new
+    /// messages look like retransmissions of the old ones.
+    #[test]
+    fn restarted_sender() {
+        let mailboxes = Mailboxes::default();
+        let mut nodes: [Node<_>; 2] = [0, 1]
+            .map(|me| ReliableDelivery::new(mailboxes.clone(), me, ReliableConfig::default()));
+        for message in 0..5 {
+            nodes[0].deliver(&message, [1].iter()).unwrap();
+            assert_eq!(vec![message], exchange(&mailboxes, &nodes));
+        }
+        let old_epoch = nodes[0].epoch();
+        nodes[0] = ReliableDelivery::new(mailboxes.clone(), 0, ReliableConfig::default());
+        for message in 10..12 {
+            nodes[0].deliver(&message, [1].iter()).unwrap();
+        }
+        // A late acknowledgement for the previous incarnation doesn't count for the new one.
+        nodes[0].receive(&Wire::Ack {
+            seq: 0,
+            epoch: old_epoch,
+        });
+        assert_eq!(2, nodes[0].in_flight());
+        assert_eq!(vec![10, 11], exchange(&mailboxes, &nodes));
+        exchange(&mailboxes, &nodes);
+        assert_eq!(0, nodes[0].in_flight());
+    }
+
+    #[test]
+    fn gives_up() {
+        let config = ReliableConfig {
+            max_attempts: 3,
+            ..ReliableConfig::default()
+        };
+        // Nobody ever acknowledges anything.
+        let node: Node<_> = ReliableDelivery::new(Mailboxes::default(), 0, config);
+        node.deliver(&10, [1].iter()).unwrap();
+        let mut now = Instant::now();
+        for _ in 0..5 {
+            now += config.max_timeout;
+            node.tick(now);
+        }
+        assert_eq!(0, node.in_flight());
+        assert_eq!(2, node.stats().retransmitted);
+        assert_eq!(1, node.stats().given_up);
+        assert_eq!(3, node.inner().take(1).len());
+    }
+}
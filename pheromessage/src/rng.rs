@@ -0,0 +1,51 @@
+//! Sources of randomness for gossip. Everything random in this crate (choosing peers,
+//! generating message IDs, sampling network topologies) can be given its own random number
+//! generator, so seeding those (e.g. with `StdRng::seed_from_u64()`) makes a run reproducible.
+
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+
+/// A random number generator that uses the thread-local generator (`rand::thread_rng()`).
+/// Unlike `ThreadRng`, it holds no state so it can be freely sent between threads, which makes
+/// it a good default for anything that doesn't need to be reproducible.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadLocalRng;
+
+impl RngCore for ThreadLocalRng {
+    fn next_u32(&mut self) -> u32 {
+        rand::thread_rng().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand::thread_rng().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        rand::thread_rng().try_fill_bytes(dest)
+    }
+}
+
+/// Create a new generator seeded from the given `rng`, so that a single seeded generator can
+/// deterministically seed all the nodes in a network.
+pub fn derive_rng<R: Rng + ?Sized>(rng: &mut R) -> StdRng {
+    StdRng::seed_from_u64(rng.gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_rngs_are_reproducible() {
+        let mut first = StdRng::seed_from_u64(7);
+        let mut second = StdRng::seed_from_u64(7);
+        let first: Vec<u64> = (0..3).map(|_| derive_rng(&mut first).gen()).collect();
+        let second: Vec<u64> = (0..3).map(|_| derive_rng(&mut second).gen()).collect();
+        assert_eq!(first, second);
+        // Every derived generator is different.
+        assert_ne!(first[0], first[1]);
+    }
+}
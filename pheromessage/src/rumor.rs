@@ -0,0 +1,434 @@
+//! Rumor mongering: the classic epidemic where a node keeps spreading a rumor (message) every
+//! time it hears it, until it loses interest in it according to a stopping rule, or the rumor
+//! has travelled a maximum number of hops. Compared to uniform gossip (which passes on a message
+//! exactly once), this lets us trade more redundant messages for better coverage.
+
+use std::{hash::Hash, marker::PhantomData};
+
+use rand::{Rng, RngCore};
+
+use crate::{
+    gossip, is_excluded,
+    peers::{Membership, PeerSet},
+    rng::ThreadLocalRng,
+    seen::{SeenStore, UnboundedSeenStore},
+    select::{ExcludingSelector, Exclusion, PeerSelector, UniformSelector},
+    Delivery, Gossip, Message, ReceiveFrom, SharedData,
+};
+
+/// A message that carries the number of hops it's been passed along, as needed for
+/// rumor mongering.
+pub trait HopCount: Message {
+    /// The number of times this message has been passed on from one node to another.
+    fn hops(&self) -> usize;
+
+    /// A copy of this message to pass on to the next hop.
+    fn next_hop(&self) -> Self;
+}
+
+/// The rule for when a node loses interest in spreading a rumor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StoppingRule {
+    /// Spread the rumor every time it's received, until it's been received `k` times
+    /// (`Counter(1)` spreads it only once, like uniform gossip).
+    Counter(usize),
+    /// Lose interest with probability `1/k` after every time the rumor is spread.
+    BlindCoin(usize),
+    /// Lose interest with probability `1/k` every time the rumor is received by a node that
+    /// already knew it. Since pushes aren't acknowledged, the feedback is taken on the receiving
+    /// side: the node that got a rumor it already knew is the one that loses interest.
+    Feedback(usize),
+}
+
+/// The configuration of rumor mongering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RumorConfig {
+    /// How many peers to reach out to every time a rumor is spread.
+    pub fanout: usize,
+    /// The maximum number of hops a rumor is passed along.
+    pub max_hops: usize,
+    /// When to lose interest in spreading a rumor.
+    pub stopping_rule: StoppingRule,
+}
+
+/// A gossip mechanism based on rumor mongering, where messages (`M`) have to carry their
+/// hop counts (`M: HopCount`).
+/// `L` is the store used to keep track of how often messages (by ID `I`) have been seen,
+/// `C` is the strategy used to select which peers to gossip to, and `R` is the random number
+/// generator used for the stopping rule.
+pub struct RumorGossip<
+    P,
+    S,
+    D,
+    I,
+    L = UnboundedSeenStore<I>,
+    C = UniformSelector,
+    R = ThreadLocalRng,
+> {
+    /// The set of peers.
+    peers: PeerSet<P>,
+    config: RumorConfig,
+    /// Count of how often I've seen each message by ID.
+    seen_messages: L,
+    /// The messages I lost interest in spreading.
+    lost_interest: L,
+    /// The delivery mechanism to send gossip messages.
+    delivery: D,
+    /// The data being gossipped about.
+    data: S,
+    /// The strategy for selecting which peers to gossip to.
+    selector: C,
+    rng: R,
+    _i: PhantomData<I>,
+}
+
+impl<P, S, D, I> RumorGossip<P, S, D, I>
+where
+    P: Eq + Hash + Clone,
+    I: Clone,
+{
+    /// Create a new rumor mongering gossip mechanism that will gossip to the given set of
+    /// `peers` as configured by `config`, using the given `delivery` mechanism and maintaining
+    /// the given `data`.
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
+    pub fn create(
+        peers: Vec<P>,
+        config: RumorConfig,
+        data: S,
+        delivery: D,
+    ) -> RumorGossip<P, S, D, I> {
+        RumorGossip::create_with_seen_store(
+            peers,
+            config,
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
+    }
+}
+
+impl<P, S, D, I, L> RumorGossip<P, S, D, I, L>
+where
+    P: Eq + Hash + Clone,
+    L: Clone,
+{
+    /// Create a new rumor mongering gossip mechanism like `create()`, but keeping track of how
+    /// often messages have been seen in the given (empty) `seen_messages` store. A copy of it is
+    /// used to keep track of the messages this node lost interest in.
+    pub fn create_with_seen_store(
+        peers: Vec<P>,
+        config: RumorConfig,
+        data: S,
+        delivery: D,
+        seen_messages: L,
+    ) -> RumorGossip<P, S, D, I, L> {
+        RumorGossip {
+            peers: peers.into(),
+            config,
+            lost_interest: seen_messages.clone(),
+            seen_messages,
+            delivery,
+            data,
+            selector: UniformSelector::new(),
+            rng: ThreadLocalRng,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, R> RumorGossip<P, S, D, I, L, C, R> {
+    /// Use the given `selector` to select which peers to gossip to from now on.
+    pub fn with_peer_selector<C2>(self, selector: C2) -> RumorGossip<P, S, D, I, L, C2, R> {
+        RumorGossip {
+            peers: self.peers,
+            config: self.config,
+            seen_messages: self.seen_messages,
+            lost_interest: self.lost_interest,
+            delivery: self.delivery,
+            data: self.data,
+            selector,
+            rng: self.rng,
+            _i: PhantomData,
+        }
+    }
+
+    /// Use the given `rng` for the stopping rule from now on.
+    pub fn with_rng<R2>(self, rng: R2) -> RumorGossip<P, S, D, I, L, C, R2> {
+        RumorGossip {
+            peers: self.peers,
+            config: self.config,
+            seen_messages: self.seen_messages,
+            lost_interest: self.lost_interest,
+            delivery: self.delivery,
+            data: self.data,
+            selector: self.selector,
+            rng,
+            _i: PhantomData,
+        }
+    }
+
+    /// The configuration of the rumor mongering.
+    pub fn config(&self) -> &RumorConfig {
+        &self.config
+    }
+
+    /// The peers gossipped to.
+    pub fn peers(&self) -> &[P] {
+        self.peers.as_slice()
+    }
+
+    /// The store keeping track of how often messages have been seen.
+    pub fn seen_messages(&self) -> &L {
+        &self.seen_messages
+    }
+
+    /// Spread the given message to the next hop (if it hasn't gone too far already), except
+    /// to any `excluded` peers.
+    fn spread<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        M: HopCount,
+        D: Delivery<M, P>,
+        C: PeerSelector<P>,
+        P: Clone,
+    {
+        if message.hops() >= self.config.max_hops {
+            return Ok(());
+        }
+        gossip(
+            &self.delivery,
+            &mut ExcludingSelector::new(&mut self.selector, excluded),
+            &message.next_hop(),
+            self.peers.as_slice(),
+            self.config.fanout,
+        )
+    }
+
+    /// Receive the given message from a peer, not spreading it to any `excluded` peers.
+    fn receive_excluding<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        M: HopCount<I = I>,
+        D: Delivery<M, P>,
+        L: SeenStore<I>,
+        S: SharedData<M>,
+        C: PeerSelector<P>,
+        R: RngCore,
+        P: Clone,
+    {
+        let id = message.id();
+        if self.lost_interest.contains(&id) {
+            return Ok(());
+        }
+        let count_seen = self.seen_messages.see(message.id());
+        if count_seen == 1 {
+            self.data.update(message);
+        }
+        let lose_interest = match self.config.stopping_rule {
+            StoppingRule::Counter(k) => count_seen > k,
+            StoppingRule::Feedback(k) => count_seen > 1 && self.rng.gen_ratio(1, k.max(1) as u32),
+            StoppingRule::BlindCoin(_) => false,
+        };
+        if lose_interest {
+            self.lost_interest.see(id);
+            return Ok(());
+        }
+        self.spread(message, excluded)?;
+        if let StoppingRule::BlindCoin(k) = self.config.stopping_rule {
+            if self.rng.gen_ratio(1, k.max(1) as u32) {
+                self.lost_interest.see(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P, S, D, M, I, L, C, R> Gossip<M, S> for RumorGossip<P, S, D, I, L, C, R>
+where
+    M: HopCount<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    R: RngCore,
+    P: Clone,
+{
+    type Error = D::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.receive_excluding(message, None)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.data.update(message);
+        self.seen_messages.see(message.id());
+        self.spread(message, None)
+    }
+
+    fn data(&self) -> &S {
+        &self.data
+    }
+}
+
+impl<P, S, D, M, I, L, C, R, Q> ReceiveFrom<M, S, Q> for RumorGossip<P, S, D, I, L, C, R>
+where
+    M: HopCount<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    R: RngCore,
+    P: PartialEq<Q> + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.receive_excluding(message, Some(&|p: &P| is_excluded(p, sender, excluded)))
+    }
+}
+
+impl<P, S, D, I, L, C, R> RumorGossip<P, S, D, I, L, C, R>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Start gossipping to the given peer. Returns `false` if it was already a peer.
+    pub fn add_peer(&mut self, peer: P) -> bool {
+        self.peers.insert(peer)
+    }
+
+    /// Stop gossipping to the given peer. Returns `false` if it wasn't a peer.
+    pub fn remove_peer(&mut self, peer: &P) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+}
+
+impl<P, S, D, I, L, C, R> Membership<P> for RumorGossip<P, S, D, I, L, C, R>
+where
+    P: Eq + Hash + Clone,
+{
+    fn join(&mut self, peer: P) {
+        self.add_peer(peer);
+    }
+
+    fn leave(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    /// A message with an ID and a hop count.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Rumor {
+        id: usize,
+        hops: usize,
+    }
+
+    impl Message for Rumor {
+        type I = usize;
+
+        fn id(&self) -> usize {
+            self.id
+        }
+    }
+
+    impl HopCount for Rumor {
+        fn hops(&self) -> usize {
+            self.hops
+        }
+
+        fn next_hop(&self) -> Self {
+            Rumor {
+                id: self.id,
+                hops: self.hops + 1,
+            }
+        }
+    }
+
+    impl SharedData<Rumor> for () {
+        fn update(&mut self, _message: &Rumor) {}
+    }
+
+    /// Records every message sent as (endpoint, hops).
+    struct Sent(RefCell<Vec<(usize, usize)>>);
+
+    impl Delivery<Rumor, usize> for Sent {
+        type Error = ();
+
+        fn deliver<'a, I>(&self, message: &Rumor, endpoints: I) -> Result<(), ()>
+        where
+            I: ExactSizeIterator<Item = &'a usize>,
+        {
+            for endpoint in endpoints {
+                self.0.borrow_mut().push((*endpoint, message.hops));
+            }
+            Ok(())
+        }
+    }
+
+    fn node(stopping_rule: StoppingRule, max_hops: usize) -> RumorGossip<usize, (), Sent, usize> {
+        RumorGossip::create(
+            vec![1, 2],
+            RumorConfig {
+                fanout: 2,
+                max_hops,
+                stopping_rule,
+            },
+            (),
+            Sent(RefCell::new(Vec::new())),
+        )
+    }
+
+    fn sent(gossip: &RumorGossip<usize, (), Sent, usize>) -> usize {
+        gossip.delivery.0.borrow().len()
+    }
+
+    #[test]
+    fn counter() {
+        let mut gossip = node(StoppingRule::Counter(2), 10);
+        let rumor = Rumor { id: 5, hops: 3 };
+        gossip.receive(&rumor).unwrap();
+        assert_eq!(vec![(1, 4), (2, 4)], {
+            let mut sent = gossip.delivery.0.borrow().clone();
+            sent.sort();
+            sent
+        });
+        // Spread again the second time, then lose interest.
+        gossip.receive(&rumor).unwrap();
+        assert_eq!(4, sent(&gossip));
+        gossip.receive(&rumor).unwrap();
+        gossip.receive(&rumor).unwrap();
+        assert_eq!(4, sent(&gossip));
+    }
+
+    #[test]
+    fn hop_limit() {
+        let mut gossip = node(StoppingRule::Counter(10), 3);
+        gossip.receive(&Rumor { id: 5, hops: 3 }).unwrap();
+        assert_eq!(0, sent(&gossip));
+        gossip.update(&Rumor { id: 6, hops: 0 }).unwrap();
+        assert_eq!(2, sent(&gossip));
+    }
+
+    #[test]
+    fn coins_eventually_lose_interest() {
+        for rule in [StoppingRule::BlindCoin(2), StoppingRule::Feedback(2)] {
+            let mut gossip = node(rule, 100).with_rng(StdRng::seed_from_u64(7));
+            let rumor = Rumor { id: 5, hops: 0 };
+            for _ in 0..100 {
+                gossip.receive(&rumor).unwrap();
+            }
+            assert!(gossip.lost_interest.contains(&5), "{rule:?}");
+            // With 1/2 chance of losing interest on every round, it shouldn't take long.
+            assert!(gossip.delivery.0.borrow().len() < 60, "{rule:?}");
+        }
+    }
+}
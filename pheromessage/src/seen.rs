@@ -0,0 +1,494 @@
+//! Stores that keep track of which messages a gossip node has seen (and how often),
+//! with different strategies for bounding how much memory that takes.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    mem,
+    time::{Duration, Instant},
+};
+
+/// A store of the IDs (`I`) of messages seen by a gossip node, used to suppress duplicates.
+pub trait SeenStore<I> {
+    /// Record that the message with the given ID has been seen, and return how many times
+    /// it has been seen so far (including this time).
+    fn see(&mut self, id: I) -> usize;
+
+    /// Checks if the message with the given ID has been seen (and not forgotten since).
+    fn contains(&self, id: &I) -> bool;
+
+    /// The number of message IDs currently remembered.
+    fn len(&self) -> usize;
+
+    /// Checks if no message IDs are currently remembered.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The highest count `see()` returns, if counts saturate (past it, a message keeps being
+    /// reported as seen that many times). `None` means counts never saturate.
+    fn max_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A seen store that can list the message IDs it remembers (e.g. to hand them over to a node
+/// joining the network, so it doesn't take old messages for new ones).
+pub trait SeenIds<I>: SeenStore<I> {
+    /// The message IDs currently remembered, in no particular order.
+    fn seen_ids(&self) -> Vec<I>;
+}
+
+/// A seen store that remembers every message ID forever. This is the simplest and most
+/// accurate store, but its memory grows with every new message.
+#[derive(Debug, Clone)]
+pub struct UnboundedSeenStore<I> {
+    counts: HashMap<I, usize>,
+}
+
+impl<I> Default for UnboundedSeenStore<I> {
+    fn default() -> Self {
+        Self {
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl<I> SeenStore<I> for UnboundedSeenStore<I>
+where
+    I: Eq + Hash,
+{
+    fn see(&mut self, id: I) -> usize {
+        let count = self.counts.entry(id).or_default();
+        *count += 1;
+        *count
+    }
+
+    fn contains(&self, id: &I) -> bool {
+        self.counts.contains_key(id)
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+impl<I> SeenIds<I> for UnboundedSeenStore<I>
+where
+    I: Eq + Hash + Clone,
+{
+    fn seen_ids(&self) -> Vec<I> {
+        self.counts.keys().cloned().collect()
+    }
+}
+
+/// A seen store that remembers at most `capacity` message IDs, forgetting the least
+/// recently seen ones first.
+#[derive(Debug, Clone)]
+pub struct LruSeenStore<I> {
+    /// The maximum number of IDs to remember.
+    capacity: usize,
+    /// For every remembered ID: how many times it's been seen, and the stamp of the last time.
+    counts: HashMap<I, (usize, u64)>,
+    /// The order in which IDs were seen. An entry is stale (and skipped) if its stamp is
+    /// older than the one in `counts`.
+    order: VecDeque<(I, u64)>,
+    /// The stamp to give to the next sighting.
+    next_stamp: u64,
+}
+
+impl<I> LruSeenStore<I> {
+    /// Create a new store that remembers up to `capacity` message IDs.
+    pub fn new(capacity: usize) -> LruSeenStore<I> {
+        LruSeenStore {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            next_stamp: 0,
+        }
+    }
+}
+
+impl<I> SeenStore<I> for LruSeenStore<I>
+where
+    I: Eq + Hash + Clone,
+{
+    fn see(&mut self, id: I) -> usize {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        let entry = self.counts.entry(id.clone()).or_insert((0, stamp));
+        entry.0 += 1;
+        entry.1 = stamp;
+        let count = entry.0;
+        self.order.push_back((id, stamp));
+        // Forget the least recently seen IDs until we're within capacity.
+        while self.counts.len() > self.capacity {
+            match self.order.pop_front() {
+                Some((old, old_stamp)) => {
+                    if self.counts.get(&old).map(|e| e.1) == Some(old_stamp) {
+                        self.counts.remove(&old);
+                    }
+                }
+                None => break,
+            }
+        }
+        // Repeated sightings leave stale entries in the order, so compact it once
+        // it gets too big to keep memory bounded.
+        if self.order.len() > 2 * self.capacity.max(1) {
+            let counts = &self.counts;
+            self.order
+                .retain(|(id, stamp)| counts.get(id).map(|e| e.1) == Some(*stamp));
+        }
+        count
+    }
+
+    fn contains(&self, id: &I) -> bool {
+        self.counts.contains_key(id)
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+impl<I> SeenIds<I> for LruSeenStore<I>
+where
+    I: Eq + Hash + Clone,
+{
+    fn seen_ids(&self) -> Vec<I> {
+        self.counts.keys().cloned().collect()
+    }
+}
+
+/// A seen store that forgets message IDs a fixed time (`ttl`) after they were first seen.
+#[derive(Debug, Clone)]
+pub struct TtlSeenStore<I> {
+    /// How long to remember every ID.
+    ttl: Duration,
+    /// For every remembered ID: how many times it's been seen, and when it was first seen.
+    counts: HashMap<I, (usize, Instant)>,
+    /// The IDs in the order they were first seen.
+    order: VecDeque<(I, Instant)>,
+}
+
+impl<I> TtlSeenStore<I> {
+    /// Create a new store that remembers every message ID for the given `ttl`.
+    pub fn new(ttl: Duration) -> TtlSeenStore<I> {
+        TtlSeenStore {
+            ttl,
+            counts: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Record that the message with the given ID has been seen at the given time. This is
+    /// `see()` with an explicit clock, and times given should never go backwards.
+    pub fn see_at(&mut self, id: I, now: Instant) -> usize
+    where
+        I: Eq + Hash + Clone,
+    {
+        self.expire(now);
+        let entry = self.counts.entry(id.clone()).or_insert((0, now));
+        if entry.0 == 0 {
+            self.order.push_back((id, now));
+        }
+        entry.0 += 1;
+        entry.0
+    }
+
+    /// Checks if the message with the given ID has been seen and not expired by the given time.
+    pub fn contains_at(&self, id: &I, now: Instant) -> bool
+    where
+        I: Eq + Hash,
+    {
+        match self.counts.get(id) {
+            Some((_, first_seen)) => now.saturating_duration_since(*first_seen) < self.ttl,
+            None => false,
+        }
+    }
+
+    /// Forget all the IDs that have expired by the given time.
+    pub fn expire(&mut self, now: Instant)
+    where
+        I: Eq + Hash,
+    {
+        while let Some((_, first_seen)) = self.order.front() {
+            if now.saturating_duration_since(*first_seen) < self.ttl {
+                break;
+            }
+            let (id, _) = self.order.pop_front().unwrap();
+            self.counts.remove(&id);
+        }
+    }
+}
+
+impl<I> SeenStore<I> for TtlSeenStore<I>
+where
+    I: Eq + Hash + Clone,
+{
+    fn see(&mut self, id: I) -> usize {
+        self.see_at(id, Instant::now())
+    }
+
+    fn contains(&self, id: &I) -> bool {
+        self.contains_at(id, Instant::now())
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+impl<I> SeenIds<I> for TtlSeenStore<I>
+where
+    I: Eq + Hash + Clone,
+{
+    fn seen_ids(&self) -> Vec<I> {
+        let now = Instant::now();
+        self.counts
+            .keys()
+            .filter(|id| self.contains_at(id, now))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A seen store that keeps two generations of message IDs: the current one and the previous one.
+/// Once the current generation fills up to `generation_size` IDs, the previous one is forgotten and
+/// the current one takes its place. An ID seen again while in the previous generation is carried
+/// over to the current one, so IDs are remembered for at least `generation_size` new messages
+/// after they were last seen, and at most `2 * generation_size` IDs are remembered at any time.
+#[derive(Debug, Clone)]
+pub struct GenerationalSeenStore<I> {
+    generation_size: usize,
+    current: HashMap<I, usize>,
+    previous: HashMap<I, usize>,
+}
+
+impl<I> GenerationalSeenStore<I> {
+    /// Create a new store that rotates generations every `generation_size` new IDs.
+    pub fn new(generation_size: usize) -> GenerationalSeenStore<I> {
+        GenerationalSeenStore {
+            generation_size,
+            current: HashMap::with_capacity(generation_size),
+            previous: HashMap::new(),
+        }
+    }
+}
+
+impl<I> SeenStore<I> for GenerationalSeenStore<I>
+where
+    I: Eq + Hash,
+{
+    fn see(&mut self, id: I) -> usize {
+        let previous_count = self.previous.remove(&id).unwrap_or_default();
+        let count = self.current.entry(id).or_insert(previous_count);
+        *count += 1;
+        let count = *count;
+        if self.current.len() >= self.generation_size {
+            self.previous = mem::replace(
+                &mut self.current,
+                HashMap::with_capacity(self.generation_size),
+            );
+        }
+        count
+    }
+
+    fn contains(&self, id: &I) -> bool {
+        self.current.contains_key(id) || self.previous.contains_key(id)
+    }
+
+    fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+}
+
+impl<I> SeenIds<I> for GenerationalSeenStore<I>
+where
+    I: Eq + Hash + Clone,
+{
+    fn seen_ids(&self) -> Vec<I> {
+        self.current
+            .keys()
+            .chain(self.previous.keys())
+            .cloned()
+            .collect()
+    }
+}
+
+/// A log of the latest messages (`M`) seen by a gossip node by ID (`I`), for when the messages
+/// themselves have to be passed on again later (e.g. to peers that missed them). It keeps at
+/// most `capacity` messages, forgetting the oldest ones first.
+#[derive(Debug, Clone)]
+pub struct MessageLog<I, M> {
+    /// The maximum number of messages to keep.
+    capacity: usize,
+    /// The messages by ID.
+    messages: HashMap<I, M>,
+    /// The IDs of the messages, oldest first.
+    order: VecDeque<I>,
+}
+
+impl<I, M> MessageLog<I, M> {
+    /// Create a new log that keeps up to `capacity` messages.
+    pub fn new(capacity: usize) -> MessageLog<I, M> {
+        MessageLog {
+            capacity,
+            messages: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The IDs of the messages in the log, oldest first.
+    pub fn ids(&self) -> impl Iterator<Item = &I> {
+        self.order.iter()
+    }
+
+    /// The number of messages in the log.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Checks if the log has no messages.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+impl<I, M> MessageLog<I, M>
+where
+    I: Eq + Hash + Clone,
+{
+    /// Add the given message to the log, forgetting the oldest one if the log is full.
+    /// Returns `false` if it was already there.
+    pub fn insert(&mut self, id: I, message: M) -> bool {
+        if self.capacity == 0 || self.messages.contains_key(&id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.clone());
+        self.messages.insert(id, message);
+        true
+    }
+
+    /// The message with the given ID, if it's in the log.
+    pub fn get(&self, id: &I) -> Option<&M> {
+        self.messages.get(id)
+    }
+
+    /// Checks if the message with the given ID is in the log.
+    pub fn contains(&self, id: &I) -> bool {
+        self.messages.contains_key(id)
+    }
+
+    /// The messages in the log, oldest first.
+    pub fn messages(&self) -> impl Iterator<Item = &M> {
+        self.order.iter().map(|id| &self.messages[id])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sees a long stream of distinct IDs, with every ID repeated shortly after it's first seen,
+    /// and checks that the repeat is recognized and the store stays within `max_len`.
+    fn long_stream<L: SeenStore<u64>>(store: &mut L, max_len: usize) {
+        for i in 0..10_000 {
+            assert_eq!(1, store.see(i));
+            if i >= 10 {
+                assert_eq!(2, store.see(i - 10));
+            }
+            assert!(store.len() <= max_len);
+        }
+        assert!(store.contains(&9_999));
+        assert!(!store.contains(&0));
+    }
+
+    #[test]
+    fn unbounded() {
+        let mut store = UnboundedSeenStore::default();
+        assert_eq!(1, store.see(5));
+        assert_eq!(2, store.see(5));
+        assert_eq!(3, store.see(5));
+        assert!(store.contains(&5));
+        assert!(!store.contains(&6));
+        assert_eq!(1, store.len());
+    }
+
+    #[test]
+    fn lru_stays_bounded() {
+        long_stream(&mut LruSeenStore::new(100), 100);
+    }
+
+    #[test]
+    fn lru_keeps_recently_seen() {
+        let mut store = LruSeenStore::new(2);
+        store.see(1);
+        store.see(2);
+        // Seeing 1 again makes 2 the least recently seen, so it's the one to go.
+        store.see(1);
+        store.see(3);
+        assert!(store.contains(&1));
+        assert!(!store.contains(&2));
+        assert!(store.contains(&3));
+    }
+
+    #[test]
+    fn generational_stays_bounded() {
+        long_stream(&mut GenerationalSeenStore::new(100), 200);
+    }
+
+    #[test]
+    fn ttl_stays_bounded() {
+        let mut store = TtlSeenStore::new(Duration::from_millis(100));
+        let start = Instant::now();
+        for i in 0..10_000 {
+            // One new message every millisecond.
+            let now = start + Duration::from_millis(i);
+            assert_eq!(1, store.see_at(i, now));
+            if i >= 10 {
+                assert_eq!(2, store.see_at(i - 10, now));
+            }
+            assert!(store.len() <= 100);
+        }
+        let end = start + Duration::from_millis(10_000);
+        assert!(store.contains_at(&9_999, end));
+        assert!(!store.contains_at(&0, end));
+        assert!(!store.contains_at(&9_900, end));
+    }
+
+    #[test]
+    fn seen_ids() {
+        let mut store = LruSeenStore::new(2);
+        for i in 0..3 {
+            store.see(i);
+        }
+        let mut ids = store.seen_ids();
+        ids.sort();
+        assert_eq!(vec![1, 2], ids);
+        let mut store = GenerationalSeenStore::new(2);
+        for i in 0..3 {
+            store.see(i);
+        }
+        let mut ids = store.seen_ids();
+        ids.sort();
+        assert_eq!(vec![0, 1, 2], ids);
+    }
+
+    #[test]
+    fn message_log_stays_bounded() {
+        let mut log = MessageLog::new(3);
+        for i in 0..5 {
+            assert!(log.insert(i, i * 10));
+        }
+        assert!(!log.insert(4, 0));
+        assert_eq!(vec![2, 3, 4], log.ids().copied().collect::<Vec<_>>());
+        assert_eq!(Some(&40), log.get(&4));
+        assert!(!log.contains(&1));
+    }
+}
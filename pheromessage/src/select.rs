@@ -0,0 +1,455 @@
+//! Strategies for selecting which peers to gossip a message to.
+
+use rand::{prelude::*, seq::index::sample};
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use crate::rng::ThreadLocalRng;
+
+/// A strategy for selecting which peers (`P`) to gossip a message to.
+pub trait PeerSelector<P> {
+    /// Select up to `fanout` distinct peers from the given `peers`, returning their indices
+    /// in `peers`. If there are `fanout` peers or less, all of them should be selected.
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize>;
+}
+
+/// Selects a uniformly random subset of peers every time, using the random number
+/// generator `R`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformSelector<R = ThreadLocalRng> {
+    rng: R,
+}
+
+impl UniformSelector {
+    /// Create a new selector that uses the thread-local random number generator.
+    pub fn new() -> UniformSelector {
+        UniformSelector::with_rng(ThreadLocalRng)
+    }
+}
+
+impl<R> UniformSelector<R> {
+    /// Create a new selector that uses the given `rng` to select peers.
+    pub fn with_rng(rng: R) -> UniformSelector<R> {
+        UniformSelector { rng }
+    }
+}
+
+impl<P, R> PeerSelector<P> for UniformSelector<R>
+where
+    R: RngCore,
+{
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize> {
+        sample(&mut self.rng, peers.len(), fanout.min(peers.len())).into_vec()
+    }
+}
+
+/// Selects peers in order, continuing from where the last selection stopped and wrapping
+/// around at the end, so every peer gets selected equally often.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundRobinSelector {
+    /// The index of the next peer to select.
+    next: usize,
+}
+
+impl<P> PeerSelector<P> for RoundRobinSelector {
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize> {
+        if peers.is_empty() {
+            return Vec::new();
+        }
+        let start = self.next % peers.len();
+        let count = fanout.min(peers.len());
+        self.next = (start + count) % peers.len();
+        (start..start + count).map(|i| i % peers.len()).collect()
+    }
+}
+
+/// Selects a random subset of peers, where the chance of every peer being selected is
+/// proportional to its score as given by the `score` function (a peer with a score of `0`
+/// will never be selected).
+#[derive(Debug, Clone, Copy)]
+pub struct WeightedSelector<F, R = ThreadLocalRng> {
+    score: F,
+    rng: R,
+}
+
+impl<F> WeightedSelector<F> {
+    /// Create a new selector that weighs peers by the given `score` function.
+    pub fn new(score: F) -> WeightedSelector<F> {
+        WeightedSelector::with_rng(score, ThreadLocalRng)
+    }
+}
+
+impl<F, R> WeightedSelector<F, R> {
+    /// Create a new selector like `new()`, but using the given `rng` to select peers.
+    pub fn with_rng(score: F, rng: R) -> WeightedSelector<F, R> {
+        WeightedSelector { score, rng }
+    }
+}
+
+impl<P, F, R> PeerSelector<P> for WeightedSelector<F, R>
+where
+    F: Fn(&P) -> f64,
+    R: RngCore,
+{
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize> {
+        let indices: Vec<_> = (0..peers.len())
+            .filter(|&i| (self.score)(&peers[i]) > 0.0)
+            .collect();
+        match indices.choose_multiple_weighted(&mut self.rng, fanout, |&i| (self.score)(&peers[i]))
+        {
+            Ok(chosen) => chosen.copied().collect(),
+            // The only possible errors are for invalid weights (negative/NaN/infinite),
+            // so fall back to ignoring the weights, but still only among the peers with a score.
+            Err(_) => sample(&mut self.rng, indices.len(), fanout.min(indices.len()))
+                .into_iter()
+                .map(|i| indices[i])
+                .collect(),
+        }
+    }
+}
+
+/// How many selections a peer can be missing from the peers to select from before
+/// `LeastRecentlyContactedSelector` forgets it. Not right away, since a peer can be left out
+/// of a few selections without being gone (e.g. when it sent the message being passed on).
+const FORGET_AFTER: u64 = 100;
+
+/// Selects the peers that were least recently selected (with never selected peers first).
+/// Unlike `RoundRobinSelector`, this keeps track of peers by identity rather than position,
+/// so it stays fair as the set of peers changes. Peers that stop being passed in are
+/// forgotten after a while, so departed peers don't pile up.
+#[derive(Debug, Clone)]
+pub struct LeastRecentlyContactedSelector<P> {
+    /// The last time (as a count of selections) every peer was selected, and the last time it
+    /// was among the peers to select from.
+    last_contacted: HashMap<P, (u64, u64)>,
+    /// The number of selections done so far.
+    selections: u64,
+}
+
+impl<P> Default for LeastRecentlyContactedSelector<P> {
+    fn default() -> Self {
+        Self {
+            last_contacted: HashMap::new(),
+            selections: 0,
+        }
+    }
+}
+
+impl<P> PeerSelector<P> for LeastRecentlyContactedSelector<P>
+where
+    P: Eq + Hash + Clone,
+{
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize> {
+        self.selections += 1;
+        let selections = self.selections;
+        for peer in peers {
+            if let Some((_, last_passed)) = self.last_contacted.get_mut(peer) {
+                *last_passed = selections;
+            }
+        }
+        self.last_contacted
+            .retain(|_, (_, last_passed)| selections - *last_passed <= FORGET_AFTER);
+        let mut indices: Vec<_> = (0..peers.len()).collect();
+        let count = fanout.min(peers.len());
+        if count < peers.len() {
+            let last_contacted =
+                |i: &usize| self.last_contacted.get(&peers[*i]).map(|(last, _)| *last);
+            indices.select_nth_unstable_by_key(count, last_contacted);
+            indices.truncate(count);
+        }
+        for &i in indices.iter() {
+            self.last_contacted
+                .insert(peers[i].clone(), (selections, selections));
+        }
+        indices
+    }
+}
+
+/// Selects peers by their (estimated) latency: a tunable fraction of every selection goes to
+/// the nearest peers, and the rest to random peers among the farther ones, trading off fast
+/// delivery against spreading messages across the whole network. Peers with no latency
+/// estimate are considered the farthest.
+#[derive(Debug, Clone)]
+pub struct LatencySelector<P, R = ThreadLocalRng> {
+    /// The latency estimate of every known peer.
+    latencies: HashMap<P, Duration>,
+    /// The fraction of every selection that goes to the nearest peers.
+    near_fraction: f64,
+    rng: R,
+}
+
+impl<P> LatencySelector<P> {
+    /// Create a new selector that selects `near_fraction` (between `0` and `1`) of the peers
+    /// from the nearest ones, using the thread-local random number generator for the rest.
+    pub fn new(near_fraction: f64) -> LatencySelector<P> {
+        LatencySelector::with_rng(near_fraction, ThreadLocalRng)
+    }
+}
+
+impl<P, R> LatencySelector<P, R> {
+    /// Create a new selector like `new()`, but using the given `rng` to select peers.
+    pub fn with_rng(near_fraction: f64, rng: R) -> LatencySelector<P, R> {
+        assert!(
+            (0.0..=1.0).contains(&near_fraction),
+            "The near fraction has to be between 0 and 1"
+        );
+        LatencySelector {
+            latencies: HashMap::new(),
+            near_fraction,
+            rng,
+        }
+    }
+
+    /// The fraction of every selection that goes to the nearest peers.
+    pub fn near_fraction(&self) -> f64 {
+        self.near_fraction
+    }
+}
+
+impl<P, R> LatencySelector<P, R>
+where
+    P: Eq + Hash,
+{
+    /// Set the latency estimate of the given peer.
+    pub fn set_latency(&mut self, peer: P, latency: Duration) {
+        self.latencies.insert(peer, latency);
+    }
+
+    /// Update the latency estimate of the given peer with a new measured sample, as an
+    /// exponentially weighted moving average (where the new sample weighs 1/8, like TCP's
+    /// round trip time estimate). The first sample becomes the estimate.
+    pub fn observe_latency(&mut self, peer: P, sample: Duration) {
+        self.latencies
+            .entry(peer)
+            .and_modify(|estimate| *estimate = (*estimate * 7 + sample) / 8)
+            .or_insert(sample);
+    }
+
+    /// The latency estimate of the given peer, if there is one.
+    pub fn latency(&self, peer: &P) -> Option<Duration> {
+        self.latencies.get(peer).copied()
+    }
+
+    /// Forget the latency estimate of the given peer (e.g. when it leaves).
+    pub fn remove(&mut self, peer: &P) -> Option<Duration> {
+        self.latencies.remove(peer)
+    }
+}
+
+impl<P, R> PeerSelector<P> for LatencySelector<P, R>
+where
+    P: Eq + Hash,
+    R: RngCore,
+{
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize> {
+        let count = fanout.min(peers.len());
+        let near = (count as f64 * self.near_fraction).round() as usize;
+        // Shuffle before the (stable) sort, so peers at the same latency are chosen at random.
+        let mut indices: Vec<_> = (0..peers.len()).collect();
+        indices.shuffle(&mut self.rng);
+        indices.sort_by_key(|&i| self.latency(&peers[i]).unwrap_or(Duration::MAX));
+        let far = indices.split_off(near);
+        indices.extend(
+            sample(&mut self.rng, far.len(), count - near)
+                .into_iter()
+                .map(|i| far[i]),
+        );
+        indices
+    }
+}
+
+/// The peers (`P`) a message shouldn't be passed on to (e.g. the peer it came from), if any.
+pub(crate) type Exclusion<'a, P> = Option<&'a dyn Fn(&P) -> bool>;
+
+/// A selector that never selects excluded peers, by having the underlying selector select
+/// only from the peers that aren't excluded.
+pub(crate) struct ExcludingSelector<'a, C, P> {
+    selector: &'a mut C,
+    excluded: Exclusion<'a, P>,
+}
+
+impl<'a, C, P> ExcludingSelector<'a, C, P> {
+    /// Select peers with the given `selector`, except the `excluded` ones.
+    pub(crate) fn new(selector: &'a mut C, excluded: Exclusion<'a, P>) -> Self {
+        ExcludingSelector { selector, excluded }
+    }
+}
+
+impl<C, P> PeerSelector<P> for ExcludingSelector<'_, C, P>
+where
+    C: PeerSelector<P>,
+    P: Clone,
+{
+    fn select(&mut self, peers: &[P], fanout: usize) -> Vec<usize> {
+        let Some(excluded) = self.excluded else {
+            return self.selector.select(peers, fanout);
+        };
+        let allowed: Vec<_> = (0..peers.len()).filter(|&i| !excluded(&peers[i])).collect();
+        if allowed.len() == peers.len() {
+            return self.selector.select(peers, fanout);
+        }
+        // Select from the allowed peers only, so the underlying selector never sees (or
+        // accounts for) the excluded ones, then map back to indices in `peers`.
+        let allowed_peers: Vec<_> = allowed.iter().map(|&i| peers[i].clone()).collect();
+        self.selector
+            .select(&allowed_peers, fanout)
+            .into_iter()
+            .map(|i| allowed[i])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, collections::HashSet};
+
+    use super::*;
+
+    /// Check that the selection is a set of `expected_len` distinct valid indices.
+    fn check_selection(selection: &[usize], num_peers: usize, expected_len: usize) {
+        assert_eq!(expected_len, selection.len());
+        assert!(selection.iter().all(|&i| i < num_peers));
+        assert_eq!(expected_len, selection.iter().collect::<HashSet<_>>().len());
+    }
+
+    #[test]
+    fn uniform() {
+        let peers: Vec<_> = (0..10).collect();
+        let mut selector = UniformSelector::new();
+        check_selection(&selector.select(&peers, 4), 10, 4);
+        check_selection(&selector.select(&peers, 20), 10, 10);
+    }
+
+    #[test]
+    fn seeded_uniform() {
+        let peers: Vec<_> = (0..100).collect();
+        let mut first = UniformSelector::with_rng(StdRng::seed_from_u64(3));
+        let mut second = UniformSelector::with_rng(StdRng::seed_from_u64(3));
+        for _ in 0..10 {
+            assert_eq!(first.select(&peers, 5), second.select(&peers, 5));
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let peers = [10, 11, 12, 13, 14];
+        let mut selector = RoundRobinSelector::default();
+        assert_eq!(vec![0, 1, 2], selector.select(&peers, 3));
+        assert_eq!(vec![3, 4, 0], selector.select(&peers, 3));
+        assert_eq!(vec![1, 2, 3, 4, 0], selector.select(&peers, 7));
+    }
+
+    #[test]
+    fn weighted() {
+        let peers: Vec<usize> = (0..10).collect();
+        // Only even peers have a score.
+        let mut selector =
+            WeightedSelector::new(|p: &usize| if p.is_multiple_of(2) { 1.0 } else { 0.0 });
+        for _ in 0..10 {
+            let selection = selector.select(&peers, 3);
+            check_selection(&selection, 10, 3);
+            assert!(selection.iter().all(|i| i.is_multiple_of(2)));
+        }
+        // Never select more than the peers with a score.
+        check_selection(&selector.select(&peers, 8), 10, 5);
+        // Scores turning invalid fall back to a uniform selection, still only among the peers
+        // that had a score.
+        let calls = Cell::new(0);
+        let mut selector = WeightedSelector::new(|p: &usize| {
+            calls.set(calls.get() + 1);
+            match (calls.get() > peers.len(), *p < 3) {
+                (true, _) => f64::NAN,
+                (false, true) => 1.0,
+                (false, false) => 0.0,
+            }
+        });
+        let mut selection = selector.select(&peers, 5);
+        selection.sort();
+        assert_eq!(vec![0, 1, 2], selection);
+    }
+
+    #[test]
+    fn least_recently_contacted() {
+        let peers = ["a", "b", "c", "d"];
+        let mut selector = LeastRecentlyContactedSelector::default();
+        let first = selector.select(&peers, 2);
+        check_selection(&first, 4, 2);
+        // The next selection has to be the two that weren't contacted yet.
+        let mut second = selector.select(&peers, 2);
+        second.extend(first);
+        check_selection(&second, 4, 4);
+        // Peers keep being tracked by identity even as they move around.
+        let peers = ["e", "d", "c", "b", "a"];
+        assert_eq!(vec![0], selector.select(&peers, 1));
+    }
+
+    #[test]
+    fn least_recently_contacted_forgets_departed_peers() {
+        let mut selector = LeastRecentlyContactedSelector::default();
+        selector.select(&["a", "b", "c"], 3);
+        // Left out of a single selection isn't gone.
+        selector.select(&["b", "c"], 1);
+        assert!(selector.last_contacted.contains_key("a"));
+        for _ in 0..FORGET_AFTER {
+            selector.select(&["b", "c"], 1);
+        }
+        assert!(!selector.last_contacted.contains_key("a"));
+        assert_eq!(2, selector.last_contacted.len());
+    }
+
+    #[test]
+    fn excluding() {
+        let peers: Vec<usize> = (0..5).collect();
+        let mut uniform = UniformSelector::new();
+        let excluded = |p: &usize| *p < 2;
+        let mut selector = ExcludingSelector::new(&mut uniform, Some(&excluded));
+        for _ in 0..10 {
+            let mut selection = selector.select(&peers, 3);
+            selection.sort();
+            assert_eq!(vec![2, 3, 4], selection);
+        }
+        // Never more than the peers that aren't excluded.
+        check_selection(&selector.select(&peers, 5), 5, 3);
+        let mut selector = ExcludingSelector::new(&mut uniform, None);
+        check_selection(&selector.select(&peers, 5), 5, 5);
+    }
+
+    #[test]
+    fn excluding_keeps_excluded_peers_out_of_selector_state() {
+        let peers = ["a", "b", "c", "d"];
+        let mut least_recent = LeastRecentlyContactedSelector::default();
+        let excluded = |p: &&str| *p == "a";
+        let mut selected: Vec<_> = (0..3)
+            .flat_map(|_| {
+                ExcludingSelector::new(&mut least_recent, Some(&excluded)).select(&peers, 1)
+            })
+            .collect();
+        selected.sort();
+        assert_eq!(vec![1, 2, 3], selected);
+        // The excluded peer was never contacted, so it's the least recently contacted one now.
+        assert_eq!(vec![0], least_recent.select(&peers, 1));
+    }
+
+    #[test]
+    fn latency() {
+        let peers: Vec<usize> = (0..10).collect();
+        let mut selector = LatencySelector::with_rng(0.5, StdRng::seed_from_u64(5));
+        for &peer in &peers {
+            selector.set_latency(peer, Duration::from_millis(peer as u64));
+        }
+        for _ in 0..10 {
+            let selection = selector.select(&peers, 4);
+            check_selection(&selection, 10, 4);
+            // Half the selection is the nearest peers, the rest is random among the others.
+            assert_eq!(&[0, 1], &selection[..2]);
+        }
+        // Only the nearest peers.
+        let mut selector = LatencySelector::new(1.0);
+        selector.set_latency(7, Duration::from_millis(1));
+        selector.set_latency(3, Duration::from_millis(2));
+        assert_eq!(vec![7, 3], selector.select(&peers, 2));
+        check_selection(&selector.select(&peers, 20), 10, 10);
+        // Estimates move towards new samples.
+        selector.observe_latency(3, Duration::from_millis(10));
+        assert_eq!(Some(Duration::from_millis(3)), selector.latency(&3));
+    }
+}
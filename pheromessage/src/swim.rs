@@ -0,0 +1,962 @@
+//! A SWIM-style failure detector and membership protocol (after "SWIM: Scalable
+//! Weakly-consistent Infection-style Process Group Membership Protocol" by Das et al.).
+//! Every node periodically probes one member, asks a few others to probe it indirectly if it
+//! doesn't answer in time, suspects it if that fails too, and declares it dead if the suspicion
+//! isn't refuted in time. Changes in membership are disseminated by piggybacking them on the
+//! protocol's own messages (pings and acks).
+//!
+//! Wrapping a gossip mechanism (e.g. `UniformGossip` or `PreferentialGossip`) in `SwimGossip`
+//! piggybacks the changes on the gossip's messages too, and applies every change to the
+//! gossip's peers as soon as it happens. A bare `Swim` only tracks membership: to keep a
+//! gossip's peers in line with it, call `update_peers()` after every `tick()` and `receive()`
+//! (or act on `drain_events()` directly).
+//!
+//! The protocol doesn't keep its own time: `tick()` should be called regularly (more often than
+//! the probe timeout) and `receive()` for every protocol message, both given the current time.
+//! This lets it work over any `Delivery` and be simulated deterministically.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    iter, mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{seq::SliceRandom, RngCore};
+
+use crate::{
+    peers::Membership, rng::ThreadLocalRng, Delivery, DeliveryReport, Gossip, ReceiveFrom,
+};
+
+/// The state of a member as far as a node can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemberState {
+    /// The member answered its last probe (or hasn't been probed yet).
+    Alive,
+    /// The member failed to answer a probe and will be declared dead unless it refutes that.
+    Suspect,
+    /// The member is considered to have failed.
+    Dead,
+}
+
+/// An update about the state of a member (by ID `K`), as disseminated between nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MemberUpdate<K> {
+    /// The member this update is about.
+    pub member: K,
+    /// The new state of the member.
+    pub state: MemberState,
+    /// The incarnation of the member this update is about. Only a member itself increments
+    /// its incarnation, to refute suspicions about it.
+    pub incarnation: u64,
+}
+
+/// The kind of a SWIM protocol message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SwimMessageKind<K> {
+    /// A direct probe, to be answered with an `Ack`.
+    Ping,
+    /// A request to probe the given member on behalf of the sender.
+    PingReq(K),
+    /// An answer to a probe (directly or relayed from an indirect probe).
+    Ack,
+}
+
+/// A SWIM protocol message between nodes with IDs of type `K`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SwimMessage<K> {
+    /// The node that sent the message.
+    pub from: K,
+    /// The sequence number of the probe this message is part of, used to match acks to probes.
+    pub sequence: u64,
+    /// What this message is for.
+    pub kind: SwimMessageKind<K>,
+    /// Membership updates piggybacked on this message.
+    pub updates: Vec<MemberUpdate<K>>,
+}
+
+/// A change in membership that users of the membership (e.g. gossip) need to know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MembershipEvent<K> {
+    /// The member came back to life and should be gossipped to again.
+    Joined(K),
+    /// The member was declared dead and should no longer be gossipped to.
+    Failed(K),
+}
+
+/// The configuration of the SWIM protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SwimConfig {
+    /// The time between probes, which is also how long a probe has to succeed (directly or
+    /// indirectly) before the probed member is suspected.
+    pub protocol_period: Duration,
+    /// How long to wait for a direct answer to a probe before asking other members to probe
+    /// indirectly (should be less than `protocol_period`).
+    pub probe_timeout: Duration,
+    /// How many members to ask to probe indirectly.
+    pub indirect_probes: usize,
+    /// How long a member stays suspected before it's declared dead.
+    pub suspicion_timeout: Duration,
+    /// The maximum number of updates to piggyback on every message.
+    pub max_piggyback: usize,
+    /// Every update is piggybacked `retransmit_multiplier * log2(number of members)` times
+    /// before it's dropped.
+    pub retransmit_multiplier: usize,
+}
+
+impl Default for SwimConfig {
+    fn default() -> Self {
+        Self {
+            protocol_period: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(300),
+            indirect_probes: 3,
+            suspicion_timeout: Duration::from_secs(5),
+            max_piggyback: 8,
+            retransmit_multiplier: 3,
+        }
+    }
+}
+
+/// What a node knows about a member.
+struct Member<P> {
+    /// The endpoint to send messages to the member.
+    endpoint: P,
+    state: MemberState,
+    incarnation: u64,
+    /// When the member was last suspected (if it's currently suspect).
+    suspected_at: Option<Instant>,
+}
+
+/// A probe in progress.
+struct Probe<K> {
+    target: K,
+    sequence: u64,
+    started: Instant,
+    indirect_sent: bool,
+}
+
+/// An indirect probe being done on behalf of another node.
+struct Relay<K> {
+    requester: K,
+    /// The sequence number of the requester's probe.
+    sequence: u64,
+    started: Instant,
+}
+
+/// A node in the SWIM membership protocol, identified by an ID of type `K`, that talks to
+/// other members through endpoints of type `P` using the delivery mechanism `D`, and chooses
+/// members to probe using the random number generator `R`.
+/// Delivery errors are treated like lost messages: a member that can't be reached will just
+/// fail its probes and eventually be declared dead.
+pub struct Swim<K, P, D, R = ThreadLocalRng> {
+    /// My own ID.
+    id: K,
+    /// My own incarnation, incremented to refute suspicions about me.
+    incarnation: u64,
+    /// What I know about every member (including dead ones).
+    members: HashMap<K, Member<P>>,
+    /// The IDs of all members in the order they became known, for deterministic iteration.
+    member_ids: Vec<K>,
+    config: SwimConfig,
+    delivery: D,
+    rng: R,
+    /// The members left to probe in this round (in random order).
+    probe_order: Vec<K>,
+    /// The probe in progress, if any.
+    probe: Option<Probe<K>>,
+    /// When the last probe was started.
+    last_probe: Option<Instant>,
+    /// The indirect probes I'm doing for others, by the sequence number of my own ping.
+    relays: HashMap<u64, Relay<K>>,
+    next_sequence: u64,
+    /// The updates to piggyback on messages, with the number of times left to send each,
+    /// freshest first.
+    updates: VecDeque<(MemberUpdate<K>, usize)>,
+    /// The membership changes not yet handed out by `drain_events()`.
+    events: Vec<MembershipEvent<K>>,
+}
+
+impl<K, P, D> Swim<K, P, D>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Create a new SWIM node with the given `id` that starts out knowing the given `members`
+    /// (by ID and endpoint), and talks to them using the given `delivery` mechanism.
+    pub fn create(id: K, members: Vec<(K, P)>, config: SwimConfig, delivery: D) -> Swim<K, P, D> {
+        let mut swim = Swim {
+            id,
+            incarnation: 0,
+            members: HashMap::new(),
+            member_ids: Vec::new(),
+            config,
+            delivery,
+            rng: ThreadLocalRng,
+            probe_order: Vec::new(),
+            probe: None,
+            last_probe: None,
+            relays: HashMap::new(),
+            next_sequence: 0,
+            updates: VecDeque::new(),
+            events: Vec::new(),
+        };
+        for (member, endpoint) in members {
+            swim.add_member(member, endpoint);
+        }
+        // The initial members aren't news to anyone.
+        swim.events.clear();
+        swim
+    }
+}
+
+impl<K, P, D, R> Swim<K, P, D, R> {
+    /// Use the given `rng` to choose members to probe from now on.
+    pub fn with_rng<R2>(self, rng: R2) -> Swim<K, P, D, R2> {
+        Swim {
+            id: self.id,
+            incarnation: self.incarnation,
+            members: self.members,
+            member_ids: self.member_ids,
+            config: self.config,
+            delivery: self.delivery,
+            rng,
+            probe_order: self.probe_order,
+            probe: self.probe,
+            last_probe: self.last_probe,
+            relays: self.relays,
+            next_sequence: self.next_sequence,
+            updates: self.updates,
+            events: self.events,
+        }
+    }
+
+    /// My own ID.
+    pub fn id(&self) -> &K {
+        &self.id
+    }
+
+    /// My own incarnation.
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    /// Take the membership changes that happened since the last call.
+    pub fn drain_events(&mut self) -> Vec<MembershipEvent<K>> {
+        mem::take(&mut self.events)
+    }
+}
+
+impl<K, P, D, R> Swim<K, P, D, R>
+where
+    K: Eq + Hash + Clone,
+    R: RngCore,
+{
+    /// The state of the given member, if it's known at all.
+    pub fn state(&self, member: &K) -> Option<MemberState> {
+        self.members.get(member).map(|m| m.state)
+    }
+
+    /// The members (by ID and endpoint) that aren't considered dead.
+    pub fn live_members(&self) -> impl Iterator<Item = (&K, &P)> {
+        self.member_ids
+            .iter()
+            .map(|id| (id, &self.members[id]))
+            .filter(|(_, m)| m.state != MemberState::Dead)
+            .map(|(id, m)| (id, &m.endpoint))
+    }
+
+    /// Start probing the given member (known out of band) if it isn't known yet.
+    pub fn add_member(&mut self, member: K, endpoint: P) {
+        if member == self.id || self.members.contains_key(&member) {
+            return;
+        }
+        self.members.insert(
+            member.clone(),
+            Member {
+                endpoint,
+                state: MemberState::Alive,
+                incarnation: 0,
+                suspected_at: None,
+            },
+        );
+        self.member_ids.push(member.clone());
+        self.events.push(MembershipEvent::Joined(member));
+    }
+
+    /// Apply the membership changes that happened since the last call to the given gossip,
+    /// where `peer_for` gives the gossip peer for a member ID.
+    pub fn update_peers<G, GP, F>(&mut self, gossip: &mut G, mut peer_for: F)
+    where
+        G: Membership<GP>,
+        F: FnMut(&K) -> GP,
+    {
+        for event in self.drain_events() {
+            match event {
+                MembershipEvent::Joined(member) => gossip.join(peer_for(&member)),
+                MembershipEvent::Failed(member) => gossip.leave(&peer_for(&member)),
+            }
+        }
+    }
+
+    /// Move the protocol forward to the given time: expire suspicions, escalate or fail
+    /// the current probe, and start a new one every protocol period.
+    pub fn tick(&mut self, now: Instant)
+    where
+        D: Delivery<SwimMessage<K>, P>,
+    {
+        // Suspects that didn't refute the suspicion in time are dead.
+        let expired: Vec<_> = self
+            .member_ids
+            .iter()
+            .filter_map(|id| {
+                let member = &self.members[id];
+                match member.suspected_at {
+                    Some(at) if now >= at + self.config.suspicion_timeout => {
+                        Some((id.clone(), member.incarnation))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        for (member, incarnation) in expired {
+            self.apply(
+                MemberUpdate {
+                    member,
+                    state: MemberState::Dead,
+                    incarnation,
+                },
+                now,
+            );
+        }
+        let period = self.config.protocol_period;
+        self.relays.retain(|_, relay| now < relay.started + period);
+        // Move the current probe along.
+        if let Some(probe) = &self.probe {
+            if now >= probe.started + period {
+                // No answer in a whole period - suspect the target.
+                let target = probe.target.clone();
+                self.probe = None;
+                let member = &self.members[&target];
+                if member.state == MemberState::Alive {
+                    let incarnation = member.incarnation;
+                    self.apply(
+                        MemberUpdate {
+                            member: target,
+                            state: MemberState::Suspect,
+                            incarnation,
+                        },
+                        now,
+                    );
+                }
+            } else if !probe.indirect_sent && now >= probe.started + self.config.probe_timeout {
+                // No direct answer - ask others to probe it for me.
+                let (target, sequence) = (probe.target.clone(), probe.sequence);
+                let mut helpers: Vec<_> = self
+                    .member_ids
+                    .iter()
+                    .filter(|id| **id != target && self.members[*id].state == MemberState::Alive)
+                    .cloned()
+                    .collect();
+                helpers.shuffle(&mut self.rng);
+                helpers.truncate(self.config.indirect_probes);
+                for helper in helpers {
+                    self.send(&helper, sequence, SwimMessageKind::PingReq(target.clone()));
+                }
+                if let Some(probe) = &mut self.probe {
+                    probe.indirect_sent = true;
+                }
+            }
+        }
+        // Start a new probe every period.
+        if self.probe.is_none() && self.last_probe.is_none_or(|last| now >= last + period) {
+            if let Some(target) = self.next_probe_target() {
+                let sequence = self.next_sequence();
+                self.send(&target, sequence, SwimMessageKind::Ping);
+                self.probe = Some(Probe {
+                    target,
+                    sequence,
+                    started: now,
+                    indirect_sent: false,
+                });
+                self.last_probe = Some(now);
+            }
+        }
+    }
+
+    /// Handle the given protocol message received at the given time.
+    pub fn receive(&mut self, message: &SwimMessage<K>, now: Instant)
+    where
+        D: Delivery<SwimMessage<K>, P>,
+    {
+        for update in message.updates.iter() {
+            self.apply(update.clone(), now);
+        }
+        match &message.kind {
+            SwimMessageKind::Ping => {
+                self.send(&message.from, message.sequence, SwimMessageKind::Ack)
+            }
+            SwimMessageKind::PingReq(target) => {
+                let sequence = self.next_sequence();
+                self.relays.insert(
+                    sequence,
+                    Relay {
+                        requester: message.from.clone(),
+                        sequence: message.sequence,
+                        started: now,
+                    },
+                );
+                self.send(target, sequence, SwimMessageKind::Ping);
+            }
+            SwimMessageKind::Ack => {
+                if self
+                    .probe
+                    .as_ref()
+                    .is_some_and(|p| p.sequence == message.sequence)
+                {
+                    self.probe = None;
+                } else if let Some(relay) = self.relays.remove(&message.sequence) {
+                    self.send(&relay.requester, relay.sequence, SwimMessageKind::Ack);
+                }
+            }
+        }
+    }
+
+    /// Apply an update about a member if it's newer than what I know, and disseminate it.
+    fn apply(&mut self, update: MemberUpdate<K>, now: Instant) {
+        if update.member == self.id {
+            // Someone thinks I'm suspect or dead - refute it with a new incarnation.
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                self.disseminate(MemberUpdate {
+                    member: self.id.clone(),
+                    state: MemberState::Alive,
+                    incarnation: self.incarnation,
+                });
+            }
+            return;
+        }
+        let Some(member) = self.members.get_mut(&update.member) else {
+            // I can't talk to members I don't have an endpoint for.
+            return;
+        };
+        let newer = match (update.state, member.state) {
+            (MemberState::Alive, _) => update.incarnation > member.incarnation,
+            (MemberState::Suspect, MemberState::Alive) => update.incarnation >= member.incarnation,
+            (MemberState::Suspect, MemberState::Suspect) => update.incarnation > member.incarnation,
+            (MemberState::Suspect, MemberState::Dead) => false,
+            (MemberState::Dead, MemberState::Dead) => false,
+            (MemberState::Dead, _) => true,
+        };
+        if !newer {
+            return;
+        }
+        let was_dead = member.state == MemberState::Dead;
+        member.state = update.state;
+        member.incarnation = member.incarnation.max(update.incarnation);
+        member.suspected_at = (update.state == MemberState::Suspect).then_some(now);
+        match update.state {
+            MemberState::Dead => self
+                .events
+                .push(MembershipEvent::Failed(update.member.clone())),
+            MemberState::Alive if was_dead => self
+                .events
+                .push(MembershipEvent::Joined(update.member.clone())),
+            _ => {}
+        }
+        self.disseminate(update);
+    }
+
+    /// Queue the given update to be piggybacked on the next messages.
+    fn disseminate(&mut self, update: MemberUpdate<K>) {
+        self.updates.retain(|(u, _)| u.member != update.member);
+        let members = (self.members.len() + 2) as f64;
+        let transmissions = self.config.retransmit_multiplier * members.log2().ceil() as usize;
+        self.updates.push_front((update, transmissions.max(1)));
+    }
+
+    /// The updates to piggyback on the next message, without counting them as sent.
+    fn pending_updates(&self) -> Vec<MemberUpdate<K>> {
+        self.updates
+            .iter()
+            .take(self.config.max_piggyback)
+            .map(|(update, _)| update.clone())
+            .collect()
+    }
+
+    /// Take the updates to piggyback on the next message.
+    fn piggyback(&mut self) -> Vec<MemberUpdate<K>> {
+        let updates = self
+            .updates
+            .iter_mut()
+            .take(self.config.max_piggyback)
+            .map(|(update, left)| {
+                *left -= 1;
+                update.clone()
+            })
+            .collect();
+        self.updates.retain(|(_, left)| *left > 0);
+        updates
+    }
+
+    fn send(&mut self, to: &K, sequence: u64, kind: SwimMessageKind<K>)
+    where
+        D: Delivery<SwimMessage<K>, P>,
+    {
+        if !self.members.contains_key(to) {
+            return;
+        }
+        let message = SwimMessage {
+            from: self.id.clone(),
+            sequence,
+            kind,
+            updates: self.piggyback(),
+        };
+        // Failing to deliver is just a lost message as far as the protocol is concerned.
+        let _ = self
+            .delivery
+            .deliver(&message, iter::once(&self.members[to].endpoint));
+    }
+
+    /// The next member to probe: members are probed in a random order, every live member once
+    /// per round, which bounds the time it takes to detect a failure.
+    fn next_probe_target(&mut self) -> Option<K> {
+        for refilled in [false, true] {
+            while let Some(target) = self.probe_order.pop() {
+                if self
+                    .members
+                    .get(&target)
+                    .is_some_and(|m| m.state != MemberState::Dead)
+                {
+                    return Some(target);
+                }
+            }
+            if refilled {
+                break;
+            }
+            self.probe_order = self.live_members().map(|(id, _)| id.clone()).collect();
+            self.probe_order.shuffle(&mut self.rng);
+        }
+        None
+    }
+
+    fn next_sequence(&mut self) -> u64 {
+        self.next_sequence += 1;
+        self.next_sequence
+    }
+}
+
+/// A gossip message (`M`) with membership updates (about members with IDs `K`) piggybacked
+/// on it, as sent between the nodes of a `SwimGossip`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Piggybacked<M, K> {
+    /// The gossip message.
+    pub message: M,
+    /// Membership updates piggybacked on the message.
+    pub updates: Vec<MemberUpdate<K>>,
+}
+
+/// The membership updates for a `PiggybackDelivery` to piggyback on gossip messages.
+struct Outgoing<K> {
+    updates: Vec<MemberUpdate<K>>,
+    /// Whether any message was actually sent with them (gossip doesn't pass duplicates on).
+    sent: bool,
+}
+
+/// A handle to the membership updates a `PiggybackDelivery` piggybacks on gossip messages,
+/// for the `SwimGossip` whose gossip delivers its messages with it to fill in.
+pub struct Piggyback<K>(Arc<Mutex<Outgoing<K>>>);
+
+impl<K> Clone for Piggyback<K> {
+    fn clone(&self) -> Self {
+        Piggyback(self.0.clone())
+    }
+}
+
+/// A delivery mechanism for the gossip in a `SwimGossip`, that delivers gossip messages
+/// through the underlying delivery mechanism `D` with the node's membership updates (about
+/// members with IDs `K`) piggybacked on them (like `channel::TaggedChannels` does with the
+/// sender of every message).
+pub struct PiggybackDelivery<D, K> {
+    delivery: D,
+    piggyback: Piggyback<K>,
+}
+
+impl<D, K> PiggybackDelivery<D, K> {
+    /// Piggyback membership updates on the messages delivered with the given `delivery`.
+    pub fn new(delivery: D) -> PiggybackDelivery<D, K> {
+        PiggybackDelivery {
+            delivery,
+            piggyback: Piggyback(Arc::new(Mutex::new(Outgoing {
+                updates: Vec::new(),
+                sent: false,
+            }))),
+        }
+    }
+
+    /// The handle to give to the `SwimGossip` wrapping the gossip that uses this delivery.
+    pub fn piggyback(&self) -> Piggyback<K> {
+        self.piggyback.clone()
+    }
+}
+
+impl<D, K> PiggybackDelivery<D, K>
+where
+    K: Clone,
+{
+    /// The given message with the current updates piggybacked on it, to be sent to the given
+    /// number of endpoints.
+    fn piggybacked<M: Clone>(&self, message: &M, endpoints: usize) -> Piggybacked<M, K> {
+        let mut outgoing = self.piggyback.0.lock().unwrap();
+        outgoing.sent |= endpoints > 0;
+        Piggybacked {
+            message: message.clone(),
+            updates: outgoing.updates.clone(),
+        }
+    }
+}
+
+impl<M, P, D, K> Delivery<M, P> for PiggybackDelivery<D, K>
+where
+    D: Delivery<Piggybacked<M, K>, P>,
+    M: Clone,
+    K: Clone,
+{
+    type Error = D::Error;
+
+    fn deliver<'a, I>(&self, message: &M, endpoints: I) -> Result<(), Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let message = self.piggybacked(message, endpoints.len());
+        self.delivery.deliver(&message, endpoints)
+    }
+
+    fn deliver_each<'a, I>(
+        &self,
+        message: &M,
+        endpoints: I,
+    ) -> Result<DeliveryReport<Self::Error>, Self::Error>
+    where
+        I: ExactSizeIterator<Item = &'a P>,
+        P: 'a,
+    {
+        let message = self.piggybacked(message, endpoints.len());
+        self.delivery.deliver_each(&message, endpoints)
+    }
+}
+
+/// A gossip mechanism (`G`) whose peers are kept up to date by a SWIM node (with the same
+/// generic parameters as `Swim`): the node's membership updates are piggybacked on the gossip's
+/// messages as well as its own, and every membership change is applied to the gossip's peers as
+/// soon as it happens, with `peer_for` (`F`) giving the gossip peer for a member ID.
+/// The gossip has to deliver its messages with the `PiggybackDelivery` whose `piggyback()`
+/// handle the node was created with, and gossip messages arrive as `Piggybacked` messages.
+pub struct SwimGossip<G, K, P, D, F, R = ThreadLocalRng> {
+    swim: Swim<K, P, D, R>,
+    gossip: G,
+    piggyback: Piggyback<K>,
+    peer_for: F,
+}
+
+impl<G, K, P, D, F, R> SwimGossip<G, K, P, D, F, R> {
+    /// Keep the peers of the given `gossip` up to date with the given `swim` node, where the
+    /// gossip delivers its messages with the `PiggybackDelivery` the `piggyback` handle is from.
+    pub fn new(
+        swim: Swim<K, P, D, R>,
+        gossip: G,
+        piggyback: Piggyback<K>,
+        peer_for: F,
+    ) -> SwimGossip<G, K, P, D, F, R> {
+        SwimGossip {
+            swim,
+            gossip,
+            piggyback,
+            peer_for,
+        }
+    }
+
+    /// The SWIM node keeping the gossip's peers up to date.
+    pub fn swim(&self) -> &Swim<K, P, D, R> {
+        &self.swim
+    }
+
+    /// The gossip mechanism whose peers are kept up to date.
+    pub fn gossip(&self) -> &G {
+        &self.gossip
+    }
+
+    /// The gossip mechanism whose peers are kept up to date, to change it.
+    pub fn gossip_mut(&mut self) -> &mut G {
+        &mut self.gossip
+    }
+}
+
+impl<G, K, P, D, F, R, GP> SwimGossip<G, K, P, D, F, R>
+where
+    K: Eq + Hash + Clone,
+    R: RngCore,
+    F: FnMut(&K) -> GP,
+    G: Membership<GP>,
+{
+    /// Move the SWIM protocol forward to the given time (see `Swim::tick()`).
+    pub fn tick(&mut self, now: Instant)
+    where
+        D: Delivery<SwimMessage<K>, P>,
+    {
+        self.swim.tick(now);
+        self.swim.update_peers(&mut self.gossip, &mut self.peer_for);
+    }
+
+    /// Handle the given SWIM protocol message received at the given time (see `Swim::receive()`).
+    pub fn receive_swim(&mut self, message: &SwimMessage<K>, now: Instant)
+    where
+        D: Delivery<SwimMessage<K>, P>,
+    {
+        self.swim.receive(message, now);
+        self.swim.update_peers(&mut self.gossip, &mut self.peer_for);
+    }
+
+    /// Handle the given gossip message received at the given time: apply the membership updates
+    /// piggybacked on it, then have the gossip receive it.
+    pub fn receive<M, S>(
+        &mut self,
+        message: &Piggybacked<M, K>,
+        now: Instant,
+    ) -> Result<(), G::Error>
+    where
+        G: Gossip<M, S>,
+    {
+        self.apply_piggybacked(&message.updates, now);
+        self.send_with(|gossip| gossip.receive(&message.message))
+    }
+
+    /// Like `receive()`, for a gossip message from a known `sender` (see `ReceiveFrom`).
+    pub fn receive_from<M, S, Q>(
+        &mut self,
+        message: &Piggybacked<M, K>,
+        sender: &Q,
+        now: Instant,
+    ) -> Result<(), G::Error>
+    where
+        G: ReceiveFrom<M, S, Q>,
+    {
+        self.apply_piggybacked(&message.updates, now);
+        self.send_with(|gossip| gossip.receive_from(&message.message, sender))
+    }
+
+    /// Have the gossip update its data with a message of my own (see `Gossip::update()`).
+    pub fn update<M, S>(&mut self, message: &M) -> Result<(), G::Error>
+    where
+        G: Gossip<M, S>,
+    {
+        self.send_with(|gossip| gossip.update(message))
+    }
+
+    fn apply_piggybacked(&mut self, updates: &[MemberUpdate<K>], now: Instant) {
+        for update in updates {
+            self.swim.apply(update.clone(), now);
+        }
+        self.swim.update_peers(&mut self.gossip, &mut self.peer_for);
+    }
+
+    /// Have the gossip do something with my pending updates ready to be piggybacked, and count
+    /// them as sent if it passed anything on.
+    fn send_with<T>(&mut self, send: impl FnOnce(&mut G) -> T) -> T {
+        *self.piggyback.0.lock().unwrap() = Outgoing {
+            updates: self.swim.pending_updates(),
+            sent: false,
+        };
+        let result = send(&mut self.gossip);
+        if mem::take(&mut self.piggyback.0.lock().unwrap().sent) {
+            self.swim.piggyback();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, sync::mpsc};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        channel::{ChannelEndpoint, Channels, CHANNELS},
+        peers::PeerSet,
+        tests::Network,
+        UniformGossip,
+    };
+
+    type Endpoint = ChannelEndpoint<SwimMessage<usize>>;
+    type Node = Swim<usize, Endpoint, Channels, StdRng>;
+    /// The receiver for a node, or `None` if it crashed.
+    type Receiver = Option<mpsc::Receiver<SwimMessage<usize>>>;
+
+    fn config() -> SwimConfig {
+        SwimConfig {
+            protocol_period: Duration::from_millis(100),
+            probe_timeout: Duration::from_millis(30),
+            indirect_probes: 2,
+            suspicion_timeout: Duration::from_millis(300),
+            ..SwimConfig::default()
+        }
+    }
+
+    /// Create a fully connected network of the given size over channels.
+    fn network(size: usize) -> (Vec<Node>, Vec<Receiver>) {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..size).map(|_| mpsc::channel()).unzip();
+        let endpoints: Vec<_> = senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, sender)| ChannelEndpoint::new(i, sender))
+            .collect();
+        let nodes = (0..size)
+            .map(|i| {
+                let members = endpoints.iter().cloned().enumerate().collect();
+                Swim::create(i, members, config(), CHANNELS)
+                    .with_rng(StdRng::seed_from_u64(i as u64))
+            })
+            .collect();
+        (nodes, receivers.into_iter().map(Some).collect())
+    }
+
+    /// Run the live nodes (those with receivers) for the given number of 10ms steps.
+    fn run(nodes: &mut [Node], receivers: &[Receiver], start: Instant, steps: u32) {
+        for step in 0..steps {
+            let now = start + Duration::from_millis(10) * step;
+            for (node, receiver) in nodes.iter_mut().zip(receivers) {
+                let Some(receiver) = receiver else { continue };
+                node.tick(now);
+                while let Ok(message) = receiver.try_recv() {
+                    node.receive(&message, now);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detects_crash() {
+        let (mut nodes, mut receivers) = network(5);
+        let start = Instant::now();
+        run(&mut nodes, &receivers, start, 50);
+        assert!(nodes
+            .iter_mut()
+            .all(|n| n.live_members().count() == 4 && n.drain_events().is_empty()));
+        // Crash the last node by dropping its receiver.
+        receivers[4] = None;
+        run(&mut nodes, &receivers, start + Duration::from_secs(1), 300);
+        for node in nodes.iter().take(4) {
+            assert_eq!(Some(MemberState::Dead), node.state(&4));
+            assert_eq!(3, node.live_members().count());
+        }
+        // The failure gets applied to the gossip peers.
+        let mut gossip: UniformGossip<_, _, _, usize> = UniformGossip::create(
+            vec![1, 2, 3, 4],
+            2,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        );
+        nodes[0].update_peers(&mut gossip, |member| *member);
+        let mut peers = gossip.peers.as_slice().to_vec();
+        peers.sort();
+        assert_eq!(vec![1, 2, 3], peers);
+    }
+
+    /// Membership changes travel on gossip messages, and are applied to the gossip right away.
+    #[test]
+    fn piggybacks_on_gossip() {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..3).map(|_| mpsc::channel()).unzip();
+        let endpoints: Vec<ChannelEndpoint<Piggybacked<usize, usize>>> = senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, sender)| ChannelEndpoint::new(i, sender))
+            .collect();
+        let (swims, swim_receivers) = network(3);
+        let mut nodes: Vec<_> = swims
+            .into_iter()
+            .enumerate()
+            .map(|(i, swim)| {
+                let delivery = PiggybackDelivery::new(CHANNELS);
+                let piggyback = delivery.piggyback();
+                let peers = endpoints.iter().filter(|e| e.index() != i).cloned();
+                let gossip: UniformGossip<_, (), _, usize> =
+                    UniformGossip::create(peers.collect(), 2, (), delivery);
+                let endpoints = endpoints.clone();
+                SwimGossip::new(swim, gossip, piggyback, move |member: &usize| {
+                    endpoints[*member].clone()
+                })
+            })
+            .collect();
+        fn indices<M>(peers: &PeerSet<ChannelEndpoint<M>>) -> Vec<usize> {
+            let mut indices: Vec<_> = peers.iter().map(ChannelEndpoint::index).collect();
+            indices.sort();
+            indices
+        }
+        let now = Instant::now();
+        // Node 0 hears that node 2 is dead, and stops gossipping to it.
+        nodes[0].receive_swim(
+            &SwimMessage {
+                from: 1,
+                sequence: 0,
+                kind: SwimMessageKind::Ack,
+                updates: vec![MemberUpdate {
+                    member: 2,
+                    state: MemberState::Dead,
+                    incarnation: 0,
+                }],
+            },
+            now,
+        );
+        assert_eq!(vec![1], indices(&nodes[0].gossip().peers));
+        nodes[0].update(&5).unwrap();
+        assert!(receivers[2].try_recv().is_err());
+        // Node 1 only hears about it through the gossip message.
+        let message = receivers[1].try_recv().unwrap();
+        assert_eq!(5, message.message);
+        assert_eq!(Some(MemberState::Alive), nodes[1].swim().state(&2));
+        nodes[1].receive(&message, now).unwrap();
+        assert!(swim_receivers[1].as_ref().unwrap().try_recv().is_err());
+        assert_eq!(Some(MemberState::Dead), nodes[1].swim().state(&2));
+        assert_eq!(vec![0], indices(&nodes[1].gossip().peers));
+        // And passes it on with the message.
+        assert_eq!(2, receivers[0].try_recv().unwrap().updates[0].member);
+        assert!(receivers[2].try_recv().is_err());
+    }
+
+    #[test]
+    fn refutes_suspicion() {
+        let (mut nodes, receivers) = network(2);
+        let now = Instant::now();
+        // Node 1 pings node 0, telling it that it's suspected.
+        nodes[0].receive(
+            &SwimMessage {
+                from: 1,
+                sequence: 7,
+                kind: SwimMessageKind::Ping,
+                updates: vec![MemberUpdate {
+                    member: 0,
+                    state: MemberState::Suspect,
+                    incarnation: 0,
+                }],
+            },
+            now,
+        );
+        assert_eq!(1, nodes[0].incarnation());
+        // The ack carries the refutation, which node 1 accepts.
+        let ack = receivers[1].as_ref().unwrap().try_recv().unwrap();
+        assert_eq!(SwimMessageKind::Ack, ack.kind);
+        assert_eq!(7, ack.sequence);
+        assert_eq!(
+            vec![MemberUpdate {
+                member: 0,
+                state: MemberState::Alive,
+                incarnation: 1,
+            }],
+            ack.updates
+        );
+        nodes[1].receive(&ack, now);
+        assert_eq!(Some(MemberState::Alive), nodes[1].state(&0));
+    }
+}
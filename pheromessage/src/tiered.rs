@@ -0,0 +1,566 @@
+//! Tiered gossip: a generalization of preferential gossip to any number of tiers of peers,
+//! where every tier gets word of updates before the next one.
+
+use std::{hash::Hash, marker::PhantomData};
+
+use crate::{
+    bootstrap::{PeerRole, Snapshot, StateSnapshot, StateTransfer},
+    gossip_observed, is_excluded,
+    leave::Departure,
+    observe::{GossipObserver, NoopObserver},
+    peers::{Membership, PeerSet},
+    seen::{SeenIds, SeenStore, UnboundedSeenStore},
+    select::{ExcludingSelector, Exclusion, PeerSelector, UniformSelector},
+    Delivery, Gossip, Message, ReceiveFrom, SharedData,
+};
+
+/// The configuration of a single tier in tiered gossip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TierConfig {
+    /// How many peers in this tier to reach out to when gossipping to it.
+    pub fanout: usize,
+    /// How many times a node in this tier has to see a message before it passes it on to
+    /// the next tier (`0` means never).
+    pub forward_after: usize,
+}
+
+/// A gossip mechanism that divides peers into tiers (e.g. core data centers, then regional ones,
+/// then edge nodes), where updates are first gossipped within the first tier, and every
+/// tier passes on updates to the next one after seeing them a configured number of times.
+///
+/// A node in tier `k` passes a message on to other nodes in tier `k` the first time it sees it,
+/// and to nodes in tier `k + 1` when it's seen it `forward_after` times (as configured for tier `k`).
+/// Once a tier has seen a message that many times, it's likely that most of that tier has
+/// already seen it, so it's time to let the next tier know.
+/// `PreferentialGossip` is the special case of two tiers, where primaries forward after seeing
+/// a message twice.
+/// `L` is the store used to keep track of how often messages (by ID `I`) have been seen,
+/// `C` is the strategy used to select which peers to gossip to, and `O` is the observer
+/// notified of the decisions made about every message.
+pub struct TieredGossip<
+    P,
+    S,
+    D,
+    I,
+    L = UnboundedSeenStore<I>,
+    C = UniformSelector,
+    O = NoopObserver,
+> {
+    /// The endpoints for the peers in every tier.
+    tiers: Vec<PeerSet<P>>,
+    /// The configuration of every tier.
+    configs: Vec<TierConfig>,
+    /// The tier I belong in.
+    tier: usize,
+    /// Count of how often I've seen each message by ID.
+    message_log: L,
+    /// The delivery mechanism to send gossip messages.
+    delivery: D,
+    /// The data being gossipped about.
+    data: S,
+    /// The strategy for selecting which peers to gossip to.
+    selector: C,
+    /// The observer of the gossip decisions.
+    observer: O,
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach.
+    replace_failed: bool,
+    _i: PhantomData<I>,
+}
+
+impl<P, S, D, I> TieredGossip<P, S, D, I>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new tiered gossip mechanism that will gossip to the given peers in each of the
+    /// `tiers` as configured by `configs` (which has to have the same length), with this node
+    /// itself in tier number `tier`, using the given `delivery` mechanism and maintaining the
+    /// given `data`.
+    /// Peers to gossip to within every tier are chosen at random (use `with_peer_selector()`
+    /// to select them differently).
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
+    pub fn create(
+        tiers: Vec<Vec<P>>,
+        configs: Vec<TierConfig>,
+        tier: usize,
+        data: S,
+        delivery: D,
+    ) -> TieredGossip<P, S, D, I>
+    where
+        I: Eq + Hash,
+    {
+        TieredGossip::create_with_seen_store(
+            tiers,
+            configs,
+            tier,
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
+    }
+}
+
+impl<P, S, D, I, L> TieredGossip<P, S, D, I, L>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new tiered gossip mechanism like `create()`, but keeping track of how
+    /// often messages have been seen in the given `message_log` store.
+    /// If the store's counts saturate, they have to go past the `forward_after` of this node's
+    /// tier (unless it's the last one), or the node would pass messages on to the next tier
+    /// never or on every sighting from then on.
+    pub fn create_with_seen_store(
+        tiers: Vec<Vec<P>>,
+        configs: Vec<TierConfig>,
+        tier: usize,
+        data: S,
+        delivery: D,
+        message_log: L,
+    ) -> TieredGossip<P, S, D, I, L>
+    where
+        L: SeenStore<I>,
+    {
+        assert_eq!(
+            tiers.len(),
+            configs.len(),
+            "Every tier needs a configuration"
+        );
+        assert!(tier < tiers.len(), "Node tier is out of range");
+        if let Some(max_count) = message_log.max_count() {
+            assert!(
+                tier + 1 == tiers.len() || configs[tier].forward_after < max_count,
+                "The seen store has to count past forward_after"
+            );
+        }
+        TieredGossip {
+            tiers: tiers.into_iter().map(PeerSet::from).collect(),
+            configs,
+            tier,
+            message_log,
+            delivery,
+            data,
+            selector: UniformSelector::new(),
+            observer: NoopObserver,
+            replace_failed: false,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> TieredGossip<P, S, D, I, L, C, O> {
+    /// Use the given `selector` to select which peers to gossip to from now on.
+    pub fn with_peer_selector<C2>(self, selector: C2) -> TieredGossip<P, S, D, I, L, C2, O> {
+        TieredGossip {
+            tiers: self.tiers,
+            configs: self.configs,
+            tier: self.tier,
+            message_log: self.message_log,
+            delivery: self.delivery,
+            data: self.data,
+            selector,
+            observer: self.observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Notify the given `observer` of the gossip decisions from now on.
+    pub fn with_observer<O2>(self, observer: O2) -> TieredGossip<P, S, D, I, L, C, O2> {
+        TieredGossip {
+            tiers: self.tiers,
+            configs: self.configs,
+            tier: self.tier,
+            message_log: self.message_log,
+            delivery: self.delivery,
+            data: self.data,
+            selector: self.selector,
+            observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Whether to pass a message on to other peers (in the same tier) instead of the ones it
+    /// failed to reach (by default, failed peers are just reported to the observer).
+    pub fn with_failed_peer_replacement(self, replace_failed: bool) -> Self {
+        TieredGossip {
+            replace_failed,
+            ..self
+        }
+    }
+
+    /// The observer of the gossip decisions.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The tier this node belongs in.
+    pub fn tier(&self) -> usize {
+        self.tier
+    }
+
+    /// The store keeping track of how often messages have been seen.
+    pub fn seen_messages(&self) -> &L {
+        &self.message_log
+    }
+
+    /// The delivery mechanism used to send gossip messages.
+    pub fn delivery(&self) -> &D {
+        &self.delivery
+    }
+
+    /// The peers in the given tier.
+    pub fn peers(&self, tier: usize) -> &[P] {
+        self.tiers[tier].as_slice()
+    }
+
+    /// Gossip the given message to the given tier, except any `excluded` peers.
+    fn gossip_to_tier<M>(
+        &mut self,
+        message: &M,
+        tier: usize,
+        excluded: Exclusion<P>,
+    ) -> Result<(), D::Error>
+    where
+        D: Delivery<M, P>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
+        gossip_observed(
+            &self.delivery,
+            &mut ExcludingSelector::new(&mut self.selector, excluded),
+            &mut self.observer,
+            message,
+            self.tiers[tier].as_slice(),
+            self.configs[tier].fanout,
+            self.replace_failed,
+        )
+    }
+
+    /// Receive the given message from a peer, not passing it on to any `excluded` peers.
+    fn receive_excluding<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        M: Message<I = I>,
+        D: Delivery<M, P>,
+        L: SeenStore<I>,
+        S: SharedData<M>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
+        // Update the amount of times I've seen this message.
+        let count_seen = self.message_log.see(message.id());
+        self.observer.on_seen_store_size(self.message_log.len());
+        if count_seen == 1 {
+            // This is the first time I've seen this message - update the data and
+            // pass it on within my tier.
+            self.observer.on_first_seen(message);
+            self.data.update(message);
+            self.gossip_to_tier(message, self.tier, excluded)?;
+        } else {
+            self.observer.on_duplicate(message, count_seen);
+        }
+        // If I've seen it enough times, it's time to let the next tier (if any) know.
+        let next_tier = self.tier + 1;
+        if next_tier < self.tiers.len() && count_seen == self.configs[self.tier].forward_after {
+            self.gossip_to_tier(message, next_tier, excluded)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P, S, D, I, L, C, O> TieredGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Add the given peer to the given tier, moving it there if it's already in another tier.
+    /// Returns `false` if it was already in that tier.
+    pub fn add_peer(&mut self, peer: P, tier: usize) -> bool {
+        match self.peer_tier(&peer) {
+            Some(current) if current == tier => return false,
+            Some(current) => {
+                self.tiers[current].remove(&peer);
+            }
+            None => {}
+        }
+        self.tiers[tier].insert(peer)
+    }
+
+    /// Remove the given peer from whichever tier it's in. Returns `false` if it wasn't a peer.
+    pub fn remove_peer(&mut self, peer: &P) -> bool {
+        self.tiers.iter_mut().any(|t| t.remove(peer).is_some())
+    }
+
+    /// The tier the given peer is in, if it's a peer at all.
+    pub fn peer_tier(&self, peer: &P) -> Option<usize> {
+        self.tiers.iter().position(|t| t.contains(peer))
+    }
+}
+
+/// New nodes join the last tier (and can be moved up with `add_peer()` later).
+impl<P, S, D, I, L, C, O> Membership<P> for TieredGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn join(&mut self, peer: P) {
+        if self.peer_tier(&peer).is_none() {
+            let last = self.tiers.len() - 1;
+            self.tiers[last].insert(peer);
+        }
+    }
+
+    fn leave(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+/// A leaving peer is replaced by its successor, if that's in a later tier, and a node succeeding
+/// a peer in an earlier tier moves up to that tier.
+impl<P, S, D, I, L, C, O> Departure<P> for TieredGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn peer_left(&mut self, peer: &P, successor: Option<&P>) {
+        let Some(tier) = self.peer_tier(peer) else {
+            return;
+        };
+        self.remove_peer(peer);
+        if let Some(successor) = successor {
+            if self.peer_tier(successor).is_some_and(|t| t > tier) {
+                self.add_peer(successor.clone(), tier);
+            }
+        }
+    }
+
+    fn succeed(&mut self, peer: &P) {
+        if let Some(tier) = self.peer_tier(peer) {
+            self.tier = self.tier.min(tier);
+            self.remove_peer(peer);
+        }
+    }
+}
+
+/// Peers in the first tier are primaries, and the rest are secondaries (which join the last
+/// tier, like new nodes).
+impl<P, S, D, I, L, C, O> StateTransfer<P, S::State, I> for TieredGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+    S: Snapshot,
+    L: SeenIds<I>,
+    I: Clone,
+{
+    fn peers_with_roles(&self) -> Vec<(P, PeerRole)> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .flat_map(|(tier, peers)| {
+                let role = if tier == 0 {
+                    PeerRole::Primary
+                } else {
+                    PeerRole::Secondary
+                };
+                peers.iter().map(move |peer| (peer.clone(), role))
+            })
+            .collect()
+    }
+
+    fn add_peer_with_role(&mut self, peer: P, role: PeerRole) {
+        match role {
+            PeerRole::Primary => {
+                self.add_peer(peer, 0);
+            }
+            PeerRole::Secondary => self.join(peer),
+        }
+    }
+
+    fn snapshot(&self) -> StateSnapshot<S::State, I> {
+        StateSnapshot {
+            data: self.data.snapshot(),
+            seen: self.message_log.seen_ids(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: StateSnapshot<S::State, I>) {
+        self.data.restore(snapshot.data);
+        // Count the messages as seen often enough to have been passed on to the next tier
+        // already, so seeing them again doesn't pass them on.
+        let times = self.configs[self.tier].forward_after.max(1);
+        for id in snapshot.seen {
+            if !self.message_log.contains(&id) {
+                for _ in 0..times {
+                    self.message_log.see(id.clone());
+                }
+            }
+        }
+    }
+}
+
+impl<P, S, D, M, I, L, C, O> Gossip<M, S> for TieredGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: Clone,
+{
+    type Error = D::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.receive_excluding(message, None)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.observer.on_update(message);
+        self.data.update(message);
+        self.message_log.see(message.id());
+        self.observer.on_seen_store_size(self.message_log.len());
+        // Updates always start from the first tier.
+        self.gossip_to_tier(message, 0, None)
+    }
+
+    fn data(&self) -> &S {
+        &self.data
+    }
+}
+
+impl<P, S, D, M, I, L, C, O, Q> ReceiveFrom<M, S, Q> for TieredGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: PartialEq<Q> + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.receive_excluding(message, Some(&|p: &P| is_excluded(p, sender, excluded)))
+    }
+}
+
+/// Gets the tier of a node given its global index, where the first `tier_sizes[0]` nodes are
+/// in the first tier, the next `tier_sizes[1]` nodes are in the second and so on.
+pub(crate) fn tier_for_node(tier_sizes: &[usize], global_node_index: usize) -> usize {
+    let mut end = 0;
+    for (tier, size) in tier_sizes.iter().enumerate() {
+        end += size;
+        if global_node_index < end {
+            return tier;
+        }
+    }
+    tier_sizes.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use crate::{bloom::BloomSeenStore, tests::Network};
+
+    fn config(fanout: usize, forward_after: usize) -> TierConfig {
+        TierConfig {
+            fanout,
+            forward_after,
+        }
+    }
+
+    /// Create a node in the middle tier of three tiers (endpoints 1-2, 3-4, 5-6), that forwards
+    /// after seeing a message three times.
+    fn middle_node() -> TieredGossip<usize, (), Network, usize> {
+        TieredGossip::create(
+            vec![vec![1, 2], vec![3, 4], vec![5, 6]],
+            vec![config(2, 2), config(2, 3), config(2, 2)],
+            1,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        )
+    }
+
+    /// The endpoints that got any message so far, sorted.
+    fn reached(gossip: &TieredGossip<usize, (), Network, usize>) -> Vec<usize> {
+        let mut reached: Vec<_> = gossip.delivery.0.borrow().keys().copied().collect();
+        reached.sort();
+        reached
+    }
+
+    #[test]
+    fn forwards_to_next_tier() {
+        let mut gossip = middle_node();
+        gossip.receive(&10).unwrap();
+        // First time: just my own tier.
+        assert_eq!(vec![3, 4], reached(&gossip));
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![3, 4], reached(&gossip));
+        // Third time: the next tier, but never the first one.
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![3, 4, 5, 6], reached(&gossip));
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![3, 4, 5, 6], reached(&gossip));
+        assert_eq!(1, gossip.delivery.0.borrow()[&5].len());
+    }
+
+    #[test]
+    #[should_panic(expected = "count past forward_after")]
+    fn seen_store_saturating_at_forward_after() {
+        TieredGossip::<usize, (), Network, usize, _>::create_with_seen_store(
+            vec![vec![1, 2], vec![3, 4]],
+            vec![config(2, 2), config(2, 0)],
+            0,
+            (),
+            Network(RefCell::new(HashMap::new())),
+            BloomSeenStore::new(100, 0.01, 2),
+        );
+    }
+
+    #[test]
+    fn update_starts_at_first_tier() {
+        let mut gossip = middle_node();
+        gossip.update(&10).unwrap();
+        assert_eq!(vec![1, 2], reached(&gossip));
+    }
+
+    #[test]
+    fn last_tier_stays_put() {
+        let mut gossip: TieredGossip<usize, (), Network, usize> = TieredGossip::create(
+            vec![vec![1], vec![2]],
+            vec![config(1, 1), config(1, 1)],
+            1,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        );
+        gossip.receive(&10).unwrap();
+        gossip.receive(&10).unwrap();
+        assert_eq!(vec![2], reached(&gossip));
+    }
+
+    #[test]
+    fn move_peers_between_tiers() {
+        let mut gossip = middle_node();
+        assert!(gossip.add_peer(7, 2));
+        assert!(!gossip.add_peer(7, 2));
+        // Moving a peer takes it out of its old tier.
+        assert!(gossip.add_peer(3, 0));
+        assert_eq!(Some(0), gossip.peer_tier(&3));
+        assert_eq!(vec![4], gossip.peers(1));
+        assert!(gossip.remove_peer(&1));
+        assert!(!gossip.remove_peer(&1));
+        gossip.update(&10).unwrap();
+        assert_eq!(vec![2, 3], reached(&gossip));
+    }
+
+    #[test]
+    fn tiers_for_nodes() {
+        let sizes = [2, 3, 1];
+        let tiers: Vec<_> = (0..6).map(|i| tier_for_node(&sizes, i)).collect();
+        assert_eq!(vec![0, 0, 1, 1, 1, 2], tiers);
+    }
+}
@@ -0,0 +1,414 @@
+//! Zone-aware gossip: nodes gossip uniformly within their own zone (e.g. a region or a data
+//! center), and a few gateway nodes in every zone pass messages on between zones.
+
+use std::{hash::Hash, marker::PhantomData};
+
+use crate::{
+    gossip_observed, is_excluded,
+    observe::{GossipObserver, NoopObserver},
+    peers::{Membership, PeerSet},
+    seen::{SeenStore, UnboundedSeenStore},
+    select::{ExcludingSelector, Exclusion, PeerSelector, UniformSelector},
+    Delivery, Gossip, Message, ReceiveFrom, SharedData,
+};
+
+/// The configuration of zone-aware gossip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ZoneConfig {
+    /// How many peers in its own zone a node gossips to.
+    pub fanout: usize,
+    /// How many gateways in other zones a gateway gossips to.
+    pub gateway_fanout: usize,
+}
+
+/// A gossip mechanism for a network divided into zones (e.g. regions), where every node
+/// gossips uniformly to peers within its own zone, and a few designated gateway nodes in
+/// every zone also gossip to the gateways of other zones.
+///
+/// Every node passes a message on the first time it sees it: to `fanout` peers in its own zone,
+/// and if it's a gateway also to `gateway_fanout` gateways in other zones. So a message
+/// crosses between zones only once a gateway got it through the gossip in its zone, which
+/// keeps most of the traffic within zones. Unlike `PreferentialGossip`, where all the primaries
+/// form a single global set, every zone has its own gateways.
+/// A node is a gateway if it knows about gateways in other zones.
+/// `L` is the store used to keep track of seen messages (by ID `I`), `C` is the strategy used
+/// to select which peers to gossip to, and `O` is the observer notified of the decisions made
+/// about every message.
+pub struct ZoneGossip<P, S, D, I, L = UnboundedSeenStore<I>, C = UniformSelector, O = NoopObserver>
+{
+    /// The endpoints for the other nodes in my zone (including its gateways).
+    zone_peers: PeerSet<P>,
+    /// The endpoints for the gateways of other zones (empty if I'm not a gateway).
+    remote_gateways: PeerSet<P>,
+    /// How many peers to gossip to.
+    config: ZoneConfig,
+    /// Record of which messages I've seen by ID.
+    message_log: L,
+    /// The delivery mechanism to send gossip messages.
+    delivery: D,
+    /// The data being gossipped about.
+    data: S,
+    /// The strategy for selecting which peers to gossip to.
+    selector: C,
+    /// The observer of the gossip decisions.
+    observer: O,
+    /// Whether to pass a message on to other peers instead of the ones it failed to reach.
+    replace_failed: bool,
+    _i: PhantomData<I>,
+}
+
+impl<P, S, D, I> ZoneGossip<P, S, D, I>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new zone-aware gossip mechanism that will gossip to the given peers in its own
+    /// zone, and (if it's a gateway) to the given gateways of other zones, as configured by
+    /// `config`, using the given `delivery` mechanism and maintaining the given `data`.
+    /// Nodes that aren't gateways should be given no `remote_gateways`.
+    /// Peers to gossip to are chosen at random (use `with_peer_selector()` to select them
+    /// differently).
+    /// Every message ID seen will be remembered forever - use `create_with_seen_store()`
+    /// to bound that.
+    pub fn create(
+        zone_peers: Vec<P>,
+        remote_gateways: Vec<P>,
+        config: ZoneConfig,
+        data: S,
+        delivery: D,
+    ) -> ZoneGossip<P, S, D, I> {
+        ZoneGossip::create_with_seen_store(
+            zone_peers,
+            remote_gateways,
+            config,
+            data,
+            delivery,
+            UnboundedSeenStore::default(),
+        )
+    }
+}
+
+impl<P, S, D, I, L> ZoneGossip<P, S, D, I, L>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Create a new zone-aware gossip mechanism like `create()`, but keeping track of seen
+    /// messages in the given `message_log` store.
+    pub fn create_with_seen_store(
+        zone_peers: Vec<P>,
+        remote_gateways: Vec<P>,
+        config: ZoneConfig,
+        data: S,
+        delivery: D,
+        message_log: L,
+    ) -> ZoneGossip<P, S, D, I, L> {
+        ZoneGossip {
+            zone_peers: zone_peers.into(),
+            remote_gateways: remote_gateways.into(),
+            config,
+            message_log,
+            delivery,
+            data,
+            selector: UniformSelector::new(),
+            observer: NoopObserver,
+            replace_failed: false,
+            _i: PhantomData,
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> ZoneGossip<P, S, D, I, L, C, O> {
+    /// Use the given `selector` to select which peers to gossip to from now on.
+    pub fn with_peer_selector<C2>(self, selector: C2) -> ZoneGossip<P, S, D, I, L, C2, O> {
+        ZoneGossip {
+            zone_peers: self.zone_peers,
+            remote_gateways: self.remote_gateways,
+            config: self.config,
+            message_log: self.message_log,
+            delivery: self.delivery,
+            data: self.data,
+            selector,
+            observer: self.observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Notify the given `observer` of the gossip decisions from now on.
+    pub fn with_observer<O2>(self, observer: O2) -> ZoneGossip<P, S, D, I, L, C, O2> {
+        ZoneGossip {
+            zone_peers: self.zone_peers,
+            remote_gateways: self.remote_gateways,
+            config: self.config,
+            message_log: self.message_log,
+            delivery: self.delivery,
+            data: self.data,
+            selector: self.selector,
+            observer,
+            replace_failed: self.replace_failed,
+            _i: PhantomData,
+        }
+    }
+
+    /// Whether to pass a message on to other peers (in the same zone, or other gateways)
+    /// instead of the ones it failed to reach (by default, failed peers are just reported to
+    /// the observer).
+    pub fn with_failed_peer_replacement(self, replace_failed: bool) -> Self {
+        ZoneGossip {
+            replace_failed,
+            ..self
+        }
+    }
+
+    /// The observer of the gossip decisions.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The store keeping track of seen messages.
+    pub fn seen_messages(&self) -> &L {
+        &self.message_log
+    }
+
+    /// The delivery mechanism used to send gossip messages.
+    pub fn delivery(&self) -> &D {
+        &self.delivery
+    }
+
+    /// The configuration of how many peers to gossip to.
+    pub fn config(&self) -> ZoneConfig {
+        self.config
+    }
+
+    /// The peers in my own zone.
+    pub fn zone_peers(&self) -> &[P] {
+        self.zone_peers.as_slice()
+    }
+
+    /// The gateways of other zones I pass messages on to.
+    pub fn remote_gateways(&self) -> &[P] {
+        self.remote_gateways.as_slice()
+    }
+
+    /// Checks if I'm a gateway, i.e. if I pass messages on to other zones.
+    pub fn is_gateway(&self) -> bool {
+        !self.remote_gateways.is_empty()
+    }
+
+    /// Pass the given message on to peers in my zone, and to other zones if I'm a gateway,
+    /// except any `excluded` peers.
+    fn gossip<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        D: Delivery<M, P>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
+        gossip_observed(
+            &self.delivery,
+            &mut ExcludingSelector::new(&mut self.selector, excluded),
+            &mut self.observer,
+            message,
+            self.zone_peers.as_slice(),
+            self.config.fanout,
+            self.replace_failed,
+        )?;
+        if self.remote_gateways.is_empty() {
+            return Ok(());
+        }
+        gossip_observed(
+            &self.delivery,
+            &mut ExcludingSelector::new(&mut self.selector, excluded),
+            &mut self.observer,
+            message,
+            self.remote_gateways.as_slice(),
+            self.config.gateway_fanout,
+            self.replace_failed,
+        )
+    }
+
+    /// Receive the given message from a peer, not passing it on to any `excluded` peers.
+    fn receive_excluding<M>(&mut self, message: &M, excluded: Exclusion<P>) -> Result<(), D::Error>
+    where
+        M: Message<I = I>,
+        D: Delivery<M, P>,
+        L: SeenStore<I>,
+        S: SharedData<M>,
+        C: PeerSelector<P>,
+        O: GossipObserver<M, P>,
+        P: Clone,
+    {
+        let count_seen = self.message_log.see(message.id());
+        self.observer.on_seen_store_size(self.message_log.len());
+        if count_seen == 1 {
+            // This is the first time I've seen this message - update the data and
+            // pass it on.
+            self.observer.on_first_seen(message);
+            self.data.update(message);
+            self.gossip(message, excluded)
+        } else {
+            self.observer.on_duplicate(message, count_seen);
+            Ok(())
+        }
+    }
+}
+
+impl<P, S, D, I, L, C, O> ZoneGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    /// Add the given peer in my own zone. Returns `false` if it was already there.
+    pub fn add_zone_peer(&mut self, peer: P) -> bool {
+        self.zone_peers.insert(peer)
+    }
+
+    /// Add the given gateway of another zone (which makes me a gateway). Returns `false` if
+    /// it was already there.
+    pub fn add_remote_gateway(&mut self, peer: P) -> bool {
+        self.remote_gateways.insert(peer)
+    }
+
+    /// Remove the given peer, whether it's in my zone or a gateway of another zone. Returns
+    /// `false` if it wasn't a peer.
+    pub fn remove_peer(&mut self, peer: &P) -> bool {
+        let in_zone = self.zone_peers.remove(peer).is_some();
+        self.remote_gateways.remove(peer).is_some() || in_zone
+    }
+}
+
+/// New nodes join my own zone (gateways of other zones have to be added with
+/// `add_remote_gateway()`).
+impl<P, S, D, I, L, C, O> Membership<P> for ZoneGossip<P, S, D, I, L, C, O>
+where
+    P: Eq + Hash + Clone,
+{
+    fn join(&mut self, peer: P) {
+        if !self.remote_gateways.contains(&peer) {
+            self.zone_peers.insert(peer);
+        }
+    }
+
+    fn leave(&mut self, peer: &P) {
+        self.remove_peer(peer);
+    }
+}
+
+impl<P, S, D, M, I, L, C, O> Gossip<M, S> for ZoneGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: Clone,
+{
+    type Error = D::Error;
+
+    fn receive(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.receive_excluding(message, None)
+    }
+
+    fn update(&mut self, message: &M) -> Result<(), Self::Error> {
+        self.observer.on_update(message);
+        self.data.update(message);
+        self.message_log.see(message.id());
+        self.observer.on_seen_store_size(self.message_log.len());
+        self.gossip(message, None)
+    }
+
+    fn data(&self) -> &S {
+        &self.data
+    }
+}
+
+impl<P, S, D, M, I, L, C, O, Q> ReceiveFrom<M, S, Q> for ZoneGossip<P, S, D, I, L, C, O>
+where
+    M: Message<I = I>,
+    D: Delivery<M, P>,
+    L: SeenStore<I>,
+    S: SharedData<M>,
+    C: PeerSelector<P>,
+    O: GossipObserver<M, P>,
+    P: PartialEq<Q> + Clone,
+{
+    fn receive_from_excluding(
+        &mut self,
+        message: &M,
+        sender: &Q,
+        excluded: &[Q],
+    ) -> Result<(), Self::Error> {
+        self.receive_excluding(message, Some(&|p: &P| is_excluded(p, sender, excluded)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use crate::tests::Network;
+
+    const CONFIG: ZoneConfig = ZoneConfig {
+        fanout: 2,
+        gateway_fanout: 1,
+    };
+
+    /// Create a node with peers 1-2 in its zone, and the given gateways of other zones.
+    fn node(remote_gateways: Vec<usize>) -> ZoneGossip<usize, (), Network, usize> {
+        ZoneGossip::create(
+            vec![1, 2],
+            remote_gateways,
+            CONFIG,
+            (),
+            Network(RefCell::new(HashMap::new())),
+        )
+    }
+
+    /// The endpoints that got any message so far, sorted.
+    fn reached(gossip: &ZoneGossip<usize, (), Network, usize>) -> Vec<usize> {
+        let mut reached: Vec<_> = gossip.delivery.0.borrow().keys().copied().collect();
+        reached.sort();
+        reached
+    }
+
+    #[test]
+    fn stays_in_zone() {
+        let mut gossip = node(vec![]);
+        assert!(!gossip.is_gateway());
+        gossip.receive(&10).unwrap();
+        gossip.update(&11).unwrap();
+        assert_eq!(vec![1, 2], reached(&gossip));
+    }
+
+    #[test]
+    fn gateway_crosses_zones() {
+        let mut gossip = node(vec![5, 6]);
+        assert!(gossip.is_gateway());
+        gossip.receive(&10).unwrap();
+        // My whole zone, and one of the other gateways.
+        let reached = reached(&gossip);
+        assert_eq!(3, reached.len());
+        assert_eq!(&[1, 2], &reached[..2]);
+        // Duplicates aren't passed on.
+        gossip.receive(&10).unwrap();
+        let total: usize = gossip.delivery.0.borrow().values().map(Vec::len).sum();
+        assert_eq!(3, total);
+    }
+
+    #[test]
+    fn membership() {
+        let mut gossip = node(vec![5]);
+        gossip.join(3);
+        // Gateways of other zones stay gateways.
+        gossip.join(5);
+        assert_eq!(vec![1, 2, 3], gossip.zone_peers());
+        assert_eq!(vec![5], gossip.remote_gateways());
+        gossip.leave(&5);
+        assert!(!gossip.is_gateway());
+        assert!(gossip.remove_peer(&1));
+        assert!(!gossip.remove_peer(&1));
+        assert!(gossip.add_remote_gateway(7));
+        gossip.update(&10).unwrap();
+        assert_eq!(vec![2, 3, 7], reached(&gossip));
+    }
+}